@@ -0,0 +1,30 @@
+use std::time::Duration;
+
+/// Summary statistics over the wall times of repeated runs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimingStats {
+    pub min: Duration,
+    pub max: Duration,
+    pub mean: Duration,
+    /// Population standard deviation
+    pub stddev: Duration,
+}
+
+impl TimingStats {
+    /// Statistics over `samples`, or None if there are none.
+    pub fn from_samples(samples: &[Duration]) -> Option<Self> {
+        let min = *samples.iter().min()?;
+        let max = *samples.iter().max()?;
+
+        let secs: Vec<f64> = samples.iter().map(Duration::as_secs_f64).collect();
+        let mean = secs.iter().sum::<f64>() / secs.len() as f64;
+        let variance = secs.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / secs.len() as f64;
+
+        Some(Self {
+            min,
+            max,
+            mean: Duration::from_secs_f64(mean),
+            stddev: Duration::from_secs_f64(variance.sqrt()),
+        })
+    }
+}
@@ -0,0 +1,147 @@
+use anyhow::{bail, Context, Result};
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+/// Capability names by number, as in linux/capability.h without CAP_
+const NAMES: [&str; 41] = [
+    "chown",
+    "dac_override",
+    "dac_read_search",
+    "fowner",
+    "fsetid",
+    "kill",
+    "setgid",
+    "setuid",
+    "setpcap",
+    "linux_immutable",
+    "net_bind_service",
+    "net_broadcast",
+    "net_admin",
+    "net_raw",
+    "ipc_lock",
+    "ipc_owner",
+    "sys_module",
+    "sys_rawio",
+    "sys_chroot",
+    "sys_ptrace",
+    "sys_pacct",
+    "sys_admin",
+    "sys_boot",
+    "sys_nice",
+    "sys_resource",
+    "sys_time",
+    "sys_tty_config",
+    "mknod",
+    "lease",
+    "audit_write",
+    "audit_control",
+    "setfcap",
+    "mac_override",
+    "mac_admin",
+    "syslog",
+    "wake_alarm",
+    "block_suspend",
+    "audit_read",
+    "perfmon",
+    "bpf",
+    "checkpoint_restore",
+];
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Parse a capability name such as "CAP_NET_BIND_SERVICE" or
+/// "net_bind_service" into its number.
+pub fn from_name(name: &str) -> Result<u32> {
+    let lower = name.to_ascii_lowercase();
+    let short = lower.strip_prefix("cap_").unwrap_or(&lower);
+    match NAMES.iter().position(|&known| known == short) {
+        Some(cap) => Ok(cap as u32),
+        None => bail!("unknown capability {:?}", name),
+    }
+}
+
+fn name(cap: u32) -> String {
+    format!("CAP_{}", NAMES[cap as usize].to_ascii_uppercase())
+}
+
+/// Those of the named capabilities missing from our effective set.
+pub fn missing(names: &[String]) -> Result<Vec<String>> {
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    if unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("capget");
+    }
+
+    let mut missing = Vec::new();
+    for name in names {
+        let cap = from_name(name)?;
+        if data[cap as usize / 32].effective & (1 << (cap % 32)) == 0 {
+            missing.push(self::name(cap));
+        }
+    }
+    Ok(missing)
+}
+
+/// Keep the permitted set when the next setuid leaves root, which would
+/// otherwise clear it. Effective is still cleared, and execve resets this.
+pub fn keep_across_setuid() -> Result<()> {
+    if unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("prctl(PR_SET_KEEPCAPS)");
+    }
+    Ok(())
+}
+
+/// Cut the permitted, effective and inheritable sets down to `caps` and
+/// raise them into the ambient set, so a program without file capabilities
+/// gets exactly those once exec'd as a non-root user.
+pub fn raise_ambient(caps: &[u32]) -> Result<()> {
+    let mut data = [CapData::default(); 2];
+    for &cap in caps {
+        let set = &mut data[cap as usize / 32];
+        let bit = 1 << (cap % 32);
+        set.effective |= bit;
+        set.permitted |= bit;
+        set.inheritable |= bit;
+    }
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    if unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) } != 0 {
+        let names: Vec<String> = caps.iter().map(|&cap| name(cap)).collect();
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("cannot keep {} across setuid", names.join(", ")));
+    }
+
+    for &cap in caps {
+        let ret = unsafe {
+            libc::prctl(
+                libc::PR_CAP_AMBIENT,
+                libc::PR_CAP_AMBIENT_RAISE,
+                cap as libc::c_ulong,
+                0,
+                0,
+            )
+        };
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("cannot raise {} into the ambient set", name(cap)));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,282 @@
+use crate::sync;
+use anyhow::{bail, Context, Result};
+use nix::sys::signal::{SigHandler, Signal};
+use nix::sys::wait::waitpid;
+use nix::unistd::{chown, fork, ForkResult, Gid, Pid, Uid};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::fd::OwnedFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Where the unified (v2) hierarchy has to be mounted
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+/// Groups all delegated jail cgroups under the root
+const RSJAIL_CGROUP: &str = "rsjail";
+/// Interface files the owner of a delegated cgroup needs to write
+const DELEGATED_FILES: [&str; 3] = ["cgroup.procs", "cgroup.threads", "cgroup.subtree_control"];
+
+/// How long removal waits for killed processes to leave the subtree
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+static DELEGATION_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A cgroup v2 subtree for one jail, killed and removed on drop, that can be
+/// delegated to the jail's root.
+///
+/// Its own cgroup has every available controller enabled for children, so
+/// by the "no internal processes" rule the jailed program starts in the
+/// `init` leaf below it, much like systemd's delegation. Limits go on the
+/// subtree's own cgroup, which is never handed over.
+///
+/// It has to be created before rsjail enters any namespace. Once in a new
+/// user namespace rsjail can neither move the child with a freshly opened
+/// cgroup.procs nor remove directories the jail's root owns, so the leaf's
+/// cgroup.procs is opened up front and removal is left to a cleaner process
+/// forked here, which also runs if rsjail dies.
+#[derive(Debug)]
+pub struct JailCgroup {
+    path: PathBuf,
+    procs: File,
+    cleaner: Pid,
+    /// Closing it tells the cleaner to remove the subtree
+    cleaner_wait: Option<OwnedFd>,
+}
+
+/// Counters from memory.events, covering the whole subtree
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryEvents {
+    /// Times memory.high was exceeded and the jail throttled
+    pub high: u64,
+    /// Times memory.max was about to be exceeded
+    pub max: u64,
+}
+
+impl MemoryEvents {
+    /// Parse the flat-keyed contents of a memory.events file.
+    pub fn parse(events: &str) -> Self {
+        let mut parsed = Self::default();
+        for line in events.lines() {
+            let Some((key, value)) = line.split_once(' ') else {
+                continue;
+            };
+            let value = value.trim().parse().unwrap_or(0);
+            match key {
+                "high" => parsed.high = value,
+                "max" => parsed.max = value,
+                _ => {}
+            }
+        }
+        parsed
+    }
+}
+
+impl JailCgroup {
+    /// Create `rsjail/<pid>-<n>`, and with an `owner`, the host ids root in
+    /// the jail maps to, hand it to them.
+    pub fn create(owner: Option<(Uid, Gid)>) -> Result<Self> {
+        let root = Path::new(CGROUP_ROOT);
+        if !root.join("cgroup.controllers").exists() {
+            bail!("jail cgroups need cgroup v2 mounted at {}", CGROUP_ROOT);
+        }
+
+        let parent = root.join(RSJAIL_CGROUP);
+        fs::create_dir_all(&parent)
+            .with_context(|| format!("cannot create cgroup {}", parent.display()))?;
+        enable_controllers(root)?;
+        enable_controllers(&parent)?;
+
+        let run = DELEGATION_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = parent.join(format!("{}-{}", std::process::id(), run));
+        fs::create_dir(&path)
+            .with_context(|| format!("cannot create cgroup {}", path.display()))?;
+        let leaf = path.join("init");
+        let prepared = prepare(&path, &leaf, owner).and_then(|()| {
+            Ok(OpenOptions::new()
+                .write(true)
+                .open(leaf.join("cgroup.procs"))?)
+        });
+        let procs = match prepared {
+            Ok(procs) => procs,
+            Err(e) => {
+                let _ = remove_cgroup(&path);
+                return Err(e);
+            }
+        };
+
+        let channel = sync::Channel::to_child()?;
+        let cleaner = match unsafe { fork() }? {
+            ForkResult::Child => {
+                channel.close_parent_end();
+                // `rsjail stop` and `down` signal every child of rsjail,
+                // this one has to outlive them
+                for signal in [Signal::SIGTERM, Signal::SIGINT, Signal::SIGHUP] {
+                    let _ = unsafe { nix::sys::signal::signal(signal, SigHandler::SigIgn) };
+                }
+                // Nothing is ever written, this returns once rsjail closes its
+                // end or dies
+                let _ = sync::wait_released(&channel.child);
+                drain(&path);
+                if let Err(e) = remove_cgroup(&path) {
+                    log::warn!("failed to remove cgroup {}: {}", path.display(), e);
+                }
+                unsafe { libc::_exit(0) };
+            }
+            ForkResult::Parent { child } => child,
+        };
+
+        log::debug!("created cgroup {}", path.display());
+        Ok(Self {
+            path,
+            procs,
+            cleaner,
+            cleaner_wait: Some(channel.parent),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Throttle the subtree above `bytes` instead of killing anything.
+    pub fn set_memory_high(&self, bytes: u64) -> Result<()> {
+        let file = self.path.join("memory.high");
+        if !file.exists() {
+            bail!("memory_high needs the memory controller, which is not available");
+        }
+        fs::write(&file, bytes.to_string())
+            .with_context(|| format!("cannot write {}", file.display()))
+    }
+
+    /// The subtree's memory.events, if the memory controller is enabled.
+    pub fn memory_events(&self) -> Option<MemoryEvents> {
+        let events = fs::read_to_string(self.path.join("memory.events")).ok()?;
+        Some(MemoryEvents::parse(&events))
+    }
+
+    /// Freeze or thaw the whole subtree, see `set_frozen`.
+    pub fn freeze(&self, frozen: bool) -> Result<()> {
+        set_frozen(&self.path, frozen)
+    }
+
+    /// Move `pid` into the leaf the jailed program starts in.
+    pub fn add(&mut self, pid: Pid) -> Result<()> {
+        self.procs
+            .write_all(pid.to_string().as_bytes())
+            .with_context(|| format!("cannot move {} into cgroup {}", pid, self.path.display()))
+    }
+}
+
+impl Drop for JailCgroup {
+    fn drop(&mut self) {
+        // Anything the jail left running in its subtree (cgroup.kill is 5.14+)
+        let _ = fs::write(self.path.join("cgroup.kill"), "1");
+        drop(self.cleaner_wait.take());
+        let _ = waitpid(self.cleaner, None);
+    }
+}
+
+/// Freeze every process in `cgroup` and below, or thaw them again, through
+/// cgroup.freeze (Linux 5.2+). Freezing is done once cgroup.events says
+/// so, which this waits a little for.
+pub fn set_frozen(cgroup: &Path, frozen: bool) -> Result<()> {
+    let file = cgroup.join("cgroup.freeze");
+    if !file.exists() {
+        bail!("the cgroup freezer needs cgroup v2 on Linux 5.2 or later");
+    }
+    fs::write(&file, if frozen { "1" } else { "0" })
+        .with_context(|| format!("cannot write {}", file.display()))?;
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    while is_frozen(cgroup) != Some(frozen) {
+        if Instant::now() >= deadline {
+            log::warn!("cgroup {} hasn't settled yet", cgroup.display());
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok(())
+}
+
+/// Whether `cgroup` is frozen, per its cgroup.events. None without one or
+/// without the freezer.
+pub fn is_frozen(cgroup: &Path) -> Option<bool> {
+    let events = fs::read_to_string(cgroup.join("cgroup.events")).ok()?;
+    events.lines().find_map(|line| match line {
+        "frozen 1" => Some(true),
+        "frozen 0" => Some(false),
+        _ => None,
+    })
+}
+
+/// The cgroup v2 directory `pid` is in, as the host sees it.
+pub fn of_process(pid: Pid) -> Option<PathBuf> {
+    let cgroups = fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()?;
+    let path = cgroups.lines().find_map(|line| line.strip_prefix("0::"))?;
+    Some(Path::new(CGROUP_ROOT).join(path.trim_start_matches('/')))
+}
+
+/// Enable the controllers in `cgroup` and its `leaf`, and hand both over
+/// if there is an owner.
+fn prepare(cgroup: &Path, leaf: &Path, owner: Option<(Uid, Gid)>) -> Result<()> {
+    enable_controllers(cgroup)?;
+    fs::create_dir(leaf)?;
+    let Some(owner) = owner else {
+        return Ok(());
+    };
+    for dir in [cgroup, leaf] {
+        chown(dir, Some(owner.0), Some(owner.1))?;
+        for file in DELEGATED_FILES {
+            chown(&dir.join(file), Some(owner.0), Some(owner.1))?;
+        }
+    }
+    Ok(())
+}
+
+/// Wait a little for killed processes to leave `cgroup`, which can't be
+/// removed while populated.
+fn drain(cgroup: &Path) {
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    while Instant::now() < deadline {
+        match fs::read_to_string(cgroup.join("cgroup.events")) {
+            Ok(events) if events.lines().any(|line| line == "populated 1") => {
+                thread::sleep(Duration::from_millis(10));
+            }
+            _ => return,
+        }
+    }
+}
+
+/// Enable every controller available in `cgroup` for its children. A
+/// controller that can't be enabled is skipped rather than failing the jail.
+fn enable_controllers(cgroup: &Path) -> Result<()> {
+    let available = fs::read_to_string(cgroup.join("cgroup.controllers"))?;
+    for controller in available.split_whitespace() {
+        if let Err(e) = fs::write(
+            cgroup.join("cgroup.subtree_control"),
+            format!("+{}", controller),
+        ) {
+            log::debug!(
+                "cannot enable {} below {}: {}",
+                controller,
+                cgroup.display(),
+                e
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Remove `cgroup` and any cgroups created beneath it, deepest first.
+/// Cgroup directories only ever contain interface files, which rmdir takes
+/// care of.
+fn remove_cgroup(cgroup: &Path) -> std::io::Result<()> {
+    for entry in fs::read_dir(cgroup)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            remove_cgroup(&entry.path())?;
+        }
+    }
+    fs::remove_dir(cgroup)
+}
@@ -0,0 +1,292 @@
+use crate::config::parse_error;
+use anyhow::{bail, Context, Result};
+use nix::errno::Errno;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::{setsid, Pid};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, OpenOptions};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Several jails that `rsjail up` starts and `rsjail down` stops together,
+/// read from a JSON file like
+///
+/// ```json
+/// {"jails": {"db": {"config": "db.json"},
+///            "web": {"config": "web.json", "depends_on": ["db"]}}}
+/// ```
+///
+/// JSON only: a file named .yaml or .yml is refused rather than misread.
+/// Config paths are relative to the file. Each jail runs under an rsjail
+/// of its own, detached, which keeps its pid and output in
+/// `.rsjail-<file stem>/` next to the file.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ComposeFile {
+    pub jails: BTreeMap<String, ComposeJail>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ComposeJail {
+    pub config: String,
+    /// Jails started before this one and stopped after it
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+}
+
+impl ComposeFile {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "yaml" || extension == "yml")
+        {
+            bail!(
+                "{}: compose files are JSON, YAML is not supported",
+                path.display()
+            );
+        }
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("cannot read compose file {}", path.display()))?;
+        let mut compose: ComposeFile =
+            serde_json::from_str(&content).map_err(|e| parse_error(path, &e))?;
+        compose.path = path.to_path_buf();
+
+        if let Some(name) = compose
+            .jails
+            .keys()
+            .find(|name| name.is_empty() || name.contains('/'))
+        {
+            bail!("jail name {:?} must be non-empty and without '/'", name);
+        }
+        compose.start_order()?;
+        Ok(compose)
+    }
+
+    /// Every jail after the ones it depends on.
+    pub fn start_order(&self) -> Result<Vec<&str>> {
+        fn visit<'a>(
+            compose: &'a ComposeFile,
+            name: &'a str,
+            visiting: &mut Vec<&'a str>,
+            order: &mut Vec<&'a str>,
+        ) -> Result<()> {
+            if order.contains(&name) {
+                return Ok(());
+            }
+            if visiting.contains(&name) {
+                visiting.push(name);
+                bail!("jails depend on each other: {}", visiting.join(" -> "));
+            }
+            visiting.push(name);
+            for dependency in &compose.jails[name].depends_on {
+                if !compose.jails.contains_key(dependency) {
+                    bail!(
+                        "jail {} depends on {}, which isn't defined",
+                        name,
+                        dependency
+                    );
+                }
+                visit(compose, dependency, visiting, order)?;
+            }
+            visiting.pop();
+            order.push(name);
+            Ok(())
+        }
+
+        let mut order = Vec::new();
+        for name in self.jails.keys() {
+            visit(self, name, &mut Vec::new(), &mut order)?;
+        }
+        Ok(order)
+    }
+
+    /// Where jail `name`'s config is.
+    pub fn config_path(&self, name: &str) -> PathBuf {
+        self.dir().join(&self.jails[name].config)
+    }
+
+    /// Start each jail not already running, in dependency order, with
+    /// `rsjail` (this binary). Every config is checked with --validate-only
+    /// first, so a bad one doesn't leave the rest half started. Returns the
+    /// jails started and the pids of their rsjail.
+    pub fn up(&self, rsjail: &Path) -> Result<Vec<(String, Pid)>> {
+        let order = self.start_order()?;
+        for name in &order {
+            let output = self
+                .rsjail(rsjail, name)
+                .arg("--validate-only")
+                .output()
+                .with_context(|| format!("cannot run {}", rsjail.display()))?;
+            if !output.status.success() {
+                bail!(
+                    "jail {}: {}",
+                    name,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+        }
+
+        fs::create_dir_all(self.state_dir())?;
+        let mut started = Vec::new();
+        for name in order {
+            if let Some(pid) = self.running(name) {
+                log::info!("jail {} is already up (pid {})", name, pid);
+                continue;
+            }
+            let log_path = self.state_dir().join(format!("{}.log", name));
+            let log = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&log_path)
+                .with_context(|| format!("cannot open {}", log_path.display()))?;
+
+            let mut command = self.rsjail(rsjail, name);
+            command
+                .stdin(Stdio::null())
+                .stdout(log.try_clone()?)
+                .stderr(log);
+            // A session of its own, away from our terminal's hangup
+            unsafe {
+                command.pre_exec(|| {
+                    setsid()?;
+                    Ok(())
+                });
+            }
+            let child = command
+                .spawn()
+                .with_context(|| format!("cannot start jail {}", name))?;
+            let pid = Pid::from_raw(child.id() as i32);
+            fs::write(self.pid_file(name), format!("{}\n", pid))?;
+            started.push((name.to_string(), pid));
+        }
+        Ok(started)
+    }
+
+    /// Stop the jails `up` started, dependents first. Each one's program is
+    /// sent SIGTERM and, if its rsjail hasn't cleaned up and exited within
+    /// `timeout`, SIGKILL. Returns the jails that were running.
+    pub fn down(&self, timeout: Duration) -> Result<Vec<String>> {
+        let mut stopped = Vec::new();
+        for name in self.start_order()?.into_iter().rev() {
+            if let Some(pid) = self.running(name) {
+                stop(pid, timeout).with_context(|| format!("cannot stop jail {}", name))?;
+                stopped.push(name.to_string());
+            }
+            match fs::remove_file(self.pid_file(name)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(e).context("cannot remove pid file");
+                }
+                _ => {}
+            }
+        }
+        Ok(stopped)
+    }
+
+    /// The pid of the rsjail running jail `name`, per its pid file, if it
+    /// is still that process.
+    pub fn running(&self, name: &str) -> Option<Pid> {
+        let pid = fs::read_to_string(self.pid_file(name)).ok()?;
+        let pid = Pid::from_raw(pid.trim().parse().ok()?);
+        // The pid may have been reused since
+        let cmdline = fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+        let config = self.config_path(name);
+        cmdline
+            .split(|byte| *byte == 0)
+            .any(|arg| arg == config.as_os_str().as_bytes())
+            .then_some(pid)
+    }
+
+    fn rsjail(&self, rsjail: &Path, name: &str) -> Command {
+        let mut command = Command::new(rsjail);
+        // Relative paths in the config mean the same as running it from there
+        command
+            .arg("--config")
+            .arg(self.config_path(name))
+            .current_dir(self.dir());
+        command
+    }
+
+    fn dir(&self) -> PathBuf {
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        std::path::absolute(dir).unwrap_or_else(|_| dir.to_path_buf())
+    }
+
+    fn state_dir(&self) -> PathBuf {
+        let stem = self.path.file_stem().unwrap_or_default().to_string_lossy();
+        self.dir().join(format!(".rsjail-{}", stem))
+    }
+
+    fn pid_file(&self, name: &str) -> PathBuf {
+        self.state_dir().join(format!("{}.pid", name))
+    }
+}
+
+/// Signal the program under the rsjail `supervisor` until it and then
+/// rsjail are gone, SIGKILLing rsjail itself as a last resort.
+pub(crate) fn stop(supervisor: Pid, timeout: Duration) -> Result<()> {
+    for signal in [Signal::SIGTERM, Signal::SIGKILL] {
+        for child in children(supervisor) {
+            let _ = kill(child, signal);
+        }
+        if wait_gone(supervisor, timeout) {
+            return Ok(());
+        }
+    }
+    // die_with_parent takes the program along
+    kill(supervisor, Signal::SIGKILL)?;
+    if !wait_gone(supervisor, timeout) {
+        bail!("rsjail {} is still running", supervisor);
+    }
+    Ok(())
+}
+
+/// The children of every thread of `pid`, thread_mode forks from one.
+fn children(pid: Pid) -> BTreeSet<Pid> {
+    let Ok(tasks) = fs::read_dir(format!("/proc/{}/task", pid)) else {
+        return BTreeSet::new();
+    };
+    tasks
+        .filter_map(|task| fs::read_to_string(task.ok()?.path().join("children")).ok())
+        .flat_map(|children| {
+            children
+                .split_whitespace()
+                .filter_map(|child| child.parse().ok())
+                .map(Pid::from_raw)
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Wait for `pid` to exit, not counting the time until whoever it was
+/// reparented to reaps it.
+fn wait_gone(pid: Pid, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if is_zombie(pid) || kill(pid, None) == Err(Errno::ESRCH) {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Whether `pid` has exited and waits to be reaped.
+fn is_zombie(pid: Pid) -> bool {
+    let Ok(stat) = fs::read_to_string(format!("/proc/{}/stat", pid)) else {
+        return false;
+    };
+    // The state follows the command name, which may hold anything
+    stat.rsplit_once(") ")
+        .is_some_and(|(_, rest)| rest.starts_with('Z'))
+}
@@ -1,44 +1,729 @@
+use crate::env;
+use crate::glob;
+use crate::seccomp;
+use anyhow::{anyhow, bail, Context, Result};
+use nix::sys::signal::Signal;
+use nix::sys::statvfs::{statvfs, FsFlags};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Suffixes `parse_bytes` accepts, and what they multiply by
+const BYTE_UNITS: [(&str, u64); 4] = [
+    ("k", 1 << 10),
+    ("m", 1 << 20),
+    ("g", 1 << 30),
+    ("t", 1 << 40),
+];
+/// Suffixes `parse_seconds` accepts, and what they multiply by
+const SECOND_UNITS: [(&str, u64); 4] = [("s", 1), ("m", 60), ("h", 60 * 60), ("d", 24 * 60 * 60)];
+
+/// Prefix marking an `exec_args` entry as a file of arguments, one per line
+pub const ARG_FILE_PREFIX: &str = "@file:";
+
+/// Prefix marking a mount `src` as relative to `src_root`
+pub const SRC_ROOT_PREFIX: &str = "./";
+
+/// Sysctls that belong to the IPC namespace, besides everything in fs.mqueue
+const IPC_SYSCTLS: [&str; 8] = [
+    "kernel.msgmax",
+    "kernel.msgmnb",
+    "kernel.msgmni",
+    "kernel.sem",
+    "kernel.shm_rmid_forced",
+    "kernel.shmall",
+    "kernel.shmmax",
+    "kernel.shmmni",
+];
+
+/// Host directories that are almost certainly a mistake as a chroot_dir
+const SYSTEM_DIRS: [&str; 11] = [
+    "/bin", "/boot", "/dev", "/etc", "/lib", "/lib64", "/proc", "/sbin", "/sys", "/usr", "/var",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct JailConfig {
     pub name: String,
     pub hostname: Option<String>,
     pub chroot_dir: Option<String>,
+    #[serde(default)]
+    pub allow_root_chroot: bool, // Permit chroot_dir to be the host's "/"
+    #[serde(default)]
+    pub require_writable_chroot: bool, // Reject a chroot_dir on a read-only host filesystem
+    // How chroot_dir becomes the root; implied by chroot_dir when unset
+    pub fs_isolation: Option<FsIsolation>,
+    // What to do with whatever an earlier run left in chroot_dir
+    #[serde(default)]
+    pub chroot_policy: ChrootPolicy,
+    // Use this directory below chroot_dir as the root, once mounts are in place
+    pub root_subpath: Option<String>,
+    // Populate chroot_dir with a command the first time it is used
+    pub provision: Option<ProvisionConfig>,
     pub exec_bin: String,
     pub exec_args: Vec<String>,
+    #[serde(default)]
+    pub login_shell: bool, // Start exec_bin as a login shell ("-bash")
+    // Command to start exec_bin through, e.g. ["/usr/bin/valgrind", "-q"];
+    // exec_bin's path and exec_args after argv[0] are appended to it
+    pub wrapper: Option<Vec<String>>,
+    // Shared object to LD_PRELOAD into the program, ahead of any LD_PRELOAD
+    // from env. With auto_mount_libs it is bound in like exec_bin's libraries,
+    // as are the wrapper and both their libraries.
+    pub preload: Option<String>,
+    // Host binary to run exec_bin under, e.g. "/usr/bin/qemu-aarch64-static"
+    // for a foreign-architecture one, bound read-only at the same path.
+    // "auto" picks the host's qemu-user for exec_bin's architecture, and
+    // nothing when exec_bin is native.
+    pub interpreter: Option<String>,
+    // Start the program under an init as PID 1 of the jail's PID namespace,
+    // which passes on signals such as SIGTERM and reaps orphans: rsjail's
+    // own, or init_helper, a binary inside the jail started like tini with
+    // "--" and the command
+    #[serde(default)]
+    pub init: bool,
+    pub init_helper: Option<String>,
+    // Open the program (the wrapper when set) once inside the jail and exec
+    // it with fexecve, so the file checked is the file run. Not for #!
+    // scripts, whose interpreter can't reopen the close-on-exec fd.
+    #[serde(default)]
+    pub exec_via_fd: bool,
+    // PATH for the program, instead of the default of whichever of /bin,
+    // /usr/bin, /sbin and /usr/sbin the jail has
+    pub exec_path: Option<String>,
     
     // Namespace configuration
+    // The program becomes PID 1 of the new namespace: orphans are reparented
+    // to it and stay zombies unless it reaps them, and once it exits the
+    // kernel kills everything left inside. Without it, Jail::run adopts what
+    // the program leaves running and kills it once the program exits.
     pub clone_newpid: bool,
     pub clone_newnet: bool,
     pub clone_newns: bool,
     pub clone_newuts: bool,
     pub clone_newipc: bool,
     pub clone_newuser: bool,
+    // The jail's networking spelled out instead of implied by clone_newnet,
+    // which it overrides when set: host for the host's network and no net
+    // namespace, none for a namespace without any interface up, loopback
+    // for one with only lo up. veth, a namespace joined to the host by a
+    // veth pair, is not supported yet and fails validation.
+    pub network_mode: Option<NetworkMode>,
+    // Time namespace (5.6+), which like clone_newpid only applies to children.
+    // The offsets shift CLOCK_MONOTONIC and CLOCK_BOOTTIME inside it by that
+    // many seconds, e.g. to test code that looks at uptime.
+    #[serde(default)]
+    pub clone_newtime: bool,
+    pub time_offset_monotonic: Option<i64>,
+    pub time_offset_boottime: Option<i64>,
+    // Cgroup namespace (4.6+), entered by the child once it is in the jail
+    // cgroup of cgroup_delegate or memory_high, so that one reads as the
+    // root from inside and the host hierarchy above it stays hidden.
+    #[serde(default)]
+    pub clone_newcgroup: bool,
+    // Skip namespaces the kernel can't create instead of failing
+    #[serde(default)]
+    pub ignore_unsupported_ns: bool,
+    // Unshare the namespaces in rsjail itself and fork into them, as rsjail
+    // used to, instead of cloning the child straight into them. rsjail then
+    // sits in the jail's mount, network and other namespaces too, which
+    // keeps them around for freeze_on_exit to nsenter.
+    #[serde(default)]
+    pub unshare_in_parent: bool,
+    // Set the jail up from a thread of rsjail that unshares the namespaces
+    // for itself alone and forks the program once mounts, hostname and root
+    // are in place, instead of leaving all of it to a cloned child. The
+    // program still gets a process of its own. rsjail's other threads stay
+    // where they are, and a failed setup comes back as an error from run.
+    // Only the mount, net, uts, ipc and cgroup namespaces can be unshared by
+    // a single thread, so clone_newpid, clone_newuser and clone_newtime are
+    // rejected.
+    #[serde(default)]
+    pub thread_mode: bool,
     
-    // Resource limits
-    pub rlimit_as: Option<u64>,      // Memory limit
-    pub rlimit_cpu: Option<u64>,     // CPU time limit
-    pub rlimit_nofile: Option<u64>,  // File descriptor limit
+    // Resource limits. Byte and second values may also be written with a
+    // unit, "512M" or "1G" (powers of 1024) and "10s", "5m" or "2h".
+    #[serde(default, deserialize_with = "deserialize_byte_rlimit")]
+    pub rlimit_as: Option<Rlimit>,      // Memory limit
+    #[serde(default, deserialize_with = "deserialize_seconds_rlimit")]
+    pub rlimit_cpu: Option<Rlimit>,     // CPU time limit
+    pub rlimit_nofile: Option<Rlimit>,  // File descriptor limit
+    // Locked memory limit, for programs that mlock keys or buffers. Raising
+    // it past rsjail's hard limit takes CAP_SYS_RESOURCE on the host.
+    #[serde(default, deserialize_with = "deserialize_byte_rlimit")]
+    pub rlimit_memlock: Option<Rlimit>,
+    // Data segment limit, which since Linux 4.7 covers private mappings too
+    #[serde(default, deserialize_with = "deserialize_byte_rlimit")]
+    pub rlimit_data: Option<Rlimit>,
     
     // Mount points
     pub mounts: Vec<MountConfig>,
+    // Host directory that mount srcs starting with "./" are relative to, e.g.
+    // an image layer, so the same mounts work wherever the layer lives
+    pub src_root: Option<String>,
+    // Upper bound on mounts in total, counting the ones rsjail adds itself,
+    // so a config from an untrusted source can't ask for thousands
+    #[serde(default = "default_max_mounts")]
+    pub max_mounts: usize,
+    #[serde(default)]
+    pub auto_mount_libs: bool, // Bind the shared libraries exec_bin needs
+    // Bind the host's CA certificates read-only at the same paths, for
+    // programs that talk TLS: whichever of /etc/ssl/certs (with the
+    // /usr/share/ca-certificates its links point to) and /etc/pki/tls/certs
+    // (with /etc/pki/ca-trust/extracted) it has. Having neither fails, or
+    // with ca_certs_optional only warns.
+    #[serde(default)]
+    pub bind_ca_certs: bool,
+    #[serde(default)]
+    pub ca_certs_optional: bool,
+    #[serde(default)]
+    pub mount_proc: bool, // Fresh procfs at /proc
+    // hidepid for that procfs: 1 keeps other users' /proc/<pid> dirs from
+    // being read, 2 hides them altogether. Members of proc_gid see them all.
+    pub proc_hidepid: Option<u8>,
+    pub proc_gid: Option<u32>,
+    #[serde(default)]
+    pub mount_sys: bool, // Read-only sysfs at /sys
+    // Hide the sensitive /proc and /sys entries OCI runtimes mask by default
+    // whenever mount_proc or mount_sys is on
+    #[serde(default = "default_true")]
+    pub default_proc_mask: bool,
+    #[serde(default)]
+    pub mask_paths: Vec<String>, // Jail paths hidden behind /dev/null or an empty tmpfs
+    #[serde(default)]
+    pub private_tmp: bool, // Empty tmpfs over /tmp and /var/tmp, with or without chroot
+    // Empty writable directories created for each run and gone afterwards
+    #[serde(default)]
+    pub volumes: Vec<VolumeConfig>,
+    // Cap the disk volumes, together, at a fixed size: they are put on a
+    // filesystem image of that size in the scratch dir, loop mounted, rather
+    // than straight on the scratch dir's filesystem. Needs root (losetup and
+    // the mount need CAP_SYS_ADMIN on the host) and mkfs.<fstype>.
+    pub disk_quota: Option<DiskQuota>,
+    // FUSE filesystems rsjail mounts inside the jail, each served by a helper
+    // it starts before entering the jail's root. The program needs no
+    // /dev/fuse for these; to mount its own, bind /dev/fuse in with device.
+    #[serde(default)]
+    pub fuse_mounts: Vec<FuseMount>,
+    // Give the jail its own cgroup v2 subtree at /sys/fs/cgroup, writable by
+    // its root, so nested container managers can create cgroups and enable
+    // controllers below it. Needs cgroup v2 mounted at /sys/fs/cgroup on the
+    // host (cgroup.kill, which reaps leftovers on exit, is 5.14+). Without
+    // clone_newuser the jail's root owns the subtree's own limits as well and
+    // can raise them; with it only the delegated interface files are writable.
+    // Without clone_newcgroup, /proc/self/cgroup shows the host path.
+    #[serde(default)]
+    pub cgroup_delegate: bool,
+    // Bytes above which the jail's cgroup is throttled and pushed to reclaim
+    // rather than killed, via cgroup v2 memory.high. Runs in the same
+    // rsjail/<pid>-<n> subtree cgroup_delegate uses, delegated or not, and
+    // JailResult reports how often it and memory.max were hit. Takes units
+    // like the rlimits.
+    #[serde(default, deserialize_with = "deserialize_bytes")]
+    pub memory_high: Option<u64>,
+    // Read /proc/<pid>/status of the program and everything below it every
+    // sample_interval_ms (100 by default) while it runs, and report the
+    // largest VmPeak, VmHWM and total VmRSS seen in JailResult. That shows
+    // more than max_rss, which only has the largest single process.
+    #[serde(default)]
+    pub sample_memory: bool,
+    pub sample_interval_ms: Option<u64>,
+    // Pipe the program's stdout and stderr through rsjail, which prefixes
+    // every line with "[name] ", for the output of several jails at once
+    #[serde(default)]
+    pub log_prefix: bool,
     
     // User configuration
     pub uid: Option<u32>,
     pub gid: Option<u32>,
+    // Without clone_newuser, replace the supplementary groups rsjail was
+    // started with (wheel, sudo, ...) by `groups` when switching uid or gid
+    #[serde(default = "default_true")]
+    pub drop_groups: bool,
+    #[serde(default)]
+    pub groups: Vec<u32>,
+    // Capabilities such as "CAP_NET_BIND_SERVICE" the program keeps after
+    // setuid to the non-root uid, through the ambient set, so it needs no
+    // file capabilities. Without clone_newuser they are host capabilities.
+    #[serde(default)]
+    pub ambient_caps: Vec<String>,
+    // Set no_new_privs at its setup step, so nothing the program execs gains
+    // privileges through setuid bits or file capabilities. Landlock and
+    // seccomp set it anyway when they have rules.
+    #[serde(default)]
+    pub no_new_privs: bool,
+    // User namespace id mappings, by default root inside is the invoking user
+    #[serde(default)]
+    pub uid_map: Vec<IdMap>,
+    #[serde(default)]
+    pub gid_map: Vec<IdMap>,
+    // Hand /tmp, /var/tmp, HOME and the volumes over to uid/gid once the
+    // maps are written. They are otherwise owned by whoever the maps leave
+    // their host owner as, often root, and the program can't write there.
+    // The directories themselves only, not what is in them.
+    #[serde(default)]
+    pub chown_jail_dirs: bool,
+    // PAM service whose session stack rsjail runs for the uid user (or the
+    // invoking one) around the jail: opened before anything is unshared,
+    // closed once the child is reaped. Needs rsjail built with the pam
+    // feature and a matching /etc/pam.d/<service> on the host, e.g. with
+    // pam_limits or pam_systemd; no authentication or account checks run.
+    pub pam_service: Option<String>,
     
-    // Time limit
+    // Time limit, in seconds or with a unit like rlimit_cpu
+    #[serde(default, deserialize_with = "deserialize_seconds")]
     pub time_limit: Option<u64>,
+    // A CPU time budget as judges set one, in seconds or with a unit.
+    // RLIMIT_CPU becomes this, so the program is SIGKILLed once it is used
+    // up, and time_limit, if unset, this plus wall_margin, so a program that
+    // sleeps or blocks instead is stopped too. rlimit_cpu and time_limit
+    // still override either half, and JailResult tells which one was hit.
+    #[serde(default, deserialize_with = "deserialize_seconds")]
+    pub cpu_limit: Option<u64>,
+    // What cpu_limit adds for the time_limit it derives, 1s by default
+    #[serde(default, deserialize_with = "deserialize_seconds")]
+    pub wall_margin: Option<u64>,
+    // Seconds the child may spend setting up (mounts, pivot_root, ...) before
+    // reaching exec, after which it is killed
+    pub setup_timeout: Option<u64>,
+    // Sent when time_limit is hit, defaults to SIGKILL. As PID 1 of a new PID
+    // namespace the program only sees signals it installed a handler for.
+    pub timeout_signal: Option<String>,
+    // Seconds teardown waits for the jail's processes to go, after
+    // timeout_signal or once leftovers are killed, before SIGKILLing PID 1
+    // and with it the whole PID namespace, or giving up on the leftovers.
+    // Unset waits for as long as it takes, which a process stuck in
+    // uninterruptible sleep can make forever.
+    #[serde(default, deserialize_with = "deserialize_seconds")]
+    pub cleanup_timeout: Option<u64>,
+    // The codes the rsjail command exits with, see ExitCodeMap for defaults,
+    // so they can be kept apart from the program's own
+    #[serde(default)]
+    pub exit_code_map: ExitCodeMap,
+    #[serde(default = "default_true")]
+    pub die_with_parent: bool, // SIGKILL the jailed program if rsjail dies
+    // Make the jailed program a session and process group leader (setsid),
+    // detached from rsjail's controlling terminal and job control. Suits
+    // daemon-like workloads.
+    #[serde(default)]
+    pub new_session: bool,
+    
+    // Readiness notification: the program finds a pipe's fd number in
+    // NOTIFY_FD and writes the line "READY" (or systemd's "READY=1") to it
+    // once it is up. Without that within notify_timeout seconds it is sent
+    // timeout_signal, as for time_limit.
+    #[serde(default)]
+    pub notify: bool,
+    pub notify_timeout: Option<u64>,
+    
+    // Exec the target in place of rsjail instead of forking a child. Nothing
+    // supervises the program afterwards, so there is no PID namespace, time
+    // limit, exit status reporting or scratch cleanup in this mode.
+    #[serde(default)]
+    pub no_fork: bool,
+    
+    // Sysctls to set inside the jail, e.g. "net.ipv4.ip_unprivileged_port_start".
+    // Only those the jail's net, uts or ipc namespace makes private are allowed.
+    #[serde(default)]
+    pub sysctls: HashMap<String, String>,
+    // Refuse new connections beyond this many tracked at once, in or out.
+    // Needs clone_newnet and the host's nft command (nftables).
+    pub max_connections: Option<u32>,
+    // Lines like "10.0.0.5 myservice" for the jail's /etc/hosts, a copy of
+    // chroot_dir's own (or a localhost-only one) bound over it read-only
+    #[serde(default)]
+    pub extra_hosts: Vec<String>,
+    // Bind the jail's net namespace to /var/run/netns/<name> while it runs,
+    // so `ip netns exec <name> ...` and other netns tools can reach it
+    pub netns_name: Option<String>,
+    
+    // Environment
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    // Host file of KEY=VALUE lines read along with the config, for whatever
+    // env doesn't set itself
+    pub env_file: Option<String>,
+    pub forward_term: Option<bool>, // Forward TERM/COLUMNS/LINES, defaults to on with a tty
+    // TZ for the jailed program, e.g. "Europe/Berlin". With mount_localtime
+    // the host's zoneinfo file for it is also bound read-only at
+    // /etc/localtime and at its own zoneinfo path inside chroot_dir.
+    pub timezone: Option<String>,
+    #[serde(default)]
+    pub mount_localtime: bool,
+    #[serde(default)]
+    pub forward_tz: bool, // Pass the host's TZ through when timezone is unset
+    
+    // Base directory for per-run ephemeral storage, defaults to the system temp dir
+    pub scratch_dir: Option<String>,
+    
+    // Landlock filesystem rules, applied to paths as seen inside the jail
+    #[serde(default)]
+    pub landlock: Vec<LandlockRule>,
+    #[serde(default)]
+    pub landlock_required: bool, // Fail instead of warning without kernel support
+
+    // Count the calls to each named syscall ("clone": 100) across all of the
+    // jail's processes through a seccomp user-notification filter (5.5+),
+    // and kill the jail once one goes over. JailResult reports which. Forks
+    // reach the kernel as clone, clone3 or, from shells, vfork, so limit
+    // all of them. Sets no_new_privs, as landlock does.
+    #[serde(default)]
+    pub syscall_limits: BTreeMap<String, u64>,
+    // A seccomp filter deciding what each named syscall gets ("ptrace":
+    // "errno") and, through default_action, every other one. Names are
+    // those syscall_limits knows. "log" lets the call through but records
+    // it in the kernel audit log, so default_action "log" with the
+    // program's known syscalls allowed shows what else it calls without
+    // stopping it. Logging needs Linux 4.14, with "log" in
+    // /proc/sys/kernel/seccomp/actions_logged, and auditd or dmesg to read
+    // the records. Installed at the seccomp setup step, sets no_new_privs.
+    pub seccomp_policy: Option<SeccompPolicy>,
+
+    // The order the child sets itself up in once the namespaces, hostname
+    // and sysctls are in place, by default that of SetupStep::DEFAULT_ORDER.
+    // A step can only be left out when none of the settings it applies are
+    // set. Steps that depend on each other have to keep their order: mounts
+    // before setuid, caps and landlock, setuid before caps, and
+    // no_new_privs before seccomp, whose filter may deny the prctl. A seccomp
+    // step other than the last is installed before init forks, so init's
+    // fork counts toward syscall_limits.
+    pub setup_steps: Option<Vec<SetupStep>>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct MountConfig {
     pub src: String,
     pub dst: String,
     pub fstype: Option<String>,
     pub is_bind: bool,
     pub rw: bool,
+    #[serde(default)]
+    pub device: bool, // src is a character or block device node to expose
+    pub data: Option<String>, // filesystem-specific options, e.g. "subvol=@home"
+    // Create dst and its parents if missing. Turn off when dst already
+    // exists below a read-only mount, where it can't be created.
+    #[serde(default = "default_true")]
+    pub create_target: bool,
+    // Skip the bind mount if src doesn't exist. A src with wildcards such
+    // as "/usr/lib/libc*.so*" binds every match at dst/<its name>,
+    // failing if there are none unless this is set.
+    #[serde(default)]
+    pub optional: bool,
+    // Leave dst alone if it is a directory that already has entries, e.g.
+    // one a lower layer of a layered chroot_dir provisioned
+    #[serde(default)]
+    pub skip_if_nonempty: bool,
+}
+
+impl Default for MountConfig {
+    fn default() -> Self {
+        Self {
+            src: String::new(),
+            dst: String::new(),
+            fstype: None,
+            is_bind: false,
+            rw: false,
+            device: false,
+            data: None,
+            create_target: true,
+            optional: false,
+            skip_if_nonempty: false,
+        }
+    }
+}
+
+impl MountConfig {
+    /// Whether skip_if_nonempty leaves `target`, where dst is on the host,
+    /// alone.
+    pub fn skipped_at(&self, target: &Path) -> bool {
+        self.skip_if_nonempty
+            && fs::read_dir(target).is_ok_and(|mut entries| entries.next().is_some())
+    }
+}
+
+/// One line of a user namespace uid_map or gid_map: `count` ids starting at
+/// `inside` in the jail are `outside` onwards on the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdMap {
+    pub inside: u32,
+    pub outside: u32,
+    pub count: u32,
+}
+
+impl IdMap {
+    pub fn contains(&self, id: u32) -> bool {
+        id >= self.inside && id - self.inside < self.count
+    }
+}
+
+/// How a run's outcome becomes an exit code, recorded in
+/// `JailResult::mapped_exit_code`. Each code is 0-255.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExitCodeMap {
+    /// The child failed before it could exec the program, 125 by default
+    pub setup_failed: i32,
+    /// time_limit, notify_timeout or setup_timeout was hit, 124 by default
+    pub timeout: i32,
+    /// Added to the program's own exit code, capped at 255, 0 by default.
+    /// A program killed by a signal gets 128 plus its number, as in a shell.
+    pub offset: i32,
+}
+
+impl Default for ExitCodeMap {
+    fn default() -> Self {
+        Self {
+            setup_failed: 125,
+            timeout: 124,
+            offset: 0,
+        }
+    }
+}
+
+impl ExitCodeMap {
+    /// The code for a run that ended this way.
+    pub fn code(
+        &self,
+        exit_code: Option<i32>,
+        signal: Option<Signal>,
+        timed_out: bool,
+        setup_failed: bool,
+    ) -> i32 {
+        if timed_out {
+            return self.timeout;
+        }
+        if setup_failed {
+            return self.setup_failed;
+        }
+        match (exit_code, signal) {
+            (Some(code), _) => (code + self.offset).min(255),
+            (None, Some(signal)) => 128 + signal as i32,
+            (None, None) => self.setup_failed,
+        }
+    }
+}
+
+/// How the jail's root filesystem is set up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FsIsolation {
+    /// Leave the filesystem alone, e.g. when Landlock alone restricts it
+    None,
+    Chroot,
+    /// pivot_root into chroot_dir and detach the old root, so no path leads
+    /// back to the host filesystem; needs clone_newns
+    PivotRoot,
+}
+
+/// What network the jail gets, see network_mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NetworkMode {
+    /// The host's own interfaces
+    Host,
+    /// A net namespace with nothing up, not even lo
+    None,
+    /// A net namespace with lo up, for programs that talk to themselves
+    Loopback,
+    /// A net namespace joined to the host by a veth pair; not supported yet
+    Veth,
+}
+
+/// One phase of the child's setup, see setup_steps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SetupStep {
+    /// Mounts, masks and the switch into chroot_dir or private_tmp, then
+    /// chown_jail_dirs
+    Mounts,
+    /// Supplementary groups, gid and uid
+    Setuid,
+    /// Raising ambient_caps
+    Caps,
+    /// Setting no_new_privs
+    NoNewPrivs,
+    /// The rlimit_* settings, by default before setuid so that raising a
+    /// hard limit still has root's privilege
+    Rlimits,
+    /// The landlock rules
+    Landlock,
+    /// The seccomp_policy and syscall_limits filters
+    Seccomp,
+}
+
+impl SetupStep {
+    pub const DEFAULT_ORDER: [SetupStep; 7] = [
+        SetupStep::Mounts,
+        SetupStep::Rlimits,
+        SetupStep::Setuid,
+        SetupStep::Caps,
+        SetupStep::NoNewPrivs,
+        SetupStep::Landlock,
+        SetupStep::Seccomp,
+    ];
+}
+
+/// How a chroot_dir that already has contents is treated before the run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChrootPolicy {
+    /// Use it as is, only creating missing standard directories
+    #[default]
+    Reuse,
+    /// Delete the standard directories (bin, etc, usr, ...) and start them
+    /// over empty, stopping at symlinks and mount points
+    Clean,
+    /// Refuse to run unless it is empty or doesn't exist yet
+    RequireEmpty,
+}
+
+/// A resource limit, either a single value used as both the soft and hard
+/// limit or an explicit pair.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Rlimit {
+    Both(u64),
+    Split { soft: u64, hard: u64 },
+}
+
+impl Rlimit {
+    pub fn soft(&self) -> u64 {
+        match *self {
+            Rlimit::Both(limit) => limit,
+            Rlimit::Split { soft, .. } => soft,
+        }
+    }
+
+    pub fn hard(&self) -> u64 {
+        match *self {
+            Rlimit::Both(limit) => limit,
+            Rlimit::Split { hard, .. } => hard,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LandlockRule {
+    pub path: String,
+    pub access: Vec<LandlockAccess>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LandlockAccess {
+    Read,
+    Write,
+    Execute,
+}
+
+/// See seccomp_policy.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SeccompPolicy {
+    #[serde(default)]
+    pub default_action: SeccompAction,
+    #[serde(default)]
+    pub syscalls: BTreeMap<String, SeccompAction>,
+}
+
+/// What a seccomp_policy does with a syscall.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SeccompAction {
+    #[default]
+    Allow,
+    /// Kill the whole process
+    Kill,
+    /// Fail the call with EPERM
+    Errno,
+    /// Send SIGSYS, which a handler can catch
+    Trap,
+    /// Allow the call and record it in the audit log
+    Log,
+}
+
+/// A command that builds chroot_dir, run by the parent on the host unless
+/// `marker` (relative to chroot_dir) exists, which it creates on success.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProvisionConfig {
+    pub command: Vec<String>,
+    pub marker: String,
+}
+
+/// A FUSE filesystem at `mount_path` in the jail. rsjail opens /dev/fuse,
+/// mounts the connection and runs `helper` with "/dev/fd/<n>" appended as
+/// its mount point, which libfuse 3.3 and later take for one already
+/// mounted, e.g. `["/usr/bin/squashfuse", "-f", "/images/store.sqfs"]`.
+/// The helper sees the host's filesystem and the jail's other namespaces.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FuseMount {
+    pub mount_path: String,
+    pub helper: Vec<String>,
+    /// Kernel mount options: allow_other, default_permissions, max_read=N
+    /// and blksize=N. nonempty is accepted and dropped, the kernel mounts
+    /// over a non-empty directory anyway. Helper options go in `helper`.
+    #[serde(default)]
+    pub options: Vec<String>,
+}
+
+impl FuseMount {
+    /// `options` as they go to the kernel.
+    pub fn kernel_options(&self) -> Vec<&str> {
+        self.options
+            .iter()
+            .map(String::as_str)
+            .filter(|option| *option != "nonempty")
+            .collect()
+    }
+}
+
+/// A named scratch volume, like a Kubernetes emptyDir.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VolumeConfig {
+    pub name: String,
+    pub mount_path: String,
+    pub backing: VolumeBacking,
+    /// tmpfs size, e.g. "64m" or "10%"; disk-backed volumes are unbounded
+    #[serde(default)]
+    pub size: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VolumeBacking {
+    /// In memory, counted against the size limit
+    Tmpfs,
+    /// A directory in the run's scratch dir
+    Disk,
+}
+
+/// The image behind `disk_quota`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiskQuota {
+    /// In bytes, or with units like the rlimits, e.g. "512M"
+    #[serde(deserialize_with = "deserialize_byte_size")]
+    pub size: u64,
+    /// Formatted with mkfs.<fstype>
+    #[serde(default = "default_quota_fstype")]
+    pub fstype: String,
+}
+
+/// Filesystem types the kernel lets a user namespace mount (FS_USERNS_MOUNT)
+pub const USERNS_FSTYPES: [&str; 9] = [
+    "tmpfs", "ramfs", "proc", "sysfs", "devpts", "mqueue", "cgroup2", "overlay", "fuse",
+];
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_quota_fstype() -> String {
+    "ext4".to_string()
+}
+
+fn default_max_mounts() -> usize {
+    256
 }
 
 impl Default for JailConfig {
@@ -47,21 +732,1430 @@ impl Default for JailConfig {
             name: "default".to_string(),
             hostname: None,
             chroot_dir: None,
+            allow_root_chroot: false,
+            require_writable_chroot: false,
+            fs_isolation: None,
+            chroot_policy: ChrootPolicy::Reuse,
+            root_subpath: None,
+            provision: None,
             exec_bin: "/bin/sh".to_string(),
             exec_args: vec!["/bin/sh".to_string()],
+            login_shell: false,
+            wrapper: None,
+            preload: None,
+            interpreter: None,
+            init: false,
+            init_helper: None,
+            exec_via_fd: false,
+            exec_path: None,
             clone_newpid: true,
             clone_newnet: true,
             clone_newns: true,
             clone_newuts: true,
             clone_newipc: true,
             clone_newuser: true,
+            network_mode: None,
+            clone_newtime: false,
+            time_offset_monotonic: None,
+            time_offset_boottime: None,
+            clone_newcgroup: false,
+            ignore_unsupported_ns: false,
+            unshare_in_parent: false,
+            thread_mode: false,
             rlimit_as: None,
             rlimit_cpu: None,
             rlimit_nofile: None,
+            rlimit_memlock: None,
+            rlimit_data: None,
             mounts: Vec::new(),
+            src_root: None,
+            max_mounts: default_max_mounts(),
+            auto_mount_libs: false,
+            bind_ca_certs: false,
+            ca_certs_optional: false,
+            mount_proc: false,
+            proc_hidepid: None,
+            proc_gid: None,
+            mount_sys: false,
+            default_proc_mask: true,
+            mask_paths: Vec::new(),
+            private_tmp: false,
+            volumes: Vec::new(),
+            disk_quota: None,
+            fuse_mounts: Vec::new(),
+            cgroup_delegate: false,
+            memory_high: None,
+            sample_memory: false,
+            log_prefix: false,
+            sample_interval_ms: None,
             uid: None,
             gid: None,
+            drop_groups: true,
+            groups: Vec::new(),
+            ambient_caps: Vec::new(),
+            no_new_privs: false,
+            uid_map: Vec::new(),
+            gid_map: Vec::new(),
+            chown_jail_dirs: false,
+            pam_service: None,
             time_limit: None,
+            cpu_limit: None,
+            wall_margin: None,
+            setup_timeout: None,
+            timeout_signal: None,
+            cleanup_timeout: None,
+            exit_code_map: ExitCodeMap::default(),
+            die_with_parent: true,
+            new_session: false,
+            notify: false,
+            notify_timeout: None,
+            no_fork: false,
+            sysctls: HashMap::new(),
+            max_connections: None,
+            extra_hosts: Vec::new(),
+            netns_name: None,
+            env: HashMap::new(),
+            env_file: None,
+            forward_term: None,
+            timezone: None,
+            mount_localtime: false,
+            forward_tz: false,
+            scratch_dir: None,
+            landlock: Vec::new(),
+            landlock_required: false,
+            syscall_limits: BTreeMap::new(),
+            seccomp_policy: None,
+            setup_steps: None,
+        }
+    }
+}
+
+impl JailConfig {
+    /// Read a config file and resolve everything that has to be looked up on
+    /// the host before the jail starts.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::load_from(path.as_ref(), false)
+    }
+
+    /// Like `load`, but a key that isn't a config field is an error rather
+    /// than silently ignored.
+    pub fn load_strict(path: impl AsRef<Path>) -> Result<Self> {
+        Self::load_from(path.as_ref(), true)
+    }
+
+    fn load_from(path: &Path, strict: bool) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("cannot read config file {}", path.display()))?;
+        let mut config: JailConfig =
+            serde_json::from_str(&content).map_err(|e| parse_error(path, &e))?;
+
+        if strict {
+            let raw: serde_json::Value = serde_json::from_str(&content)?;
+            if let Some(field) = unknown_field(&raw, &serde_json::to_value(&config)?, "") {
+                bail!("{}: unknown field {}", path.display(), field);
+            }
+        }
+
+        config.expand_env_vars()?;
+        config.expand_arg_files()?;
+        config.load_env_file()?;
+        config.resolve_mount_sources()?;
+
+        Ok(config)
+    }
+
+    /// Check the config for mistakes that would make running it unsafe.
+    pub fn validate(&self) -> Result<()> {
+        for check in Self::CHECKS {
+            check(self)?;
+        }
+        Ok(())
+    }
+
+    /// Every mistake `validate` would find, not only the first. Each check
+    /// still stops at its own first one.
+    pub fn problems(&self) -> Vec<anyhow::Error> {
+        Self::CHECKS
+            .iter()
+            .filter_map(|check| check(self).err())
+            .collect()
+    }
+
+    /// Independent of each other, so one failing doesn't hide the others
+    const CHECKS: [fn(&Self) -> Result<()>; 14] = [
+        Self::validate_program,
+        Self::validate_no_fork,
+        Self::validate_limits,
+        Self::validate_signals,
+        Self::validate_mounts,
+        Self::validate_volumes,
+        Self::validate_network,
+        Self::validate_provision,
+        Self::validate_localtime,
+        Self::validate_users,
+        Self::validate_isolation,
+        Self::validate_chroot_dir,
+        Self::validate_setup_steps,
+        Self::validate_kernel_features,
+    ];
+
+    fn validate_program(&self) -> Result<()> {
+        if self.exec_bin.is_empty() {
+            bail!("exec_bin cannot be empty");
+        }
+        if self.exec_path.is_some() && self.env.contains_key("PATH") {
+            bail!("exec_path and env both set PATH, keep one");
+        }
+        if let Some(wrapper) = &self.wrapper {
+            match wrapper.first() {
+                None => bail!("wrapper needs a command"),
+                Some(command) if !command.starts_with('/') => {
+                    bail!("wrapper command {} must be an absolute path", command)
+                }
+                Some(_) => {}
+            }
+            if self.login_shell {
+                bail!("login_shell cannot be combined with wrapper, which gets argv[0]");
+            }
+        }
+        if let Some(interpreter) = &self.interpreter {
+            if interpreter != "auto" && !interpreter.starts_with('/') {
+                bail!(
+                    "interpreter {} must be an absolute path or \"auto\"",
+                    interpreter
+                );
+            }
+            if self.wrapper.is_some() {
+                bail!("interpreter cannot be combined with wrapper, put it in the wrapper");
+            }
+            if self.login_shell {
+                bail!("login_shell cannot be combined with interpreter, which gets argv[0]");
+            }
+        }
+        if self.init && !self.clone_newpid {
+            bail!("init needs clone_newpid, it is only an init as PID 1 of a PID namespace");
+        }
+        if let Some(helper) = &self.init_helper {
+            if !self.init {
+                bail!("init_helper is only used with init");
+            }
+            if !helper.starts_with('/') {
+                bail!("init_helper {} must be an absolute path", helper);
+            }
+            if self.login_shell {
+                bail!("login_shell cannot be combined with init_helper, which gets argv[0]");
+            }
+        }
+        if let Some(preload) = &self.preload
+            && (!preload.starts_with('/') || preload.contains([':', ' ']))
+        {
+            bail!("preload {:?} must be one absolute path", preload);
+        }
+        Ok(())
+    }
+
+    fn validate_no_fork(&self) -> Result<()> {
+        if !self.no_fork {
+            return Ok(());
+        }
+        if self.clone_newpid {
+            bail!("no_fork cannot be combined with clone_newpid, which only affects children");
+        }
+        if self.clone_newtime {
+            bail!("no_fork cannot be combined with clone_newtime, which only affects children");
+        }
+        if self.time_limit.is_some() {
+            bail!("no_fork cannot be combined with time_limit, nothing is left to enforce it");
+        }
+        if self.cpu_limit.is_some() {
+            bail!("no_fork cannot be combined with cpu_limit, nothing is left to enforce it");
+        }
+        if self.notify {
+            bail!("no_fork cannot be combined with notify, nothing is left to listen");
+        }
+        if self.setup_timeout.is_some() {
+            bail!("no_fork cannot be combined with setup_timeout, nothing is left to enforce it");
+        }
+        if self.cleanup_timeout.is_some() {
+            bail!("no_fork cannot be combined with cleanup_timeout, nothing is left to enforce it");
+        }
+        if self.cgroup_delegate {
+            bail!("no_fork cannot be combined with cgroup_delegate, nothing is left to clean up");
+        }
+        if self.memory_high.is_some() {
+            bail!("no_fork cannot be combined with memory_high, nothing is left to clean up");
+        }
+        if self.sample_memory {
+            bail!("no_fork cannot be combined with sample_memory, nothing is left to sample");
+        }
+        if self.log_prefix {
+            bail!("no_fork cannot be combined with log_prefix, nothing is left to relay output");
+        }
+        if !self.syscall_limits.is_empty() {
+            bail!("no_fork cannot be combined with syscall_limits, nothing is left to count");
+        }
+        if self.pam_service.is_some() {
+            bail!("no_fork cannot be combined with pam_service, nothing is left to close it");
+        }
+        if !self.extra_hosts.is_empty() {
+            bail!("no_fork cannot be combined with extra_hosts");
+        }
+        if self.netns_name.is_some() {
+            bail!("no_fork cannot be combined with netns_name, nothing is left to unbind it");
+        }
+        Ok(())
+    }
+
+    fn validate_limits(&self) -> Result<()> {
+        for (name, limit) in [
+            ("rlimit_as", self.rlimit_as),
+            ("rlimit_cpu", self.rlimit_cpu),
+            ("rlimit_nofile", self.rlimit_nofile),
+            ("rlimit_memlock", self.rlimit_memlock),
+            ("rlimit_data", self.rlimit_data),
+        ] {
+            if let Some(limit) = limit
+                && limit.soft() > limit.hard()
+            {
+                bail!(
+                    "{}: soft limit {} exceeds hard limit {}",
+                    name,
+                    limit.soft(),
+                    limit.hard()
+                );
+            }
+        }
+        if self.sample_interval_ms.is_some() && !self.sample_memory {
+            bail!("sample_interval_ms needs sample_memory");
+        }
+        if self.sample_interval_ms == Some(0) {
+            bail!("sample_interval_ms must be greater than 0");
+        }
+        if self.cpu_limit == Some(0) {
+            bail!("cpu_limit must be at least 1 second");
+        }
+        if self.wall_margin.is_some() && self.cpu_limit.is_none() {
+            bail!("wall_margin only applies to the time_limit cpu_limit derives");
+        }
+        if self.memory_high == Some(0) {
+            bail!("memory_high must be greater than 0");
+        }
+        if let Some(name) = self
+            .syscall_limits
+            .keys()
+            .find(|name| seccomp::syscall_number(name).is_none())
+        {
+            bail!(
+                "syscall_limits: cannot count {:?} on this architecture",
+                name
+            );
+        }
+        if let Some(name) = self
+            .seccomp_policy
+            .iter()
+            .flat_map(|policy| policy.syscalls.keys())
+            .find(|name| seccomp::syscall_number(name).is_none())
+        {
+            bail!(
+                "seccomp_policy: cannot filter {:?} on this architecture",
+                name
+            );
+        }
+
+        for key in self.sysctls.keys() {
+            let Some((option, enabled)) = self.sysctl_namespace(key) else {
+                bail!(
+                    "sysctl {} is not namespaced, setting it would change the host",
+                    key
+                );
+            };
+            if !enabled {
+                bail!("sysctl {} is only namespaced with {}", key, option);
+            }
+        }
+
+        if !self.clone_newtime
+            && (self.time_offset_monotonic.is_some() || self.time_offset_boottime.is_some())
+        {
+            bail!("time offsets need clone_newtime");
+        }
+        Ok(())
+    }
+
+    fn validate_signals(&self) -> Result<()> {
+        if self.notify_timeout.is_some() && !self.notify {
+            bail!("notify_timeout needs notify");
+        }
+        if let Some(name) = &self.timeout_signal {
+            parse_signal(name).context("timeout_signal")?;
+        }
+        let map = &self.exit_code_map;
+        for (name, code) in [
+            ("setup_failed", map.setup_failed),
+            ("timeout", map.timeout),
+            ("offset", map.offset),
+        ] {
+            if !(0..=255).contains(&code) {
+                bail!("exit_code_map.{} must be 0-255, got {}", name, code);
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_mounts(&self) -> Result<()> {
+        for mount in self.mounts.iter().filter(|mount| mount.device) {
+            validate_device_mount(mount)?;
+        }
+        // Nested destinations are fine, they get mounted parents first, but
+        // a second mount on the same one would silently hide the first
+        let mut targets = HashSet::new();
+        for mount in &self.mounts {
+            let target: PathBuf = Path::new(&mount.dst).components().collect();
+            if !targets.insert(target) {
+                bail!("more than one mount has dst {}", mount.dst);
+            }
+        }
+        // Library mounts are only known once exec_bin is resolved, the jail
+        // checks again with those
+        let localtime_mounts = if self.mount_localtime { 2 } else { 0 };
+        let added_mounts = localtime_mounts + usize::from(!self.extra_hosts.is_empty());
+        self.check_mount_count(
+            self.mounts.len() + self.volumes.len() + self.fuse_mounts.len() + added_mounts,
+        )?;
+
+        let globs = self
+            .mounts
+            .iter()
+            .filter(|mount| glob::is_pattern(&mount.src));
+        for mount in globs {
+            if !mount.is_bind || mount.device {
+                bail!(
+                    "mount {}: only bind mounts take a src with wildcards",
+                    mount.dst
+                );
+            }
+            if !mount.src.starts_with('/') {
+                bail!(
+                    "mount {}: a src with wildcards must be an absolute path",
+                    mount.dst
+                );
+            }
+        }
+        for mount in self.mounts.iter().filter(|mount| mount.data.is_some()) {
+            if mount.is_bind || mount.fstype.is_none() {
+                bail!(
+                    "mount {}: data is only passed to filesystem mounts, set fstype and drop is_bind",
+                    mount.dst
+                );
+            }
+        }
+        if self.clone_newuser {
+            let unsupported = self.mounts.iter().find(|mount| {
+                !mount.is_bind
+                    && !mount
+                        .fstype
+                        .as_deref()
+                        .is_some_and(|fstype| USERNS_FSTYPES.contains(&fstype))
+            });
+            if let Some(mount) = unsupported {
+                bail!(
+                    "mount {}: type {} can't be mounted in a user namespace, which only allows \
+                     bind mounts and {}; bind in a directory mounted on the host instead",
+                    mount.dst,
+                    mount.fstype.as_deref().unwrap_or("none"),
+                    USERNS_FSTYPES.join(", ")
+                );
+            }
+        }
+        if let Some(hidepid) = self.proc_hidepid
+            && hidepid > 2
+        {
+            bail!("proc_hidepid must be 0, 1 or 2, not {}", hidepid);
+        }
+        if (self.proc_hidepid.is_some() || self.proc_gid.is_some()) && !self.mount_proc {
+            bail!("proc_hidepid and proc_gid need mount_proc");
+        }
+        if let Some(path) = self.mask_paths.iter().find(|path| !path.starts_with('/')) {
+            bail!(
+                "mask_paths entry {} must be an absolute path inside the jail",
+                path
+            );
+        }
+        Ok(())
+    }
+
+    fn validate_network(&self) -> Result<()> {
+        if self.network_mode == Some(NetworkMode::Veth) {
+            bail!("network_mode veth is not supported yet, rsjail cannot create veth pairs");
+        }
+        if let Some(limit) = self.max_connections {
+            if !self.net_namespace() {
+                bail!("max_connections needs a net namespace, it would limit the host");
+            }
+            if limit == 0 {
+                bail!("max_connections must be at least 1");
+            }
+        }
+        for entry in &self.extra_hosts {
+            validate_hosts_entry(entry)?;
+        }
+        if let Some(name) = &self.netns_name {
+            if !self.net_namespace() {
+                bail!("netns_name needs a net namespace to bind");
+            }
+            if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+                bail!("netns_name {:?} must be a plain file name", name);
+            }
+            if self.unshare_in_parent && self.clone_newns {
+                // rsjail's own mount namespace is the jail's then
+                bail!("netns_name cannot be combined with unshare_in_parent and clone_newns");
+            }
         }
+        Ok(())
     }
-}
\ No newline at end of file
+
+    fn validate_provision(&self) -> Result<()> {
+        if let Some(provision) = &self.provision {
+            if provision.command.is_empty() {
+                bail!("provision command cannot be empty");
+            }
+            let marker = Path::new(&provision.marker);
+            if provision.marker.is_empty()
+                || marker.is_absolute()
+                || marker
+                    .components()
+                    .any(|component| component == std::path::Component::ParentDir)
+            {
+                bail!("provision marker must be a path inside chroot_dir, relative to it");
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_localtime(&self) -> Result<()> {
+        if self.mount_localtime {
+            let Some(timezone) = &self.timezone else {
+                bail!("mount_localtime needs timezone");
+            };
+            let zone = Path::new(timezone);
+            if timezone.is_empty()
+                || zone.is_absolute()
+                || zone
+                    .components()
+                    .any(|component| component == std::path::Component::ParentDir)
+            {
+                bail!("mount_localtime needs timezone to be a zoneinfo name like Europe/Berlin");
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_users(&self) -> Result<()> {
+        for cap in &self.ambient_caps {
+            crate::caps::from_name(cap)?;
+        }
+        if !self.ambient_caps.is_empty() && self.uid.unwrap_or(0) == 0 {
+            bail!("ambient_caps needs a non-root uid, root gets every capability anyway");
+        }
+        if !self.groups.is_empty() {
+            if self.clone_newuser {
+                bail!("groups cannot be set in a user namespace, which denies setgroups");
+            }
+            if !self.drop_groups || (self.uid.is_none() && self.gid.is_none()) {
+                bail!("groups needs drop_groups and a uid or gid to switch to");
+            }
+        }
+        if self.chown_jail_dirs && !self.clone_newuser {
+            bail!("chown_jail_dirs needs clone_newuser");
+        }
+        if let Some(service) = &self.pam_service {
+            if !cfg!(feature = "pam") {
+                bail!("pam_service needs rsjail built with the pam feature");
+            }
+            if service.is_empty() || service.contains('/') {
+                bail!("pam_service {:?} is not a service name", service);
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_isolation(&self) -> Result<()> {
+        if self.thread_mode {
+            for (option, enabled) in [
+                ("clone_newpid", self.clone_newpid),
+                ("clone_newuser", self.clone_newuser),
+                ("clone_newtime", self.clone_newtime),
+                ("no_fork", self.no_fork),
+                ("unshare_in_parent", self.unshare_in_parent),
+            ] {
+                if enabled {
+                    bail!(
+                        "thread_mode cannot be used with {}, a thread can only unshare \
+                         the mount, net, uts, ipc and cgroup namespaces",
+                        option
+                    );
+                }
+            }
+        }
+        if self.private_tmp && !self.clone_newns {
+            bail!("private_tmp needs clone_newns");
+        }
+        if self.cgroup_delegate && !self.clone_newns {
+            bail!("cgroup_delegate needs clone_newns");
+        }
+
+        match self.fs_isolation() {
+            FsIsolation::None => {
+                for (option, enabled) in [
+                    ("mount_proc", self.mount_proc),
+                    ("mount_sys", self.mount_sys),
+                    ("mask_paths", !self.mask_paths.is_empty()),
+                    ("root_subpath", self.root_subpath.is_some()),
+                    ("volumes", !self.volumes.is_empty()),
+                    ("fuse_mounts", !self.fuse_mounts.is_empty()),
+                    ("provision", self.provision.is_some()),
+                    ("cgroup_delegate", self.cgroup_delegate),
+                    ("mount_localtime", self.mount_localtime),
+                    ("bind_ca_certs", self.bind_ca_certs),
+                    ("extra_hosts", !self.extra_hosts.is_empty()),
+                    ("chown_jail_dirs", self.chown_jail_dirs),
+                ] {
+                    if enabled {
+                        bail!("{} needs chroot_dir and filesystem isolation", option);
+                    }
+                }
+                if self.chroot_dir.is_some() {
+                    log::warn!("fs_isolation is none, chroot_dir and mounts are ignored");
+                }
+            }
+            isolation => {
+                if self.chroot_dir.is_none() {
+                    bail!("fs_isolation {:?} needs chroot_dir", isolation);
+                }
+                if isolation == FsIsolation::PivotRoot && !self.clone_newns {
+                    bail!("fs_isolation pivot_root needs clone_newns");
+                }
+                if let Some(subpath) = &self.root_subpath
+                    && Path::new(subpath)
+                        .components()
+                        .any(|component| component == std::path::Component::ParentDir)
+                {
+                    bail!("root_subpath {} must stay inside chroot_dir", subpath);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// What the config asks of the running kernel. Namespaces it lacks are
+    /// fine with ignore_unsupported_ns, which skips them.
+    fn validate_kernel_features(&self) -> Result<()> {
+        let features = env::detect_features();
+        if !self.ignore_unsupported_ns {
+            for (what, wanted, available) in [
+                (
+                    "clone_newuser",
+                    self.clone_newuser,
+                    features.user_namespaces,
+                ),
+                (
+                    "clone_newtime",
+                    self.clone_newtime,
+                    features.time_namespaces,
+                ),
+                (
+                    "clone_newcgroup",
+                    self.clone_newcgroup,
+                    features.cgroup_namespaces,
+                ),
+            ] {
+                if wanted && !available {
+                    bail!("{} is set, but this kernel can't create one", what);
+                }
+            }
+        }
+        let seccomp = self.seccomp_policy.is_some() || !self.syscall_limits.is_empty();
+        if seccomp && !features.seccomp {
+            bail!("seccomp_policy and syscall_limits need a kernel with seccomp");
+        }
+        Ok(())
+    }
+
+    fn validate_setup_steps(&self) -> Result<()> {
+        let steps = self.setup_steps();
+        let position = |step| steps.iter().position(|&s| s == step);
+        for (i, step) in steps.iter().enumerate() {
+            if steps[..i].contains(step) {
+                bail!("setup_steps lists {:?} twice", step);
+            }
+        }
+        for (before, after, why) in [
+            (SetupStep::Mounts, SetupStep::Setuid, "mounting needs root"),
+            (SetupStep::Mounts, SetupStep::Caps, "mounting needs root"),
+            (
+                SetupStep::Mounts,
+                SetupStep::Landlock,
+                "landlock paths are the jail's",
+            ),
+            (
+                SetupStep::Setuid,
+                SetupStep::Caps,
+                "setuid would drop the raised caps",
+            ),
+            (
+                SetupStep::NoNewPrivs,
+                SetupStep::Seccomp,
+                "the seccomp filter may deny its prctl",
+            ),
+        ] {
+            if let (Some(first), Some(second)) = (position(before), position(after))
+                && first > second
+            {
+                bail!("setup_steps has {:?} before {:?}, {}", after, before, why);
+            }
+        }
+        // Leaving a step out must not quietly drop a restriction
+        for (step, settings, configured) in [
+            (
+                SetupStep::Mounts,
+                "chroot_dir, mounts, mask_paths or private_tmp",
+                self.chroot_dir.is_some()
+                    || !self.mounts.is_empty()
+                    || !self.mask_paths.is_empty()
+                    || self.private_tmp,
+            ),
+            (
+                SetupStep::Rlimits,
+                "rlimit_* or cpu_limit",
+                self.rlimit_as.is_some()
+                    || self.effective_rlimit_cpu().is_some()
+                    || self.rlimit_nofile.is_some()
+                    || self.rlimit_memlock.is_some()
+                    || self.rlimit_data.is_some(),
+            ),
+            (
+                SetupStep::Setuid,
+                "uid, gid or groups",
+                self.uid.is_some() || self.gid.is_some() || !self.groups.is_empty(),
+            ),
+            (
+                SetupStep::Caps,
+                "ambient_caps",
+                !self.ambient_caps.is_empty(),
+            ),
+            (SetupStep::NoNewPrivs, "no_new_privs", self.no_new_privs),
+            (SetupStep::Landlock, "landlock", !self.landlock.is_empty()),
+            (
+                SetupStep::Seccomp,
+                "seccomp_policy or syscall_limits",
+                self.seccomp_policy.is_some() || !self.syscall_limits.is_empty(),
+            ),
+        ] {
+            if configured && position(step).is_none() {
+                bail!(
+                    "setup_steps leaves out {:?} while {} is set",
+                    step,
+                    settings
+                );
+            }
+        }
+        if self.thread_mode && position(SetupStep::Mounts).is_some_and(|i| i > 0) {
+            bail!(
+                "thread_mode mounts before forking the child, so mounts has to be the first of \
+                 setup_steps"
+            );
+        }
+        Ok(())
+    }
+
+    fn validate_chroot_dir(&self) -> Result<()> {
+        if let Some(chroot_dir) = &self.chroot_dir {
+            let resolved =
+                fs::canonicalize(chroot_dir).unwrap_or_else(|_| PathBuf::from(chroot_dir));
+            if resolved == Path::new("/") && !self.allow_root_chroot {
+                bail!(
+                    "chroot_dir {} is the host root; set allow_root_chroot to do this deliberately",
+                    chroot_dir
+                );
+            }
+            if SYSTEM_DIRS.iter().any(|dir| resolved == Path::new(dir)) {
+                log::warn!(
+                    "chroot_dir {} is a host system directory, jail setup will modify it",
+                    chroot_dir
+                );
+            }
+            if self.require_writable_chroot {
+                // Where it would be created, if it doesn't exist yet
+                let existing = resolved.ancestors().find(|dir| dir.exists());
+                let read_only = existing
+                    .and_then(|dir| statvfs(dir).ok())
+                    .is_some_and(|stat| stat.flags().contains(FsFlags::ST_RDONLY));
+                if read_only {
+                    bail!(
+                        "chroot_dir {} is on a read-only filesystem, which require_writable_chroot rejects",
+                        chroot_dir
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_volumes(&self) -> Result<()> {
+        let mut names = HashSet::new();
+        for volume in &self.volumes {
+            if volume.name.is_empty() || volume.name.contains('/') {
+                bail!(
+                    "volume name {:?} must be non-empty and without '/'",
+                    volume.name
+                );
+            }
+            if !names.insert(&volume.name) {
+                bail!("more than one volume is named {}", volume.name);
+            }
+            if !volume.mount_path.starts_with('/') {
+                bail!(
+                    "volume {} mount_path must be an absolute path inside the jail",
+                    volume.name
+                );
+            }
+            match volume.backing {
+                VolumeBacking::Tmpfs => {
+                    if let Some(size) = &volume.size
+                        && (size.is_empty() || size.contains(','))
+                    {
+                        bail!("volume {} has an invalid size {:?}", volume.name, size);
+                    }
+                }
+                VolumeBacking::Disk => {
+                    if volume.size.is_some() {
+                        bail!("volume {}: size only applies to tmpfs", volume.name);
+                    }
+                    if self.no_fork {
+                        bail!(
+                            "volume {}: disk volumes need the scratch dir no_fork lacks",
+                            volume.name
+                        );
+                    }
+                }
+            }
+        }
+        if let Some(quota) = &self.disk_quota {
+            if !self
+                .volumes
+                .iter()
+                .any(|volume| volume.backing == VolumeBacking::Disk)
+            {
+                bail!("disk_quota bounds the disk volumes, and there are none");
+            }
+            if quota.size < 1 << 20 {
+                bail!("disk_quota size must be at least 1M");
+            }
+            if quota.fstype.is_empty() || !quota.fstype.chars().all(|c| c.is_ascii_alphanumeric()) {
+                bail!("disk_quota has an invalid fstype {:?}", quota.fstype);
+            }
+        }
+        self.validate_fuse_mounts()
+    }
+
+    fn validate_fuse_mounts(&self) -> Result<()> {
+        for fuse in &self.fuse_mounts {
+            if !fuse.mount_path.starts_with('/') {
+                bail!(
+                    "fuse mount_path {} must be an absolute path inside the jail",
+                    fuse.mount_path
+                );
+            }
+            match fuse.helper.first() {
+                Some(helper) if helper.starts_with('/') => {}
+                _ => bail!(
+                    "fuse mount {} needs a helper command with an absolute path",
+                    fuse.mount_path
+                ),
+            }
+            for option in &fuse.options {
+                let name = option.split('=').next().unwrap_or_default();
+                let known = match name {
+                    "allow_other" | "default_permissions" | "nonempty" => name == option,
+                    "max_read" | "blksize" => option[name.len()..]
+                        .strip_prefix('=')
+                        .is_some_and(|value| value.parse::<u32>().is_ok()),
+                    _ => false,
+                };
+                if !known {
+                    bail!(
+                        "fuse mount {}: {:?} is not a kernel option, helper options go in helper",
+                        fuse.mount_path,
+                        option
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The clone_new* option that gives the jail its own copy of sysctl
+    /// `key`, and whether it is enabled. None if no namespace covers it.
+    fn sysctl_namespace(&self, key: &str) -> Option<(&'static str, bool)> {
+        if IPC_SYSCTLS.contains(&key) || key.starts_with("fs.mqueue.") {
+            Some(("clone_newipc", self.clone_newipc))
+        } else if key == "kernel.hostname" || key == "kernel.domainname" {
+            Some(("clone_newuts", self.clone_newuts))
+        } else if key.starts_with("net.") {
+            Some(("clone_newnet", self.net_namespace()))
+        } else {
+            None
+        }
+    }
+
+    /// time_limit, or the one cpu_limit derives.
+    pub fn effective_time_limit(&self) -> Option<u64> {
+        self.time_limit.or_else(|| {
+            let margin = self.wall_margin.unwrap_or(1);
+            self.cpu_limit.map(|cpu| cpu.saturating_add(margin))
+        })
+    }
+
+    /// rlimit_cpu, or the one cpu_limit derives.
+    pub fn effective_rlimit_cpu(&self) -> Option<Rlimit> {
+        self.rlimit_cpu.or_else(|| self.cpu_limit.map(Rlimit::Both))
+    }
+
+    /// The capabilities rsjail itself needs for this config, by name. Inside
+    /// a new user namespace the child holds them all, so only what happens
+    /// on the host side counts.
+    pub fn required_capabilities(&self) -> Vec<String> {
+        let mut caps = Vec::new();
+        if self.clone_newuser {
+            if self.disk_quota.is_some() || self.netns_name.is_some() {
+                // The loop device, its mount and the netns binding are set
+                // up on the host
+                caps.push("sys_admin");
+            }
+        } else {
+            let namespaces = self.clone_newpid
+                || self.net_namespace()
+                || self.clone_newns
+                || self.clone_newuts
+                || self.clone_newipc
+                || self.clone_newtime
+                || self.clone_newcgroup;
+            let mounts = !self.mounts.is_empty() || self.mount_proc || self.private_tmp;
+            if namespaces || mounts || self.fs_isolation() == FsIsolation::PivotRoot {
+                caps.push("sys_admin");
+            }
+            if self.fs_isolation() == FsIsolation::Chroot {
+                caps.push("sys_chroot");
+            }
+            if self.uid.is_some() {
+                caps.push("setuid");
+            }
+            if self.gid.is_some() || self.switches_groups() {
+                caps.push("setgid");
+            }
+            if self.chown_jail_dirs {
+                caps.push("chown");
+            }
+            if self.max_connections.is_some() {
+                caps.push("net_admin");
+            }
+            // Only what is held can be handed on
+            caps.extend(self.ambient_caps.iter().map(String::as_str));
+        }
+        let mut caps: Vec<String> = caps.into_iter().map(str::to_string).collect();
+        caps.sort();
+        caps.dedup();
+        caps
+    }
+
+    /// Whether the supplementary groups are replaced by `groups`.
+    pub fn switches_groups(&self) -> bool {
+        !self.clone_newuser && self.drop_groups && (self.uid.is_some() || self.gid.is_some())
+    }
+
+    /// The uid_map to write, mapping root to `host_uid` unless configured.
+    pub fn uid_maps(&self, host_uid: u32) -> Vec<IdMap> {
+        default_id_map(&self.uid_map, host_uid)
+    }
+
+    /// The gid_map to write, mapping root to `host_gid` unless configured.
+    pub fn gid_maps(&self, host_gid: u32) -> Vec<IdMap> {
+        default_id_map(&self.gid_map, host_gid)
+    }
+
+    /// Check that `count` mounts stay within max_mounts.
+    pub fn check_mount_count(&self, count: usize) -> Result<()> {
+        if count > self.max_mounts {
+            bail!("{} mounts exceed max_mounts {}", count, self.max_mounts);
+        }
+        Ok(())
+    }
+
+    /// Check that uid and gid exist inside the user namespace, so switching
+    /// to them can fail with an explanation rather than EINVAL.
+    pub fn check_id_mapping(&self) -> Result<()> {
+        // Only the inside ranges matter, which don't depend on the host id
+        for (kind, id, maps) in [
+            ("uid", self.uid, self.uid_maps(0)),
+            ("gid", self.gid, self.gid_maps(0)),
+        ] {
+            let Some(id) = id else {
+                continue;
+            };
+            if !maps.iter().any(|map| map.contains(id)) {
+                let ranges: Vec<String> = maps
+                    .iter()
+                    .map(|map| {
+                        format!(
+                            "{}-{}",
+                            map.inside,
+                            map.inside + map.count.saturating_sub(1)
+                        )
+                    })
+                    .collect();
+                bail!(
+                    "{} {} is not mapped in the user namespace (mapped: {}); add it to {}_map or pick a mapped {}",
+                    kind,
+                    id,
+                    ranges.join(", "),
+                    kind,
+                    kind
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The configured fs_isolation, or what chroot_dir implies without one.
+    pub fn fs_isolation(&self) -> FsIsolation {
+        match (self.fs_isolation, &self.chroot_dir) {
+            (Some(isolation), _) => isolation,
+            (None, Some(_)) => FsIsolation::Chroot,
+            (None, None) => FsIsolation::None,
+        }
+    }
+
+    /// The configured network_mode, or what clone_newnet implies without one.
+    pub fn network_mode(&self) -> NetworkMode {
+        match self.network_mode {
+            Some(mode) => mode,
+            None if self.clone_newnet => NetworkMode::None,
+            None => NetworkMode::Host,
+        }
+    }
+
+    /// The configured setup_steps, or the default order without them.
+    pub fn setup_steps(&self) -> &[SetupStep] {
+        self.setup_steps
+            .as_deref()
+            .unwrap_or(&SetupStep::DEFAULT_ORDER)
+    }
+
+    /// Whether the jail gets a net namespace of its own.
+    pub fn net_namespace(&self) -> bool {
+        self.network_mode() != NetworkMode::Host
+    }
+
+    /// Expand `${VAR}` and `${VAR:-default}` in host and jail paths using
+    /// the environment rsjail was started with.
+    pub fn expand_env_vars(&mut self) -> Result<()> {
+        let lookup = |name: &str| std::env::var(name).ok();
+
+        if let Some(chroot_dir) = &self.chroot_dir {
+            self.chroot_dir = Some(expand_vars(chroot_dir, lookup).context("chroot_dir")?);
+        }
+        self.exec_bin = expand_vars(&self.exec_bin, lookup).context("exec_bin")?;
+        if let Some(preload) = &self.preload {
+            self.preload = Some(expand_vars(preload, lookup).context("preload")?);
+        }
+        for (index, mount) in self.mounts.iter_mut().enumerate() {
+            mount.src = expand_vars(&mount.src, lookup)
+                .with_context(|| format!("mounts[{}].src", index))?;
+            mount.dst = expand_vars(&mount.dst, lookup)
+                .with_context(|| format!("mounts[{}].dst", index))?;
+        }
+
+        Ok(())
+    }
+
+    /// Add the variables of env_file that env doesn't already set.
+    pub fn load_env_file(&mut self) -> Result<()> {
+        let Some(file) = &self.env_file else {
+            return Ok(());
+        };
+        let content =
+            fs::read_to_string(file).with_context(|| format!("cannot read env_file {}", file))?;
+        let vars = parse_env_file(&content).with_context(|| format!("env_file {}", file))?;
+        for (key, value) in vars {
+            self.env.entry(key).or_insert(value);
+        }
+        Ok(())
+    }
+
+    /// Replace every `@file:<path>` entry of `exec_args` with the lines of
+    /// the named host file.
+    pub fn expand_arg_files(&mut self) -> Result<()> {
+        let mut args = Vec::with_capacity(self.exec_args.len());
+
+        for arg in &self.exec_args {
+            match arg.strip_prefix(ARG_FILE_PREFIX) {
+                Some(file) => {
+                    let content = fs::read_to_string(file)
+                        .with_context(|| format!("cannot read argument file {}", file))?;
+                    args.extend(content.lines().map(str::to_string));
+                }
+                None => args.push(arg.clone()),
+            }
+        }
+
+        self.exec_args = args;
+        Ok(())
+    }
+
+    /// Turn every mount `src` starting with `./` into the canonical host path
+    /// under `src_root`, refusing any that would end up outside it.
+    pub fn resolve_mount_sources(&mut self) -> Result<()> {
+        if !self
+            .mounts
+            .iter()
+            .any(|mount| mount.src.starts_with(SRC_ROOT_PREFIX))
+        {
+            return Ok(());
+        }
+        let Some(src_root) = &self.src_root else {
+            bail!("mount srcs starting with {} need src_root", SRC_ROOT_PREFIX);
+        };
+        let root = fs::canonicalize(src_root)
+            .with_context(|| format!("cannot resolve src_root {}", src_root))?;
+
+        for mount in &mut self.mounts {
+            let Some(relative) = mount.src.strip_prefix(SRC_ROOT_PREFIX) else {
+                continue;
+            };
+            let src = fs::canonicalize(root.join(relative))
+                .with_context(|| format!("cannot resolve mount src {}", mount.src))?;
+            if !src.starts_with(&root) {
+                bail!("mount src {} escapes src_root", mount.src);
+            }
+            mount.src = src.to_string_lossy().into_owned();
+        }
+
+        Ok(())
+    }
+}
+
+fn validate_device_mount(mount: &MountConfig) -> Result<()> {
+    if !mount.is_bind {
+        bail!("device mount {} must be a bind mount", mount.src);
+    }
+
+    let metadata =
+        fs::metadata(&mount.src).with_context(|| format!("device mount source {}", mount.src))?;
+    let file_type = metadata.file_type();
+    if !file_type.is_char_device() && !file_type.is_block_device() {
+        bail!("device mount source {} is not a device node", mount.src);
+    }
+
+    log::warn!(
+        "exposing host device {} to the jail gives it direct access to that hardware",
+        mount.src
+    );
+    Ok(())
+}
+
+/// Check that `entry` is one hosts(5) line: an address, then names.
+fn validate_hosts_entry(entry: &str) -> Result<()> {
+    if entry.contains('\n') {
+        bail!("extra_hosts entry {:?} spans several lines", entry);
+    }
+    let mut fields = entry.split_whitespace();
+    let address = fields.next().unwrap_or_default();
+    if address.parse::<std::net::IpAddr>().is_err() {
+        bail!("extra_hosts entry {:?} needs an IP address first", entry);
+    }
+
+    let mut names = fields.peekable();
+    if names.peek().is_none() {
+        bail!("extra_hosts entry {:?} has no hostname", entry);
+    }
+    for name in names {
+        let valid = name.len() <= 253
+            && name.split('.').all(|label| {
+                !label.is_empty()
+                    && !label.starts_with('-')
+                    && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            });
+        if !valid {
+            bail!("extra_hosts entry {:?}: bad hostname {:?}", entry, name);
+        }
+    }
+    Ok(())
+}
+
+/// Parse a byte count such as "64K", "512M" or "1G", in powers of 1024, or
+/// a plain number of bytes.
+pub fn parse_bytes(text: &str) -> Result<u64> {
+    parse_with_units(text, &BYTE_UNITS)
+        .with_context(|| format!("bad byte size {:?}, expected e.g. 512M or 1G", text))
+}
+
+/// Parse a duration such as "10s", "5m", "2h" or "1d", or a plain number
+/// of seconds, into seconds.
+pub fn parse_seconds(text: &str) -> Result<u64> {
+    parse_with_units(text, &SECOND_UNITS)
+        .with_context(|| format!("bad duration {:?}, expected e.g. 10s or 5m", text))
+}
+
+fn parse_with_units(text: &str, units: &[(&str, u64)]) -> Result<u64> {
+    let lower = text.trim().to_ascii_lowercase();
+    let (number, factor) = units
+        .iter()
+        .find_map(|&(suffix, factor)| Some((lower.strip_suffix(suffix)?, factor)))
+        .unwrap_or((&lower, 1));
+    let number: u64 = number.trim_end().parse()?;
+    number
+        .checked_mul(factor)
+        .ok_or_else(|| anyhow!("{} is too large", text))
+}
+
+/// A config number that may also be given as a string with a unit.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Quantity {
+    Number(u64),
+    Text(String),
+}
+
+impl Quantity {
+    fn resolve(self, parse: fn(&str) -> Result<u64>) -> Result<u64> {
+        match self {
+            Quantity::Number(number) => Ok(number),
+            Quantity::Text(text) => parse(&text),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum QuantityRlimit {
+    Both(Quantity),
+    Split { soft: Quantity, hard: Quantity },
+}
+
+fn deserialize_quantity<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+    parse: fn(&str) -> Result<u64>,
+) -> std::result::Result<Option<u64>, D::Error> {
+    Option::<Quantity>::deserialize(deserializer)?
+        .map(|quantity| quantity.resolve(parse))
+        .transpose()
+        .map_err(|e| serde::de::Error::custom(format!("{:#}", e)))
+}
+
+fn deserialize_quantity_rlimit<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+    parse: fn(&str) -> Result<u64>,
+) -> std::result::Result<Option<Rlimit>, D::Error> {
+    let resolve = |quantity: Quantity| {
+        quantity
+            .resolve(parse)
+            .map_err(|e| serde::de::Error::custom(format!("{:#}", e)))
+    };
+    let Some(limit) = Option::<QuantityRlimit>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    Ok(Some(match limit {
+        QuantityRlimit::Both(limit) => Rlimit::Both(resolve(limit)?),
+        QuantityRlimit::Split { soft, hard } => Rlimit::Split {
+            soft: resolve(soft)?,
+            hard: resolve(hard)?,
+        },
+    }))
+}
+
+fn deserialize_bytes<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<u64>, D::Error> {
+    deserialize_quantity(deserializer, parse_bytes)
+}
+
+fn deserialize_byte_size<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<u64, D::Error> {
+    Quantity::deserialize(deserializer)?
+        .resolve(parse_bytes)
+        .map_err(|e| serde::de::Error::custom(format!("{:#}", e)))
+}
+
+fn deserialize_seconds<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<u64>, D::Error> {
+    deserialize_quantity(deserializer, parse_seconds)
+}
+
+fn deserialize_byte_rlimit<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<Rlimit>, D::Error> {
+    deserialize_quantity_rlimit(deserializer, parse_bytes)
+}
+
+fn deserialize_seconds_rlimit<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<Rlimit>, D::Error> {
+    deserialize_quantity_rlimit(deserializer, parse_seconds)
+}
+
+/// Parse a signal name such as "SIGTERM" or "term".
+pub fn parse_signal(name: &str) -> Result<Signal> {
+    let upper = name.to_ascii_uppercase();
+    let full = if upper.starts_with("SIG") {
+        upper
+    } else {
+        format!("SIG{}", upper)
+    };
+    Signal::from_str(&full).map_err(|_| anyhow!("unknown signal name {:?}", name))
+}
+
+fn default_id_map(configured: &[IdMap], host_id: u32) -> Vec<IdMap> {
+    if !configured.is_empty() {
+        return configured.to_vec();
+    }
+    vec![IdMap {
+        inside: 0,
+        outside: host_id,
+        count: 1,
+    }]
+}
+
+/// A serde_json error as `file:line:column: message`, the form editors and
+/// terminals can jump to.
+pub(crate) fn parse_error(path: &Path, e: &serde_json::Error) -> anyhow::Error {
+    let message = e.to_string();
+    let position = format!(" at line {} column {}", e.line(), e.column());
+    let message = message.strip_suffix(&position).unwrap_or(&message);
+    let (line, column) = (e.line(), e.column());
+    anyhow!("{}:{}:{}: {}", path.display(), line, column, message)
+}
+
+/// The first key in `raw` that is missing after a round trip through the
+/// config types, which means serde ignored it, as a dotted path.
+fn unknown_field(
+    raw: &serde_json::Value,
+    parsed: &serde_json::Value,
+    prefix: &str,
+) -> Option<String> {
+    use serde_json::Value;
+
+    match (raw, parsed) {
+        (Value::Object(raw), Value::Object(parsed)) => raw.iter().find_map(|(key, value)| {
+            let path = if prefix.is_empty() {
+                key.clone()
+            } else {
+                format!("{}.{}", prefix, key)
+            };
+            match parsed.get(key) {
+                Some(parsed) => unknown_field(value, parsed, &path),
+                None => Some(path),
+            }
+        }),
+        (Value::Array(raw), Value::Array(parsed)) => {
+            raw.iter()
+                .zip(parsed)
+                .enumerate()
+                .find_map(|(index, (raw, parsed))| {
+                    unknown_field(raw, parsed, &format!("{}[{}]", prefix, index))
+                })
+        }
+        _ => None,
+    }
+}
+
+/// Substitute `${VAR}` and `${VAR:-default}` references in `input`. A
+/// variable that `lookup` can't resolve is an error unless it has a default.
+pub fn expand_vars(input: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+
+    while let Some(start) = rest.find("${") {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some(end) = after.find('}') else {
+            bail!("unterminated variable reference in {:?}", input);
+        };
+
+        let reference = &after[..end];
+        let (name, default) = match reference.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (reference, None),
+        };
+        match lookup(name).or_else(|| default.map(str::to_string)) {
+            Some(value) => output.push_str(&value),
+            None => bail!("environment variable {} is not set", name),
+        }
+
+        rest = &after[end + 1..];
+    }
+
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// The variables of a dotenv-style file: `KEY=VALUE` lines, optionally
+/// after `export`, with `#` comments and blank lines skipped. A value may be
+/// 'single quoted', taken as is, or "double quoted", where \", \\ and \n
+/// are escapes; an unquoted one ends at a ` #` comment.
+pub fn parse_env_file(content: &str) -> Result<Vec<(String, String)>> {
+    let mut vars = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((key, value)) = line.split_once('=') else {
+            bail!("line {}: expected KEY=VALUE", index + 1);
+        };
+        let key = key.trim();
+        let valid = key.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            bail!("line {}: {:?} is not a variable name", index + 1, key);
+        }
+        let value = parse_env_value(value.trim())
+            .with_context(|| format!("line {}: value of {}", index + 1, key))?;
+        vars.push((key.to_string(), value));
+    }
+    Ok(vars)
+}
+
+fn parse_env_value(value: &str) -> Result<String> {
+    let (quote, rest) = match value.chars().next() {
+        Some(quote @ ('"' | '\'')) => (quote, &value[1..]),
+        _ => {
+            let end = value.find(" #").unwrap_or(value.len());
+            return Ok(value[..end].trim_end().to_string());
+        }
+    };
+
+    let mut parsed = String::new();
+    let mut chars = rest.char_indices();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            c if c == quote => {
+                let after = rest[index + 1..].trim_start();
+                if !after.is_empty() && !after.starts_with('#') {
+                    bail!("unexpected {:?} after the closing quote", after);
+                }
+                return Ok(parsed);
+            }
+            '\\' if quote == '"' => match chars.next() {
+                Some((_, 'n')) => parsed.push('\n'),
+                Some((_, escaped)) => parsed.push(escaped),
+                None => break,
+            },
+            c => parsed.push(c),
+        }
+    }
+    bail!("missing closing {}", quote)
+}
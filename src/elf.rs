@@ -0,0 +1,348 @@
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_STRTAB: u64 = 5;
+const DT_RPATH: u64 = 15;
+const DT_RUNPATH: u64 = 29;
+
+/// The parts of an ELF file needed to work out what it takes to execute it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElfInfo {
+    pub is_64bit: bool,
+    pub machine: u16,
+    /// PT_INTERP, the dynamic loader the kernel starts for this binary
+    pub interpreter: Option<String>,
+    /// DT_NEEDED entries, in declaration order
+    pub needed: Vec<String>,
+    /// DT_RUNPATH, or DT_RPATH if there is no RUNPATH
+    pub runpath: Vec<String>,
+}
+
+/// The name qemu-user gives the architecture of an ELF file, as in
+/// qemu-aarch64, for the common ones.
+pub fn qemu_arch(info: &ElfInfo) -> Option<&'static str> {
+    Some(match (info.machine, info.is_64bit) {
+        (3, false) => "i386",
+        (62, true) => "x86_64",
+        (40, false) => "arm",
+        (183, true) => "aarch64",
+        (243, false) => "riscv32",
+        (243, true) => "riscv64",
+        (22, true) => "s390x",
+        (258, true) => "loongarch64",
+        _ => return None,
+    })
+}
+
+/// Whether the file starts with the ELF magic.
+pub fn is_elf(path: &Path) -> bool {
+    let mut magic = [0u8; 4];
+    fs::File::open(path)
+        .and_then(|mut file| std::io::Read::read_exact(&mut file, &mut magic))
+        .map(|_| magic == *b"\x7fELF")
+        .unwrap_or(false)
+}
+
+/// Parse the program headers and dynamic section of an ELF file.
+pub fn parse(path: &Path) -> Result<ElfInfo> {
+    let data = fs::read(path).with_context(|| format!("cannot read {}", path.display()))?;
+    parse_bytes(&data).with_context(|| format!("cannot parse ELF file {}", path.display()))
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    is_64bit: bool,
+    big_endian: bool,
+}
+
+impl Reader<'_> {
+    fn bytes<const N: usize>(&self, offset: u64) -> Result<[u8; N]> {
+        let start = usize::try_from(offset)?;
+        match self.data.get(start..start + N) {
+            Some(bytes) => Ok(bytes.try_into()?),
+            None => bail!("truncated at offset {}", offset),
+        }
+    }
+
+    fn u16(&self, offset: u64) -> Result<u16> {
+        let bytes = self.bytes(offset)?;
+        Ok(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, offset: u64) -> Result<u32> {
+        let bytes = self.bytes(offset)?;
+        Ok(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn u64(&self, offset: u64) -> Result<u64> {
+        let bytes = self.bytes(offset)?;
+        Ok(if self.big_endian {
+            u64::from_be_bytes(bytes)
+        } else {
+            u64::from_le_bytes(bytes)
+        })
+    }
+
+    /// A word-sized field: 8 bytes in ELF64, 4 bytes in ELF32.
+    fn word(&self, offset: u64) -> Result<u64> {
+        if self.is_64bit {
+            self.u64(offset)
+        } else {
+            self.u32(offset).map(u64::from)
+        }
+    }
+
+    fn c_str(&self, offset: u64) -> Result<String> {
+        let start = usize::try_from(offset)?;
+        let tail = self
+            .data
+            .get(start..)
+            .ok_or_else(|| anyhow::anyhow!("string offset {} out of range", offset))?;
+        let end = tail.iter().position(|&b| b == 0).unwrap_or(tail.len());
+        Ok(String::from_utf8_lossy(&tail[..end]).into_owned())
+    }
+}
+
+struct ProgramHeader {
+    kind: u32,
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+}
+
+fn parse_bytes(data: &[u8]) -> Result<ElfInfo> {
+    if data.len() < 16 || data[..4] != *b"\x7fELF" {
+        bail!("not an ELF file");
+    }
+    let reader = Reader {
+        data,
+        is_64bit: data[4] == 2,
+        big_endian: data[5] == 2,
+    };
+
+    let machine = reader.u16(18)?;
+    let (phoff, phentsize, phnum) = if reader.is_64bit {
+        (reader.u64(32)?, reader.u16(54)?, reader.u16(56)?)
+    } else {
+        (u64::from(reader.u32(28)?), reader.u16(42)?, reader.u16(44)?)
+    };
+
+    let mut headers = Vec::with_capacity(phnum as usize);
+    for index in 0..u64::from(phnum) {
+        let base = phoff + index * u64::from(phentsize);
+        headers.push(if reader.is_64bit {
+            ProgramHeader {
+                kind: reader.u32(base)?,
+                offset: reader.u64(base + 8)?,
+                vaddr: reader.u64(base + 16)?,
+                filesz: reader.u64(base + 32)?,
+            }
+        } else {
+            ProgramHeader {
+                kind: reader.u32(base)?,
+                offset: u64::from(reader.u32(base + 4)?),
+                vaddr: u64::from(reader.u32(base + 8)?),
+                filesz: u64::from(reader.u32(base + 16)?),
+            }
+        });
+    }
+
+    let mut info = ElfInfo {
+        is_64bit: reader.is_64bit,
+        machine,
+        interpreter: None,
+        needed: Vec::new(),
+        runpath: Vec::new(),
+    };
+
+    if let Some(interp) = headers.iter().find(|ph| ph.kind == PT_INTERP) {
+        info.interpreter = Some(reader.c_str(interp.offset)?);
+    }
+
+    let Some(dynamic) = headers.iter().find(|ph| ph.kind == PT_DYNAMIC) else {
+        return Ok(info);
+    };
+
+    let entry_size = if reader.is_64bit { 16 } else { 8 };
+    let mut strtab_vaddr = None;
+    let mut needed_offsets = Vec::new();
+    let mut rpath = None;
+    let mut runpath = None;
+
+    for index in 0..dynamic.filesz / entry_size {
+        let base = dynamic.offset + index * entry_size;
+        let tag = reader.word(base)?;
+        let value = reader.word(base + entry_size / 2)?;
+        match tag {
+            DT_NULL => break,
+            DT_NEEDED => needed_offsets.push(value),
+            DT_STRTAB => strtab_vaddr = Some(value),
+            DT_RPATH => rpath = Some(value),
+            DT_RUNPATH => runpath = Some(value),
+            _ => {}
+        }
+    }
+
+    let Some(strtab_vaddr) = strtab_vaddr else {
+        return Ok(info);
+    };
+    // DT_STRTAB is a virtual address; map it back to a file offset
+    let strtab = headers
+        .iter()
+        .filter(|ph| ph.kind == PT_LOAD)
+        .find(|ph| strtab_vaddr >= ph.vaddr && strtab_vaddr < ph.vaddr + ph.filesz)
+        .map(|ph| strtab_vaddr - ph.vaddr + ph.offset)
+        .ok_or_else(|| anyhow::anyhow!("DT_STRTAB is outside every loaded segment"))?;
+
+    for offset in needed_offsets {
+        info.needed.push(reader.c_str(strtab + offset)?);
+    }
+    if let Some(offset) = runpath.or(rpath) {
+        info.runpath = reader
+            .c_str(strtab + offset)?
+            .split(':')
+            .filter(|dir| !dir.is_empty())
+            .map(str::to_string)
+            .collect();
+    }
+
+    Ok(info)
+}
+
+/// Debian-style multiarch directory name for an ELF machine type.
+fn multiarch_triplet(machine: u16) -> Option<&'static str> {
+    match machine {
+        3 => Some("i386-linux-gnu"),
+        40 => Some("arm-linux-gnueabihf"),
+        62 => Some("x86_64-linux-gnu"),
+        183 => Some("aarch64-linux-gnu"),
+        243 => Some("riscv64-linux-gnu"),
+        _ => None,
+    }
+}
+
+/// Directories listed in /etc/ld.so.conf, following `include` lines.
+fn ld_so_conf_dirs(conf: &Path, dirs: &mut Vec<PathBuf>, depth: usize) {
+    let Ok(content) = fs::read_to_string(conf) else {
+        return;
+    };
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(pattern) = line.strip_prefix("include") {
+            if depth > 4 {
+                continue;
+            }
+            let pattern = Path::new(pattern.trim());
+            let (Some(parent), Some(file_pattern)) = (pattern.parent(), pattern.file_name()) else {
+                continue;
+            };
+            let file_pattern = file_pattern.to_string_lossy();
+            let (prefix, suffix) = file_pattern.split_once('*').unwrap_or((&file_pattern, ""));
+            let Ok(entries) = fs::read_dir(parent) else {
+                continue;
+            };
+            let mut includes: Vec<PathBuf> = entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| {
+                    let name = path.file_name().unwrap_or_default().to_string_lossy();
+                    name.starts_with(prefix) && name.ends_with(suffix)
+                })
+                .collect();
+            includes.sort();
+            for include in includes {
+                ld_so_conf_dirs(&include, dirs, depth + 1);
+            }
+        } else {
+            dirs.push(PathBuf::from(line));
+        }
+    }
+}
+
+/// Library search directories for a binary, in the order the loader would
+/// try them (RUNPATH first, ld.so.conf, then the built-in defaults).
+fn search_dirs(info: &ElfInfo, origin: &Path) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = info
+        .runpath
+        .iter()
+        .map(|dir| PathBuf::from(dir.replace("$ORIGIN", &origin.to_string_lossy())))
+        .collect();
+
+    ld_so_conf_dirs(Path::new("/etc/ld.so.conf"), &mut dirs, 0);
+
+    if let Some(triplet) = multiarch_triplet(info.machine) {
+        dirs.push(Path::new("/lib").join(triplet));
+        dirs.push(Path::new("/usr/lib").join(triplet));
+    }
+    if info.is_64bit {
+        dirs.extend(["/lib64", "/usr/lib64"].map(PathBuf::from));
+    }
+    dirs.extend(["/lib", "/usr/lib"].map(PathBuf::from));
+    dirs
+}
+
+/// Find `name` in `dirs`, skipping candidates built for a different class
+/// or machine (e.g. 32-bit libraries in /usr/lib on a 64-bit host).
+fn find_library(name: &str, dirs: &[PathBuf], info: &ElfInfo) -> Option<PathBuf> {
+    dirs.iter().map(|dir| dir.join(name)).find(|candidate| {
+        parse(candidate)
+            .map(|lib| lib.is_64bit == info.is_64bit && lib.machine == info.machine)
+            .unwrap_or(false)
+    })
+}
+
+/// Host paths of every shared library `binary` needs, transitively, plus its
+/// dynamic loader. Statically linked binaries yield an empty list.
+pub fn resolve_dependencies(binary: &Path) -> Result<Vec<PathBuf>> {
+    let root = parse(binary)?;
+    let mut resolved = BTreeSet::new();
+
+    if let Some(interpreter) = &root.interpreter {
+        resolved.insert(PathBuf::from(interpreter));
+    }
+
+    let mut pending = vec![(binary.to_path_buf(), root)];
+    let mut seen = BTreeSet::new();
+    while let Some((path, info)) = pending.pop() {
+        let origin = path.parent().unwrap_or(Path::new("/")).to_path_buf();
+        let dirs = search_dirs(&info, &origin);
+
+        for name in &info.needed {
+            if !seen.insert(name.clone()) {
+                continue;
+            }
+            // A DT_NEEDED containing a slash is a path, not a name to search for
+            let library = if name.contains('/') {
+                Some(PathBuf::from(name))
+            } else {
+                find_library(name, &dirs, &info)
+            };
+            let Some(library) = library else {
+                bail!("{}: cannot find shared library {}", binary.display(), name);
+            };
+            let library_info = parse(&library)?;
+            resolved.insert(library.clone());
+            pending.push((library, library_info));
+        }
+    }
+
+    Ok(resolved.into_iter().collect())
+}
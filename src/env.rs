@@ -0,0 +1,146 @@
+use nix::sys::resource::{getrlimit, Resource};
+use std::fs;
+
+/// Kernel features rsjail can make use of, as detected on the running host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelFeatures {
+    pub kernel_release: String,
+    pub user_namespaces: bool,
+    pub time_namespaces: bool,
+    pub cgroup_namespaces: bool,
+    pub cgroup_v2: bool,
+    pub seccomp: bool,
+    pub overlayfs: bool,
+    pub overlayfs_userns: bool,
+    pub idmapped_mounts: bool,
+    pub pidfd: bool,
+}
+
+impl KernelFeatures {
+    /// Feature names paired with their availability, in display order.
+    pub fn matrix(&self) -> Vec<(&'static str, bool)> {
+        vec![
+            ("user namespaces", self.user_namespaces),
+            ("time namespaces", self.time_namespaces),
+            ("cgroup namespaces", self.cgroup_namespaces),
+            ("cgroup v2", self.cgroup_v2),
+            ("seccomp", self.seccomp),
+            ("overlayfs", self.overlayfs),
+            ("overlayfs in userns", self.overlayfs_userns),
+            ("idmapped mounts", self.idmapped_mounts),
+            ("pidfd", self.pidfd),
+        ]
+    }
+}
+
+/// Probe the running kernel for the features rsjail relies on, for `check`
+/// and for `JailConfig::validate` to hold a config against.
+pub fn detect_features() -> KernelFeatures {
+    let kernel_release = read_trimmed("/proc/sys/kernel/osrelease").unwrap_or_default();
+    let version = parse_kernel_version(&kernel_release);
+
+    let overlayfs = filesystem_supported("overlay");
+    KernelFeatures {
+        user_namespaces: user_namespaces_enabled(),
+        time_namespaces: fs::exists("/proc/self/ns/time").unwrap_or(false),
+        cgroup_namespaces: fs::exists("/proc/self/ns/cgroup").unwrap_or(false),
+        cgroup_v2: cgroup_v2_mounted(),
+        seccomp: seccomp_available(),
+        overlayfs,
+        // Unprivileged overlay mounts inside a user namespace landed in 5.11
+        overlayfs_userns: overlayfs && version >= (5, 11),
+        idmapped_mounts: idmapped_mounts_available(),
+        pidfd: crate::pidfd::available(),
+        kernel_release,
+    }
+}
+
+/// Parse the leading "major.minor" of a kernel release string.
+pub fn parse_kernel_version(release: &str) -> (u32, u32) {
+    let mut parts = release
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|part| part.parse().unwrap_or(0));
+    let major = parts.next().unwrap_or(0);
+    let minor = parts.next().unwrap_or(0);
+    (major, minor)
+}
+
+/// The resource limits a config can set
+pub const RLIMITS: [Resource; 5] = [
+    Resource::RLIMIT_AS,
+    Resource::RLIMIT_CPU,
+    Resource::RLIMIT_NOFILE,
+    Resource::RLIMIT_MEMLOCK,
+    Resource::RLIMIT_DATA,
+];
+
+/// The soft and hard value rsjail runs with for each of RLIMITS, which
+/// the jail inherits and can't raise the hard one of without privilege.
+pub fn resource_limits() -> Vec<(Resource, u64, u64)> {
+    RLIMITS
+        .into_iter()
+        .filter_map(|resource| {
+            let (soft, hard) = getrlimit(resource).ok()?;
+            Some((resource, soft, hard))
+        })
+        .collect()
+}
+
+fn read_trimmed(path: &str) -> Option<String> {
+    fs::read_to_string(path).ok().map(|s| s.trim().to_string())
+}
+
+fn user_namespaces_enabled() -> bool {
+    let max = read_trimmed("/proc/sys/user/max_user_namespaces")
+        .and_then(|s| s.parse::<u64>().ok())
+        .unwrap_or(0);
+
+    // Debian and Ubuntu kernels carry an extra switch for unprivileged use,
+    // which root isn't held to
+    let unprivileged = read_trimmed("/proc/sys/kernel/unprivileged_userns_clone")
+        .map(|s| s != "0")
+        .unwrap_or(true);
+
+    max > 0 && (unprivileged || nix::unistd::geteuid().is_root())
+}
+
+fn cgroup_v2_mounted() -> bool {
+    fs::read_to_string("/proc/self/mounts")
+        .map(|mounts| {
+            mounts
+                .lines()
+                .any(|line| line.split_whitespace().nth(2) == Some("cgroup2"))
+        })
+        .unwrap_or(false)
+}
+
+fn seccomp_available() -> bool {
+    // PR_GET_SECCOMP fails with EINVAL when the kernel lacks CONFIG_SECCOMP
+    unsafe { libc::prctl(libc::PR_GET_SECCOMP, 0, 0, 0, 0) >= 0 }
+}
+
+fn filesystem_supported(name: &str) -> bool {
+    fs::read_to_string("/proc/filesystems")
+        .map(|filesystems| {
+            filesystems
+                .lines()
+                .any(|line| line.split_whitespace().last() == Some(name))
+        })
+        .unwrap_or(false)
+}
+
+fn idmapped_mounts_available() -> bool {
+    // mount_setattr(2) is the entry point for idmapped mounts; an invalid fd
+    // yields EBADF when the syscall exists and ENOSYS when it does not
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            -1,
+            std::ptr::null::<libc::c_char>(),
+            0,
+            std::ptr::null::<libc::c_void>(),
+            0,
+        )
+    };
+    ret == 0 || std::io::Error::last_os_error().raw_os_error() != Some(libc::ENOSYS)
+}
@@ -0,0 +1,97 @@
+use anyhow::{bail, Result};
+use std::fs;
+use std::path::PathBuf;
+
+/// Whether `text` has any of the wildcards `matches` understands.
+pub fn is_pattern(text: &str) -> bool {
+    text.contains(['*', '?', '['])
+}
+
+/// Whether the file name `name` matches `pattern`, where `*` stands for any
+/// run of characters, `?` for any one and `[a-z]` or `[!a-z]` for one in or
+/// not in the class, as in the shell. A leading dot must be matched by one.
+pub fn matches(pattern: &str, name: &str) -> bool {
+    if name.starts_with('.') && !pattern.starts_with('.') {
+        return false;
+    }
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    match_from(&pattern, &name)
+}
+
+fn match_from(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|skip| match_from(rest, &name[skip..])),
+        Some(('?', rest)) => !name.is_empty() && match_from(rest, &name[1..]),
+        Some(('[', rest)) => match class_end(rest) {
+            Some(end) => name.first().is_some_and(|c| {
+                in_class(&rest[..end], *c) && match_from(&rest[end + 1..], &name[1..])
+            }),
+            // Unclosed, so a plain '['
+            None => name.first() == Some(&'[') && match_from(rest, &name[1..]),
+        },
+        Some((c, rest)) => name.first() == Some(c) && match_from(rest, &name[1..]),
+    }
+}
+
+/// The index of the `]` closing a class whose body starts `class`. A `]`
+/// right at the start (after any `!`) is part of the class.
+fn class_end(class: &[char]) -> Option<usize> {
+    let start = usize::from(matches!(class.first(), Some('!' | '^')));
+    (start + 1..class.len()).find(|&i| class[i] == ']')
+}
+
+fn in_class(class: &[char], c: char) -> bool {
+    let (negated, class) = match class.split_first() {
+        Some(('!' | '^', rest)) => (true, rest),
+        _ => (false, class),
+    };
+    let mut found = false;
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == '-' {
+            found |= (class[i]..=class[i + 2]).contains(&c);
+            i += 3;
+        } else {
+            found |= class[i] == c;
+            i += 1;
+        }
+    }
+    found != negated
+}
+
+/// The paths matching the absolute path `pattern`, sorted, with wildcards
+/// allowed in any component but never matching a '/'. Fails once there are
+/// more than `limit`, rather than walking on through a huge tree.
+pub fn expand(pattern: &str, limit: usize) -> Result<Vec<PathBuf>> {
+    let mut paths = vec![PathBuf::from("/")];
+    for component in pattern.split('/').filter(|component| !component.is_empty()) {
+        let mut next = Vec::new();
+        for dir in &paths {
+            if !is_pattern(component) {
+                let path = dir.join(component);
+                if path.symlink_metadata().is_ok() {
+                    next.push(path);
+                }
+                continue;
+            }
+            // Not a directory, or one we can't read, matches nothing
+            let Ok(entries) = fs::read_dir(dir) else {
+                continue;
+            };
+            let mut names: Vec<_> = entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name())
+                .filter(|name| name.to_str().is_some_and(|name| matches(component, name)))
+                .collect();
+            names.sort();
+            next.extend(names.into_iter().map(|name| dir.join(name)));
+            if next.len() > limit {
+                bail!("{} matches more than {} paths", pattern, limit);
+            }
+        }
+        paths = next;
+    }
+    Ok(paths)
+}
@@ -0,0 +1,187 @@
+use crate::{cgroup, compose, JailConfig};
+use anyhow::{bail, Context, Result};
+use nix::unistd::{setsid, Pid};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+/// Where `rsjail start` keeps its state files unless told otherwise
+pub const DEFAULT_STATE_DIR: &str = "/run/rsjail";
+
+/// A jail `rsjail start` keeps up with nothing in it but a holder process
+/// (see `Jail::hold`), for `rsjail exec` to run commands in until `rsjail
+/// stop`. It runs under an rsjail of its own, detached, which records it
+/// in `<state dir>/<name>.json` once the jail is set up and removes that
+/// again when the holder is gone. Its output goes to `<name>.log` there.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HeldJail {
+    /// The config the jail was started from, absolute
+    pub config: PathBuf,
+    /// That config as loaded and validated then, which `exec` applies
+    /// whatever has happened to the file since
+    pub jail: JailConfig,
+    /// The rsjail the jail runs under
+    pub supervisor: i32,
+    /// Its child holding the jail's namespaces
+    pub holder: i32,
+    /// The jail's cgroup subtree, if it has one of its own
+    #[serde(default)]
+    pub cgroup: Option<PathBuf>,
+}
+
+impl HeldJail {
+    /// The state file of jail `name`.
+    pub fn state_file(state_dir: &Path, name: &str) -> Result<PathBuf> {
+        if name.is_empty() || name.contains('/') {
+            bail!("jail name {:?} must be non-empty and without '/'", name);
+        }
+        Ok(state_dir.join(format!("{}.json", name)))
+    }
+
+    /// Jail `name`, if it is still running.
+    pub fn load(state_dir: &Path, name: &str) -> Result<Option<Self>> {
+        let path = Self::state_file(state_dir, name)?;
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("cannot read {}", path.display())),
+        };
+        let held: Self = serde_json::from_str(&content)
+            .with_context(|| format!("cannot parse {}", path.display()))?;
+        Ok(held.is_running().then_some(held))
+    }
+
+    /// The running jails in `state_dir`, by name.
+    pub fn list(state_dir: &Path) -> Result<Vec<(String, Self)>> {
+        let entries = match fs::read_dir(state_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("cannot read {}", state_dir.display()));
+            }
+        };
+        let mut jails = Vec::new();
+        for entry in entries {
+            let file_name = entry?.file_name();
+            let Some(name) = file_name.to_str().and_then(|n| n.strip_suffix(".json")) else {
+                continue;
+            };
+            if let Some(held) = Self::load(state_dir, name)? {
+                jails.push((name.to_string(), held));
+            }
+        }
+        jails.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(jails)
+    }
+
+    /// Write the state file at `path`, in one step so `start` never reads
+    /// half of it.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let partial = path.with_extension("json.tmp");
+        fs::write(&partial, serde_json::to_string(self)?)
+            .with_context(|| format!("cannot write {}", partial.display()))?;
+        fs::rename(&partial, path).with_context(|| format!("cannot write {}", path.display()))
+    }
+
+    /// Whether the holder is still there under its rsjail. Either pid may
+    /// have been reused since.
+    pub fn is_running(&self) -> bool {
+        let Ok(stat) = fs::read_to_string(format!("/proc/{}/stat", self.holder)) else {
+            return false;
+        };
+        // State and ppid follow the command name, which may hold anything
+        let Some((_, rest)) = stat.rsplit_once(") ") else {
+            return false;
+        };
+        let mut fields = rest.split_whitespace();
+        let state = fields.next();
+        let ppid = fields.next().and_then(|ppid| ppid.parse::<i32>().ok());
+        state != Some("Z") && ppid == Some(self.supervisor)
+    }
+
+    pub fn holder(&self) -> Pid {
+        Pid::from_raw(self.holder)
+    }
+
+    /// Start jail `name` from `config` with `rsjail` (this binary) and wait
+    /// until it is set up. Fails if it is already running or its setup
+    /// does, with the end of its log.
+    pub fn start(rsjail: &Path, config: &Path, state_dir: &Path, name: &str) -> Result<Self> {
+        if let Some(held) = Self::load(state_dir, name)? {
+            bail!("jail {} is already running (pid {})", name, held.holder);
+        }
+        fs::create_dir_all(state_dir)
+            .with_context(|| format!("cannot create {}", state_dir.display()))?;
+        let state_file = Self::state_file(state_dir, name)?;
+        let _ = fs::remove_file(&state_file);
+        let config = std::path::absolute(config)?;
+        let log_path = state_dir.join(format!("{}.log", name));
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)
+            .with_context(|| format!("cannot open {}", log_path.display()))?;
+
+        let mut command = Command::new(rsjail);
+        // Relative paths in the config mean the same as running it from there
+        command
+            .arg("--config")
+            .arg(&config)
+            .arg("--hold")
+            .arg(&state_file)
+            .current_dir(config.parent().unwrap_or(Path::new("/")))
+            .stdin(Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log);
+        // A session of its own, away from our terminal's hangup
+        unsafe {
+            command.pre_exec(|| {
+                setsid()?;
+                Ok(())
+            });
+        }
+        let mut child = command
+            .spawn()
+            .with_context(|| format!("cannot start jail {}", name))?;
+
+        loop {
+            if let Some(held) = Self::load(state_dir, name)? {
+                return Ok(held);
+            }
+            if let Some(status) = child.try_wait()? {
+                let log = fs::read_to_string(&log_path).unwrap_or_default();
+                let lines: Vec<&str> = log.lines().collect();
+                let tail = lines[lines.len().saturating_sub(5)..].join("\n");
+                bail!("jail {} exited ({}): {}", name, status, tail);
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Freeze every process in the jail, or thaw them, through its
+    /// cgroup's freezer. Fails without a cgroup, see `Jail::freeze`.
+    pub fn freeze(&self, frozen: bool) -> Result<()> {
+        let Some(cgroup) = &self.cgroup else {
+            bail!("jail has no cgroup of its own, which memory_high or cgroup_delegate give it");
+        };
+        cgroup::set_frozen(cgroup, frozen)
+    }
+
+    /// Whether the jail is frozen, None without a cgroup to freeze.
+    pub fn is_frozen(&self) -> Option<bool> {
+        cgroup::is_frozen(self.cgroup.as_deref()?)
+    }
+
+    /// SIGTERM the holder, and SIGKILL it if its rsjail hasn't cleaned up
+    /// and exited within `timeout`. A frozen jail is thawed first, or it
+    /// could never act on the SIGTERM.
+    pub fn stop(&self, timeout: Duration) -> Result<()> {
+        if self.is_frozen() == Some(true) {
+            self.freeze(false)?;
+        }
+        compose::stop(Pid::from_raw(self.supervisor), timeout)
+    }
+}
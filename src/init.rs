@@ -0,0 +1,101 @@
+use anyhow::{Context, Result};
+use nix::sys::signal::{kill, SigSet, SigmaskHow, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{fork, ForkResult, Pid};
+use std::convert::Infallible;
+
+/// What a container runtime or `rsjail down` sends to stop or poke the
+/// program, which PID 1 would otherwise never see without handlers of its own
+const FORWARDED: [Signal; 7] = [
+    Signal::SIGTERM,
+    Signal::SIGINT,
+    Signal::SIGHUP,
+    Signal::SIGQUIT,
+    Signal::SIGUSR1,
+    Signal::SIGUSR2,
+    Signal::SIGWINCH,
+];
+
+/// Stay behind as a minimal init, in the way of tini: fork, run `exec` in
+/// the child, pass the forwarded signals on to it, reap every orphan that
+/// is reparented here, and exit the way the program did once it is gone, a
+/// signal N as exit code 128+N. Only returns if the fork fails.
+pub fn run(exec: impl FnOnce() -> Result<Infallible>) -> Result<Infallible> {
+    let mut signals: SigSet = FORWARDED.into_iter().collect();
+    signals.add(Signal::SIGCHLD);
+    let mut previous = SigSet::empty();
+    nix::sys::signal::sigprocmask(SigmaskHow::SIG_BLOCK, Some(&signals), Some(&mut previous))
+        .context("cannot block the signals init forwards")?;
+
+    match unsafe { fork() }.context("cannot fork the program from init")? {
+        ForkResult::Child => {
+            nix::sys::signal::sigprocmask(SigmaskHow::SIG_SETMASK, Some(&previous), None)?;
+            exec()
+        }
+        ForkResult::Parent { child } => {
+            // Our copies of the setup pipes would keep the parent from
+            // seeing the program exec
+            close_inherited_fds();
+            std::process::exit(supervise(child, &signals))
+        }
+    }
+}
+
+/// Stay behind in place of a program, as the holder of a jail that `rsjail
+/// exec` runs commands in: reap whatever is reparented here and exit once
+/// sent SIGTERM or SIGINT.
+pub fn hold() -> Result<Infallible> {
+    let signals: SigSet = [Signal::SIGTERM, Signal::SIGINT, Signal::SIGCHLD]
+        .into_iter()
+        .collect();
+    nix::sys::signal::sigprocmask(SigmaskHow::SIG_BLOCK, Some(&signals), None)
+        .context("cannot block the signals the holder waits for")?;
+    // Closing the setup pipes is what tells the parent setup is done
+    close_inherited_fds();
+    loop {
+        match signals.wait() {
+            Ok(Signal::SIGCHLD) => {
+                while let Ok(status) = waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+                    if status == WaitStatus::StillAlive {
+                        break;
+                    }
+                }
+            }
+            Ok(_) => std::process::exit(0),
+            Err(_) => {}
+        }
+    }
+}
+
+fn close_inherited_fds() {
+    if unsafe { libc::syscall(libc::SYS_close_range, 3, u32::MAX, 0) } != 0 {
+        // Before Linux 5.9
+        let max = unsafe { libc::sysconf(libc::_SC_OPEN_MAX) }.clamp(0, 1 << 16);
+        for fd in 3..max as i32 {
+            unsafe { libc::close(fd) };
+        }
+    }
+}
+
+fn supervise(program: Pid, signals: &SigSet) -> i32 {
+    loop {
+        let Ok(signal) = signals.wait() else {
+            continue;
+        };
+        if signal != Signal::SIGCHLD {
+            let _ = kill(program, signal);
+            continue;
+        }
+        // One SIGCHLD may stand for several exits
+        loop {
+            match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+                Ok(WaitStatus::Exited(pid, code)) if pid == program => return code,
+                Ok(WaitStatus::Signaled(pid, signal, _)) if pid == program => {
+                    return 128 + signal as i32;
+                }
+                Ok(WaitStatus::StillAlive) | Err(_) => break,
+                Ok(_) => {}
+            }
+        }
+    }
+}
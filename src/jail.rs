@@ -1,257 +1,3822 @@
-use crate::config::{JailConfig, MountConfig};
-use anyhow::Result;
+use crate::caps;
+use crate::cgroup::{self, JailCgroup, MemoryEvents};
+use crate::config::{
+    parse_signal, ChrootPolicy, FsIsolation, FuseMount, IdMap, JailConfig, MountConfig,
+    NetworkMode, Rlimit, SetupStep, VolumeBacking, VolumeConfig, USERNS_FSTYPES,
+};
+use crate::elf;
+use crate::glob;
+use crate::init;
+use crate::landlock;
+#[cfg(feature = "pam")]
+use crate::pam;
+use crate::pidfd::{self, Cloned};
+use crate::relay::{OutputPipes, OutputRelay};
+use crate::sampler::{MemoryPeaks, MemorySampler};
+use crate::scratch::{self, MountGuard, NetnsBinding, QuotaImage, ScratchDir};
+use crate::seccomp::{self, ListenerSocket, SyscallGuard};
+use crate::sync;
+use crate::timings::{self, SetupPhase, SetupTimer};
+use anyhow::{anyhow, bail, Context, Result};
 #[cfg(target_os = "linux")]
-use nix::mount::{mount, MsFlags};
-use nix::sched::{unshare, CloneFlags};
-use nix::sys::resource::{setrlimit, Resource};
-use nix::sys::wait::{waitpid, WaitStatus};
+use nix::errno::Errno;
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use nix::sched::{clone, setns, unshare, CloneFlags};
+use nix::sys::resource::{getrlimit, setrlimit, Resource};
+use nix::sys::signal::{kill, Signal};
+use nix::sys::statvfs::{statvfs, FsFlags};
+use nix::sys::wait::{WaitPidFlag, WaitStatus};
 use nix::unistd::{
-    chroot, execve, fork, getgid, getpid, getuid, setgid, sethostname, setuid, ForkResult, Gid,
-    Pid, Uid,
+    chroot, execve, fexecve, fork, getgid, getuid, pivot_root, setgid, setgroups, sethostname,
+    setsid, setuid, ForkResult, Gid, Pid, Uid, User,
 };
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::Infallible;
 use std::ffi::CString;
 use std::fs::{self, OpenOptions};
-use std::io::Write;
-use std::path::Path;
+use std::io::{IsTerminal, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::{FileExt, MetadataExt, PermissionsExt};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Not in nix; unshare-only, clone(2) reads this bit as part of CSIGNAL
+const CLONE_NEWTIME: CloneFlags = CloneFlags::from_bits_retain(libc::CLONE_NEWTIME);
+
+/// Namespaces rsjail knows how to create, in the order they must be unshared
+/// when falling back to one at a time (user first so the rest can follow).
+const NAMESPACES: [(CloneFlags, &str); 8] = [
+    (CloneFlags::CLONE_NEWUSER, "user"),
+    (CloneFlags::CLONE_NEWPID, "pid"),
+    (CloneFlags::CLONE_NEWNET, "net"),
+    (CloneFlags::CLONE_NEWNS, "mnt"),
+    (CloneFlags::CLONE_NEWUTS, "uts"),
+    (CloneFlags::CLONE_NEWIPC, "ipc"),
+    (CLONE_NEWTIME, "time"),
+    // Unshared by the child itself, see ChildSetup::cgroup_namespace
+    (CloneFlags::CLONE_NEWCGROUP, "cgroup"),
+];
+
+/// Stack for a child started with clone(2); it only lives until the exec
+const CLONE_STACK_SIZE: usize = 2 * 1024 * 1024;
+
+/// Entries OCI runtimes mask by default, hidden when /proc or /sys is mounted
+const DEFAULT_MASKED_PATHS: [&str; 11] = [
+    "/proc/acpi",
+    "/proc/asound",
+    "/proc/kcore",
+    "/proc/keys",
+    "/proc/latency_stats",
+    "/proc/sched_debug",
+    "/proc/scsi",
+    "/proc/sysrq-trigger",
+    "/proc/timer_list",
+    "/proc/timer_stats",
+    "/sys/firmware",
+];
+
+/// Where the host keeps the tz database mount_localtime binds from
+const ZONEINFO_DIR: &str = "/usr/share/zoneinfo";
+
+/// The host directories bind_ca_certs binds: each distribution's
+/// certificate directory first, then what its links point into
+const CA_CERT_DIRS: [(&str, &[&str]); 2] = [
+    ("/etc/ssl/certs", &["/usr/share/ca-certificates"]),
+    ("/etc/pki/tls/certs", &["/etc/pki/ca-trust/extracted"]),
+];
+
+/// The /etc/hosts extra_hosts adds to when chroot_dir has none
+const DEFAULT_HOSTS: &str = "127.0.0.1\tlocalhost\n::1\tlocalhost ip6-localhost ip6-loopback\n";
+
+/// Directories every chroot_dir gets, and all chroot_policy clean removes
+const JAIL_DIRS: [&str; 9] = [
+    "bin", "lib", "lib64", "usr", "etc", "tmp", "proc", "dev", "sys",
+];
+
+/// The directories the default PATH is made of, those the jail has
+const STANDARD_PATH: [&str; 4] = ["/bin", "/usr/bin", "/sbin", "/usr/sbin"];
+
+/// How often the parent checks the child while a time limit is pending
+const TIMEOUT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The longest single argument or environment string execve takes,
+/// terminating NUL included (MAX_ARG_STRLEN)
+const MAX_ARG_STRLEN: usize = 32 * 4096;
+
+/// Names of the namespaces contained in `flags`.
+pub fn namespace_names(flags: CloneFlags) -> Vec<&'static str> {
+    NAMESPACES
+        .iter()
+        .filter(|(flag, _)| flags.contains(*flag))
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+/// One mount as rsjail applies it, see `Jail::resolved_mounts`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedMount {
+    pub src: String,
+    /// Relative to chroot_dir, like `MountConfig::dst`
+    pub dst: String,
+    pub fstype: Option<String>,
+    pub flags: MsFlags,
+    pub data: Option<String>,
+}
+
+impl ResolvedMount {
+    /// Flags and data the way mount(8) lists them, e.g. "bind,ro".
+    pub fn options(&self) -> String {
+        let mut options: Vec<&str> = [
+            (MsFlags::MS_BIND, "bind"),
+            (MsFlags::MS_RDONLY, "ro"),
+            (MsFlags::MS_NOSUID, "nosuid"),
+            (MsFlags::MS_NODEV, "nodev"),
+            (MsFlags::MS_NOEXEC, "noexec"),
+        ]
+        .into_iter()
+        .filter(|(flag, _)| self.flags.contains(*flag))
+        .map(|(_, name)| name)
+        .collect();
+        options.extend(self.data.as_deref());
+        if options.is_empty() {
+            return "defaults".to_string();
+        }
+        options.join(",")
+    }
+}
+
+impl From<&MountConfig> for ResolvedMount {
+    fn from(mount: &MountConfig) -> Self {
+        let mut flags = MsFlags::empty();
+        if mount.is_bind {
+            flags |= MsFlags::MS_BIND;
+        }
+        if !mount.rw {
+            flags |= MsFlags::MS_RDONLY;
+        }
+        Self {
+            src: mount.src.clone(),
+            dst: mount.dst.clone(),
+            fstype: mount.fstype.clone(),
+            flags,
+            data: mount.data.clone(),
+        }
+    }
+}
+
+/// Outcome of a completed jail run.
+#[derive(Debug, Clone)]
+pub struct JailResult {
+    /// The child's pid as seen by the host
+    pub pid: Pid,
+    /// Namespaces that were actually created for the jail
+    pub namespaces: CloneFlags,
+    pub exit_code: Option<i32>,
+    pub signal: Option<Signal>,
+    /// Time from just before fork until the child was reaped
+    pub wall_time: Duration,
+    pub user_time: Duration,
+    pub sys_time: Duration,
+    /// Peak resident set size in kilobytes
+    pub max_rss_kb: u64,
+    /// Whether the child was signalled for exceeding time_limit, for not
+    /// reporting READY within notify_timeout, or for exceeding setup_timeout
+    pub timed_out: bool,
+    /// Whether that was setup_timeout, i.e. the child never reached exec
+    pub setup_timed_out: bool,
+    /// Whether the program ran out of RLIMIT_CPU (rlimit_cpu or cpu_limit)
+    /// rather than time, killed by SIGXCPU or SIGKILL past the soft limit
+    pub cpu_limit_hit: bool,
+    /// Whether the child failed before it could exec the program, so that
+    /// `exit_code` is rsjail's rather than the program's
+    pub setup_failed: bool,
+    /// The outcome through exit_code_map, what the rsjail command exits with
+    pub mapped_exit_code: i32,
+    /// Time from just before fork until the program reported READY
+    pub ready_after: Option<Duration>,
+    /// How often the jail's cgroup hit memory.high and memory.max, if it had
+    /// one with the memory controller
+    pub memory_events: Option<MemoryEvents>,
+    /// What sample_memory saw, if it was on
+    pub memory_peaks: Option<MemoryPeaks>,
+    /// Processes still running when the program exited without a PID
+    /// namespace, which were then killed. Only `run` tracks them.
+    pub leftover_processes: usize,
+    /// Whether cleanup_timeout ran out, so the jail had to be SIGKILLed
+    /// after timeout_signal or leftovers were given up on still exiting
+    pub cleanup_timed_out: bool,
+    /// The syscall of syscall_limits the jail was killed for calling too
+    /// often
+    pub syscall_limit_hit: Option<String>,
+    /// With `Jail::record_timings`, how long each setup phase took, in the
+    /// order they ended
+    pub setup_timings: Option<Vec<SetupPhase>>,
+}
 
+impl JailResult {
+    /// Machine-readable resource accounting, with times in microseconds.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "exit_code": self.exit_code,
+            "signal": self.signal.map(|signal| signal.as_str()),
+            "wall_time_us": self.wall_time.as_micros() as u64,
+            "user_time_us": self.user_time.as_micros() as u64,
+            "sys_time_us": self.sys_time.as_micros() as u64,
+            "max_rss_kb": self.max_rss_kb,
+            "timed_out": self.timed_out,
+            "setup_timed_out": self.setup_timed_out,
+            "cpu_limit_hit": self.cpu_limit_hit,
+            "setup_failed": self.setup_failed,
+            "mapped_exit_code": self.mapped_exit_code,
+            "ready_after_us": self.ready_after.map(|ready| ready.as_micros() as u64),
+            "leftover_processes": self.leftover_processes,
+            "cleanup_timed_out": self.cleanup_timed_out,
+            "syscall_limit_hit": self.syscall_limit_hit,
+            "setup_timings_us": self.setup_timings.as_ref().map(|phases| {
+                phases
+                    .iter()
+                    .map(|phase| serde_json::json!({
+                        "phase": phase.name,
+                        "us": phase.duration.as_micros() as u64,
+                    }))
+                    .collect::<Vec<_>>()
+            }),
+            "memory_events": self.memory_events.map(|events| serde_json::json!({
+                "high": events.high,
+                "max": events.max,
+            })),
+            "memory_peaks": self.memory_peaks.map(|peaks| serde_json::json!({
+                "vm_peak_kb": peaks.vm_peak_kb,
+                "vm_hwm_kb": peaks.vm_hwm_kb,
+                "total_rss_kb": peaks.total_rss_kb,
+                "samples": peaks.samples,
+            })),
+        })
+    }
+}
+
+#[derive(Clone)]
 pub struct Jail {
     config: JailConfig,
+    freeze_on_exit: bool,
+    save_volumes_to: Option<PathBuf>,
+    restore_volumes_from: Option<PathBuf>,
+    /// The interpreter once worked out, see `interpreter`
+    interpreter: OnceLock<Option<String>>,
+    /// Likewise the PATH, see `exec_path`
+    exec_path: OnceLock<String>,
+    record_timings: bool,
+    hold: bool,
 }
 
 impl Jail {
     pub fn new(config: JailConfig) -> Self {
-        Self { config }
+        Self {
+            config,
+            freeze_on_exit: false,
+            save_volumes_to: None,
+            restore_volumes_from: None,
+            interpreter: OnceLock::new(),
+            exec_path: OnceLock::new(),
+            record_timings: false,
+            hold: false,
+        }
     }
 
-    pub fn run(&self) -> Result<()> {
-        // Create Namespace
-        self.create_namespaces()?;
-        
-        // fork child process
-        match unsafe { fork() }? {
-            ForkResult::Parent { child } => {
-                // Parent process wait for child process
-                self.wait_for_child(child)?;
-            }
-            ForkResult::Child => {
-                // Child process setup environment and execute program
-                if let Err(e) = self.setup_child_environment() {
-                    eprintln!("Child setup failed: {}", e);
-                    std::process::exit(1);
-                }
+    pub fn config(&self) -> &JailConfig {
+        &self.config
+    }
+
+    /// Once the child has exited, or setup has failed, wait for Enter before
+    /// tearing anything down, so the jail can be inspected. Only applies
+    /// while stdin is a terminal.
+    pub fn freeze_on_exit(mut self, freeze: bool) -> Self {
+        self.freeze_on_exit = freeze;
+        self
+    }
+
+    /// Once the child has exited, copy each disk-backed volume to
+    /// `<dir>/<volume name>`, replacing what an earlier run left there. Plain
+    /// directory copies of those volumes only: tmpfs volumes are gone with
+    /// the jail's mount namespace by then, and chroot_dir is left alone.
+    pub fn save_volumes_to(mut self, dir: Option<PathBuf>) -> Self {
+        self.save_volumes_to = dir;
+        self
+    }
+
+    /// Seed each disk-backed volume from `<dir>/<volume name>`, as written by
+    /// `save_volumes_to`, so every run starts from the same state.
+    pub fn restore_volumes_from(mut self, dir: Option<PathBuf>) -> Self {
+        self.restore_volumes_from = dir;
+        self
+    }
+
+    /// Time each phase of the setup, from the clone through the mounts and
+    /// setup_steps to the exec, for `JailResult::setup_timings`.
+    pub fn record_timings(mut self, record: bool) -> Self {
+        self.record_timings = record;
+        self
+    }
+
+    /// Leave a holder process in the jail instead of running exec_bin, which
+    /// keeps its namespaces alive until sent SIGTERM so `exec_into` can run
+    /// commands there.
+    pub fn hold(mut self, hold: bool) -> Self {
+        self.hold = hold;
+        self
+    }
+
+    /// Run `command` in the jail of `holder`, a process left by `hold`: join
+    /// its namespaces, root and cgroup, then apply the setup_steps that are
+    /// per process, all but mounts and seccomp, and exec. A command without
+    /// '/' is looked up on the jail's PATH. Returns its exit code, a signal
+    /// N as 128+N.
+    pub fn exec_into(&self, holder: Pid, command: &[String]) -> Result<i32> {
+        let Some(program) = command.first() else {
+            bail!("no command to run in the jail");
+        };
+        let pidfd = pidfd::open(holder)
+            .with_context(|| format!("cannot open the jail's holder {}", holder))?;
+        // The holder's root may not be the root of its mount namespace, so
+        // it's opened while ours can still reach it
+        let root = fs::File::open(format!("/proc/{}/root", holder))
+            .with_context(|| format!("cannot open the root of holder {}", holder))?;
+        let namespaces = NAMESPACES
+            .iter()
+            .filter(|(_, name)| !same_namespace(holder, name))
+            .fold(CloneFlags::empty(), |flags, (flag, _)| flags | *flag);
+        let path = self.exec_path().to_string();
+        let (args, env) = exec_strings(command.to_vec(), self.environment())?;
+
+        // Holds the child back until it is in the holder's cgroup
+        let cgroup_ready = sync::Channel::to_child()?;
+        let child = fork_child(&mut || {
+            cgroup_ready.close_parent_end();
+            if !matches!(sync::wait_released(&cgroup_ready.child), Ok(true)) {
+                return 1;
+            }
+            let Err(e) = self.join_holder(&pidfd, namespaces, &root, || {
+                let program = find_program(program, &path);
+                check_executable(&program)?;
+                let program = exec_string(program.to_string_lossy().into_owned(), || {
+                    "the program path".to_string()
+                })?;
+                Ok(execve(&program, &args, &env)?)
+            });
+            eprintln!("Cannot run {} in the jail: {:#}", program, e);
+            1
+        })?;
+
+        if let Some(procs) = holder_cgroup_procs(holder) {
+            let moved = fs::write(&procs, child.to_string())
+                .with_context(|| format!("cannot move {} into {}", child, procs.display()))
+                .and_then(|()| Ok(sync::release(&cgroup_ready.parent)?));
+            if let Err(e) = moved {
+                let _ = kill(child, Signal::SIGKILL);
+                let _ = nix::sys::wait::waitpid(child, None);
+                return Err(e);
+            }
+        } else {
+            sync::release(&cgroup_ready.parent)?;
+        }
+
+        loop {
+            match nix::sys::wait::waitpid(child, None) {
+                Ok(WaitStatus::Exited(_, code)) => return Ok(code),
+                Ok(WaitStatus::Signaled(_, signal, _)) => return Ok(128 + signal as i32),
+                Ok(_) | Err(Errno::EINTR) => {}
+                Err(e) => return Err(e).context("cannot wait for the command"),
             }
         }
-        
-        Ok(())
     }
 
-    fn create_namespaces(&self) -> Result<()> {
-        let mut flags = CloneFlags::empty();
-        
-        if self.config.clone_newpid {
-            flags |= CloneFlags::CLONE_NEWPID;
+    /// The child's side of `exec_into`. Only a process forked after joining
+    /// is in the PID and time namespaces, so `exec` runs under init.
+    fn join_holder(
+        &self,
+        pidfd: &OwnedFd,
+        namespaces: CloneFlags,
+        root: &fs::File,
+        exec: impl FnOnce() -> Result<Infallible>,
+    ) -> Result<Infallible> {
+        if !namespaces.is_empty() {
+            log::debug!("joining namespaces {:?}", namespace_names(namespaces));
+            setns(pidfd, namespaces).context("cannot join the jail's namespaces")?;
         }
-        if self.config.clone_newnet {
-            flags |= CloneFlags::CLONE_NEWNET;
+        nix::unistd::fchdir(root).context("cannot enter the jail's root")?;
+        chroot(".").context("cannot enter the jail's root")?;
+        std::env::set_current_dir("/")?;
+
+        init::run(|| {
+            for step in self.config.setup_steps() {
+                match step {
+                    SetupStep::Setuid => self.setup_user_permissions()?,
+                    SetupStep::Caps => self.raise_ambient_caps()?,
+                    SetupStep::NoNewPrivs => self.set_no_new_privs()?,
+                    SetupStep::Rlimits => self.setup_resource_limits()?,
+                    SetupStep::Landlock if !self.config.landlock.is_empty() => {
+                        landlock::restrict_self(
+                            &self.config.landlock,
+                            self.config.landlock_required,
+                        )?;
+                    }
+                    // Only the policy, syscall_limits counts for the
+                    // program rsjail ran
+                    SetupStep::Seccomp => self.install_seccomp_policy()?,
+                    // Whatever they set up is shared with the holder or
+                    // went with its exec
+                    SetupStep::Landlock | SetupStep::Mounts => {}
+                }
+            }
+            exec()
+        })
+    }
+
+    /// Run the jail to completion: `spawn` followed by `JailHandle::wait`.
+    pub fn run(&self) -> Result<JailResult> {
+        self.spawn()?.wait()
+    }
+
+    /// Start the jail and return once the child is running, leaving the
+    /// waiting to the handle.
+    ///
+    /// Without clone_newpid, what the program leaves behind is told apart
+    /// from our other children by what existed at spawn, so those jails
+    /// should run one at a time.
+    pub fn spawn(&self) -> Result<JailHandle<'_>> {
+        let launched = self
+            .launch()
+            .inspect_err(|e| self.pause_for_inspection(&format!("setup failed: {:#}", e), None))?;
+        Ok(JailHandle {
+            jail: self,
+            launched: Some(launched),
+            reaped: None,
+            timed_out: false,
+        })
+    }
+
+    /// Like `run`, but awaits the child on the tokio runtime instead of
+    /// blocking in `waitpid`.
+    #[cfg(feature = "tokio")]
+    pub async fn run_async(&self) -> Result<JailResult> {
+        if self.config.no_fork {
+            bail!("no_fork cannot be used with run_async");
         }
-        if self.config.clone_newns {
-            flags |= CloneFlags::CLONE_NEWNS;
+        if self.config.notify {
+            bail!("notify cannot be used with run_async");
         }
-        if self.config.clone_newuts {
-            flags |= CloneFlags::CLONE_NEWUTS;
+        if self.config.clone_newtime {
+            bail!("clone_newtime cannot be used with run_async, clone(2) can't create it");
         }
-        if self.config.clone_newipc {
-            flags |= CloneFlags::CLONE_NEWIPC;
+        if self.config.unshare_in_parent {
+            bail!("unshare_in_parent cannot be used with run_async");
         }
-        if self.config.clone_newuser {
-            flags |= CloneFlags::CLONE_NEWUSER;
+        if self.config.thread_mode {
+            bail!("thread_mode cannot be used with run_async");
         }
 
-        unshare(flags)?;
-        Ok(())
+        let launched = self.launch_cloned()?;
+
+        let mut setup_timed_out = false;
+        if let Some(secs) = self.config.setup_timeout {
+            let readable = tokio::io::unix::AsyncFd::new(launched.exec_reached.as_raw_fd())?;
+            let deadline =
+                tokio::time::Instant::from_std(launched.started + Duration::from_secs(secs));
+            if tokio::time::timeout_at(deadline, readable.readable())
+                .await
+                .is_err()
+            {
+                log::warn!("child {} hit setup_timeout, killing it", launched.child);
+                launched.signal(Signal::SIGKILL)?;
+                setup_timed_out = true;
+            }
+        }
+
+        let (launched, (status, usage, timed_out)) = match &launched.pidfd {
+            Some(pidfd) => {
+                let (status, usage, timed_out) = self.reap_pidfd(&launched, pidfd).await?;
+                (launched, (status, usage, timed_out || setup_timed_out))
+            }
+            None => {
+                // Without a pidfd there is nothing to await, so wait on a
+                // blocking thread instead
+                let jail = Jail::new(self.config.clone());
+                tokio::task::spawn_blocking(move || {
+                    let reaped = jail.reap(&launched, setup_timed_out);
+                    reaped.map(|reaped| (launched, reaped))
+                })
+                .await??
+            }
+        };
+
+        log::debug!("reaped child {}: {:?}", launched.child, status);
+        let mut result = self.finish(launched, status, usage, timed_out)?;
+        result.setup_timed_out = setup_timed_out;
+        Ok(result)
     }
 
-    fn setup_child_environment(&self) -> Result<()> {
-        // Setup user namespace mapping
-        if self.config.clone_newuser {
-            self.setup_uid_gid_mapping()?;
+    /// Validate, then start the child in its namespaces, from where it goes
+    /// on to set itself up and exec the target. With `no_fork` this process
+    /// becomes the target instead and only returns on failure.
+    fn launch(&self) -> Result<Launched> {
+        self.config.validate()?;
+        self.check_saved_volumes()?;
+        self.apply_chroot_policy()?;
+        self.provision()?;
+        let setup = self.child_setup()?;
+        #[cfg(feature = "pam")]
+        let pam_session = self.open_pam_session()?;
+        let mut cgroup = self.jail_cgroup(&setup)?;
+
+        if self.config.no_fork {
+            self.create_namespaces(self.requested_namespaces())?;
+            let setup = ChildSetup {
+                mounts: self.with_volumes(&setup.mounts, None)?,
+                ..setup
+            };
+            // Become the jailed program; only comes back if that failed
+            let Err(e) = self.setup_child_environment(&setup);
+            return Err(e);
         }
 
-        // Setup hostname
-        if let Some(hostname) = &self.config.hostname {
-            sethostname(hostname)?;
+        // Without a PID namespace nothing kills what the program leaves
+        // behind, so adopt it rather than let it escape to the host's init.
+        // Whether it gets one may only be known once it is running.
+        let requested = self.requested_namespaces();
+        let adopted_from = (!requested.contains(CloneFlags::CLONE_NEWPID)
+            || self.config.ignore_unsupported_ns)
+            .then(|| {
+                set_child_subreaper(true);
+                children()
+            });
+        let launched = if self.config.unshare_in_parent {
+            let unsharing = Instant::now();
+            self.create_namespaces(requested).and_then(|namespaces| {
+                let unshared = unsharing.elapsed();
+                self.spawn_child(&setup, namespaces, &mut cgroup, |setup, child_main| {
+                    if let Some(fd) = setup.timings_fd {
+                        timings::record(fd, "unshare", unshared);
+                    }
+                    timings::time(setup.timings_fd, "fork", || fork_child(child_main))
+                        .map(|pid| (pid, None))
+                })
+            })
+        } else if self.config.thread_mode {
+            self.spawn_from_thread(setup, requested, cgroup)
+        } else {
+            self.clone_with_time(&setup, requested, &mut cgroup)
+        };
+        let launched = launched.inspect_err(|_| {
+            if adopted_from.is_some() {
+                set_child_subreaper(false);
+            }
+        })?;
+        let adopted_from = match adopted_from {
+            Some(_) if launched.namespaces.contains(CloneFlags::CLONE_NEWPID) => {
+                set_child_subreaper(false);
+                None
+            }
+            adopted_from => adopted_from,
+        };
+        let launched = Launched {
+            adopted_from,
+            ..launched
+        };
+        #[cfg(feature = "pam")]
+        let launched = Launched {
+            _pam_session: pam_session,
+            ..launched
+        };
+        self.bind_netns(launched)
+    }
+
+    /// Like `launch`, without the time namespace and `no_fork`, which both
+    /// need this process to unshare. That keeps it usable from a
+    /// multithreaded async runtime, `clone_newuser` included.
+    #[cfg(feature = "tokio")]
+    fn launch_cloned(&self) -> Result<Launched> {
+        self.config.validate()?;
+        self.check_saved_volumes()?;
+        self.apply_chroot_policy()?;
+        self.provision()?;
+        let setup = self.child_setup()?;
+        #[cfg(feature = "pam")]
+        let pam_session = self.open_pam_session()?;
+        let mut cgroup = self.jail_cgroup(&setup)?;
+
+        let launched = self.clone_child(&setup, self.requested_namespaces(), &mut cgroup)?;
+        #[cfg(feature = "pam")]
+        let launched = Launched {
+            _pam_session: pam_session,
+            ..launched
+        };
+        self.bind_netns(launched)
+    }
+
+    /// Bind the running child's net namespace as netns_name, killing the
+    /// child if that fails.
+    fn bind_netns(&self, launched: Launched) -> Result<Launched> {
+        let Some(name) = &self.config.netns_name else {
+            return Ok(launched);
+        };
+        if !launched.namespaces.contains(CloneFlags::CLONE_NEWNET) {
+            log::warn!("the jail has no net namespace, not binding netns {}", name);
+            return Ok(launched);
         }
+        match NetnsBinding::create(launched.child, name) {
+            Ok(netns) => Ok(Launched {
+                _netns: Some(netns),
+                ..launched
+            }),
+            Err(e) => {
+                let _ = launched.signal(Signal::SIGKILL);
+                let _ = launched.wait_until(None);
+                Err(e)
+            }
+        }
+    }
 
-        // Setup filesystem
-        if let Some(chroot_dir) = &self.config.chroot_dir {
-            self.setup_filesystem(chroot_dir)?;
+    /// `clone_child`, but also into a time namespace if asked for. clone(2)
+    /// can't create one, unsharing one however only moves the children
+    /// started from then on, so this process moves back once it's cloned.
+    fn clone_with_time(
+        &self,
+        setup: &ChildSetup,
+        namespaces: CloneFlags,
+        cgroup: &mut Option<JailCgroup>,
+    ) -> Result<Launched> {
+        if !namespaces.contains(CLONE_NEWTIME) {
+            return self.clone_child(setup, namespaces, cgroup);
         }
+        let host_time = fs::File::open("/proc/self/ns/time_for_children");
+        let time = self.create_namespaces(CLONE_NEWTIME)?;
+        let launched = self.clone_child(setup, namespaces - CLONE_NEWTIME, cgroup);
+        if let Ok(host_time) = &host_time
+            && let Err(e) = setns(host_time, CLONE_NEWTIME)
+        {
+            log::warn!("cannot return to the host time namespace: {}", e);
+        }
+        let launched = launched?;
+        Ok(Launched {
+            namespaces: launched.namespaces | time,
+            ..launched
+        })
+    }
 
-        // Setup user permissions
-        self.setup_user_permissions()?;
+    /// Clone the child straight into `namespaces`, so this process never
+    /// enters them and stays in the host's as a plain supervisor. clone3
+    /// gets its pidfd in the same call, clone(2) is for kernels and seccomp
+    /// profiles without clone3.
+    fn clone_child(
+        &self,
+        setup: &ChildSetup,
+        mut namespaces: CloneFlags,
+        cgroup: &mut Option<JailCgroup>,
+    ) -> Result<Launched> {
+        static CLONE3_MISSING: AtomicBool = AtomicBool::new(false);
+        // Probing costs a throwaway child per namespace, so only do it once
+        // a clone with everything has actually failed
+        let mut probed = false;
+        let mut stack = vec![0u8; CLONE_STACK_SIZE];
 
-        // Setup resource limits
-        self.setup_resource_limits()?;
+        loop {
+            log::debug!("cloning into namespaces {:?}", namespace_names(namespaces));
+            let result = self.spawn_child(setup, namespaces, cgroup, |setup, mut child_main| {
+                timings::time(setup.timings_fd, "clone", || {
+                    // Its pidfd is of no use where there is no waiting on one
+                    if pidfd::available() && !CLONE3_MISSING.load(Ordering::Relaxed) {
+                        match unsafe { pidfd::clone3(namespaces) } {
+                            Ok(Cloned::Parent(pid, pidfd)) => return Ok((pid, Some(pidfd))),
+                            Ok(Cloned::Child) => std::process::exit(child_main() as i32),
+                            Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => {
+                                log::debug!("no clone3, falling back to clone");
+                                CLONE3_MISSING.store(true, Ordering::Relaxed);
+                            }
+                            Err(e) => return Err(e.into()),
+                        }
+                    }
+                    let pid = unsafe {
+                        clone(
+                            Box::new(&mut child_main),
+                            &mut stack,
+                            namespaces,
+                            Some(libc::SIGCHLD),
+                        )
+                    }?;
+                    Ok((pid, None))
+                })
+            });
+            match result {
+                Err(e) if self.config.ignore_unsupported_ns && !probed => {
+                    log::warn!(
+                        "clone({:?}) failed: {}, probing namespaces individually",
+                        namespace_names(namespaces),
+                        e
+                    );
+                    namespaces = probe_namespaces(namespaces);
+                    probed = true;
+                }
+                result => return result,
+            }
+        }
+    }
 
-        // Execute target program
-        self.exec_target_program()?;
+    /// Unshare `namespaces` in a new thread, which sets the jail up and forks
+    /// the child, see `thread_mode`. The thread then waits for the returned
+    /// child to be dropped: the child's parent death signal goes off when
+    /// the thread that forked it exits, not when rsjail does.
+    fn spawn_from_thread(
+        &self,
+        setup: ChildSetup,
+        namespaces: CloneFlags,
+        mut cgroup: Option<JailCgroup>,
+    ) -> Result<Launched> {
+        let jail = self.clone();
+        let (launched_tx, launched_rx) = std::sync::mpsc::channel();
+        let done = sync::Channel::to_child()?;
 
-        Ok(())
-    }
+        std::thread::Builder::new()
+            .name("rsjail-setup".to_string())
+            .spawn(move || {
+                let unsharing = Instant::now();
+                let launched = unshare(CloneFlags::CLONE_FS)
+                    .context("cannot unshare the setup thread's root and cwd")
+                    .and_then(|()| jail.create_namespaces(namespaces))
+                    .and_then(|namespaces| {
+                        let unshared = unsharing.elapsed();
+                        jail.spawn_child(&setup, namespaces, &mut cgroup, |setup, child_main| {
+                            if let Some(fd) = setup.timings_fd {
+                                timings::record(fd, "unshare", unshared);
+                            }
+                            jail.setup_jail_root(setup)?;
+                            timings::time(setup.timings_fd, "fork", || fork_child(child_main))
+                                .map(|pid| (pid, None))
+                        })
+                    });
+                if launched_tx.send(launched).is_ok() {
+                    let _ = sync::wait_released(&done.child);
+                }
+            })
+            .context("cannot start the setup thread")?;
 
-    fn setup_uid_gid_mapping(&self) -> Result<()> {
-        let pid = getpid();
-        
-        // Setup UID mapping
-        let uid_map = format!("0 {} 1", getuid());
-        let uid_map_path = format!("/proc/{}/uid_map", pid);
-        let mut uid_map_file = OpenOptions::new().write(true).open(&uid_map_path)?;
-        uid_map_file.write_all(uid_map.as_bytes())?;
-        
-        // Disable setgroups
-        let setgroups_path = format!("/proc/{}/setgroups", pid);
-        let mut setgroups_file = OpenOptions::new().write(true).open(&setgroups_path)?;
-        setgroups_file.write_all(b"deny")?;
-        
-        // Setup GID mapping
-        let gid_map = format!("0 {} 1", getgid());
-        let gid_map_path = format!("/proc/{}/gid_map", pid);
-        let mut gid_map_file = OpenOptions::new().write(true).open(&gid_map_path)?;
-        gid_map_file.write_all(gid_map.as_bytes())?;
-        
-        Ok(())
+        let launched = launched_rx
+            .recv()
+            .context("the setup thread exited without forking the child")??;
+        Ok(Launched {
+            _setup_thread: Some(done.parent),
+            ..launched
+        })
     }
 
-    fn setup_filesystem(&self, chroot_dir: &str) -> Result<()> {
-        // Create basic directory structure
-        self.create_jail_directories(chroot_dir)?;
-        
-        // Setup mount points
-        for mount_config in &self.config.mounts {
-            self.setup_mount(chroot_dir, mount_config)?;
+    /// Start the child with `spawn`, which is handed the final setup and the
+    /// code the child must run and returns its pid, and a pidfd for it if
+    /// it got one on the way. The child takes over `cgroup` once it runs.
+    fn spawn_child<F>(
+        &self,
+        setup: &ChildSetup,
+        namespaces: CloneFlags,
+        cgroup: &mut Option<JailCgroup>,
+        spawn: F,
+    ) -> Result<Launched>
+    where
+        F: FnOnce(&ChildSetup, &mut dyn FnMut() -> isize) -> Result<(Pid, Option<OwnedFd>)>,
+    {
+        let scratch = ScratchDir::create(&self.scratch_base())?;
+        // The child's mounts are ours too unless it is cloned into a mount
+        // namespace of its own; thread_mode's goes with the setup thread
+        let mount_guard = self
+            .config
+            .chroot_dir
+            .as_ref()
+            .filter(|_| self.config.fs_isolation() != FsIsolation::None)
+            .filter(|_| {
+                !self.config.thread_mode
+                    && (self.config.unshare_in_parent
+                        || !namespaces.contains(CloneFlags::CLONE_NEWNS))
+            })
+            .map(|chroot_dir| MountGuard::new(Path::new(chroot_dir)));
+        // Under the disk volumes, so mounted before they are created
+        let disk_quota = self
+            .config
+            .disk_quota
+            .as_ref()
+            .map(|quota| {
+                QuotaImage::create(
+                    &scratch.path().join("disk.img"),
+                    &scratch.path().join("volumes"),
+                    quota.size,
+                    &quota.fstype,
+                )
+            })
+            .transpose()?;
+
+        // The parent holds its end for as long as it lives, so the child can
+        // tell whether it is already orphaned
+        let parent_alive = sync::Channel::to_child()?;
+        let notify = self
+            .config
+            .notify
+            .then(sync::Channel::to_parent)
+            .transpose()?;
+        let exec_reached = sync::Channel::to_parent()?;
+        let output = self.config.log_prefix.then(OutputPipes::new).transpose()?;
+        let listener_socket = (!self.config.syscall_limits.is_empty())
+            .then(ListenerSocket::new)
+            .transpose()?;
+        let timings = self
+            .record_timings
+            .then(sync::Channel::to_parent)
+            .transpose()?;
+        // Holds the child back until it is in its cgroup, so everything it
+        // forks lands there too
+        let cgroup_ready = cgroup.is_some().then(sync::Channel::to_child).transpose()?;
+
+        // Disk volumes and the hosts file live in the scratch dir, which only
+        // exists now
+        let mut mounts = self.with_volumes(&setup.mounts, Some(scratch.path()))?;
+        mounts.extend(self.hosts_mount(scratch.path())?);
+        let setup = &ChildSetup {
+            mounts,
+            notify_fd: notify.as_ref().map(|notify| notify.child.as_raw_fd()),
+            listener_fd: listener_socket
+                .as_ref()
+                .map(|socket| socket.child.as_raw_fd()),
+            timings_fd: timings.as_ref().map(|timings| timings.child.as_raw_fd()),
+            cgroup: cgroup
+                .as_ref()
+                .filter(|_| self.config.cgroup_delegate)
+                .map(|cgroup| cgroup.path().to_path_buf()),
+            ..*setup
+        };
+
+        let namespaces = if setup.cgroup_namespace {
+            namespaces | CloneFlags::CLONE_NEWCGROUP
+        } else {
+            namespaces
+        };
+
+        // fork child process
+        let started = Instant::now();
+        let (child, cloned_pidfd) = spawn(setup, &mut || {
+            parent_alive.close_parent_end();
+            exec_reached.close_parent_end();
+            if let Some(notify) = &notify {
+                notify.close_parent_end();
+            }
+            if let Some(timings) = &timings {
+                timings.close_parent_end();
+            }
+            if let Some(output) = &output
+                && output.redirect_child().is_err()
+            {
+                return 1;
+            }
+            if self.config.die_with_parent {
+                die_with_parent(&parent_alive.child);
+            }
+            if let Some(cgroup_ready) = &cgroup_ready {
+                cgroup_ready.close_parent_end();
+                // The parent failed to move us and reports why
+                if !matches!(sync::wait_released(&cgroup_ready.child), Ok(true)) {
+                    let _ = sync::release(&exec_reached.child);
+                    return 1;
+                }
+            }
+
+            // Child process setup environment and execute program, which
+            // only returns if something went wrong. In thread_mode the
+            // jail's root is already set up.
+            let Err(e) = if self.config.thread_mode {
+                self.enter_jail(setup)
+            } else {
+                self.setup_child_environment(setup)
+            };
+            eprintln!("Child setup failed: {:#}", e);
+            // Tells the parent this exit code is ours, not the program's
+            let _ = sync::release(&exec_reached.child);
+            1
+        })?;
+
+        if let (Some(cgroup), Some(cgroup_ready)) = (cgroup.as_mut(), &cgroup_ready) {
+            let moved = cgroup
+                .add(child)
+                .and_then(|()| Ok(sync::release(&cgroup_ready.parent)?));
+            if let Err(e) = moved {
+                let _ = kill(child, Signal::SIGKILL);
+                let _ = nix::sys::wait::waitpid(child, None);
+                return Err(e);
+            }
         }
-        
-        // Switch root directory
-        chroot(chroot_dir)?;
-        std::env::set_current_dir("/")?;
-        
-        Ok(())
+
+        log::info!(
+            "started child {} in namespaces {:?}",
+            child,
+            namespace_names(namespaces)
+        );
+
+        // Opened before anything can reap the child, so it is guaranteed to
+        // refer to our child rather than a later process reusing its pid
+        let pidfd = if cloned_pidfd.is_some() {
+            cloned_pidfd
+        } else if pidfd::available() {
+            pidfd::open(child)
+                .inspect_err(|e| log::debug!("pidfd_open({}) failed: {}", child, e))
+                .ok()
+        } else {
+            None
+        };
+
+        let output_relay = output
+            .map(|output| output.relay(&format!("[{}] ", self.config.name)))
+            .transpose()?;
+        let syscall_guard = listener_socket
+            .map(|socket| SyscallGuard::start(socket, child, &self.config.syscall_limits))
+            .transpose()?;
+        // Drops our copy of the child's end, the rest goes with the exec
+        let clock_offset = match self.config.clone_newtime {
+            true => self.config.time_offset_monotonic.unwrap_or(0),
+            false => 0,
+        };
+        let setup_timer = timings
+            .map(|timings| SetupTimer::start(timings.parent, clock_offset))
+            .transpose()?;
+        let memory_sampler = self
+            .config
+            .sample_memory
+            .then(|| {
+                let interval = self.config.sample_interval_ms.unwrap_or(100);
+                MemorySampler::start(child, Duration::from_millis(interval))
+            })
+            .transpose()?;
+
+        Ok(Launched {
+            child,
+            pidfd,
+            started,
+            namespaces,
+            _netns: None,
+            notify: notify.map(|notify| notify.parent),
+            exec_reached: exec_reached.parent,
+            _mounts: mount_guard,
+            _disk_quota: disk_quota,
+            scratch,
+            cgroup: cgroup.take(),
+            memory_sampler,
+            output_relay,
+            syscall_guard,
+            setup_timer,
+            adopted_from: None,
+            cleanup_timed_out: Cell::new(false),
+            #[cfg(feature = "pam")]
+            _pam_session: None,
+            _parent_alive: parent_alive.parent,
+            _setup_thread: None,
+        })
     }
 
-    fn create_jail_directories(&self, chroot_dir: &str) -> Result<()> {
-        let base_path = Path::new(chroot_dir);
-        
-        // Create basic directory structure
-        if !base_path.exists() {
-            fs::create_dir_all(base_path)?;
+    /// Check or clean out chroot_dir as chroot_policy asks. Runs before
+    /// provision, which require_empty is meant to leave room for.
+    fn apply_chroot_policy(&self) -> Result<()> {
+        let Some(chroot_dir) = &self.config.chroot_dir else {
+            return Ok(());
+        };
+        if self.config.fs_isolation() == FsIsolation::None || !Path::new(chroot_dir).exists() {
+            return Ok(());
         }
-        
-        let dirs = ["bin", "lib", "lib64", "usr", "etc", "tmp", "proc", "dev", "sys"];
-        for dir in dirs {
-            let dir_path = base_path.join(dir);
-            if !dir_path.exists() {
-                fs::create_dir_all(&dir_path)?;
+
+        match self.config.chroot_policy {
+            ChrootPolicy::Reuse => {}
+            ChrootPolicy::RequireEmpty => {
+                if fs::read_dir(chroot_dir)?.next().is_some() {
+                    bail!("chroot_dir {} is not empty", chroot_dir);
+                }
+            }
+            ChrootPolicy::Clean => {
+                let device = fs::metadata(chroot_dir)?.dev();
+                for dir in JAIL_DIRS {
+                    let path = Path::new(chroot_dir).join(dir);
+                    if path.symlink_metadata().is_ok() {
+                        log::debug!("cleaning {}", path.display());
+                        remove_tree(&path, device)?;
+                    }
+                }
             }
         }
-        
         Ok(())
     }
 
-    fn setup_mount(&self, chroot_dir: &str, mount_config: &MountConfig) -> Result<()> {
-        let target = format!("{}{}", chroot_dir, mount_config.dst);
-        
-        // Ensure target directory exists
-        if let Some(parent) = Path::new(&target).parent() {
-            fs::create_dir_all(parent)?;
+    /// Run the provision command unless its marker shows an earlier run
+    /// already built chroot_dir. It runs in chroot_dir, which is also in
+    /// RSJAIL_CHROOT_DIR.
+    fn provision(&self) -> Result<()> {
+        let (Some(provision), Some(chroot_dir)) = (&self.config.provision, &self.config.chroot_dir)
+        else {
+            return Ok(());
+        };
+        let marker = Path::new(chroot_dir).join(&provision.marker);
+        if marker.exists() {
+            log::debug!("{} exists, already provisioned", marker.display());
+            return Ok(());
         }
-        
-        // If target is a file, create an empty file
-        if Path::new(&mount_config.src).is_file() {
-            fs::File::create(&target)?;
-        } else if !Path::new(&target).exists() {
-            fs::create_dir_all(&target)?;
-        }
-        
-        let mut flags = MsFlags::empty();
-        if mount_config.is_bind {
-            flags |= MsFlags::MS_BIND;
+
+        log::info!("provisioning {} with {:?}", chroot_dir, provision.command);
+        fs::create_dir_all(chroot_dir)?;
+        let status = std::process::Command::new(&provision.command[0])
+            .args(&provision.command[1..])
+            .current_dir(chroot_dir)
+            .env("RSJAIL_CHROOT_DIR", chroot_dir)
+            .status()
+            .with_context(|| format!("cannot run provision command {}", provision.command[0]))?;
+        if !status.success() {
+            bail!("provision command failed ({}), marker not created", status);
         }
-        if !mount_config.rw {
-            flags |= MsFlags::MS_RDONLY;
+
+        if let Some(parent) = marker.parent() {
+            fs::create_dir_all(parent)?;
         }
-        
-        mount(
-            Some(mount_config.src.as_str()),
-            target.as_str(),
-            mount_config.fstype.as_deref(),
-            flags,
-            None::<&str>,
-        )?;
-        
+        fs::write(&marker, b"")
+            .with_context(|| format!("cannot create provision marker {}", marker.display()))?;
         Ok(())
     }
 
-    fn setup_user_permissions(&self) -> Result<()> {
-        if let Some(gid) = self.config.gid {
-            setgid(Gid::from_raw(gid))?;
+    fn child_setup(&self) -> Result<ChildSetup> {
+        let mounts = self.mount_plan()?;
+        // For the child to inherit
+        self.exec_path();
+        self.config.check_mount_count(
+            mounts.len() + self.config.volumes.len() + self.config.fuse_mounts.len(),
+        )?;
+        Ok(ChildSetup {
+            mounts,
+            host_uid: getuid(),
+            host_gid: getgid(),
+            notify_fd: None,
+            listener_fd: None,
+            timings_fd: None,
+            cgroup: None,
+            cgroup_namespace: self.cgroup_namespace()?,
+        })
+    }
+
+    fn cgroup_namespace(&self) -> Result<bool> {
+        if !self.config.clone_newcgroup {
+            return Ok(false);
         }
-        
-        if let Some(uid) = self.config.uid {
-            setuid(Uid::from_raw(uid))?;
+        if Path::new("/proc/self/ns/cgroup").exists() {
+            return Ok(true);
         }
-        
-        Ok(())
+        if self.config.ignore_unsupported_ns {
+            log::warn!("skipping unsupported cgroup namespace");
+            return Ok(false);
+        }
+        bail!("clone_newcgroup needs cgroup namespace support, added in Linux 4.6");
     }
 
-    fn setup_resource_limits(&self) -> Result<()> {
-        if let Some(mem_limit) = self.config.rlimit_as {
-            setrlimit(Resource::RLIMIT_AS, mem_limit, mem_limit)?;
+    /// Open the PAM session for pam_service as the user the jailed program
+    /// runs as, while still in the host's namespaces.
+    #[cfg(feature = "pam")]
+    fn open_pam_session(&self) -> Result<Option<pam::Session>> {
+        let Some(service) = &self.config.pam_service else {
+            return Ok(None);
+        };
+        let uid = self.config.uid.map(Uid::from_raw).unwrap_or_else(getuid);
+        let user = User::from_uid(uid)?
+            .with_context(|| format!("pam_service needs a passwd entry for uid {}", uid))?;
+        Ok(Some(pam::Session::open(service, &user.name)?))
+    }
+
+    /// Create the cgroup for `cgroup_delegate` or `memory_high`, owned by the
+    /// host ids root in the jail maps to if delegated. Must happen before any
+    /// namespace is entered.
+    fn jail_cgroup(&self, setup: &ChildSetup) -> Result<Option<JailCgroup>> {
+        if !self.config.cgroup_delegate && self.config.memory_high.is_none() {
+            return Ok(None);
         }
-        
-        if let Some(cpu_limit) = self.config.rlimit_cpu {
-            setrlimit(Resource::RLIMIT_CPU, cpu_limit, cpu_limit)?;
+        let owner = self
+            .config
+            .cgroup_delegate
+            .then(|| self.cgroup_owner(setup));
+        let cgroup = JailCgroup::create(owner)?;
+        if let Some(memory_high) = self.config.memory_high {
+            cgroup.set_memory_high(memory_high)?;
         }
-        
-        if let Some(nofile_limit) = self.config.rlimit_nofile {
-            setrlimit(Resource::RLIMIT_NOFILE, nofile_limit, nofile_limit)?;
+        Ok(Some(cgroup))
+    }
+
+    fn cgroup_owner(&self, setup: &ChildSetup) -> (Uid, Gid) {
+        if !self.config.clone_newuser {
+            return (setup.host_uid, setup.host_gid);
         }
-        
-        Ok(())
+        let root_outside = |maps: Vec<IdMap>, host_id: u32| {
+            maps.iter()
+                .find(|map| map.contains(0))
+                .map_or(host_id, |map| map.outside - map.inside)
+        };
+        let (uid, gid) = (setup.host_uid.as_raw(), setup.host_gid.as_raw());
+        (
+            Uid::from_raw(root_outside(self.config.uid_maps(uid), uid)),
+            Gid::from_raw(root_outside(self.config.gid_maps(gid), gid)),
+        )
     }
 
-    fn exec_target_program(&self) -> Result<()> {
-        let program = CString::new(self.config.exec_bin.clone())?;
-        
-        let args: Result<Vec<CString>, _> = self
-            .config
-            .exec_args
-            .iter()
-            .map(|arg| CString::new(arg.clone()))
-            .collect();
-        let args = args?;
-        
-        let env = vec![
-            CString::new("PATH=/bin:/usr/bin:/sbin:/usr/sbin")?,
-            CString::new("HOME=/")?,
-            CString::new("USER=jail")?,
-        ];
+    fn scratch_base(&self) -> PathBuf {
+        self.config
+            .scratch_dir
+            .as_ref()
+            .map(PathBuf::from)
+            .unwrap_or_else(std::env::temp_dir)
+    }
+
+    fn requested_namespaces(&self) -> CloneFlags {
+        let mut flags = CloneFlags::empty();
         
-        execve(&program, &args, &env)?;
-        Ok(())
+        if self.config.clone_newpid {
+            flags |= CloneFlags::CLONE_NEWPID;
+        }
+        if self.config.net_namespace() {
+            flags |= CloneFlags::CLONE_NEWNET;
+        }
+        if self.config.clone_newns {
+            flags |= CloneFlags::CLONE_NEWNS;
+        }
+        if self.config.clone_newuts {
+            flags |= CloneFlags::CLONE_NEWUTS;
+        }
+        if self.config.clone_newipc {
+            flags |= CloneFlags::CLONE_NEWIPC;
+        }
+        if self.config.clone_newuser {
+            flags |= CloneFlags::CLONE_NEWUSER;
+        }
+        if self.config.clone_newtime {
+            flags |= CLONE_NEWTIME;
+        }
+
+        flags
     }
 
-    fn wait_for_child(&self, child: Pid) -> Result<()> {
-        match waitpid(child, None)? {
-            WaitStatus::Exited(pid, code) => {
-                println!("Child {} exited with code {}", pid, code);
-            }
-            WaitStatus::Signaled(pid, signal, _) => {
-                println!("Child {} killed by signal {:?}", pid, signal);
-            }
-            _ => {
-                println!("Child process status changed");
+    /// Unshare `flags` in this process, which enters them all except the pid
+    /// and time namespaces; those only take in the children it starts next.
+    fn create_namespaces(&self, flags: CloneFlags) -> Result<CloneFlags> {
+        if flags.is_empty() {
+            return Ok(flags);
+        }
+        log::debug!("unsharing namespaces {:?}", namespace_names(flags));
+        if flags.contains(CLONE_NEWTIME)
+            && !self.config.ignore_unsupported_ns
+            && !Path::new("/proc/self/ns/time").exists()
+        {
+            bail!("clone_newtime needs time namespace support, added in Linux 5.6");
+        }
+
+        let created = match unshare(flags) {
+            Ok(()) => flags,
+            Err(e) if self.config.ignore_unsupported_ns => {
+                log::warn!(
+                    "unshare({:?}) failed: {}, retrying namespaces individually",
+                    namespace_names(flags),
+                    e
+                );
+                self.create_namespaces_individually(flags)
             }
+            Err(e) => return Err(e.into()),
+        };
+        if created.contains(CLONE_NEWTIME) {
+            self.set_time_offsets()?;
+        }
+
+        Ok(created)
+    }
+
+    /// Offsets can only be set while the new time namespace is still empty,
+    /// i.e. here between unshare and the fork that puts the child in it.
+    fn set_time_offsets(&self) -> Result<()> {
+        let mut offsets = String::new();
+        for (clock, offset) in [
+            ("monotonic", self.config.time_offset_monotonic),
+            ("boottime", self.config.time_offset_boottime),
+        ] {
+            if let Some(secs) = offset {
+                offsets.push_str(&format!("{} {} 0\n", clock, secs));
+            }
+        }
+        if offsets.is_empty() {
+            return Ok(());
+        }
+
+        log::debug!("time offsets {:?}", offsets);
+        fs::write("/proc/self/timens_offsets", offsets).context("cannot set time offsets")?;
+        Ok(())
+    }
+
+    fn create_namespaces_individually(&self, flags: CloneFlags) -> CloneFlags {
+        let mut created = CloneFlags::empty();
+
+        for (flag, name) in NAMESPACES {
+            if !flags.contains(flag) {
+                continue;
+            }
+            match unshare(flag) {
+                Ok(()) => created |= flag,
+                Err(e) => log::warn!("skipping unsupported {} namespace: {}", name, e),
+            }
+        }
+
+        created
+    }
+
+    /// Every mount to apply inside chroot_dir, in order: the configured ones
+    /// followed by those rsjail adds itself. Resolved against the host.
+    fn mount_plan(&self) -> Result<Vec<MountConfig>> {
+        let mut mounts = Vec::new();
+        for mount in &self.config.mounts {
+            mounts.extend(self.expand_mount(mount)?);
+        }
+
+        if let Some(interpreter) = self.interpreter()?
+            && self.config.fs_isolation() != FsIsolation::None
+        {
+            mounts.extend(self.interpreter_mount(interpreter));
+        }
+        if self.config.auto_mount_libs && self.config.fs_isolation() != FsIsolation::None {
+            mounts.extend(self.library_mounts()?);
+        }
+        if self.config.mount_localtime && self.config.fs_isolation() != FsIsolation::None {
+            mounts.extend(self.localtime_mounts()?);
+        }
+        if self.config.bind_ca_certs && self.config.fs_isolation() != FsIsolation::None {
+            mounts.extend(self.ca_cert_mounts()?);
+        }
+        // Parents before children, so /usr/local isn't buried under /usr.
+        // The sort is stable, siblings keep their declaration order.
+        mounts.sort_by_key(|mount| Path::new(&mount.dst).components().count());
+
+        if self.config.fs_isolation() != FsIsolation::None {
+            self.check_loader(&mounts)?;
+        }
+        Ok(mounts)
+    }
+
+    /// Fail unless the dynamic loader exec_bin names in its PT_INTERP will
+    /// be in the jail, through `mounts` or chroot_dir, which the exec would
+    /// otherwise report as a bare ENOENT. Only for a native exec_bin that
+    /// is in the jail itself.
+    fn check_loader(&self, mounts: &[MountConfig]) -> Result<()> {
+        let PlannedFile::Host(exec_bin) = self.planned_file(mounts, &self.config.exec_bin) else {
+            return Ok(());
+        };
+        let Ok(info) = elf::parse(&exec_bin) else {
+            return Ok(());
+        };
+        if !is_native(&info) {
+            return Ok(());
+        }
+        if let Some(loader) = &info.interpreter
+            && self.planned_file(mounts, loader) == PlannedFile::Missing
+        {
+            bail!(
+                "exec_bin {} needs the dynamic loader {}, which is neither mounted nor in \
+                 chroot_dir; mount it or set auto_mount_libs",
+                self.config.exec_bin,
+                loader
+            );
+        }
+        Ok(())
+    }
+
+    /// Where the file at `jail_path` will be on the host once `mounts` are
+    /// made, following symlinks the way the jail will see them.
+    fn planned_file(&self, mounts: &[MountConfig], jail_path: &str) -> PlannedFile {
+        let Some(chroot_dir) = &self.config.chroot_dir else {
+            return PlannedFile::Unknown;
+        };
+        let mut jail_path = PathBuf::from(jail_path);
+        // MAXSYMLINKS, as many as the kernel follows
+        for _ in 0..40 {
+            if !jail_path.is_absolute() {
+                return PlannedFile::Unknown;
+            }
+            let mut normalized = PathBuf::new();
+            for component in jail_path.components() {
+                match component {
+                    std::path::Component::ParentDir => {
+                        normalized.pop();
+                    }
+                    component => normalized.push(component),
+                }
+            }
+            let base_path = self.base_path(&normalized.to_string_lossy());
+            let path = Path::new(&base_path);
+            let covering = mounts
+                .iter()
+                .filter(|mount| path.starts_with(&mount.dst))
+                .max_by_key(|mount| mount.dst.len());
+            let host = match covering {
+                // What a tmpfs or overlay will hold isn't known up front
+                Some(mount) if !mount.is_bind => return PlannedFile::Unknown,
+                Some(mount) => match path.strip_prefix(&mount.dst) {
+                    // The bind follows a symlink src on the host
+                    Ok(rest) if rest.as_os_str().is_empty() => {
+                        fs::canonicalize(&mount.src).unwrap_or_else(|_| PathBuf::from(&mount.src))
+                    }
+                    Ok(rest) => Path::new(&mount.src).join(rest),
+                    Err(_) => return PlannedFile::Unknown,
+                },
+                None => Path::new(chroot_dir).join(path.strip_prefix("/").unwrap_or(path)),
+            };
+            let Ok(metadata) = fs::symlink_metadata(&host) else {
+                return PlannedFile::Missing;
+            };
+            // An empty file is the bind target an earlier run left behind
+            if metadata.is_file() && metadata.len() == 0 {
+                return PlannedFile::Missing;
+            }
+            if !metadata.file_type().is_symlink() {
+                return PlannedFile::Host(host);
+            }
+            let Ok(target) = fs::read_link(&host) else {
+                return PlannedFile::Missing;
+            };
+            // An absolute target replaces the path
+            jail_path = normalized.parent().unwrap_or(Path::new("/")).join(target);
+        }
+        PlannedFile::Missing
+    }
+
+    /// A configured mount as it is made: one per match for a src with
+    /// wildcards, none for a missing optional one.
+    fn expand_mount(&self, mount: &MountConfig) -> Result<Vec<MountConfig>> {
+        if !mount.is_bind || !glob::is_pattern(&mount.src) {
+            if mount.optional && mount.is_bind && fs::symlink_metadata(&mount.src).is_err() {
+                log::info!(
+                    "skipping optional mount {}, {} is missing",
+                    mount.dst,
+                    mount.src
+                );
+                return Ok(Vec::new());
+            }
+            return Ok(vec![mount.clone()]);
+        }
+
+        let matches = glob::expand(&mount.src, self.config.max_mounts)
+            .with_context(|| format!("mount {}", mount.dst))?;
+        if matches.is_empty() && !mount.optional {
+            bail!("mount {}: nothing matches {}", mount.dst, mount.src);
+        }
+        Ok(matches
+            .into_iter()
+            .map(|path| MountConfig {
+                dst: Path::new(&mount.dst)
+                    .join(path.file_name().unwrap_or_default())
+                    .to_string_lossy()
+                    .into_owned(),
+                src: path.to_string_lossy().into_owned(),
+                ..mount.clone()
+            })
+            .collect())
+    }
+
+    /// `mounts` plus one mount per volume, in mount order. Disk volumes get
+    /// a directory in `scratch`, which no_fork runs don't have.
+    fn with_volumes(
+        &self,
+        mounts: &[MountConfig],
+        scratch: Option<&Path>,
+    ) -> Result<Vec<MountConfig>> {
+        let mut mounts = mounts.to_vec();
+
+        for volume in &self.config.volumes {
+            if volume.backing == VolumeBacking::Disk {
+                let Some(scratch) = scratch else {
+                    bail!("volume {} needs a scratch dir", volume.name);
+                };
+                let dir = scratch.join("volumes").join(&volume.name);
+                fs::create_dir_all(&dir)?;
+                // Anyone in the jail may write, as with the tmpfs ones
+                fs::set_permissions(&dir, fs::Permissions::from_mode(0o1777))?;
+                self.restore_volume(&volume.name, &dir)?;
+            }
+            log::debug!("volume {} at {}", volume.name, volume.mount_path);
+            mounts.push(self.volume_mount(volume, scratch.unwrap_or(Path::new(""))));
+        }
+
+        mounts.sort_by_key(|mount| Path::new(&mount.dst).components().count());
+        Ok(mounts)
+    }
+
+    /// The mount for `volume`, a disk one bound from its directory in
+    /// `scratch`.
+    fn volume_mount(&self, volume: &VolumeConfig, scratch: &Path) -> MountConfig {
+        let dst = self.base_path(&volume.mount_path);
+        match volume.backing {
+            VolumeBacking::Tmpfs => MountConfig {
+                src: "tmpfs".to_string(),
+                dst,
+                fstype: Some("tmpfs".to_string()),
+                rw: true,
+                data: Some(match &volume.size {
+                    Some(size) => format!("mode=1777,size={}", size),
+                    None => "mode=1777".to_string(),
+                }),
+                ..Default::default()
+            },
+            VolumeBacking::Disk => MountConfig {
+                src: scratch
+                    .join("volumes")
+                    .join(&volume.name)
+                    .to_string_lossy()
+                    .into_owned(),
+                dst,
+                is_bind: true,
+                rw: true,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Every mount the jail will make, in the order it makes them: the
+    /// configured and automatic ones, volumes, /etc/hosts, the pseudo
+    /// filesystems and the masks. Per-run paths read `<scratch>` and
+    /// `<jail cgroup>`. Masks are looked up ahead of the run, so one the
+    /// program creates itself isn't listed.
+    pub fn resolved_mounts(&self) -> Result<Vec<ResolvedMount>> {
+        if self.config.fs_isolation() == FsIsolation::None {
+            // Only the private tmpfs mounts land anywhere, on the host's dirs
+            return Ok(self
+                .pseudo_mounts(None)
+                .into_iter()
+                .filter(|mount| mount.fstype.as_deref() == Some("tmpfs"))
+                .filter(|mount| Path::new(&mount.dst).is_dir())
+                .collect());
+        }
+
+        let scratch = Path::new("<scratch>");
+        let mut mounts = self.mount_plan()?;
+        for volume in &self.config.volumes {
+            mounts.push(self.volume_mount(volume, scratch));
+        }
+        mounts.sort_by_key(|mount| Path::new(&mount.dst).components().count());
+        if !self.config.extra_hosts.is_empty() {
+            mounts.push(MountConfig {
+                src: scratch.join("hosts").to_string_lossy().into_owned(),
+                dst: self.base_path("/etc/hosts"),
+                is_bind: true,
+                rw: false,
+                ..Default::default()
+            });
+        }
+
+        let mut resolved: Vec<ResolvedMount> = mounts.iter().map(ResolvedMount::from).collect();
+        for fuse in &self.config.fuse_mounts {
+            resolved.push(ResolvedMount {
+                dst: self.base_path(&fuse.mount_path),
+                ..self.fuse_mount(fuse, "<fd>")
+            });
+        }
+        let cgroup = self
+            .config
+            .cgroup_delegate
+            .then_some(Path::new("<jail cgroup>"));
+        for pseudo in self.pseudo_mounts(cgroup) {
+            resolved.push(ResolvedMount {
+                dst: self.base_path(&pseudo.dst),
+                ..pseudo
+            });
+        }
+        for path in self.masked_paths() {
+            if let Some(host_path) = self.mask_host_path(&path)
+                && let Ok(metadata) = fs::symlink_metadata(host_path)
+                && !metadata.is_symlink()
+            {
+                resolved.push(ResolvedMount {
+                    dst: self.base_path(&path),
+                    ..mask_mount(metadata.is_dir())
+                });
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// Where to look up a masked path ahead of the run. The fresh /proc and
+    /// /sys show what the host's do, anything else has to be in chroot_dir
+    /// or a configured mount.
+    fn mask_host_path(&self, path: &str) -> Option<PathBuf> {
+        let pseudo = (self.config.mount_proc && path.starts_with("/proc/"))
+            || (self.config.mount_sys && path.starts_with("/sys/"));
+        if pseudo {
+            return Some(PathBuf::from(path));
+        }
+        // host_path's last resort is the host's own file, which the jail
+        // doesn't see
+        let host_path = self.host_path(path);
+        let covered = self
+            .mount_covering(Path::new(&self.base_path(path)))
+            .is_some();
+        (covered || host_path != Path::new(path)).then_some(host_path)
+    }
+
+    fn check_saved_volumes(&self) -> Result<()> {
+        if self.save_volumes_to.is_none() && self.restore_volumes_from.is_none() {
+            return Ok(());
+        }
+        let disk_volumes = self
+            .config
+            .volumes
+            .iter()
+            .any(|volume| volume.backing == VolumeBacking::Disk);
+        if !disk_volumes {
+            bail!("only disk-backed volumes are saved and restored, and there are none");
+        }
+        if let Some(dir) = &self.restore_volumes_from
+            && !dir.is_dir()
+        {
+            bail!("saved volumes {} to restore do not exist", dir.display());
+        }
+        Ok(())
+    }
+
+    fn restore_volume(&self, name: &str, dir: &Path) -> Result<()> {
+        let Some(saved_volumes) = &self.restore_volumes_from else {
+            return Ok(());
+        };
+        let saved = saved_volumes.join(name);
+        if !saved.is_dir() {
+            log::warn!("{} has no saved volume {}", saved_volumes.display(), name);
+            return Ok(());
+        }
+        log::debug!("restoring volume {} from {}", name, saved.display());
+        scratch::copy_tree(&saved, dir)
+            .with_context(|| format!("cannot restore volume {} from {}", name, saved.display()))
+    }
+
+    /// Copy the disk-backed volumes out of `scratch` for save_volumes_to.
+    fn save_volumes(&self, scratch: &Path) -> Result<()> {
+        let Some(saved_volumes) = &self.save_volumes_to else {
+            return Ok(());
+        };
+        for volume in &self.config.volumes {
+            if volume.backing != VolumeBacking::Disk {
+                continue;
+            }
+            let saved = saved_volumes.join(&volume.name);
+            if saved.exists() {
+                fs::remove_dir_all(&saved)
+                    .with_context(|| format!("cannot replace {}", saved.display()))?;
+            }
+            log::debug!("saving volume {} to {}", volume.name, saved.display());
+            scratch::copy_tree(&scratch.join("volumes").join(&volume.name), &saved)
+                .with_context(|| format!("cannot save volume {}", volume.name))?;
+        }
+        Ok(())
+    }
+
+    /// A read-only bind of an /etc/hosts with extra_hosts added, written to
+    /// `scratch`. It starts from chroot_dir's /etc/hosts if there is one.
+    fn hosts_mount(&self, scratch: &Path) -> Result<Option<MountConfig>> {
+        if self.config.extra_hosts.is_empty() {
+            return Ok(None);
+        }
+
+        // host_path falls back to the host's own file, and an earlier run may
+        // have left an empty placeholder to bind onto
+        let existing = self.host_path("/etc/hosts");
+        let mut hosts = match fs::read_to_string(&existing) {
+            Ok(hosts) if existing != Path::new("/etc/hosts") && !hosts.trim().is_empty() => hosts,
+            _ => {
+                let mut hosts = DEFAULT_HOSTS.to_string();
+                if let Some(hostname) = &self.config.hostname {
+                    hosts.push_str(&format!("127.0.1.1\t{}\n", hostname));
+                }
+                hosts
+            }
+        };
+        if !hosts.ends_with('\n') {
+            hosts.push('\n');
+        }
+        for entry in &self.config.extra_hosts {
+            hosts.push_str(entry);
+            hosts.push('\n');
+        }
+
+        let path = scratch.join("hosts");
+        fs::write(&path, hosts)?;
+        Ok(Some(MountConfig {
+            src: path.to_string_lossy().into_owned(),
+            dst: self.base_path("/etc/hosts"),
+            is_bind: true,
+            rw: false,
+            ..Default::default()
+        }))
+    }
+
+    /// Read-only binds for the shared libraries and loader exec_bin needs,
+    /// and the wrapper and preload with theirs, skipping any already
+    /// reachable through a configured mount or inside chroot_dir.
+    fn library_mounts(&self) -> Result<Vec<MountConfig>> {
+        let mut needed = BTreeSet::new();
+        let exec_bin = self.host_path(&self.config.exec_bin);
+        // Libraries of another architecture are the interpreter's business
+        if !elf::parse(&exec_bin).is_ok_and(|info| !is_native(&info)) {
+            needed.extend(elf::resolve_dependencies(&exec_bin)?);
+        }
+        if let Some(interpreter) = self.interpreter()?
+            && elf::is_elf(Path::new(interpreter))
+        {
+            needed.extend(elf::resolve_dependencies(Path::new(interpreter))?);
+        }
+        let wrapper = self
+            .config
+            .wrapper
+            .iter()
+            .filter_map(|wrapper| wrapper.first());
+        let helper = self.config.init_helper.iter().filter(|_| self.config.init);
+        for extra in wrapper.chain(helper).chain(&self.config.preload) {
+            let mut binary = self.host_path(extra);
+            // An empty file is the bind target an earlier run left behind
+            if fs::metadata(&binary).is_ok_and(|metadata| metadata.len() == 0) {
+                binary = PathBuf::from(extra);
+            }
+            // The wrapper may well be a script
+            if elf::is_elf(&binary) {
+                needed.extend(elf::resolve_dependencies(&binary)?);
+            }
+            if binary == Path::new(extra) {
+                needed.insert(binary);
+            }
+        }
+        let mut mounts = Vec::new();
+
+        for library in needed {
+            if self.mount_covering(&library).is_some() {
+                continue;
+            }
+            let path = library.to_string_lossy().into_owned();
+            log::debug!("auto-mounting library {}", path);
+            mounts.push(MountConfig {
+                dst: self.base_path(&path),
+                src: path,
+                fstype: None,
+                is_bind: true,
+                rw: false,
+                ..Default::default()
+            });
+        }
+
+        Ok(mounts)
+    }
+
+    /// The interpreter exec_bin runs under, with "auto" worked out from
+    /// exec_bin's ELF header. That happens once, in the parent planning the
+    /// mounts while host paths still mean what they say, and the child
+    /// inherits the answer.
+    fn interpreter(&self) -> Result<Option<&str>> {
+        if let Some(interpreter) = self.interpreter.get() {
+            return Ok(interpreter.as_deref());
+        }
+        let interpreter = match self.config.interpreter.as_deref() {
+            Some("auto") => self.find_qemu()?,
+            interpreter => interpreter.map(str::to_string),
+        };
+        Ok(self.interpreter.get_or_init(|| interpreter).as_deref())
+    }
+
+    /// The host's qemu-user for exec_bin's architecture, None if exec_bin is
+    /// native.
+    fn find_qemu(&self) -> Result<Option<String>> {
+        let exec_bin = self.host_path(&self.config.exec_bin);
+        let info = elf::parse(&exec_bin).context("interpreter \"auto\" needs an ELF exec_bin")?;
+        if is_native(&info) {
+            return Ok(None);
+        }
+
+        let Some(arch) = elf::qemu_arch(&info) else {
+            bail!(
+                "exec_bin {} is for ELF machine {}, which has no known qemu-user",
+                self.config.exec_bin,
+                info.machine
+            );
+        };
+        let dirs = ["/usr/bin", "/usr/local/bin"];
+        let candidates = dirs.iter().flat_map(|dir| {
+            [
+                format!("{}/qemu-{}-static", dir, arch),
+                format!("{}/qemu-{}", dir, arch),
+            ]
+        });
+        for candidate in candidates {
+            if Path::new(&candidate).is_file() {
+                log::info!("running {} exec_bin under {}", arch, candidate);
+                return Ok(Some(candidate));
+            }
+        }
+        bail!(
+            "exec_bin {} is for {}, but there is no qemu-{}-static or qemu-{} in {}; \
+             install qemu-user-static or set interpreter to its path",
+            self.config.exec_bin,
+            arch,
+            arch,
+            arch,
+            dirs.join(" or ")
+        );
+    }
+
+    /// A read-only bind of the interpreter at its host path, unless a mount
+    /// or chroot_dir already provides it.
+    fn interpreter_mount(&self, interpreter: &str) -> Option<MountConfig> {
+        if self.mount_covering(Path::new(interpreter)).is_some() {
+            return None;
+        }
+        // An empty file is the bind target an earlier run left behind
+        let inside = self.host_path(interpreter);
+        if inside != Path::new(interpreter) && fs::metadata(&inside).is_ok_and(|m| m.len() > 0) {
+            return None;
+        }
+        log::debug!("mounting interpreter {}", interpreter);
+        Some(MountConfig {
+            src: interpreter.to_string(),
+            dst: self.base_path(interpreter),
+            is_bind: true,
+            rw: false,
+            ..Default::default()
+        })
+    }
+
+    /// The command exec_bin is started through: the wrapper, or the
+    /// interpreter, after any init_helper.
+    fn wrapper(&self) -> Option<Vec<String>> {
+        let wrapper = self.config.wrapper.clone().or_else(|| {
+            // mount_plan has already reported an "auto" that fails
+            let interpreter = self.interpreter().ok().flatten()?;
+            Some(vec![interpreter.to_string()])
+        });
+        let helper = self.config.init_helper.as_ref();
+        let Some(helper) = helper.filter(|_| self.config.init) else {
+            return wrapper;
+        };
+        let mut argv = vec![helper.clone(), "--".to_string()];
+        argv.extend(wrapper.into_iter().flatten());
+        Some(argv)
+    }
+
+    /// Read-only binds of the CA_CERT_DIRS the host has, but where mounts
+    /// already binds something of its own.
+    fn ca_cert_mounts(&self) -> Result<Vec<MountConfig>> {
+        let dirs: Vec<&str> = CA_CERT_DIRS
+            .iter()
+            .filter(|(certs, _)| Path::new(certs).is_dir())
+            .flat_map(|(certs, linked)| std::iter::once(*certs).chain(linked.iter().copied()))
+            .filter(|dir| Path::new(dir).is_dir())
+            .collect();
+        if dirs.is_empty() {
+            let searched: Vec<&str> = CA_CERT_DIRS.iter().map(|(certs, _)| *certs).collect();
+            let searched = searched.join(" and ");
+            if !self.config.ca_certs_optional {
+                bail!(
+                    "bind_ca_certs found no CA certificates on the host in {}",
+                    searched
+                );
+            }
+            log::warn!(
+                "no CA certificates on the host in {}, binding none",
+                searched
+            );
+        }
+
+        Ok(dirs
+            .into_iter()
+            .map(|dir| MountConfig {
+                src: dir.to_string(),
+                dst: self.base_path(dir),
+                is_bind: true,
+                rw: false,
+                ..Default::default()
+            })
+            .filter(|bind| {
+                let dst = Path::new(&bind.dst);
+                !self
+                    .config
+                    .mounts
+                    .iter()
+                    .any(|mount| Path::new(&mount.dst) == dst)
+            })
+            .collect())
+    }
+
+    /// Read-only binds of the timezone's zoneinfo file at /etc/localtime and
+    /// at the path TZ names it by.
+    fn localtime_mounts(&self) -> Result<Vec<MountConfig>> {
+        let timezone = self.config.timezone.as_deref().unwrap_or_default();
+        let zone_file = Path::new(ZONEINFO_DIR).join(timezone);
+        if !zone_file.is_file() {
+            bail!("no zoneinfo file {} on the host", zone_file.display());
+        }
+        let src = zone_file.to_string_lossy().into_owned();
+
+        Ok([self.base_path("/etc/localtime"), self.base_path(&src)]
+            .into_iter()
+            .map(|dst| MountConfig {
+                src: src.clone(),
+                dst,
+                is_bind: true,
+                rw: false,
+                ..Default::default()
+            })
+            .collect())
+    }
+
+    /// The configured mount with the longest destination that contains
+    /// `jail_path`, if any.
+    fn mount_covering(&self, jail_path: &Path) -> Option<MountConfig> {
+        self.config
+            .mounts
+            .iter()
+            .filter(|mount| jail_path.starts_with(&mount.dst))
+            // mount_plan reports a bad wildcard
+            .flat_map(|mount| self.expand_mount(mount).unwrap_or_default())
+            .filter(|mount| jail_path.starts_with(&mount.dst))
+            .max_by_key(|mount| mount.dst.len())
+    }
+
+    /// Where a path inside the jail lives on the host: under the source of
+    /// the mount covering it, else inside chroot_dir, else the host path.
+    fn host_path(&self, jail_path: &str) -> PathBuf {
+        let base_path = self.base_path(jail_path);
+        let path = Path::new(&base_path);
+
+        if let Some(mount) = self.mount_covering(path)
+            && let Ok(rest) = path.strip_prefix(&mount.dst)
+        {
+            if rest.as_os_str().is_empty() {
+                return PathBuf::from(&mount.src);
+            }
+            return Path::new(&mount.src).join(rest);
+        }
+
+        if self.config.fs_isolation() != FsIsolation::None
+            && let Some(chroot_dir) = &self.config.chroot_dir
+        {
+            let rest = path.strip_prefix("/").unwrap_or(path);
+            let inside = Path::new(chroot_dir).join(rest);
+            if inside.exists() {
+                return inside;
+            }
+        }
+
+        PathBuf::from(jail_path)
+    }
+
+    /// Where `jail_path` sits relative to chroot_dir, which differs once
+    /// root_subpath is set. Configured mounts are relative to chroot_dir.
+    fn base_path(&self, jail_path: &str) -> String {
+        match &self.config.root_subpath {
+            Some(subpath) => format!("/{}{}", subpath.trim_matches('/'), jail_path),
+            None => jail_path.to_string(),
+        }
+    }
+
+    /// The directory that becomes the jail's root: chroot_dir, or the
+    /// root_subpath inside it, which only has to exist once mounts are done.
+    fn jail_root(&self, chroot_dir: &str) -> Result<String> {
+        let Some(subpath) = &self.config.root_subpath else {
+            return Ok(chroot_dir.to_string());
+        };
+
+        let root = format!("{}{}", chroot_dir, self.base_path(""));
+        let resolved = fs::canonicalize(&root)
+            .with_context(|| format!("root_subpath {} does not exist in chroot_dir", subpath))?;
+        // A symlink along the way could point anywhere on the host
+        if !resolved.starts_with(fs::canonicalize(chroot_dir)?) || !resolved.is_dir() {
+            bail!(
+                "root_subpath {} is not a directory inside chroot_dir",
+                subpath
+            );
+        }
+
+        Ok(resolved.to_string_lossy().into_owned())
+    }
+
+    fn setup_child_environment(&self, setup: &ChildSetup) -> Result<Infallible> {
+        // Setup user namespace mapping
+        if self.config.clone_newuser {
+            timings::time(setup.timings_fd, "id_maps", || {
+                self.setup_uid_gid_mapping(setup)
+            })?;
+        }
+
+        self.setup_jail_root(setup)?;
+        self.enter_jail(setup)
+    }
+
+    /// Hostname, sysctls, connection limit and in thread_mode mounts and
+    /// root: what is set up once for the jail's namespaces rather than for
+    /// the calling process, so the thread_mode setup thread can do it before
+    /// forking the child.
+    fn setup_jail_root(&self, setup: &ChildSetup) -> Result<()> {
+        // Setup hostname
+        if let Some(hostname) = &self.config.hostname {
+            log::debug!("setting hostname {}", hostname);
+            sethostname(hostname)?;
+        }
+
+        if self.config.network_mode() == NetworkMode::Loopback {
+            log::debug!("bringing up lo");
+            timings::time(setup.timings_fd, "loopback", bring_up_loopback)?;
+        }
+        if !self.config.sysctls.is_empty() {
+            timings::time(setup.timings_fd, "sysctls", || self.setup_sysctls())?;
+        }
+        if let Some(limit) = self.config.max_connections {
+            // Runs the host's nft, so before the root changes
+            timings::time(setup.timings_fd, "connection_limit", || {
+                limit_connections(limit)
+            })?;
+        }
+
+        // Otherwise a step of enter_jail; validate puts it first here
+        if self.config.thread_mode && self.config.setup_steps().contains(&SetupStep::Mounts) {
+            self.setup_root(setup)?;
+        }
+        Ok(())
+    }
+
+    fn setup_root(&self, setup: &ChildSetup) -> Result<()> {
+        if self.config.fs_isolation() != FsIsolation::None
+            && let Some(chroot_dir) = &self.config.chroot_dir
+        {
+            let root = timings::time(setup.timings_fd, "mounts", || {
+                self.setup_filesystem(chroot_dir, &setup.mounts, setup.cgroup.as_deref())
+            })?;
+            // Switch root directory
+            if self.config.fs_isolation() == FsIsolation::PivotRoot {
+                log::debug!("pivot_root to {}", root);
+                timings::time(setup.timings_fd, "pivot_root", || pivot_root_to(&root))?;
+            } else {
+                log::debug!("chroot to {}", root);
+                timings::time(setup.timings_fd, "chroot", || chroot(root.as_str()))?;
+            }
+            std::env::set_current_dir("/")?;
+        } else if self.config.private_tmp {
+            // Without a chroot these mounts land on the host's directories,
+            // which the parent must keep seeing
+            timings::time(setup.timings_fd, "mounts", || {
+                private_mount_namespace()?;
+                self.setup_private_tmp()
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Everything left for the child once its namespaces are set up: the
+    /// setup_steps in their order, down to the exec of the target.
+    fn enter_jail(&self, setup: &ChildSetup) -> Result<Infallible> {
+        if setup.cgroup_namespace {
+            unshare(CloneFlags::CLONE_NEWCGROUP).context("cannot unshare a cgroup namespace")?;
+        }
+
+        let mut steps = self.config.setup_steps();
+        // Last, so none of the setup counts, and under init so its fork
+        // doesn't either
+        let guard_at_exec = steps.last() == Some(&SetupStep::Seccomp);
+        if guard_at_exec {
+            steps = &steps[..steps.len() - 1];
+        }
+        let fd = setup.timings_fd;
+        for step in steps {
+            log::trace!("setup step {:?}", step);
+            match step {
+                SetupStep::Mounts => {
+                    if !self.config.thread_mode {
+                        self.setup_root(setup)?;
+                    }
+                    if self.config.chown_jail_dirs {
+                        timings::time(fd, "chown_jail_dirs", || self.chown_jail_dirs())?;
+                    }
+                }
+                SetupStep::Setuid => timings::time(fd, "setuid", || self.setup_user_permissions())?,
+                SetupStep::Caps => timings::time(fd, "caps", || self.raise_ambient_caps())?,
+                SetupStep::NoNewPrivs => self.set_no_new_privs()?,
+                SetupStep::Rlimits => {
+                    timings::time(fd, "rlimits", || self.setup_resource_limits())?
+                }
+                SetupStep::Landlock if !self.config.landlock.is_empty() => {
+                    log::debug!("applying {} landlock rules", self.config.landlock.len());
+                    timings::time(fd, "landlock", || {
+                        landlock::restrict_self(
+                            &self.config.landlock,
+                            self.config.landlock_required,
+                        )
+                    })?;
+                }
+                SetupStep::Landlock => {}
+                SetupStep::Seccomp if self.wants_seccomp(setup) => {
+                    timings::time(fd, "seccomp", || self.install_seccomp(setup))?;
+                }
+                SetupStep::Seccomp => {}
+            }
+        }
+
+        if self.hold {
+            log::info!("holding the jail");
+            return init::hold();
+        }
+
+        if self.config.new_session {
+            log::debug!("starting a new session");
+            // Fails when already a process group leader, as with no_fork
+            // from an interactive shell
+            setsid().context("cannot start a new session")?;
+        }
+
+        // Execute target program
+        self.exec_target_program(setup, guard_at_exec)
+    }
+
+    /// Namespaced sysctls resolve against the writer's namespaces rather than
+    /// the proc mount, so the host /proc works here before any chroot.
+    fn setup_sysctls(&self) -> Result<()> {
+        let mut sysctls: Vec<_> = self.config.sysctls.iter().collect();
+        sysctls.sort();
+
+        for (key, value) in sysctls {
+            log::debug!("sysctl {}={}", key, value);
+            let path = Path::new("/proc/sys").join(key.replace('.', "/"));
+            fs::write(&path, value).with_context(|| format!("cannot set sysctl {}", key))?;
+        }
+
+        Ok(())
+    }
+
+    fn setup_uid_gid_mapping(&self, setup: &ChildSetup) -> Result<()> {
+        // Not /proc/<getpid()>: inside a new PID namespace that would be the
+        // host's pid 1 as far as the host /proc is concerned
+        log::debug!("writing uid/gid maps");
+        
+        // Setup UID mapping
+        let uid_map = id_map_lines(&self.config.uid_maps(setup.host_uid.as_raw()));
+        let mut uid_map_file = OpenOptions::new().write(true).open("/proc/self/uid_map")?;
+        uid_map_file
+            .write_all(uid_map.as_bytes())
+            .map_err(id_map_error("uid_map"))?;
+        
+        // Disable setgroups
+        let mut setgroups_file = OpenOptions::new()
+            .write(true)
+            .open("/proc/self/setgroups")?;
+        setgroups_file.write_all(b"deny")?;
+        
+        // Setup GID mapping
+        let gid_map = id_map_lines(&self.config.gid_maps(setup.host_gid.as_raw()));
+        let mut gid_map_file = OpenOptions::new().write(true).open("/proc/self/gid_map")?;
+        gid_map_file
+            .write_all(gid_map.as_bytes())
+            .map_err(id_map_error("gid_map"))?;
+        
+        Ok(())
+    }
+
+    /// Create and mount everything below chroot_dir, returning the root to
+    /// switch to.
+    fn setup_filesystem(
+        &self,
+        chroot_dir: &str,
+        mounts: &[MountConfig],
+        cgroup: Option<&Path>,
+    ) -> Result<String> {
+        // Create basic directory structure
+        self.create_jail_directories(chroot_dir)?;
+        
+        // Setup mount points
+        for mount_config in mounts {
+            self.setup_mount(chroot_dir, mount_config)?;
+        }
+        let root = self.jail_root(chroot_dir)?;
+
+        for fuse in &self.config.fuse_mounts {
+            self.setup_fuse_mount(&root, fuse)?;
+        }
+        for pseudo in self.pseudo_mounts(cgroup) {
+            let target = format!("{}{}", root, pseudo.dst);
+            fs::create_dir_all(&target)?;
+            log::debug!("mounting {} on {}", pseudo.src, target);
+            mount(
+                Some(pseudo.src.as_str()),
+                target.as_str(),
+                pseudo.fstype.as_deref(),
+                pseudo.flags,
+                pseudo.data.as_deref(),
+            )
+            .with_context(|| format!("cannot mount {} on {}", pseudo.src, pseudo.dst))?;
+        }
+
+        for path in self.masked_paths() {
+            self.mask_path(&root, &path)?;
+        }
+        
+        Ok(root)
+    }
+
+    /// Mount a FUSE connection at `fuse.mount_path` under `root` and start
+    /// the helper that serves it, which inherits the /dev/fuse fd.
+    fn setup_fuse_mount(&self, root: &str, fuse: &FuseMount) -> Result<()> {
+        let target = format!("{}{}", root, fuse.mount_path);
+        fs::create_dir_all(&target)?;
+        let device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/fuse")
+            .context("cannot open /dev/fuse")?;
+        let fd = device.as_raw_fd();
+
+        let fuse_mount = self.fuse_mount(fuse, &fd.to_string());
+        log::debug!("mounting fuse on {} for {}", target, fuse_mount.src);
+        mount(
+            Some(fuse_mount.src.as_str()),
+            target.as_str(),
+            fuse_mount.fstype.as_deref(),
+            fuse_mount.flags,
+            fuse_mount.data.as_deref(),
+        )
+        .with_context(|| format!("cannot mount fuse on {}", fuse.mount_path))?;
+
+        let mut helper = std::process::Command::new(&fuse.helper[0]);
+        helper
+            .args(&fuse.helper[1..])
+            .arg(format!("/dev/fd/{}", fd));
+        unsafe {
+            helper.pre_exec(move || {
+                // A copy of the mount namespace of its own, or pivot_root
+                // would move its root into the jail too
+                unshare(CloneFlags::CLONE_NEWNS)?;
+                sync::inherit_on_exec(fd)
+            });
+        }
+        helper
+            .spawn()
+            .with_context(|| format!("cannot start fuse helper {}", fuse.helper[0]))?;
+        Ok(())
+    }
+
+    /// The mount for `fuse`, on the /dev/fuse fd numbered `fd`. user_id and
+    /// group_id are who may use it without allow_other.
+    fn fuse_mount(&self, fuse: &FuseMount, fd: &str) -> ResolvedMount {
+        let mut data = format!(
+            "fd={},rootmode=40000,user_id={},group_id={}",
+            fd,
+            self.config.uid.unwrap_or(0),
+            self.config.gid.unwrap_or(0)
+        );
+        for option in fuse.kernel_options() {
+            data.push(',');
+            data.push_str(option);
+        }
+        ResolvedMount {
+            src: fuse.helper[0].clone(),
+            dst: fuse.mount_path.clone(),
+            fstype: Some("fuse".to_string()),
+            flags: MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            data: Some(data),
+        }
+    }
+
+    /// The /proc, /sys, delegated cgroup and private tmp mounts, which go
+    /// onto the jail's root after the configured ones. `dst` is inside the
+    /// jail here.
+    fn pseudo_mounts(&self, cgroup: Option<&Path>) -> Vec<ResolvedMount> {
+        let pseudo_flags = MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC;
+        let mut mounts = Vec::new();
+        let mut add = |src: &str, dst: &str, fstype: Option<&str>, flags, data: Option<&str>| {
+            mounts.push(ResolvedMount {
+                src: src.to_string(),
+                dst: dst.to_string(),
+                fstype: fstype.map(str::to_string),
+                flags,
+                data: data.map(str::to_string),
+            })
+        };
+
+        if self.config.mount_proc {
+            let data: Vec<String> = self
+                .config
+                .proc_hidepid
+                .map(|hidepid| format!("hidepid={}", hidepid))
+                .into_iter()
+                .chain(self.config.proc_gid.map(|gid| format!("gid={}", gid)))
+                .collect();
+            let data = data.join(",");
+            let data = (!data.is_empty()).then_some(data.as_str());
+            add("proc", "/proc", Some("proc"), pseudo_flags, data);
+        }
+        if self.config.mount_sys {
+            let flags = pseudo_flags | MsFlags::MS_RDONLY;
+            add("sysfs", "/sys", Some("sysfs"), flags, None);
+        }
+        if let Some(cgroup) = cgroup {
+            // Just the delegated subtree, on top of a read-only /sys if any
+            let src = cgroup.to_string_lossy();
+            add(&src, "/sys/fs/cgroup", None, MsFlags::MS_BIND, None);
+        }
+        if self.config.private_tmp {
+            for dir in ["/tmp", "/var/tmp"] {
+                let flags = MsFlags::MS_NOSUID | MsFlags::MS_NODEV;
+                add("tmpfs", dir, Some("tmpfs"), flags, Some("mode=1777"));
+            }
+        }
+        mounts
+    }
+
+    /// Mount an empty, world-writable tmpfs on the host's /tmp and /var/tmp,
+    /// for a jail without its own root. A missing /var/tmp is left alone.
+    fn setup_private_tmp(&self) -> Result<()> {
+        let tmps = self
+            .pseudo_mounts(None)
+            .into_iter()
+            .filter(|mount| mount.fstype.as_deref() == Some("tmpfs"));
+        for tmp in tmps {
+            if !Path::new(&tmp.dst).is_dir() {
+                continue;
+            }
+            log::debug!("mounting private tmpfs on {}", tmp.dst);
+            mount(
+                Some(tmp.src.as_str()),
+                tmp.dst.as_str(),
+                tmp.fstype.as_deref(),
+                tmp.flags,
+                tmp.data.as_deref(),
+            )
+            .with_context(|| format!("cannot mount a private tmpfs on {}", tmp.dst))?;
+        }
+        Ok(())
+    }
+
+    /// Jail paths to hide: the OCI defaults for whichever of /proc and /sys
+    /// rsjail mounts (unless disabled), followed by mask_paths.
+    pub fn masked_paths(&self) -> Vec<String> {
+        let mut paths = Vec::new();
+
+        if self.config.default_proc_mask {
+            paths.extend(
+                DEFAULT_MASKED_PATHS
+                    .into_iter()
+                    .filter(|path| {
+                        (self.config.mount_proc && path.starts_with("/proc/"))
+                            || (self.config.mount_sys && path.starts_with("/sys/"))
+                    })
+                    .map(str::to_string),
+            );
+        }
+        paths.extend(self.config.mask_paths.iter().cloned());
+
+        paths
+    }
+
+    /// Hide `path` behind /dev/null, or an empty read-only tmpfs if it is a
+    /// directory. Paths that don't exist in the jail have nothing to hide.
+    fn mask_path(&self, root: &str, path: &str) -> Result<()> {
+        let target = format!("{}{}", root, path);
+        let Ok(metadata) = fs::symlink_metadata(&target) else {
+            return Ok(());
+        };
+
+        log::trace!("masking {}", path);
+        if metadata.is_symlink() {
+            // mount would follow it out of the chroot
+            log::warn!("not masking {}, it is a symlink", path);
+            return Ok(());
+        }
+        let mask = mask_mount(metadata.is_dir());
+        mount(
+            Some(mask.src.as_str()),
+            target.as_str(),
+            mask.fstype.as_deref(),
+            mask.flags,
+            None::<&str>,
+        )
+        .with_context(|| format!("cannot mask {}", path))?;
+
+        Ok(())
+    }
+
+    fn create_jail_directories(&self, chroot_dir: &str) -> Result<()> {
+        let base_path = Path::new(chroot_dir);
+        let create = |path: &Path| {
+            fs::create_dir_all(path).map_err(|e| {
+                if e.raw_os_error() == Some(libc::EROFS) {
+                    anyhow!(
+                        "cannot create {}: chroot_dir {} is on a read-only filesystem; create \
+                         {} in it beforehand, or use a chroot_dir on a writable one such as \
+                         a tmpfs",
+                        path.display(),
+                        chroot_dir,
+                        JAIL_DIRS.join(", ")
+                    )
+                } else {
+                    anyhow::Error::new(e).context(format!("cannot create {}", path.display()))
+                }
+            })
+        };
+        
+        // Create basic directory structure
+        if !base_path.exists() {
+            create(base_path)?;
+        }
+        
+        for dir in JAIL_DIRS {
+            let dir_path = base_path.join(dir);
+            if !dir_path.exists() {
+                create(&dir_path)?;
+            }
+        }
+        
+        Ok(())
+    }
+
+    fn setup_mount(&self, chroot_dir: &str, mount_config: &MountConfig) -> Result<()> {
+        let target = format!("{}{}", chroot_dir, mount_config.dst);
+
+        if mount_config.skipped_at(Path::new(&target)) {
+            log::debug!(
+                "{} already has entries, not mounting {} on it",
+                mount_config.dst,
+                mount_config.src
+            );
+            return Ok(());
+        }
+
+        if mount_config.create_target {
+            // Files and device nodes are bound onto an empty placeholder file
+            let src_is_file = fs::metadata(&mount_config.src)
+                .map(|metadata| !metadata.is_dir())
+                .unwrap_or(false);
+            create_mount_target(&target, src_is_file).map_err(|e| {
+                if e.raw_os_error() == Some(libc::EROFS) {
+                    anyhow!(
+                        "cannot create mount target {}, it is below a read-only mount; \
+                         create it in that mount's source and set create_target to false",
+                        mount_config.dst
+                    )
+                } else {
+                    anyhow::Error::new(e)
+                        .context(format!("cannot create mount target {}", mount_config.dst))
+                }
+            })?;
+        } else if !Path::new(&target).exists() {
+            bail!(
+                "mount target {} does not exist and create_target is off",
+                mount_config.dst
+            );
+        }
+
+        let mut flags = MsFlags::empty();
+        if mount_config.is_bind {
+            flags |= MsFlags::MS_BIND;
+        }
+        if !mount_config.rw {
+            flags |= MsFlags::MS_RDONLY;
+        }
+        log::debug!(
+            "mounting {} on {} (type {}, {:?})",
+            mount_config.src,
+            target,
+            mount_config.fstype.as_deref().unwrap_or("none"),
+            flags
+        );
+        
+        mount(
+            Some(mount_config.src.as_str()),
+            target.as_str(),
+            mount_config.fstype.as_deref(),
+            flags,
+            mount_config.data.as_deref(),
+        )
+        .map_err(|e| self.mount_error(e, mount_config))?;
+        if mount_config.is_bind && !mount_config.rw {
+            // A bind ignores MS_RDONLY, only remounting it applies that
+            remount_read_only(&target)?;
+        }
+
+        Ok(())
+    }
+
+    /// What went wrong mounting `mount`. In a user namespace EPERM is the
+    /// kernel refusing a filesystem type it doesn't allow there.
+    fn mount_error(&self, errno: Errno, mount: &MountConfig) -> anyhow::Error {
+        let error = anyhow::Error::new(errno);
+        if errno == Errno::EPERM && self.config.clone_newuser && !mount.is_bind {
+            return error.context(format!(
+                "cannot mount {} on {}: a user namespace only allows bind mounts and {}",
+                mount.src,
+                mount.dst,
+                USERNS_FSTYPES.join(", ")
+            ));
+        }
+        error.context(format!("cannot mount {} on {}", mount.src, mount.dst))
+    }
+
+    /// Give the jail's writable directories to the uid/gid the program runs
+    /// as. Root of the new user namespace may, as long as their current
+    /// owners are mapped too.
+    fn chown_jail_dirs(&self) -> Result<()> {
+        let uid = self.config.uid.unwrap_or(0);
+        let gid = self.config.gid.unwrap_or(0);
+
+        let mut dirs = vec!["/tmp".to_string(), "/var/tmp".to_string()];
+        // Never the root itself, the default HOME
+        dirs.extend(self.environment().remove("HOME").filter(|home| home != "/"));
+        let volumes = &self.config.volumes;
+        dirs.extend(volumes.iter().map(|volume| volume.mount_path.clone()));
+
+        for dir in dirs {
+            if !Path::new(&dir).is_dir() {
+                continue;
+            }
+            log::debug!("chown {} to {}:{}", dir, uid, gid);
+            std::os::unix::fs::chown(&dir, Some(uid), Some(gid)).map_err(|e| {
+                if e.raw_os_error() == Some(libc::EPERM) {
+                    anyhow!("cannot chown {}, its owner isn't mapped into the jail", dir)
+                } else {
+                    anyhow::Error::new(e)
+                        .context(format!("cannot chown {} to {}:{}", dir, uid, gid))
+                }
+            })?;
+        }
+        Ok(())
+    }
+
+    fn setup_user_permissions(&self) -> Result<()> {
+        // setuid to an unmapped id fails with a bare EINVAL, so say why first
+        if self.config.clone_newuser {
+            self.config.check_id_mapping()?;
+        }
+
+        // For the caps step to raise, which may come after
+        if !self.config.ambient_caps.is_empty() {
+            caps::keep_across_setuid()?;
+        }
+
+        // Before giving up root, which setgroups needs
+        if self.config.switches_groups() {
+            log::debug!("setgroups {:?}", self.config.groups);
+            let groups: Vec<Gid> = self
+                .config
+                .groups
+                .iter()
+                .map(|&gid| Gid::from_raw(gid))
+                .collect();
+            setgroups(&groups).context("cannot set the supplementary groups")?;
+        }
+
+        if let Some(gid) = self.config.gid {
+            log::debug!("setgid {}", gid);
+            setgid(Gid::from_raw(gid))?;
+        }
+        
+        if let Some(uid) = self.config.uid {
+            log::debug!("setuid {}", uid);
+            setuid(Uid::from_raw(uid))?;
+        }
+        
+        Ok(())
+    }
+
+    fn raise_ambient_caps(&self) -> Result<()> {
+        let ambient_caps = self
+            .config
+            .ambient_caps
+            .iter()
+            .map(|name| caps::from_name(name))
+            .collect::<Result<Vec<_>>>()?;
+        if !ambient_caps.is_empty() {
+            log::debug!("raising ambient capabilities {:?}", self.config.ambient_caps);
+            caps::raise_ambient(&ambient_caps)?;
+        }
+        Ok(())
+    }
+
+    fn set_no_new_privs(&self) -> Result<()> {
+        if self.config.no_new_privs {
+            log::debug!("setting no_new_privs");
+            if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+                return Err(std::io::Error::last_os_error()).context("cannot set no_new_privs");
+            }
+        }
+        Ok(())
+    }
+
+    fn setup_resource_limits(&self) -> Result<()> {
+        for (resource, limit) in [
+            (Resource::RLIMIT_AS, self.config.rlimit_as),
+            (Resource::RLIMIT_CPU, self.config.effective_rlimit_cpu()),
+            (Resource::RLIMIT_NOFILE, self.config.rlimit_nofile),
+            (Resource::RLIMIT_MEMLOCK, self.config.rlimit_memlock),
+            (Resource::RLIMIT_DATA, self.config.rlimit_data),
+        ] {
+            if let Some(limit) = limit {
+                set_resource_limit(resource, limit)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn exec_target_program(&self, setup: &ChildSetup, guard_at_exec: bool) -> Result<Infallible> {
+        let wrapper = self.wrapper();
+        let path = match &wrapper {
+            Some(wrapper) => &wrapper[0],
+            None => &self.config.exec_bin,
+        };
+        let program = exec_string(path.clone(), || "the program path".to_string())?;
+
+        let argv = self.exec_argv();
+        let mut environment = self.environment();
+        if let Some(fd) = setup.notify_fd {
+            // The one fd of ours the program is meant to inherit
+            sync::inherit_on_exec(fd).context("cannot pass NOTIFY_FD")?;
+            environment.insert("NOTIFY_FD".to_string(), fd.to_string());
+        }
+        log::info!("exec {} {:?}", self.config.exec_bin, argv);
+        log::trace!("environment {:?}", environment);
+
+        let (args, env) = exec_strings(argv, environment)?;
+
+        check_executable(Path::new(path))?;
+        let exec = || {
+            if guard_at_exec && self.wants_seccomp(setup) {
+                timings::time(setup.timings_fd, "seccomp", || self.install_seccomp(setup))?;
+            }
+            timings::exec_next(setup.timings_fd);
+            if self.config.exec_via_fd {
+                let file = open_executable(Path::new(path))?;
+                return Ok(fexecve(&file, &args, &env)?);
+            }
+            Ok(execve(&program, &args, &env)?)
+        };
+        if self.config.init && self.config.init_helper.is_none() {
+            return init::run(exec);
+        }
+        exec()
+    }
+
+    /// Install the seccomp_policy filter, then the syscall_limits one, whose
+    /// listener goes to the parent.
+    fn install_seccomp(&self, setup: &ChildSetup) -> Result<()> {
+        self.install_seccomp_policy()?;
+        if let Some(socket) = setup.listener_fd {
+            self.install_syscall_guard(socket)?;
+        }
+        Ok(())
+    }
+
+    fn wants_seccomp(&self, setup: &ChildSetup) -> bool {
+        self.config.seccomp_policy.is_some() || setup.listener_fd.is_some()
+    }
+
+    fn install_seccomp_policy(&self) -> Result<()> {
+        if let Some(policy) = &self.config.seccomp_policy {
+            log::debug!("installing seccomp policy {:?}", policy);
+            seccomp::install_policy(policy)?;
+        }
+        Ok(())
+    }
+
+    /// Install the syscall_limits filter and hand its listener to the
+    /// parent, which answers for every syscall it counts.
+    fn install_syscall_guard(&self, socket: RawFd) -> Result<()> {
+        let syscalls: Vec<_> = self
+            .config
+            .syscall_limits
+            .keys()
+            .filter_map(|name| seccomp::syscall_number(name))
+            .collect();
+        log::debug!("counting syscalls {:?}", self.config.syscall_limits);
+        let listener = seccomp::notify_listener(&syscalls)?;
+        seccomp::send_listener(socket, &listener)
+            .context("cannot pass the seccomp listener to rsjail")?;
+        Ok(())
+    }
+
+    /// The argument vector the jailed program is started with.
+    pub fn exec_argv(&self) -> Vec<String> {
+        if let Some(mut argv) = self.wrapper() {
+            argv.push(self.config.exec_bin.clone());
+            argv.extend(self.config.exec_args.iter().skip(1).cloned());
+            return argv;
+        }
+
+        let mut argv = self.config.exec_args.clone();
+
+        if self.config.login_shell {
+            // Like login(1): argv[0] is the shell's basename prefixed with '-'
+            let arg0 = argv.first().unwrap_or(&self.config.exec_bin);
+            let name = Path::new(arg0)
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| arg0.clone());
+            let login_arg0 = format!("-{}", name);
+            match argv.first_mut() {
+                Some(first) => *first = login_arg0,
+                None => argv.push(login_arg0),
+            }
+        }
+
+        argv
+    }
+
+    /// The environment the jailed program is started with: the built-in
+    /// defaults, then the login user's settings, then forwarded terminal
+    /// settings, then the explicit `env`.
+    pub fn environment(&self) -> BTreeMap<String, String> {
+        let mut env = BTreeMap::from([
+            ("PATH".to_string(), self.exec_path().to_string()),
+            ("HOME".to_string(), "/".to_string()),
+            ("USER".to_string(), "jail".to_string()),
+        ]);
+
+        if self.config.login_shell {
+            env.insert("SHELL".to_string(), self.config.exec_bin.clone());
+            env.extend(self.login_user_environment());
+        }
+
+        let has_tty = std::io::stdin().is_terminal() || std::io::stdout().is_terminal();
+        if self.config.forward_term.unwrap_or(has_tty) {
+            env.extend(terminal_environment());
+        }
+
+        match &self.config.timezone {
+            Some(timezone) => {
+                env.insert("TZ".to_string(), timezone.clone());
+            }
+            None if self.config.forward_tz => {
+                if let Ok(timezone) = std::env::var("TZ") {
+                    env.insert("TZ".to_string(), timezone);
+                }
+            }
+            None => {}
+        }
+
+        env.extend(self.config.env.clone());
+        if let Some(preload) = &self.config.preload {
+            let value = match env.get("LD_PRELOAD") {
+                Some(existing) => format!("{}:{}", preload, existing),
+                None => preload.clone(),
+            };
+            env.insert("LD_PRELOAD".to_string(), value);
+        }
+        env
+    }
+
+    /// exec_path, or the directories of STANDARD_PATH the jail has, all of
+    /// them if it seems to have none. Worked out once, in the parent while
+    /// host paths still mean what they say, like `interpreter`.
+    fn exec_path(&self) -> &str {
+        self.exec_path.get_or_init(|| {
+            if let Some(path) = &self.config.exec_path {
+                return path.clone();
+            }
+            let found: Vec<&str> = STANDARD_PATH
+                .into_iter()
+                .filter(|dir| self.jail_has_dir(dir))
+                .collect();
+            if found.is_empty() {
+                STANDARD_PATH.join(":")
+            } else {
+                found.join(":")
+            }
+        })
+    }
+
+    /// Whether `jail_path` is a directory in the jail: through a mount
+    /// covering it or below it, or in chroot_dir. Unlike `host_path`, never
+    /// falls back to the host's own path with a chroot_dir.
+    fn jail_has_dir(&self, jail_path: &str) -> bool {
+        let base_path = self.base_path(jail_path);
+        let path = Path::new(&base_path);
+        let below = |mount: &MountConfig| {
+            let dst = Path::new(&mount.dst);
+            dst != path && dst.starts_with(path)
+        };
+        if self.config.mounts.iter().any(below) {
+            return true;
+        }
+        if let Some(mount) = self.mount_covering(path)
+            && let Ok(rest) = path.strip_prefix(&mount.dst)
+        {
+            return Path::new(&mount.src).join(rest).is_dir();
+        }
+        match &self.config.chroot_dir {
+            Some(chroot_dir) if self.config.fs_isolation() != FsIsolation::None => {
+                let rest = path.strip_prefix("/").unwrap_or(path);
+                Path::new(chroot_dir).join(rest).is_dir()
+            }
+            _ => Path::new(jail_path).is_dir(),
+        }
+    }
+
+    /// HOME, USER and SHELL of the user the jailed program runs as, looked
+    /// up in the passwd database visible at the time of the call.
+    fn login_user_environment(&self) -> Vec<(String, String)> {
+        let uid = self.config.uid.map(Uid::from_raw).unwrap_or_else(getuid);
+
+        match User::from_uid(uid) {
+            Ok(Some(user)) => vec![
+                ("HOME".to_string(), user.dir.to_string_lossy().into_owned()),
+                ("USER".to_string(), user.name),
+                (
+                    "SHELL".to_string(),
+                    user.shell.to_string_lossy().into_owned(),
+                ),
+            ],
+            _ => {
+                log::warn!("no passwd entry for uid {}, keeping default HOME/USER", uid);
+                Vec::new()
+            }
+        }
+    }
+
+    fn timeout_signal(&self) -> Result<Signal> {
+        match &self.config.timeout_signal {
+            Some(name) => parse_signal(name),
+            None => Ok(Signal::SIGKILL),
+        }
+    }
+
+    /// With setup_timeout, wait for the child to reach exec. Returns false if
+    /// it didn't in time, in which case it has been sent SIGKILL.
+    fn await_exec(&self, launched: &Launched) -> Result<bool> {
+        let Some(secs) = self.config.setup_timeout else {
+            return Ok(true);
+        };
+        let timeout = (launched.started + Duration::from_secs(secs))
+            .saturating_duration_since(Instant::now());
+        if sync::wait_readable(&launched.exec_reached, Some(timeout))? {
+            return Ok(true);
+        }
+
+        log::warn!("child {} hit setup_timeout, killing it", launched.child);
+        launched.signal(Signal::SIGKILL)?;
+        Ok(false)
+    }
+
+    /// With `notify`, wait for the program to report READY, enforcing
+    /// notify_timeout. Returns how long it took to get ready, None if it
+    /// closed NOTIFY_FD or exited first, and whether it timed out.
+    fn await_ready(&self, launched: &Launched) -> Result<(Option<Duration>, bool)> {
+        let Some(notify) = &launched.notify else {
+            return Ok((None, false));
+        };
+        let deadline = self
+            .config
+            .notify_timeout
+            .map(|secs| launched.started + Duration::from_secs(secs));
+
+        let mut message = Vec::new();
+        loop {
+            let timeout = deadline.map(|limit| limit.saturating_duration_since(Instant::now()));
+            if !sync::wait_readable(notify, timeout)? {
+                let timeout_signal = self.timeout_signal()?;
+                log::info!("no READY within notify_timeout, sending {}", timeout_signal);
+                launched.signal(timeout_signal)?;
+                return Ok((None, true));
+            }
+
+            let mut buf = [0u8; 256];
+            let read = match nix::unistd::read(notify, &mut buf) {
+                Ok(read) => read,
+                Err(nix::errno::Errno::EINTR) => continue,
+                Err(e) => return Err(e).context("cannot read NOTIFY_FD"),
+            };
+            if read == 0 {
+                log::warn!("child {} closed NOTIFY_FD before READY", launched.child);
+                return Ok((None, false));
+            }
+            message.extend_from_slice(&buf[..read]);
+
+            if message
+                .split(|&byte| byte == b'\n')
+                .any(|line| line == b"READY" || line == b"READY=1")
+            {
+                let ready_after = launched.started.elapsed();
+                log::info!("child {} ready after {:?}", launched.child, ready_after);
+                return Ok((Some(ready_after), false));
+            }
+        }
+    }
+
+    /// Block until the child exits, enforcing time_limit along the way.
+    /// Returns its wait status, its rusage and whether it timed out, which
+    /// it already has if `timed_out` is set.
+    fn reap(
+        &self,
+        launched: &Launched,
+        mut timed_out: bool,
+    ) -> Result<(WaitStatus, libc::rusage, bool)> {
+        let timeout_signal = self.timeout_signal()?;
+
+        let mut deadline = if timed_out {
+            self.cleanup_deadline(timeout_signal)
+        } else {
+            self.config
+                .effective_time_limit()
+                .map(|secs| launched.started + Duration::from_secs(secs))
+        };
+
+        loop {
+            if let Some(reaped) = launched.wait_until(deadline)? {
+                return Ok((reaped.0, reaped.1, timed_out));
+            }
+            if timed_out {
+                launched.kill_after_cleanup_timeout()?;
+                deadline = None;
+                continue;
+            }
+            log::info!("time limit reached, sending {}", timeout_signal);
+            launched.signal(timeout_signal)?;
+            timed_out = true;
+            deadline = self.cleanup_deadline(timeout_signal);
+        }
+    }
+
+    /// How long to wait for the child to go after `signal`, if
+    /// cleanup_timeout says so and SIGKILL is still left to send.
+    fn cleanup_deadline(&self, signal: Signal) -> Option<Instant> {
+        let secs = self
+            .config
+            .cleanup_timeout
+            .filter(|_| signal != Signal::SIGKILL)?;
+        Some(Instant::now() + Duration::from_secs(secs))
+    }
+
+    /// Async counterpart of `reap`: the pidfd becomes readable once the child
+    /// has exited, after which it is reaped without blocking.
+    #[cfg(feature = "tokio")]
+    async fn reap_pidfd(
+        &self,
+        launched: &Launched,
+        pidfd: &OwnedFd,
+    ) -> Result<(WaitStatus, libc::rusage, bool)> {
+        let timeout_signal = self.timeout_signal()?;
+        let readable = tokio::io::unix::AsyncFd::new(pidfd.as_raw_fd())?;
+        let mut timed_out = false;
+
+        if let Some(secs) = self.config.effective_time_limit() {
+            let deadline =
+                tokio::time::Instant::from_std(launched.started + Duration::from_secs(secs));
+            if tokio::time::timeout_at(deadline, readable.readable())
+                .await
+                .is_err()
+            {
+                log::info!("time limit reached, sending {}", timeout_signal);
+                launched.signal(timeout_signal)?;
+                timed_out = true;
+            }
+        }
+        if let Some(deadline) = self.cleanup_deadline(timeout_signal).filter(|_| timed_out)
+            && tokio::time::timeout_at(deadline.into(), readable.readable())
+                .await
+                .is_err()
+        {
+            launched.kill_after_cleanup_timeout()?;
+        }
+        readable.readable().await?.retain_ready();
+
+        let (status, usage) = pidfd::reap(pidfd)?;
+        Ok((status, usage, timed_out))
+    }
+
+    /// Turn a reaped child's status into a `JailResult`, releasing whatever
+    /// the parent kept around for it.
+    fn finish(
+        &self,
+        mut launched: Launched,
+        status: WaitStatus,
+        usage: libc::rusage,
+        timed_out: bool,
+    ) -> Result<JailResult> {
+        let started = launched.started;
+
+        let mut result = JailResult {
+            pid: launched.child,
+            namespaces: launched.namespaces,
+            exit_code: None,
+            signal: None,
+            wall_time: started.elapsed(),
+            user_time: timeval_duration(usage.ru_utime),
+            sys_time: timeval_duration(usage.ru_stime),
+            max_rss_kb: usage.ru_maxrss as u64,
+            timed_out,
+            setup_timed_out: false,
+            cpu_limit_hit: false,
+            setup_failed: launched.setup_failed(),
+            mapped_exit_code: 0,
+            ready_after: None,
+            memory_events: launched.cgroup.as_ref().and_then(JailCgroup::memory_events),
+            memory_peaks: launched.memory_sampler.take().map(MemorySampler::finish),
+            leftover_processes: 0,
+            cleanup_timed_out: launched.cleanup_timed_out.get(),
+            syscall_limit_hit: launched.syscall_guard.take().and_then(SyscallGuard::finish),
+            setup_timings: launched.setup_timer.take().map(SetupTimer::finish),
+        };
+
+        let outcome = match status {
+            WaitStatus::Exited(pid, code) => {
+                result.exit_code = Some(code);
+                let outcome = format!("child {} exited with code {}", pid, code);
+                log::debug!("{}", outcome);
+                outcome
+            }
+            WaitStatus::Signaled(pid, signal, _) => {
+                result.signal = Some(signal);
+                let outcome = format!("child {} killed by signal {}", pid, signal);
+                log::debug!("{}", outcome);
+                outcome
+            }
+            status => {
+                let outcome = format!("child {} reaped with status {:?}", launched.child, status);
+                log::warn!("{}", outcome);
+                outcome
+            }
+        };
+
+        // SIGXCPU comes at the soft limit, SIGKILL at the hard one. rusage
+        // splits the precise runtime by ticks and may come out a bit short.
+        let cpu_time = result.user_time + result.sys_time + Duration::from_millis(50);
+        result.cpu_limit_hit = !result.timed_out
+            && self
+                .config
+                .effective_rlimit_cpu()
+                .is_some_and(|limit| match result.signal {
+                    Some(Signal::SIGXCPU) => true,
+                    Some(Signal::SIGKILL) => cpu_time >= Duration::from_secs(limit.soft()),
+                    _ => false,
+                });
+
+        result.mapped_exit_code = self.config.exit_code_map.code(
+            result.exit_code,
+            result.signal,
+            result.timed_out,
+            result.setup_failed,
+        );
+
+        if let Some(relay) = launched.output_relay.take() {
+            relay.finish();
+        }
+        self.save_volumes(launched.scratch.path())?;
+        self.pause_for_inspection(&outcome, Some(&launched));
+        drop(launched);
+        Ok(result)
+    }
+
+    /// Print where to look and block until Enter, if freeze_on_exit is on
+    /// and someone is there to press it.
+    fn pause_for_inspection(&self, outcome: &str, launched: Option<&Launched>) {
+        if !self.freeze_on_exit || !std::io::stdin().is_terminal() {
+            return;
+        }
+
+        eprintln!("rsjail: {}, frozen for inspection", outcome);
+        if let Some(chroot_dir) = &self.config.chroot_dir {
+            eprintln!("  chroot:      {}", chroot_dir);
+        }
+        if let Some(launched) = launched {
+            eprintln!("  namespaces:  {:?}", namespace_names(launched.namespaces));
+            eprintln!("  scratch:     {}", launched.scratch.path().display());
+        }
+        // Cloned namespaces are gone with the child, those rsjail unshared
+        // itself outlive it
+        if self.config.unshare_in_parent {
+            eprintln!("  enter with:  nsenter -t {} -a", std::process::id());
+        }
+        eprint!("Press Enter to clean up...");
+
+        let mut line = String::new();
+        let _ = std::io::stdin().read_line(&mut line);
+    }
+}
+
+/// A jail started by `Jail::spawn`. Dropping it before `wait` kills the
+/// child.
+pub struct JailHandle<'a> {
+    jail: &'a Jail,
+    /// Only taken by `wait` and on drop
+    launched: Option<Launched>,
+    /// Set once `try_wait` has reaped the child
+    reaped: Option<(WaitStatus, libc::rusage)>,
+    /// Whether `try_wait` already enforced time_limit
+    timed_out: bool,
+}
+
+impl JailHandle<'_> {
+    /// The child's pid as seen by the host.
+    pub fn pid(&self) -> Pid {
+        self.launched().child
+    }
+
+    fn launched(&self) -> &Launched {
+        self.launched.as_ref().expect("taken only by wait and drop")
+    }
+
+    /// The child's status once it has exited, None while it still runs.
+    /// Sends timeout_signal once time_limit has passed, as `wait` would.
+    pub fn try_wait(&mut self) -> Result<Option<WaitStatus>> {
+        if self.reaped.is_none() {
+            let launched = self.launched.as_ref().expect("taken only by wait and drop");
+            if let Some(secs) = self.jail.config.effective_time_limit()
+                && !self.timed_out
+                && launched.started.elapsed() >= Duration::from_secs(secs)
+            {
+                let timeout_signal = self.jail.timeout_signal()?;
+                log::info!("time limit reached, sending {}", timeout_signal);
+                launched.signal(timeout_signal)?;
+                self.timed_out = true;
+            }
+            self.reaped = launched.wait_until(Some(Instant::now()))?;
+        }
+        Ok(self.reaped.map(|(status, _)| status))
+    }
+
+    /// The jail's cgroup subtree, if it has one of its own: with memory_high
+    /// or cgroup_delegate.
+    pub fn cgroup_path(&self) -> Option<&Path> {
+        self.launched().cgroup.as_ref().map(JailCgroup::path)
+    }
+
+    /// Stop every process of the jail where it is, through its cgroup's
+    /// freezer, until `thaw`. Needs a cgroup of its own, see `cgroup_path`.
+    pub fn freeze(&self) -> Result<()> {
+        self.jail_cgroup()?.freeze(true)
+    }
+
+    /// Let the processes `freeze` stopped run again.
+    pub fn thaw(&self) -> Result<()> {
+        self.jail_cgroup()?.freeze(false)
+    }
+
+    fn jail_cgroup(&self) -> Result<&JailCgroup> {
+        self.launched().cgroup.as_ref().with_context(|| {
+            format!(
+                "child {} has no cgroup of its own, which memory_high or cgroup_delegate give it",
+                self.pid()
+            )
+        })
+    }
+
+    /// Send `signal` to the child, through its pidfd where there is one.
+    pub fn kill(&self, signal: Signal) -> Result<()> {
+        if self.reaped.is_some() {
+            bail!("child {} has already been reaped", self.pid());
+        }
+        self.launched().signal(signal)
+    }
+
+    /// Wait for the child to exit, enforcing the config's timeouts, then
+    /// tear the jail down.
+    pub fn wait(mut self) -> Result<JailResult> {
+        let jail = self.jail;
+        let launched = self.launched.take().expect("taken only by wait and drop");
+        let setup_timed_out = !jail.await_exec(&launched)?;
+        let (ready_after, ready_timed_out) = if setup_timed_out {
+            (None, false)
+        } else {
+            jail.await_ready(&launched)?
+        };
+        let (status, usage, timed_out) = match self.reaped.take() {
+            Some((status, usage)) => (status, usage, self.timed_out),
+            None => jail.reap(
+                &launched,
+                self.timed_out || setup_timed_out || ready_timed_out,
+            )?,
+        };
+        log::debug!("reaped child {}: {:?}", launched.child, status);
+        let leftover_processes = kill_leftovers(&launched, jail.config.cleanup_timeout);
+        let mut result = jail.finish(launched, status, usage, timed_out)?;
+        result.ready_after = ready_after;
+        result.setup_timed_out = setup_timed_out;
+        result.leftover_processes = leftover_processes;
+        Ok(result)
+    }
+}
+
+impl Drop for JailHandle<'_> {
+    fn drop(&mut self) {
+        let Some(launched) = self.launched.take() else {
+            return;
+        };
+        if self.reaped.is_none() {
+            log::debug!("handle dropped, killing child {}", launched.child);
+            let _ = launched.signal(Signal::SIGKILL);
+            let _ = launched.wait_until(None);
+        }
+        kill_leftovers(&launched, self.jail.config.cleanup_timeout);
+    }
+}
+
+/// What `Jail::planned_file` found.
+#[derive(Debug, PartialEq, Eq)]
+enum PlannedFile {
+    Host(PathBuf),
+    Missing,
+    Unknown,
+}
+
+/// Everything the child's setup needs that the parent has to work out first.
+struct ChildSetup {
+    mounts: Vec<MountConfig>,
+    /// Our ids on the host; once an unmapped user namespace exists they read
+    /// back as the overflow id
+    host_uid: Uid,
+    host_gid: Gid,
+    /// Write end of the notify pipe, filled in when the child is spawned
+    notify_fd: Option<RawFd>,
+    /// Our end of the socket the seccomp listener goes to the parent over,
+    /// likewise
+    listener_fd: Option<RawFd>,
+    /// Write end of the timings pipe with `Jail::record_timings`, likewise
+    timings_fd: Option<RawFd>,
+    /// The delegated cgroup, also only known once the child is spawned
+    cgroup: Option<PathBuf>,
+    /// The child unshares the cgroup namespace itself: by then the parent
+    /// has moved it into the jail cgroup, which becomes the namespace root
+    cgroup_namespace: bool,
+}
+
+/// A forked child together with what the parent has to keep alive until the
+/// child is reaped.
+struct Launched {
+    child: Pid,
+    /// Used instead of the pid wherever the kernel supports it, so a signal
+    /// can never reach an unrelated process that inherited a recycled pid
+    pidfd: Option<OwnedFd>,
+    started: Instant,
+    namespaces: CloneFlags,
+    /// The net namespace bound as netns_name
+    _netns: Option<NetnsBinding>,
+    /// Read end of the notify pipe
+    notify: Option<OwnedFd>,
+    /// Hits EOF once the child has exec'd or exited, after a byte if its
+    /// setup failed
+    exec_reached: OwnedFd,
+    /// The child's mounts where they are in our mount namespace, which the
+    /// volumes bound from scratch are among, so first to go
+    _mounts: Option<MountGuard>,
+    /// Declared before scratch, which it is mounted in, to go first
+    _disk_quota: Option<QuotaImage>,
+    scratch: ScratchDir,
+    cgroup: Option<JailCgroup>,
+    memory_sampler: Option<MemorySampler>,
+    output_relay: Option<OutputRelay>,
+    syscall_guard: Option<SyscallGuard>,
+    setup_timer: Option<SetupTimer>,
+    /// Our children from before the spawn, when rsjail is child subreaper
+    /// for the run; any others once the child is gone were left behind
+    adopted_from: Option<BTreeSet<Pid>>,
+    /// Set once cleanup_timeout has run out, see JailResult
+    cleanup_timed_out: Cell<bool>,
+    /// Closed only after the child has been reaped
+    #[cfg(feature = "pam")]
+    _pam_session: Option<pam::Session>,
+    _parent_alive: OwnedFd,
+    /// With thread_mode, lets the setup thread exit once closed
+    _setup_thread: Option<OwnedFd>,
+}
+
+impl Launched {
+    /// Whether the child reported a failed setup on exec_reached. Only
+    /// meaningful once it has been reaped.
+    fn setup_failed(&self) -> bool {
+        // Someone else's fork may hold a copy of the write end, so don't
+        // wait for EOF
+        let readable = sync::wait_readable(&self.exec_reached, Some(Duration::ZERO));
+        matches!(readable, Ok(true)) && matches!(sync::wait_released(&self.exec_reached), Ok(true))
+    }
+
+    /// SIGKILL the child, PID 1 of its namespace if it has one, which the
+    /// kernel then kills everything else in.
+    fn kill_after_cleanup_timeout(&self) -> Result<()> {
+        log::warn!(
+            "child {} still running after cleanup_timeout, killing it",
+            self.child
+        );
+        self.cleanup_timed_out.set(true);
+        self.signal(Signal::SIGKILL)
+    }
+
+    fn signal(&self, signal: Signal) -> Result<()> {
+        match &self.pidfd {
+            Some(pidfd) => pidfd::send_signal(pidfd, signal)?,
+            None => kill(self.child, signal)?,
         }
         Ok(())
     }
+
+    /// Reap the child if it exits before `deadline`, or return None once the
+    /// deadline has passed. Without a deadline this blocks until it exits.
+    fn wait_until(&self, deadline: Option<Instant>) -> Result<Option<(WaitStatus, libc::rusage)>> {
+        if let Some(pidfd) = &self.pidfd {
+            let timeout = deadline.map(|limit| limit.saturating_duration_since(Instant::now()));
+            if !pidfd::wait_exit(pidfd, timeout)? {
+                return Ok(None);
+            }
+            return Ok(Some(pidfd::reap(pidfd)?));
+        }
+
+        // No watchdog thread here: once the parent has unshared CLONE_NEWPID
+        // the kernel refuses to create threads in it, so poll instead.
+        // wait4 rather than waitpid so the child's rusage comes with its status
+        let mut status = 0;
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        loop {
+            let flags = if deadline.is_some() { libc::WNOHANG } else { 0 };
+            let reaped =
+                unsafe { libc::wait4(self.child.as_raw(), &mut status, flags, &mut usage) };
+            if reaped == self.child.as_raw() {
+                return Ok(Some((WaitStatus::from_raw(self.child, status)?, usage)));
+            }
+            if reaped < 0 {
+                match nix::Error::last() {
+                    nix::Error::EINTR => continue,
+                    e => return Err(e.into()),
+                }
+            }
+
+            if let Some(limit) = deadline {
+                let now = Instant::now();
+                if now >= limit {
+                    return Ok(None);
+                }
+                thread::sleep((limit - now).min(TIMEOUT_POLL_INTERVAL));
+            }
+        }
+    }
+}
+
+/// Which of `flags` this process is allowed to create, found by unsharing
+/// each of them in a throwaway child.
+fn probe_namespaces(flags: CloneFlags) -> CloneFlags {
+    let mut supported = CloneFlags::empty();
+
+    for (flag, name) in NAMESPACES {
+        if !flags.contains(flag) {
+            continue;
+        }
+        let probe = match unsafe { fork() } {
+            Ok(ForkResult::Child) => {
+                std::process::exit(unshare(flag).map_or_else(|e| e as i32, |()| 0))
+            }
+            Ok(ForkResult::Parent { child }) => match nix::sys::wait::waitpid(child, None) {
+                Ok(WaitStatus::Exited(_, 0)) => Ok(()),
+                Ok(WaitStatus::Exited(_, errno)) => Err(nix::Error::from_raw(errno)),
+                Ok(_) => Err(nix::Error::ECHILD),
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        match probe {
+            Ok(()) => supported |= flag,
+            Err(e) => log::warn!("skipping unsupported {} namespace: {}", name, e),
+        }
+    }
+
+    supported
+}
+
+/// The contents of a uid_map or gid_map file.
+fn id_map_lines(maps: &[IdMap]) -> String {
+    maps.iter()
+        .map(|map| format!("{} {} {}\n", map.inside, map.outside, map.count))
+        .collect()
+}
+
+fn id_map_error(file: &'static str) -> impl FnOnce(std::io::Error) -> anyhow::Error {
+    move |e| {
+        anyhow::Error::new(e).context(format!(
+            "cannot write {}; from inside the namespace only our own id can be mapped",
+            file
+        ))
+    }
+}
+
+/// Install nftables rules in the current network namespace that reject new
+/// connections while `limit` are already tracked.
+fn limit_connections(limit: u32) -> Result<()> {
+    let ruleset = format!(
+        "table inet rsjail {{\n\
+         \tchain input {{ type filter hook input priority 0; ct state new ct count over {limit} reject; }}\n\
+         \tchain output {{ type filter hook output priority 0; ct state new ct count over {limit} reject; }}\n\
+         }}\n"
+    );
+    log::debug!("limiting connections to {} with nft", limit);
+
+    let mut nft = match std::process::Command::new("nft")
+        .args(["-f", "-"])
+        .stdin(std::process::Stdio::piped())
+        .spawn()
+    {
+        Ok(nft) => nft,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            bail!("max_connections needs the nft command from nftables, which is not installed")
+        }
+        Err(e) => return Err(e).context("cannot run nft"),
+    };
+    nft.stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(ruleset.as_bytes())?;
+    let status = nft.wait()?;
+    if !status.success() {
+        bail!("nft rejected the max_connections rules ({})", status);
+    }
+
+    Ok(())
+}
+
+/// Bring up lo, which a new net namespace starts with down.
+fn bring_up_loopback() -> Result<()> {
+    let socket = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if socket < 0 {
+        return Err(std::io::Error::last_os_error()).context("cannot open a socket to set up lo");
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(socket) };
+    let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (name, byte) in request.ifr_name.iter_mut().zip(b"lo") {
+        *name = *byte as libc::c_char;
+    }
+    let up = unsafe {
+        libc::ioctl(socket.as_raw_fd(), libc::SIOCGIFFLAGS, &mut request) == 0 && {
+            request.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short;
+            libc::ioctl(socket.as_raw_fd(), libc::SIOCSIFFLAGS, &request) == 0
+        }
+    };
+    if !up {
+        return Err(std::io::Error::last_os_error()).context("cannot bring up lo");
+    }
+    Ok(())
+}
+
+/// What hides a masked path: an empty read-only tmpfs for a directory,
+/// /dev/null for anything else. `dst` is left for the caller.
+fn mask_mount(is_dir: bool) -> ResolvedMount {
+    let (src, fstype, flags) = if is_dir {
+        ("tmpfs", Some("tmpfs".to_string()), MsFlags::MS_RDONLY)
+    } else {
+        ("/dev/null", None, MsFlags::MS_BIND)
+    };
+    ResolvedMount {
+        src: src.to_string(),
+        dst: String::new(),
+        fstype,
+        flags,
+        data: None,
+    }
+}
+
+/// Create the directory a mount goes on, or the empty file for a `file`
+/// source, along with its parents. Existing targets are left alone.
+fn create_mount_target(target: &str, file: bool) -> std::io::Result<()> {
+    let target = Path::new(target);
+    if target.exists() {
+        return Ok(());
+    }
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if file {
+        OpenOptions::new().write(true).create_new(true).open(target)?;
+    } else {
+        fs::create_dir(target)?;
+    }
+    Ok(())
+}
+
+/// Make the bind mount at `target` read-only.
+pub fn remount_read_only(target: &str) -> Result<()> {
+    // Inside a user namespace the flags the mount came with are locked, so
+    // they have to be kept or the remount fails with EPERM
+    let current = statvfs(target)?.flags();
+    let mut flags = MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY;
+    for (kept, flag) in [
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ] {
+        if current.contains(kept) {
+            flags |= flag;
+        }
+    }
+    mount(None::<&str>, target, None::<&str>, flags, None::<&str>)
+        .with_context(|| format!("cannot make {} read-only", target))?;
+    Ok(())
+}
+
+/// Whether the host runs `info`'s architecture itself, like rsjail.
+fn is_native(info: &elf::ElfInfo) -> bool {
+    elf::parse(Path::new("/proc/self/exe"))
+        .is_ok_and(|host| (host.machine, host.is_64bit) == (info.machine, info.is_64bit))
+}
+
+/// Set `resource` to `limit`. Raising the hard limit takes CAP_SYS_RESOURCE
+/// on the host, so explain the EPERM of trying without.
+fn set_resource_limit(resource: Resource, limit: Rlimit) -> Result<()> {
+    log::debug!("{:?} {:?}", resource, limit);
+    let (_, hard) = getrlimit(resource)?;
+    match setrlimit(resource, limit.soft(), limit.hard()) {
+        Err(Errno::EPERM) if limit.hard() > hard => bail!(
+            "cannot raise the hard {:?} from {} to {} without CAP_SYS_RESOURCE on the host \
+             (a user namespace's doesn't count); request at most {} (see --show-limits)",
+            resource,
+            hard,
+            limit.hard(),
+            hard
+        ),
+        result => result.with_context(|| format!("cannot set {:?}", resource)),
+    }
+}
+
+/// Move the calling process into a mount namespace of its own in which
+/// nothing propagates back, so its mounts are invisible to the parent and
+/// the host. pivot_root also refuses shared mounts.
+fn private_mount_namespace() -> Result<()> {
+    unshare(CloneFlags::CLONE_NEWNS).context("cannot unshare a private mount namespace")?;
+    mount(
+        None::<&str>,
+        "/",
+        None::<&str>,
+        MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+        None::<&str>,
+    )
+    .context("cannot make / private")?;
+    Ok(())
+}
+
+/// Make `new_root` the root of this mount namespace and detach the old one
+/// entirely, unlike chroot which leaves it reachable underneath.
+fn pivot_root_to(new_root: &str) -> Result<()> {
+    // With unshare_in_parent the parent shares the mount namespace, and
+    // pivot_root moves the root of every process in it, so give the child
+    // one of its own
+    private_mount_namespace()?;
+
+    // The new root has to be a mount point; a recursive bind keeps
+    // everything already mounted beneath it
+    mount(
+        Some(new_root),
+        new_root,
+        None::<&str>,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        None::<&str>,
+    )
+    .with_context(|| format!("cannot bind {} onto itself", new_root))?;
+
+    // Stacking the old root on top of the new one avoids needing a put_old
+    // directory inside the jail
+    std::env::set_current_dir(new_root)?;
+    pivot_root(".", ".").context("pivot_root")?;
+    umount2(".", MntFlags::MNT_DETACH).context("cannot detach the old root")?;
+
+    Ok(())
+}
+
+/// The arguments and environment as execve takes them, any NUL byte or
+/// oversized string reported by which one it is, and checked to fit within
+/// ARG_MAX together, which execve would only answer with E2BIG.
+pub fn exec_strings(
+    argv: Vec<String>,
+    environment: BTreeMap<String, String>,
+) -> Result<(Vec<CString>, Vec<CString>)> {
+    let args = argv
+        .into_iter()
+        .enumerate()
+        .map(|(i, arg)| exec_string(arg, || format!("argument {}", i)))
+        .collect::<Result<Vec<_>>>()?;
+    let env = environment
+        .into_iter()
+        .map(|(key, value)| {
+            let what = || format!("environment variable {}", key);
+            exec_string(format!("{}={}", key, value), what)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // The strings and the pointer arrays, each ending in NULL, go on the
+    // new program's stack
+    let pointer = size_of::<*const libc::c_char>();
+    let size = args
+        .iter()
+        .chain(&env)
+        .map(|string| string.as_bytes_with_nul().len() + pointer)
+        .sum::<usize>()
+        + 2 * pointer;
+    let arg_max = unsafe { libc::sysconf(libc::_SC_ARG_MAX) };
+    if arg_max > 0 && size > arg_max as usize {
+        bail!(
+            "arguments too long: {} arguments and {} environment variables take {} bytes, \
+             more than ARG_MAX ({})",
+            args.len(),
+            env.len(),
+            size,
+            arg_max
+        );
+    }
+    Ok((args, env))
+}
+
+/// `string` for execve, with `what` it is named in any error along with
+/// the start of it.
+fn exec_string(string: String, what: impl FnOnce() -> String) -> Result<CString> {
+    let start = |string: &str| {
+        let start: String = string.chars().take(32).collect();
+        if start.len() < string.len() {
+            format!("{:?}...", start)
+        } else {
+            format!("{:?}", start)
+        }
+    };
+    if string.len() >= MAX_ARG_STRLEN {
+        bail!(
+            "{} {} is {} bytes, execve takes at most {}",
+            what(),
+            start(&string),
+            string.len(),
+            MAX_ARG_STRLEN - 1
+        );
+    }
+    CString::new(string).map_err(|e| {
+        let position = e.nul_position();
+        let string = String::from_utf8_lossy(&e.into_vec()).into_owned();
+        anyhow!(
+            "{} {} contains a NUL byte at offset {}",
+            what(),
+            start(&string),
+            position
+        )
+    })
+}
+
+/// Explain why `path` can't be executed, as seen from inside the jail.
+/// execve reports ENOENT both for a missing binary and a missing dynamic
+/// loader, so tell those apart up front.
+pub fn check_executable(path: &Path) -> Result<()> {
+    let metadata = match fs::metadata(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            bail!("exec_bin {} not found inside the jail", path.display())
+        }
+        metadata => metadata.with_context(|| format!("cannot stat {}", path.display()))?,
+    };
+    if !is_executable(&metadata) {
+        bail!("exec_bin {} is not executable", path.display());
+    }
+
+    if elf::is_elf(path)
+        && let Some(interpreter) = elf::parse(path)?.interpreter
+    {
+        match fs::metadata(&interpreter) {
+            Err(_) => bail!(
+                "exec_bin {} needs dynamic loader {}, which is missing inside the jail",
+                path.display(),
+                interpreter
+            ),
+            Ok(metadata) if !is_executable(&metadata) => bail!(
+                "exec_bin {} needs dynamic loader {}, which is not executable inside the jail",
+                path.display(),
+                interpreter
+            ),
+            Ok(_) => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Open `path` for exec_via_fd and check the file actually opened, which
+/// is the one fexecve runs whatever happens to `path` afterwards.
+fn open_executable(path: &Path) -> Result<fs::File> {
+    // Close-on-exec, like all of std's files
+    let file = fs::File::open(path).with_context(|| format!("cannot open {}", path.display()))?;
+    if !is_executable(&file.metadata()?) {
+        bail!("exec_bin {} is not executable", path.display());
+    }
+    let mut magic = [0; 2];
+    if file.read_at(&mut magic, 0)? == 2 && &magic == b"#!" {
+        bail!(
+            "exec_bin {} is a #! script, which exec_via_fd cannot run: its \
+             interpreter would have to reopen the file through the fd",
+            path.display()
+        );
+    }
+    Ok(file)
+}
+
+/// Delete `path` and everything below it without following symlinks or
+/// entering anything mounted from another device than `device`, where a
+/// leftover bind could lead onto the host.
+fn remove_tree(path: &Path, device: u64) -> Result<()> {
+    let metadata = path.symlink_metadata()?;
+    if !metadata.is_dir() {
+        fs::remove_file(path).with_context(|| format!("cannot remove {}", path.display()))?;
+        return Ok(());
+    }
+    if metadata.dev() != device {
+        bail!("{} is a mount point, not cleaning it", path.display());
+    }
+
+    for entry in fs::read_dir(path)? {
+        remove_tree(&entry?.path(), device)?;
+    }
+    fs::remove_dir(path).with_context(|| format!("cannot remove {}", path.display()))?;
+    Ok(())
+}
+
+fn is_executable(metadata: &fs::Metadata) -> bool {
+    !metadata.is_dir() && metadata.permissions().mode() & 0o111 != 0
+}
+
+fn set_child_subreaper(enabled: bool) {
+    let enabled = enabled as libc::c_ulong;
+    if unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, enabled, 0, 0, 0) } != 0 {
+        log::warn!(
+            "prctl(PR_SET_CHILD_SUBREAPER): {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+/// This process's children, from every thread's /proc children list.
+fn children() -> BTreeSet<Pid> {
+    let Ok(tasks) = fs::read_dir("/proc/self/task") else {
+        return BTreeSet::new();
+    };
+    tasks
+        .flatten()
+        .filter_map(|task| fs::read_to_string(task.path().join("children")).ok())
+        .flat_map(|children| {
+            children
+                .split_whitespace()
+                .filter_map(|pid| pid.parse().ok())
+                .map(Pid::from_raw)
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Whether `pid` is in the same namespace `name` (as under /proc/<pid>/ns)
+/// as we are. A kernel without that kind of namespace counts as the same.
+fn same_namespace(pid: Pid, name: &str) -> bool {
+    let theirs = fs::metadata(format!("/proc/{}/ns/{}", pid, name));
+    let ours = fs::metadata(format!("/proc/self/ns/{}", name));
+    match (theirs, ours) {
+        (Ok(theirs), Ok(ours)) => theirs.ino() == ours.ino() && theirs.dev() == ours.dev(),
+        _ => true,
+    }
+}
+
+/// The cgroup.procs of `pid`'s cgroup, unless that is our cgroup too.
+fn holder_cgroup_procs(pid: Pid) -> Option<PathBuf> {
+    let theirs = cgroup::of_process(pid)?;
+    if cgroup::of_process(nix::unistd::getpid()) == Some(theirs.clone()) {
+        return None;
+    }
+    Some(theirs.join("cgroup.procs"))
+}
+
+/// `program` in the first `path` directory with an executable of that name,
+/// unless it has a '/' already. Otherwise as is, for the exec to fail on.
+fn find_program(program: &str, path: &str) -> PathBuf {
+    if program.contains('/') {
+        return PathBuf::from(program);
+    }
+    path.split(':')
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| Path::new(dir).join(program))
+        .find(|candidate| {
+            fs::metadata(candidate)
+                .is_ok_and(|metadata| metadata.is_file() && is_executable(&metadata))
+        })
+        .unwrap_or_else(|| PathBuf::from(program))
+}
+
+/// Fork and run `child_main` in the child, which exits with what it returns.
+fn fork_child(child_main: &mut dyn FnMut() -> isize) -> Result<Pid> {
+    match unsafe { fork() }? {
+        ForkResult::Parent { child } => Ok(child),
+        ForkResult::Child => std::process::exit(child_main() as i32),
+    }
+}
+
+/// Kill and reap whatever the program left running that rsjail adopted as
+/// child subreaper, then stop being one. Returns how many there were.
+fn kill_leftovers(launched: &Launched, cleanup_timeout: Option<u64>) -> usize {
+    let Some(adopted_from) = &launched.adopted_from else {
+        return 0;
+    };
+
+    let deadline = cleanup_timeout.map(|secs| Instant::now() + Duration::from_secs(secs));
+    let mut killed = 0;
+    // Each kill can orphan more descendants onto us
+    loop {
+        let leftovers: Vec<Pid> = children()
+            .into_iter()
+            .filter(|pid| !adopted_from.contains(pid))
+            .collect();
+        if leftovers.is_empty() {
+            break;
+        }
+        log::debug!("killing leftover processes {:?}", leftovers);
+        for &pid in &leftovers {
+            let _ = kill(pid, Signal::SIGKILL);
+        }
+        let mut stuck = 0;
+        for &pid in &leftovers {
+            match reap_before(pid, deadline) {
+                Ok(Some(status)) => log::debug!("reaped leftover {}: {:?}", pid, status),
+                Ok(None) => stuck += 1,
+                Err(e) => log::debug!("cannot reap leftover {}: {}", pid, e),
+            }
+        }
+        killed += leftovers.len();
+        if stuck > 0 {
+            log::warn!(
+                "{} killed processes still exiting after cleanup_timeout, not waiting for them",
+                stuck
+            );
+            launched.cleanup_timed_out.set(true);
+            break;
+        }
+    }
+
+    if killed > 0 {
+        log::warn!("killed {} processes the program left running", killed);
+    }
+    set_child_subreaper(false);
+    killed
+}
+
+/// Reap `pid` if it exits before `deadline`, None once that has passed.
+/// Without a deadline this blocks until it exits.
+fn reap_before(pid: Pid, deadline: Option<Instant>) -> nix::Result<Option<WaitStatus>> {
+    let Some(deadline) = deadline else {
+        return nix::sys::wait::waitpid(pid, None).map(Some);
+    };
+    loop {
+        match nix::sys::wait::waitpid(pid, Some(WaitPidFlag::WNOHANG))? {
+            WaitStatus::StillAlive => {}
+            status => return Ok(Some(status)),
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(None);
+        }
+        thread::sleep((deadline - now).min(TIMEOUT_POLL_INTERVAL));
+    }
+}
+
+/// Have the kernel SIGKILL this process when the parent exits, and exit right
+/// away if the parent already died before that could be arranged.
+fn die_with_parent(parent_alive: &OwnedFd) {
+    if unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL, 0, 0, 0) } != 0 {
+        eprintln!(
+            "Child setup failed: prctl(PR_SET_PDEATHSIG): {}",
+            std::io::Error::last_os_error()
+        );
+        std::process::exit(1);
+    }
+
+    // getppid() can't be used for the re-check since it is 0 inside a new PID
+    // namespace; the pipe reports a hangup once every write end is closed
+    if sync::is_hung_up(parent_alive) {
+        std::process::exit(1);
+    }
+}
+
+fn timeval_duration(tv: libc::timeval) -> Duration {
+    Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64)
+}
+
+/// TERM, COLUMNS and LINES as seen by the host, falling back to the window
+/// size of the controlling terminal when the shell didn't export them.
+fn terminal_environment() -> Vec<(String, String)> {
+    let mut vars = Vec::new();
+
+    if let Ok(term) = std::env::var("TERM") {
+        vars.push(("TERM".to_string(), term));
+    }
+
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let has_size =
+        unsafe { libc::ioctl(std::io::stdout().as_raw_fd(), libc::TIOCGWINSZ, &mut size) == 0 };
+
+    for (name, fallback) in [("COLUMNS", size.ws_col), ("LINES", size.ws_row)] {
+        match std::env::var(name) {
+            Ok(value) => vars.push((name.to_string(), value)),
+            Err(_) if has_size && fallback > 0 => {
+                vars.push((name.to_string(), fallback.to_string()))
+            }
+            Err(_) => {}
+        }
+    }
+
+    vars
 }
@@ -0,0 +1,184 @@
+use crate::config::{LandlockAccess, LandlockRule};
+use anyhow::{bail, Context, Result};
+use std::ffi::CString;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+const ACCESS_FS_MAKE_FIFO: u64 = 1 << 10;
+const ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+const ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+const ACCESS_FS_REFER: u64 = 1 << 13;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+/// Rights that make sense on a regular file; anything else is EINVAL there
+const FILE_ACCESS: u64 =
+    ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE | ACCESS_FS_TRUNCATE;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// The Landlock ABI version the kernel supports, or None without Landlock.
+pub fn abi_version() -> Option<u32> {
+    let version = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0usize,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    u32::try_from(version).ok().filter(|&version| version > 0)
+}
+
+/// Every filesystem right the given ABI version knows how to restrict.
+fn handled_access(abi: u32) -> u64 {
+    let mut access = ACCESS_FS_EXECUTE
+        | ACCESS_FS_WRITE_FILE
+        | ACCESS_FS_READ_FILE
+        | ACCESS_FS_READ_DIR
+        | ACCESS_FS_REMOVE_DIR
+        | ACCESS_FS_REMOVE_FILE
+        | ACCESS_FS_MAKE_CHAR
+        | ACCESS_FS_MAKE_DIR
+        | ACCESS_FS_MAKE_REG
+        | ACCESS_FS_MAKE_SOCK
+        | ACCESS_FS_MAKE_FIFO
+        | ACCESS_FS_MAKE_BLOCK
+        | ACCESS_FS_MAKE_SYM;
+    if abi >= 2 {
+        access |= ACCESS_FS_REFER;
+    }
+    if abi >= 3 {
+        access |= ACCESS_FS_TRUNCATE;
+    }
+    access
+}
+
+fn rule_access(access: &[LandlockAccess]) -> u64 {
+    access.iter().fold(0, |bits, access| {
+        bits | match access {
+            LandlockAccess::Read => ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR,
+            LandlockAccess::Write => {
+                ACCESS_FS_WRITE_FILE
+                    | ACCESS_FS_REMOVE_DIR
+                    | ACCESS_FS_REMOVE_FILE
+                    | ACCESS_FS_MAKE_CHAR
+                    | ACCESS_FS_MAKE_DIR
+                    | ACCESS_FS_MAKE_REG
+                    | ACCESS_FS_MAKE_SOCK
+                    | ACCESS_FS_MAKE_FIFO
+                    | ACCESS_FS_MAKE_BLOCK
+                    | ACCESS_FS_MAKE_SYM
+                    | ACCESS_FS_REFER
+                    | ACCESS_FS_TRUNCATE
+            }
+            LandlockAccess::Execute => ACCESS_FS_EXECUTE,
+        }
+    })
+}
+
+fn last_error(what: &str) -> anyhow::Error {
+    anyhow::Error::new(std::io::Error::last_os_error()).context(what.to_string())
+}
+
+/// Restrict the calling process so it can only reach the listed paths with
+/// the listed rights. Without kernel support this is an error if `required`
+/// and a warning otherwise.
+pub fn restrict_self(rules: &[LandlockRule], required: bool) -> Result<()> {
+    let Some(abi) = abi_version() else {
+        if required {
+            bail!("landlock is required but not supported by this kernel");
+        }
+        log::warn!("landlock is not supported by this kernel, skipping filesystem rules");
+        return Ok(());
+    };
+
+    let handled = handled_access(abi);
+    let attr = RulesetAttr {
+        handled_access_fs: handled,
+    };
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr as *const RulesetAttr,
+            std::mem::size_of::<RulesetAttr>(),
+            0u32,
+        )
+    };
+    if fd < 0 {
+        return Err(last_error("landlock_create_ruleset"));
+    }
+    let ruleset = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+
+    for rule in rules {
+        add_path_rule(&ruleset, rule, handled)
+            .with_context(|| format!("landlock rule for {}", rule.path))?;
+    }
+
+    // Landlock refuses to restrict a process that could regain privileges
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(last_error("prctl(PR_SET_NO_NEW_PRIVS)"));
+    }
+    let ret = unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0u32) };
+    if ret != 0 {
+        return Err(last_error("landlock_restrict_self"));
+    }
+
+    Ok(())
+}
+
+fn add_path_rule(ruleset: &OwnedFd, rule: &LandlockRule, handled: u64) -> Result<()> {
+    let path = Path::new(&rule.path);
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(last_error("open"));
+    }
+    let parent = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let mut allowed = rule_access(&rule.access) & handled;
+    if !path.is_dir() {
+        allowed &= FILE_ACCESS;
+    }
+
+    let attr = PathBeneathAttr {
+        allowed_access: allowed,
+        parent_fd: parent.as_raw_fd(),
+    };
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_add_rule,
+            ruleset.as_raw_fd(),
+            LANDLOCK_RULE_PATH_BENEATH,
+            &attr as *const PathBeneathAttr,
+            0u32,
+        )
+    };
+    if ret != 0 {
+        return Err(last_error("landlock_add_rule"));
+    }
+
+    Ok(())
+}
@@ -1,5 +1,29 @@
+pub mod bench;
+pub mod caps;
+pub mod cgroup;
+pub mod compose;
 pub mod config;
+pub mod elf;
+pub mod env;
+pub mod glob;
+pub mod held;
+pub mod init;
 pub mod jail;
+pub mod landlock;
+pub mod metrics;
+#[cfg(feature = "pam")]
+pub mod pam;
+pub mod pidfd;
+pub mod relay;
+pub mod sampler;
+pub mod scratch;
+pub mod seccomp;
+pub mod sync;
+pub mod timings;
 
-pub use config::{JailConfig, MountConfig};
-pub use jail::Jail;
+pub use config::{
+    ChrootPolicy, DiskQuota, ExitCodeMap, FsIsolation, FuseMount, IdMap, JailConfig, LandlockAccess,
+    LandlockRule, MountConfig, NetworkMode, ProvisionConfig, Rlimit, SeccompAction, SeccompPolicy,
+    SetupStep, VolumeBacking, VolumeConfig,
+};
+pub use jail::{Jail, JailHandle, JailResult, ResolvedMount};
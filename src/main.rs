@@ -1,44 +1,701 @@
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
+use nix::sys::wait::waitpid;
+use nix::unistd::{fork, pipe, ForkResult};
+use std::collections::BTreeMap;
 use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-mod config;
-mod jail;
-
-use config::JailConfig;
-use jail::Jail;
+use rsjail::bench::TimingStats;
+use rsjail::compose::ComposeFile;
+use rsjail::held::{self, HeldJail};
+use rsjail::metrics::RunMetrics;
+use rsjail::{caps, env, Jail, JailConfig, JailResult, Rlimit};
 
 #[derive(Parser)]
 #[command(name = "rsjail")]
 #[command(about = "A simple jail implementation in Rust")]
+#[command(version)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
-    #[arg(short, long)]
-    config: String,
-    
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(short, long, required_unless_present = "show_limits")]
+    config: Option<String>,
+
+    /// Print the soft and hard value of each resource limit the config can
+    /// set, as rsjail and so the jail start out with, and exit
+    #[arg(long)]
+    show_limits: bool,
+
+    /// Reject config keys that are not known fields instead of ignoring them
+    #[arg(long)]
+    strict_config: bool,
+
+    /// Only load and validate the config, report every problem and exit.
+    /// Needs no root and runs nothing.
+    #[arg(long)]
+    validate_only: bool,
+
+    /// Print every mount the jail would make, in the order it makes them,
+    /// and exit without running anything
+    #[arg(long, conflicts_with = "validate_only")]
+    print_mounts: bool,
+
+    /// Log the jail lifecycle, same as --log-level debug
     #[arg(short, long)]
     verbose: bool,
+
+    /// Only log errors and warnings, not how the child exited, same as
+    /// --log-level warn
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Log level: off, error, warn, info, debug or trace (default: info, or RUST_LOG)
+    #[arg(long, value_name = "LEVEL")]
+    log_level: Option<log::LevelFilter>,
+
+    /// Base directory for per-run scratch storage, overriding the config
+    #[arg(long, value_name = "DIR")]
+    scratch_dir: Option<String>,
+
+    /// Maximum number of mounts the jail may use, overriding the config
+    #[arg(long, value_name = "N")]
+    max_mounts: Option<usize>,
+
+    /// Write resource usage of the run as JSON to this file
+    #[arg(long, value_name = "PATH")]
+    report_json: Option<String>,
+
+    /// Time each setup phase and print them to stderr as "setup;<phase> <us>"
+    /// lines, the folded format flame graph tools read. --report-json gets
+    /// them too.
+    #[arg(long, conflicts_with = "repeat")]
+    timings: bool,
+
+    /// After the child exits, wait for Enter before cleaning up, so the jail
+    /// can be inspected. Ignored unless stdin is a terminal.
+    #[arg(long, conflicts_with = "repeat")]
+    freeze_on_exit: bool,
+
+    /// After the run, copy the disk-backed volumes to DIR/<volume name>.
+    /// Only those: tmpfs volumes and chroot_dir are not saved
+    #[arg(long, value_name = "DIR", conflicts_with = "repeat")]
+    save_volumes: Option<PathBuf>,
+
+    /// Seed the disk-backed volumes from a --save-volumes DIR before every run
+    #[arg(long, value_name = "DIR")]
+    restore_volumes: Option<PathBuf>,
+
+    /// Write Prometheus text-format metrics over all runs to this file
+    #[arg(long, value_name = "PATH")]
+    metrics_file: Option<String>,
+
+    /// Run the jail N times in a row and print wall time statistics
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    #[arg(conflicts_with = "report_json")]
+    repeat: Option<u32>,
+
+    /// Run the jail with a holder in place of its program and record it in
+    /// STATE_FILE while it runs; how `start` runs jails
+    #[arg(long, value_name = "STATE_FILE", hide = true)]
+    #[arg(conflicts_with = "repeat")]
+    hold: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the version and which kernel features are available
+    Check,
+    /// Write a starter config file
+    Init(InitArgs),
+    /// Start the jails of a JSON compose file, each detached under its own
+    /// rsjail, after the ones it depends on
+    Up(ComposeArgs),
+    /// Stop the jails `up` started, in reverse order
+    Down(DownArgs),
+    /// Set up a jail and keep it, with nothing running in it, for `exec`
+    Start(StartArgs),
+    /// Run a command in a jail `start` set up
+    Exec(ExecArgs),
+    /// Take down a jail `start` set up
+    Stop(StopArgs),
+    /// Stop every process in a jail `start` set up where it is, through its
+    /// cgroup's freezer
+    Freeze(NameArgs),
+    /// Let the processes of a frozen jail run again
+    Thaw(NameArgs),
+    /// Show the jails `start` set up, whether they are frozen
+    Status(StatusArgs),
+}
+
+#[derive(clap::Args)]
+struct StateDirArgs {
+    /// Where the state files of started jails are
+    #[arg(long, value_name = "DIR", default_value = held::DEFAULT_STATE_DIR)]
+    state_dir: PathBuf,
+}
+
+#[derive(clap::Args)]
+struct StartArgs {
+    #[arg(short, long, value_name = "PATH")]
+    config: PathBuf,
+
+    /// Name to refer to the jail by (default: the config's name)
+    #[arg(long)]
+    name: Option<String>,
+
+    #[command(flatten)]
+    state: StateDirArgs,
+}
+
+#[derive(clap::Args)]
+struct ExecArgs {
+    /// The name the jail was started with
+    name: String,
+
+    #[command(flatten)]
+    state: StateDirArgs,
+
+    /// The command and its arguments
+    #[arg(last = true, required = true, value_name = "COMMAND")]
+    command: Vec<String>,
+}
+
+#[derive(clap::Args)]
+struct StopArgs {
+    /// The name the jail was started with
+    name: String,
+
+    #[command(flatten)]
+    state: StateDirArgs,
+
+    /// Seconds the jail gets to exit after SIGTERM before it is killed
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    timeout: u64,
+}
+
+#[derive(clap::Args)]
+struct NameArgs {
+    /// The name the jail was started with
+    name: String,
+
+    #[command(flatten)]
+    state: StateDirArgs,
+}
+
+#[derive(clap::Args)]
+struct StatusArgs {
+    /// The name the jail was started with (default: every running jail)
+    name: Option<String>,
+
+    #[command(flatten)]
+    state: StateDirArgs,
+}
+
+#[derive(clap::Args)]
+struct ComposeArgs {
+    /// The compose file, in JSON (not YAML), see rsjail::compose
+    #[arg(short, long, value_name = "PATH")]
+    #[arg(default_value = "rsjail-compose.json")]
+    file: PathBuf,
+}
+
+#[derive(clap::Args)]
+struct DownArgs {
+    #[command(flatten)]
+    compose: ComposeArgs,
+
+    /// Seconds each jail gets to exit after SIGTERM before it is killed
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    timeout: u64,
+}
+
+#[derive(clap::Args)]
+struct InitArgs {
+    /// Where to write the config
+    #[arg(short, long, value_name = "PATH", default_value = "rsjail.json")]
+    output: String,
+
+    /// Replace the file if it already exists
+    #[arg(long)]
+    force: bool,
+
+    /// Name of the jail
+    #[arg(long, default_value = "jail")]
+    name: String,
+
+    /// Program to run inside the jail
+    #[arg(long, value_name = "PATH", default_value = "/bin/sh")]
+    exec: String,
+
+    /// Arguments for the program
+    #[arg(last = true, value_name = "ARGS")]
+    exec_args: Vec<String>,
+
+    /// Directory to use as the jail's root
+    #[arg(long, value_name = "DIR")]
+    chroot_dir: Option<String>,
+
+    /// Namespaces to create
+    #[arg(long, value_delimiter = ',')]
+    #[arg(default_value = "pid,net,mount,uts,ipc,user")]
+    namespaces: Vec<Namespace>,
+
+    /// Address space limit in MiB
+    #[arg(long, value_name = "MIB")]
+    memory: Option<u64>,
+
+    /// Wall time limit in seconds
+    #[arg(long, value_name = "SECONDS")]
+    time_limit: Option<u64>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Namespace {
+    Pid,
+    Net,
+    Mount,
+    Uts,
+    Ipc,
+    User,
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
-    
-    if args.verbose {
-        env_logger::init();
-    }
-    
-    // Check if running with root privileges
-    if !nix::unistd::getuid().is_root() {
-        eprintln!("Error: This program must be run as root");
-        std::process::exit(1);
+
+    init_logging(args.log_level, args.verbose, args.quiet);
+
+    match args.command {
+        Some(Command::Check) => {
+            print_feature_check();
+            return Ok(());
+        }
+        // Only writes a file, so no root needed
+        Some(Command::Init(init)) => return write_starter_config(&init),
+        Some(Command::Up(up)) => return compose_up(&up),
+        Some(Command::Down(down)) => return compose_down(&down),
+        Some(Command::Start(start)) => return start_held(&start),
+        Some(Command::Exec(exec)) => return exec_held(&exec),
+        Some(Command::Stop(stop)) => return stop_held(&stop),
+        Some(Command::Freeze(freeze)) => return freeze_held(&freeze, true),
+        Some(Command::Thaw(thaw)) => return freeze_held(&thaw, false),
+        Some(Command::Status(status)) => return held_status(&status),
+        None => {}
     }
-    
+
+    if args.show_limits {
+        print_limits();
+        return Ok(());
+    }
+
     // Read config file
-    let config_content = fs::read_to_string(&args.config)?;
-    let config: JailConfig = serde_json::from_str(&config_content)?;
-    
+    let config_path = args
+        .config
+        .expect("clap enforces --config without a subcommand or --show-limits");
+    let mut config = if args.strict_config {
+        JailConfig::load_strict(&config_path)?
+    } else {
+        JailConfig::load(&config_path)?
+    };
+    if let Some(scratch_dir) = args.scratch_dir {
+        config.scratch_dir = Some(scratch_dir);
+    }
+    if let Some(max_mounts) = args.max_mounts {
+        config.max_mounts = max_mounts;
+    }
+
+    if args.validate_only {
+        return report_problems(&config_path, &config);
+    }
+    if args.print_mounts {
+        config.validate()?;
+        return print_mounts(&Jail::new(config));
+    }
+
+    check_capabilities(&config)?;
+    if args.hold.is_some() && config.no_fork {
+        bail!("a no_fork jail cannot be started, it would have no holder");
+    }
+
+    if args.repeat.is_some()
+        && !config.clone_newns
+        && (!config.mounts.is_empty() || config.auto_mount_libs)
+    {
+        eprintln!("Warning: without clone_newns every run leaves its mounts behind on the host");
+    }
+
     // Create and run jail
-    let jail = Jail::new(config);
-    jail.run()?;
-    
+    let jail = Jail::new(config)
+        .freeze_on_exit(args.freeze_on_exit)
+        .save_volumes_to(args.save_volumes)
+        .restore_volumes_from(args.restore_volumes)
+        .record_timings(args.timings);
+    if let Some(runs) = args.repeat {
+        return run_repeated(&jail, runs, args.metrics_file.as_deref());
+    }
+    if let Some(state_file) = &args.hold {
+        return run_held(jail, &config_path, state_file);
+    }
+    let result = jail.run()?;
+    log_outcome(&result);
+    for phase in result.setup_timings.iter().flatten() {
+        eprintln!("setup;{} {}", phase.name, phase.duration.as_micros());
+    }
+
+    if let Some(report_path) = &args.report_json {
+        fs::write(
+            report_path,
+            serde_json::to_string_pretty(&result.to_json())?,
+        )?;
+    }
+    if let Some(metrics_path) = &args.metrics_file {
+        let mut metrics = RunMetrics::default();
+        metrics.record(&result.to_json());
+        fs::write(metrics_path, metrics.render())?;
+    }
+
+    std::process::exit(result.mapped_exit_code);
+}
+
+/// Print what `validate` would reject in the config and fail if anything.
+fn report_problems(config_path: &str, config: &JailConfig) -> Result<()> {
+    let problems = config.problems();
+    if problems.is_empty() {
+        println!("{}: ok", config_path);
+        return Ok(());
+    }
+    for problem in &problems {
+        eprintln!("{}: {:#}", config_path, problem);
+    }
+    bail!("{} has {} problem(s)", config_path, problems.len());
+}
+
+fn print_limits() {
+    let show = |value: u64| match value {
+        libc::RLIM_INFINITY => "unlimited".to_string(),
+        value => value.to_string(),
+    };
+    for (resource, soft, hard) in env::resource_limits() {
+        println!(
+            "{:<15}soft {:<12}hard {}",
+            format!("{:?}", resource),
+            show(soft),
+            show(hard)
+        );
+    }
+}
+
+/// One line per mount, laid out like mount(8) output.
+fn print_mounts(jail: &Jail) -> Result<()> {
+    for mount in jail.resolved_mounts()? {
+        println!(
+            "{} on {} type {} ({})",
+            mount.src,
+            mount.dst,
+            mount.fstype.as_deref().unwrap_or("none"),
+            mount.options()
+        );
+    }
+    Ok(())
+}
+
+/// Exit unless we hold every capability the config needs, as root or
+/// through file capabilities on the binary.
+fn check_capabilities(config: &JailConfig) -> Result<()> {
+    let missing = caps::missing(&config.required_capabilities())?;
+    if !missing.is_empty() {
+        eprintln!(
+            "Error: This config needs {}; run as root or grant them to the binary with setcap",
+            missing.join(", ")
+        );
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn compose_up(args: &ComposeArgs) -> Result<()> {
+    let compose = ComposeFile::load(&args.file)?;
+    for name in compose.start_order()? {
+        check_capabilities(&JailConfig::load(compose.config_path(name))?)?;
+    }
+    let rsjail = std::env::current_exe().context("cannot find the rsjail binary")?;
+    for (name, pid) in compose.up(&rsjail)? {
+        println!("{}: started (pid {})", name, pid);
+    }
+    Ok(())
+}
+
+fn compose_down(args: &DownArgs) -> Result<()> {
+    let compose = ComposeFile::load(&args.compose.file)?;
+    for name in compose.down(Duration::from_secs(args.timeout))? {
+        println!("{}: stopped", name);
+    }
+    Ok(())
+}
+
+fn start_held(args: &StartArgs) -> Result<()> {
+    // From the config's directory, as HeldJail::start runs the jail
+    let path = std::path::absolute(&args.config)?;
+    let cwd = std::env::current_dir()?;
+    std::env::set_current_dir(path.parent().unwrap_or(Path::new("/")))?;
+    let config = JailConfig::load(&path);
+    std::env::set_current_dir(cwd)?;
+    let config = config?;
+    config.validate()?;
+    check_capabilities(&config)?;
+    let name = args.name.as_deref().unwrap_or(&config.name);
+    let rsjail = std::env::current_exe().context("cannot find the rsjail binary")?;
+    let held = HeldJail::start(&rsjail, &args.config, &args.state.state_dir, name)?;
+    println!("{}: started (pid {})", name, held.holder);
+    Ok(())
+}
+
+fn load_held(name: &str, state: &StateDirArgs) -> Result<HeldJail> {
+    let held = HeldJail::load(&state.state_dir, name)?;
+    held.with_context(|| format!("jail {} is not running", name))
+}
+
+fn exec_held(args: &ExecArgs) -> Result<()> {
+    let held = load_held(&args.name, &args.state)?;
+    let jail = Jail::new(held.jail.clone());
+    std::process::exit(jail.exec_into(held.holder(), &args.command)?);
+}
+
+fn stop_held(args: &StopArgs) -> Result<()> {
+    let held = load_held(&args.name, &args.state)?;
+    held.stop(Duration::from_secs(args.timeout))
+        .with_context(|| format!("cannot stop jail {}", args.name))?;
+    println!("{}: stopped", args.name);
+    Ok(())
+}
+
+fn freeze_held(args: &NameArgs, frozen: bool) -> Result<()> {
+    let held = load_held(&args.name, &args.state)?;
+    held.freeze(frozen)
+        .with_context(|| format!("cannot freeze or thaw jail {}", args.name))?;
+    let state = if frozen { "frozen" } else { "thawed" };
+    println!("{}: {}", args.name, state);
+    Ok(())
+}
+
+fn held_status(args: &StatusArgs) -> Result<()> {
+    let jails = match &args.name {
+        Some(name) => vec![(name.clone(), load_held(name, &args.state)?)],
+        None => HeldJail::list(&args.state.state_dir)?,
+    };
+    for (name, held) in jails {
+        let state = match held.is_frozen() {
+            Some(true) => "frozen",
+            Some(false) => "running",
+            None => "running, without a cgroup to freeze",
+        };
+        println!("{}: {} (pid {})", name, state, held.holder);
+    }
+    Ok(())
+}
+
+/// The rsjail side of a jail `start` set up: hold it, recorded in
+/// `state_file`, until the holder is stopped.
+fn run_held(jail: Jail, config_path: &str, state_file: &Path) -> Result<()> {
+    let jail = jail.hold(true);
+    let handle = jail.spawn()?;
+    let held = HeldJail {
+        config: std::path::absolute(config_path)?,
+        jail: jail.config().clone(),
+        supervisor: std::process::id() as i32,
+        holder: handle.pid().as_raw(),
+        cgroup: handle.cgroup_path().map(Path::to_path_buf),
+    };
+    held.save(state_file)?;
+    let result = handle.wait();
+    let _ = fs::remove_file(state_file);
+    std::process::exit(result?.mapped_exit_code);
+}
+
+fn log_outcome(result: &JailResult) {
+    let limit = if result.cpu_limit_hit {
+        " (CPU time limit)".to_string()
+    } else if result.timed_out {
+        " (time limit)".to_string()
+    } else if let Some(syscall) = &result.syscall_limit_hit {
+        format!(" (too many {} calls)", syscall)
+    } else {
+        String::new()
+    };
+    match (result.exit_code, result.signal) {
+        (Some(code), _) => log::info!("Child {} exited with code {}{}", result.pid, code, limit),
+        (None, Some(signal)) => {
+            log::info!(
+                "Child {} killed by signal {:?}{}",
+                result.pid,
+                signal,
+                limit
+            )
+        }
+        (None, None) => log::info!("Child process status changed"),
+    }
+}
+
+/// Run the jail `runs` times, each a fresh fork and setup, then summarize
+/// how long the runs took and how they ended.
+fn run_repeated(jail: &Jail, runs: u32, metrics_path: Option<&str>) -> Result<()> {
+    let mut wall_times = Vec::new();
+    let mut outcomes: BTreeMap<String, u32> = BTreeMap::new();
+    let mut metrics = RunMetrics::default();
+
+    for _ in 0..runs {
+        let report = run_isolated(jail)?;
+        metrics.record(&report);
+        wall_times.push(Duration::from_micros(
+            report["wall_time_us"].as_u64().unwrap_or(0),
+        ));
+
+        let outcome = match (report["exit_code"].as_i64(), report["signal"].as_str()) {
+            (Some(code), _) => format!("exit {}", code),
+            (None, Some(signal)) => signal.to_string(),
+            (None, None) => "unknown".to_string(),
+        };
+        *outcomes.entry(outcome).or_default() += 1;
+    }
+
+    let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+    if let Some(stats) = TimingStats::from_samples(&wall_times) {
+        println!(
+            "{} runs: min {:.3}ms  max {:.3}ms  mean {:.3}ms  stddev {:.3}ms",
+            runs,
+            ms(stats.min),
+            ms(stats.max),
+            ms(stats.mean),
+            ms(stats.stddev)
+        );
+    }
+    let outcomes: Vec<String> = outcomes
+        .iter()
+        .map(|(outcome, count)| format!("{} x{}", outcome, count))
+        .collect();
+    println!("outcomes: {}", outcomes.join(", "));
+
+    if let Some(metrics_path) = metrics_path {
+        fs::write(metrics_path, metrics.render())?;
+    }
+
+    Ok(())
+}
+
+/// Run the jail once from a throwaway process and return its JSON report.
+/// With unshare_in_parent `Jail::run` unshares the namespaces in the
+/// calling process, where the next run would start out, and with no_fork
+/// it execs the program in that process's place, so each run gets one of
+/// its own to spend.
+fn run_isolated(jail: &Jail) -> Result<serde_json::Value> {
+    let (report_read, report_write) = pipe()?;
+
+    match unsafe { fork() }? {
+        ForkResult::Child => {
+            drop(report_read);
+            let code = match jail.run() {
+                Ok(result) => {
+                    let mut report = fs::File::from(report_write);
+                    match serde_json::to_writer(&mut report, &result.to_json()) {
+                        Ok(()) => 0,
+                        Err(_) => 1,
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Error: {:#}", e);
+                    1
+                }
+            };
+            std::process::exit(code);
+        }
+        ForkResult::Parent { child } => {
+            drop(report_write);
+            let mut report = String::new();
+            fs::File::from(report_read).read_to_string(&mut report)?;
+            waitpid(child, None)?;
+
+            if report.is_empty() {
+                bail!("jail run failed, stopping");
+            }
+            Ok(serde_json::from_str(&report)?)
+        }
+    }
+}
+
+/// --log-level wins over --verbose and --quiet, which win over RUST_LOG.
+fn init_logging(level: Option<log::LevelFilter>, verbose: bool, quiet: bool) {
+    let mut builder =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+    match (level, verbose, quiet) {
+        (Some(level), _, _) => {
+            builder.filter_level(level);
+        }
+        (None, true, _) => {
+            builder.filter_level(log::LevelFilter::Debug);
+        }
+        (None, false, true) => {
+            builder.filter_level(log::LevelFilter::Warn);
+        }
+        (None, false, false) => {}
+    }
+    builder.init();
+}
+
+fn print_feature_check() {
+    let features = env::detect_features();
+
+    println!(
+        "rsjail {} (kernel {})",
+        env!("CARGO_PKG_VERSION"),
+        features.kernel_release
+    );
+    for (name, available) in features.matrix() {
+        println!("  {:<22}{}", name, if available { "yes" } else { "no" });
+    }
+}
+
+fn write_starter_config(init: &InitArgs) -> Result<()> {
+    let has = |namespace| init.namespaces.contains(&namespace);
+    let mut exec_args = vec![init.exec.clone()];
+    exec_args.extend(init.exec_args.iter().cloned());
+    let rlimit_as = match init.memory {
+        Some(mib) => match mib.checked_mul(1024 * 1024) {
+            Some(bytes) => Some(Rlimit::Both(bytes)),
+            None => bail!("--memory {} MiB is more bytes than a limit can hold", mib),
+        },
+        None => None,
+    };
+
+    let config = JailConfig {
+        name: init.name.clone(),
+        chroot_dir: init.chroot_dir.clone(),
+        exec_bin: init.exec.clone(),
+        exec_args,
+        clone_newpid: has(Namespace::Pid),
+        clone_newnet: has(Namespace::Net),
+        clone_newns: has(Namespace::Mount),
+        clone_newuts: has(Namespace::Uts),
+        clone_newipc: has(Namespace::Ipc),
+        clone_newuser: has(Namespace::User),
+        rlimit_as,
+        time_limit: init.time_limit,
+        ..Default::default()
+    };
+    config.validate()?;
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .create_new(!init.force)
+        .open(&init.output)
+        .with_context(|| format!("cannot create {}", init.output))?;
+    writeln!(file, "{}", serde_json::to_string_pretty(&config)?)?;
+
+    println!("Wrote {}", init.output);
     Ok(())
 }
@@ -0,0 +1,131 @@
+use std::fmt::Write;
+
+/// Upper bounds of the wall time histogram buckets, in seconds
+const WALL_TIME_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0, 60.0, 300.0,
+];
+/// Upper bounds of the peak RSS histogram buckets, in bytes
+const MAX_RSS_BUCKETS: [f64; 7] = [
+    (1u64 << 20) as f64,
+    (4u64 << 20) as f64,
+    (16u64 << 20) as f64,
+    (64u64 << 20) as f64,
+    (256u64 << 20) as f64,
+    (1u64 << 30) as f64,
+    (4u64 << 30) as f64,
+];
+
+/// Counters and histograms over a batch of runs, rendered in the Prometheus
+/// text exposition format.
+#[derive(Debug, Clone)]
+pub struct RunMetrics {
+    runs: u64,
+    failures: u64,
+    timeouts: u64,
+    wall_time: Histogram,
+    max_rss: Histogram,
+}
+
+impl Default for RunMetrics {
+    fn default() -> Self {
+        Self {
+            runs: 0,
+            failures: 0,
+            timeouts: 0,
+            wall_time: Histogram::new(&WALL_TIME_BUCKETS),
+            max_rss: Histogram::new(&MAX_RSS_BUCKETS),
+        }
+    }
+}
+
+impl RunMetrics {
+    /// Add one run, given its report as produced by `JailResult::to_json`.
+    /// Anything but exit code 0 counts as a failure.
+    pub fn record(&mut self, report: &serde_json::Value) {
+        self.runs += 1;
+        if report["exit_code"].as_i64() != Some(0) {
+            self.failures += 1;
+        }
+        if report["timed_out"].as_bool() == Some(true) {
+            self.timeouts += 1;
+        }
+        let wall_time_us = report["wall_time_us"].as_u64().unwrap_or(0);
+        self.wall_time.observe(wall_time_us as f64 / 1e6);
+        let max_rss_kb = report["max_rss_kb"].as_u64().unwrap_or(0);
+        self.max_rss.observe((max_rss_kb * 1024) as f64);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, help, value) in [
+            ("rsjail_runs_total", "Jail runs completed.", self.runs),
+            (
+                "rsjail_run_failures_total",
+                "Runs that did not exit with code 0.",
+                self.failures,
+            ),
+            (
+                "rsjail_run_timeouts_total",
+                "Runs killed for hitting a timeout.",
+                self.timeouts,
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        self.wall_time.render(
+            &mut out,
+            "rsjail_run_wall_time_seconds",
+            "Wall time from fork until the child was reaped.",
+        );
+        self.max_rss.render(
+            &mut out,
+            "rsjail_run_max_rss_bytes",
+            "Peak resident set size of the child.",
+        );
+        out
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    bounds: &'static [f64],
+    /// Observations per bucket, not cumulative; the last one is +Inf
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|&bound| value <= bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let total: u64 = self.counts.iter().sum();
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, total);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum);
+        let _ = writeln!(out, "{}_count {}", name, total);
+    }
+}
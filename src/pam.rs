@@ -0,0 +1,167 @@
+use crate::sync;
+use anyhow::{bail, Context, Result};
+use nix::sys::wait::waitpid;
+use nix::unistd::{fork, ForkResult, Pid};
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::os::fd::OwnedFd;
+use std::ptr;
+
+const PAM_SUCCESS: c_int = 0;
+const PAM_CONV_ERR: c_int = 19;
+/// Tells pam_end the session lives on in another process
+const PAM_DATA_SILENT: c_int = 0x4000_0000;
+
+#[repr(C)]
+struct PamMessage {
+    msg_style: c_int,
+    msg: *const c_char,
+}
+
+#[repr(C)]
+struct PamResponse {
+    resp: *mut c_char,
+    resp_retcode: c_int,
+}
+
+#[repr(C)]
+struct PamConv {
+    conv: extern "C" fn(c_int, *mut *const PamMessage, *mut *mut PamResponse, *mut c_void) -> c_int,
+    appdata_ptr: *mut c_void,
+}
+
+#[repr(C)]
+struct PamHandle {
+    _private: [u8; 0],
+}
+
+#[link(name = "pam")]
+unsafe extern "C" {
+    fn pam_start(
+        service: *const c_char,
+        user: *const c_char,
+        conv: *const PamConv,
+        pamh: *mut *mut PamHandle,
+    ) -> c_int;
+    fn pam_end(pamh: *mut PamHandle, status: c_int) -> c_int;
+    fn pam_open_session(pamh: *mut PamHandle, flags: c_int) -> c_int;
+    fn pam_close_session(pamh: *mut PamHandle, flags: c_int) -> c_int;
+    fn pam_strerror(pamh: *mut PamHandle, errnum: c_int) -> *const c_char;
+}
+
+/// Nobody is there to answer, and session modules have no business asking
+extern "C" fn no_conversation(
+    _num_msg: c_int,
+    _msg: *mut *const PamMessage,
+    _resp: *mut *mut PamResponse,
+    _appdata: *mut c_void,
+) -> c_int {
+    PAM_CONV_ERR
+}
+
+// Never changes and carries no appdata
+unsafe impl Sync for PamConv {}
+
+static CONVERSATION: PamConv = PamConv {
+    conv: no_conversation,
+    appdata_ptr: ptr::null_mut(),
+};
+
+/// A PAM session for the jail's user, closed on drop.
+///
+/// Only the session stack of the service runs (no authentication or
+/// account checks), so the host needs e.g. /etc/pam.d/<service> with the
+/// session modules wanted there, such as pam_limits or pam_systemd. Limits
+/// pam_limits sets land on rsjail itself and reach the jail through fork.
+///
+/// By the time the jail is done rsjail may sit in its namespaces, where
+/// modules can't fork or reach the host, so the session is closed by a
+/// closer process forked right after opening it, like cgroup removal.
+#[derive(Debug)]
+pub struct Session {
+    closer: Pid,
+    /// Closing it tells the closer to close the session
+    closer_wait: Option<OwnedFd>,
+}
+
+impl Session {
+    pub fn open(service: &str, user: &str) -> Result<Self> {
+        let c_service = CString::new(service).context("pam_service contains a NUL byte")?;
+        let c_user = CString::new(user).context("user name contains a NUL byte")?;
+
+        let mut handle = ptr::null_mut();
+        let ret = unsafe {
+            pam_start(
+                c_service.as_ptr(),
+                c_user.as_ptr(),
+                &CONVERSATION,
+                &mut handle,
+            )
+        };
+        if ret != PAM_SUCCESS {
+            bail!("pam_start({}, {}) failed with code {}", service, user, ret);
+        }
+
+        let ret = unsafe { pam_open_session(handle, 0) };
+        if ret != PAM_SUCCESS {
+            let message = error_message(handle, ret);
+            unsafe { pam_end(handle, ret) };
+            bail!(
+                "cannot open PAM session {} for {}: {}",
+                service,
+                user,
+                message
+            );
+        }
+        log::debug!("opened PAM session {} for {}", service, user);
+
+        let closer =
+            sync::Channel::to_child().and_then(|channel| Ok((channel, unsafe { fork() }?)));
+        let (channel, closer) = match closer {
+            Ok((channel, ForkResult::Child)) => {
+                channel.close_parent_end();
+                // Nothing is ever written, this returns once rsjail closes its
+                // end or dies
+                let _ = sync::wait_released(&channel.child);
+                close(handle);
+                unsafe { libc::_exit(0) };
+            }
+            Ok((channel, ForkResult::Parent { child })) => (channel, child),
+            Err(e) => {
+                close(handle);
+                return Err(e.into());
+            }
+        };
+        // The closer owns the session now, this is only our copy of it
+        unsafe { pam_end(handle, PAM_SUCCESS | PAM_DATA_SILENT) };
+
+        Ok(Self {
+            closer,
+            closer_wait: Some(channel.parent),
+        })
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        drop(self.closer_wait.take());
+        let _ = waitpid(self.closer, None);
+    }
+}
+
+fn close(handle: *mut PamHandle) {
+    let ret = unsafe { pam_close_session(handle, 0) };
+    if ret != PAM_SUCCESS {
+        log::warn!("cannot close PAM session: {}", error_message(handle, ret));
+    }
+    unsafe { pam_end(handle, ret) };
+}
+
+fn error_message(handle: *mut PamHandle, errnum: c_int) -> String {
+    let message = unsafe { pam_strerror(handle, errnum) };
+    if message.is_null() {
+        return format!("error {}", errnum);
+    }
+    unsafe { CStr::from_ptr(message) }
+        .to_string_lossy()
+        .into_owned()
+}
@@ -0,0 +1,162 @@
+use nix::sched::CloneFlags;
+use nix::sys::signal::Signal;
+use nix::sys::wait::WaitStatus;
+use nix::unistd::Pid;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// Whether this kernel supports managing children through pidfds: opening
+/// one (5.3) and waiting on one with waitid(P_PIDFD) (5.4). Probed once.
+pub fn available() -> bool {
+    static AVAILABLE: OnceLock<bool> = OnceLock::new();
+
+    *AVAILABLE.get_or_init(|| {
+        let Ok(pidfd) = open(nix::unistd::getpid()) else {
+            return false;
+        };
+        // We are not our own child, so a kernel that understands P_PIDFD
+        // answers ECHILD; older ones reject the id type with EINVAL
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let ret = unsafe {
+            libc::waitid(
+                libc::P_PIDFD,
+                pidfd.as_raw_fd() as libc::id_t,
+                &mut info,
+                libc::WEXITED | libc::WNOHANG,
+            )
+        };
+        ret == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::ECHILD)
+    })
+}
+
+/// struct clone_args as of Linux 5.3, which libc doesn't have
+#[repr(C)]
+#[derive(Default)]
+struct CloneArgs {
+    flags: u64,
+    pidfd: u64,
+    child_tid: u64,
+    parent_tid: u64,
+    exit_signal: u64,
+    stack: u64,
+    stack_size: u64,
+    tls: u64,
+}
+
+/// Where `clone3` returns.
+#[derive(Debug)]
+pub enum Cloned {
+    /// With the child's pid and a pidfd for it
+    Parent(Pid, OwnedFd),
+    Child,
+}
+
+/// Fork into `namespaces` with clone3(2), which hands back a pidfd for the
+/// child in the same call, before anything could reap it. Fails with
+/// ENOSYS before Linux 5.3 and under seccomp profiles that block clone3.
+///
+/// # Safety
+///
+/// As for fork(2): the child must stick to async-signal-safe calls if the
+/// process has other threads, then exec or exit.
+pub unsafe fn clone3(namespaces: CloneFlags) -> io::Result<Cloned> {
+    let mut pidfd: libc::c_int = -1;
+    let mut args = CloneArgs {
+        flags: namespaces.bits() as u64 | libc::CLONE_PIDFD as u64,
+        pidfd: &mut pidfd as *mut libc::c_int as u64,
+        exit_signal: libc::SIGCHLD as u64,
+        ..Default::default()
+    };
+    let pid = unsafe {
+        libc::syscall(
+            libc::SYS_clone3,
+            &mut args as *mut CloneArgs,
+            std::mem::size_of::<CloneArgs>(),
+        )
+    };
+    match pid {
+        0 => Ok(Cloned::Child),
+        pid if pid < 0 => Err(io::Error::last_os_error()),
+        pid => {
+            let pidfd = unsafe { OwnedFd::from_raw_fd(pidfd) };
+            Ok(Cloned::Parent(Pid::from_raw(pid as i32), pidfd))
+        }
+    }
+}
+
+/// Open a pidfd for `pid`. It keeps referring to that process even after
+/// its pid is reused.
+pub fn open(pid: Pid) -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid.as_raw(), 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+/// Deliver `signal` to the process behind `pidfd`.
+pub fn send_signal(pidfd: &OwnedFd, signal: Signal) -> io::Result<()> {
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_pidfd_send_signal,
+            pidfd.as_raw_fd(),
+            signal as libc::c_int,
+            std::ptr::null::<libc::siginfo_t>(),
+            0,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Wait up to `timeout` (forever if None) for the process to exit. Returns
+/// whether it did.
+pub fn wait_exit(pidfd: &OwnedFd, timeout: Option<Duration>) -> io::Result<bool> {
+    crate::sync::wait_readable(pidfd, timeout)
+}
+
+/// Reap the process behind `pidfd`, blocking until it has exited, and
+/// return its status along with its resource usage.
+pub fn reap(pidfd: &OwnedFd) -> io::Result<(WaitStatus, libc::rusage)> {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+
+    // The raw syscall, unlike the libc wrapper, also reports rusage
+    loop {
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_waitid,
+                libc::P_PIDFD,
+                pidfd.as_raw_fd(),
+                &mut info as *mut libc::siginfo_t,
+                libc::WEXITED,
+                &mut usage as *mut libc::rusage,
+            )
+        };
+        if ret == 0 {
+            break;
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+
+    let pid = Pid::from_raw(unsafe { info.si_pid() });
+    let status = unsafe { info.si_status() };
+    let status = match info.si_code {
+        libc::CLD_EXITED => WaitStatus::Exited(pid, status),
+        libc::CLD_KILLED | libc::CLD_DUMPED => WaitStatus::Signaled(
+            pid,
+            Signal::try_from(status).map_err(io::Error::from)?,
+            info.si_code == libc::CLD_DUMPED,
+        ),
+        _ => WaitStatus::StillAlive,
+    };
+
+    Ok((status, usage))
+}
@@ -0,0 +1,130 @@
+use crate::sync;
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::thread::JoinHandle;
+
+/// Pipes for the jailed program's stdout and stderr, whose lines the parent
+/// copies to its own with a prefix, so several jails writing to the same
+/// terminal or log can be told apart.
+#[derive(Debug)]
+pub struct OutputPipes {
+    stdout: sync::Channel,
+    stderr: sync::Channel,
+}
+
+impl OutputPipes {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            stdout: sync::Channel::to_parent()?,
+            stderr: sync::Channel::to_parent()?,
+        })
+    }
+
+    /// Make the pipes the child's stdout and stderr. Only for the child,
+    /// right after the fork.
+    pub fn redirect_child(&self) -> io::Result<()> {
+        self.stdout.close_parent_end();
+        self.stderr.close_parent_end();
+        let redirects = [
+            (&self.stdout, libc::STDOUT_FILENO),
+            (&self.stderr, libc::STDERR_FILENO),
+        ];
+        for (pipe, fd) in redirects {
+            // The copy is inherited across exec, unlike the pipe itself
+            if unsafe { libc::dup2(pipe.child.as_raw_fd(), fd) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Start copying what the child writes, each line prefixed with
+    /// `prefix`. Drops our copies of the child's ends, so the copying stops
+    /// once the child and whatever inherited them are gone.
+    pub fn relay(self, prefix: &str) -> Result<OutputRelay> {
+        let threads = [
+            (self.stdout.parent, Stream::Stdout),
+            (self.stderr.parent, Stream::Stderr),
+        ]
+        .into_iter()
+        .map(|(pipe, stream)| {
+            let prefix = prefix.to_string();
+            std::thread::Builder::new()
+                .name("rsjail-output".to_string())
+                .spawn(move || copy_lines(pipe, stream, &prefix))
+                .context("cannot start the output relay")
+        })
+        .collect::<Result<_>>()?;
+        Ok(OutputRelay { threads })
+    }
+}
+
+/// The threads started by `OutputPipes::relay`.
+#[derive(Debug)]
+pub struct OutputRelay {
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl OutputRelay {
+    /// Wait until everything written to the pipes has been copied.
+    pub fn finish(self) {
+        for thread in self.threads {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+fn copy_lines(pipe: OwnedFd, stream: Stream, prefix: &str) {
+    let pipe = BufReader::new(File::from(pipe));
+    // Nowhere left to write is no reason to warn
+    let mut write_failed = false;
+    let copied = prefix_lines(pipe, prefix, |line| {
+        let written = match stream {
+            Stream::Stdout => io::stdout().lock().write_all(line),
+            Stream::Stderr => io::stderr().lock().write_all(line),
+        };
+        write_failed = written.is_err();
+        written
+    });
+    if let Err(e) = copied
+        && !write_failed
+    {
+        log::warn!("cannot read the jail's output: {}", e);
+    }
+}
+
+/// Hand each line of `input` to `write` with `prefix` in front, in one
+/// call per line so lines of different jails don't interleave.
+pub fn prefix_lines(
+    mut input: impl BufRead,
+    prefix: &str,
+    mut write: impl FnMut(&[u8]) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match input.read_until(b'\n', &mut line) {
+            Ok(0) => return Ok(()),
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+        // A last line without a newline still ends one, so the next
+        // prefix starts a line of its own
+        if !line.ends_with(b"\n") {
+            line.push(b'\n');
+        }
+        let mut prefixed = Vec::with_capacity(prefix.len() + line.len());
+        prefixed.extend_from_slice(prefix.as_bytes());
+        prefixed.extend_from_slice(&line);
+        write(&prefixed)?;
+    }
+}
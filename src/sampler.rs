@@ -0,0 +1,112 @@
+use anyhow::{Context, Result};
+use nix::unistd::Pid;
+use std::fs;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// The most memory the jail's processes were seen using, across periodic
+/// reads of /proc/<pid>/status.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryPeaks {
+    /// Largest VmPeak (peak virtual size) of any one process, in kilobytes
+    pub vm_peak_kb: u64,
+    /// Largest VmHWM (peak resident size) of any one process, in kilobytes
+    pub vm_hwm_kb: u64,
+    /// Largest VmRSS summed over every process in one sample, in kilobytes
+    pub total_rss_kb: u64,
+    /// How many samples were taken
+    pub samples: u64,
+}
+
+impl MemoryPeaks {
+    /// Fold in the current status of `pid` and everything below it.
+    pub fn sample(&mut self, pid: Pid) {
+        let rsjail = fs::read_link("/proc/self/exe").ok();
+        let mut total_rss_kb = 0;
+        for pid in with_descendants(pid) {
+            // Not yet exec'd, the memory is still rsjail's
+            if rsjail.is_some() && fs::read_link(format!("/proc/{}/exe", pid)).ok() == rsjail {
+                continue;
+            }
+            let Ok(status) = fs::read_to_string(format!("/proc/{}/status", pid)) else {
+                continue;
+            };
+            let field = |name| status_field(&status, name).unwrap_or(0);
+            self.vm_peak_kb = self.vm_peak_kb.max(field("VmPeak"));
+            self.vm_hwm_kb = self.vm_hwm_kb.max(field("VmHWM"));
+            total_rss_kb += field("VmRSS");
+        }
+        self.total_rss_kb = self.total_rss_kb.max(total_rss_kb);
+        self.samples += 1;
+    }
+}
+
+/// A thread in the parent that samples the child's memory every interval
+/// until it is finished.
+#[derive(Debug)]
+pub struct MemorySampler {
+    stop: mpsc::Sender<()>,
+    thread: JoinHandle<MemoryPeaks>,
+}
+
+impl MemorySampler {
+    pub fn start(pid: Pid, interval: Duration) -> Result<Self> {
+        let (stop, stopped) = mpsc::channel();
+        let thread = std::thread::Builder::new()
+            .name("rsjail-memory".to_string())
+            .spawn(move || {
+                let mut peaks = MemoryPeaks::default();
+                loop {
+                    peaks.sample(pid);
+                    if stopped.recv_timeout(interval) != Err(RecvTimeoutError::Timeout) {
+                        return peaks;
+                    }
+                }
+            })
+            .context("cannot start the memory sampler")?;
+        Ok(Self { stop, thread })
+    }
+
+    /// Stop sampling and return the peaks seen.
+    pub fn finish(self) -> MemoryPeaks {
+        drop(self.stop);
+        self.thread.join().unwrap_or_default()
+    }
+}
+
+/// The value of a "Name:   1234 kB" line of /proc/<pid>/status.
+fn status_field(status: &str, name: &str) -> Option<u64> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// `pid` and all its descendants, found through the children of each of
+/// their threads.
+fn with_descendants(pid: Pid) -> Vec<Pid> {
+    let mut pids = vec![pid];
+    let mut next = 0;
+    while let Some(&pid) = pids.get(next) {
+        next += 1;
+        let Ok(tasks) = fs::read_dir(format!("/proc/{}/task", pid)) else {
+            continue;
+        };
+        for task in tasks.filter_map(|task| task.ok()) {
+            let Ok(children) = fs::read_to_string(task.path().join("children")) else {
+                continue;
+            };
+            pids.extend(
+                children
+                    .split_whitespace()
+                    .filter_map(|child| child.parse().ok())
+                    .map(Pid::from_raw),
+            );
+        }
+    }
+    pids
+}
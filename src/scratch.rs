@@ -0,0 +1,332 @@
+use anyhow::{bail, Context, Result};
+use nix::errno::Errno;
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use nix::unistd::Pid;
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::fs::{self, DirBuilder};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::{lchown, symlink, DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static RUN_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Where `ip netns` keeps the net namespaces it names
+const NETNS_DIR: &str = "/var/run/netns";
+
+/// Private per-run directory for ephemeral jail storage, removed on drop.
+#[derive(Debug)]
+pub struct ScratchDir {
+    path: PathBuf,
+}
+
+impl ScratchDir {
+    /// Create a fresh `rsjail-<pid>-<n>` directory under `base`, readable
+    /// only by the current user.
+    pub fn create(base: &Path) -> Result<Self> {
+        fs::create_dir_all(base)
+            .with_context(|| format!("cannot create scratch base {}", base.display()))?;
+
+        let run = RUN_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = base.join(format!("rsjail-{}-{}", std::process::id(), run));
+        DirBuilder::new()
+            .mode(0o700)
+            .create(&path)
+            .with_context(|| format!("cannot create scratch dir {}", path.display()))?;
+
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        if let Err(e) = remove_dir_all_same_fs(&self.path) {
+            log::warn!(
+                "failed to remove scratch dir {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// A fixed-size filesystem image, loop mounted so that nothing written
+/// below its mount point can grow past it. Unmounted, detached from its
+/// loop device and removed on drop.
+#[derive(Debug)]
+pub struct QuotaImage {
+    image: PathBuf,
+    device: String,
+    mount_point: PathBuf,
+}
+
+impl QuotaImage {
+    /// Create a sparse `size` byte image at `image`, format it with
+    /// mkfs.<fstype> and mount it at `mount_point`, created if missing.
+    pub fn create(image: &Path, mount_point: &Path, size: u64, fstype: &str) -> Result<Self> {
+        fs::File::create_new(image)
+            .and_then(|file| file.set_len(size))
+            .with_context(|| format!("cannot create disk image {}", image.display()))?;
+        let mkfs = format!("mkfs.{}", fstype);
+        run(Command::new(&mkfs).arg("-q").arg(image))?;
+        let mut losetup = Command::new("losetup");
+        let device = run(losetup.args(["--find", "--show"]).arg(image))?;
+        let quota = Self {
+            image: image.to_path_buf(),
+            device: device.trim().to_string(),
+            mount_point: mount_point.to_path_buf(),
+        };
+
+        fs::create_dir_all(mount_point)?;
+        mount(
+            Some(quota.device.as_str()),
+            mount_point,
+            Some(fstype),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            None::<&str>,
+        )
+        .with_context(|| format!("cannot mount {} at {}", quota.device, mount_point.display()))?;
+        log::debug!(
+            "mounted {} bytes of {} on {} at {}",
+            size,
+            fstype,
+            quota.device,
+            mount_point.display()
+        );
+        Ok(quota)
+    }
+}
+
+impl Drop for QuotaImage {
+    fn drop(&mut self) {
+        // EINVAL: never mounted, or only in the setup thread's mount
+        // namespace with thread_mode, where it goes with the namespace
+        match umount2(&self.mount_point, MntFlags::MNT_DETACH) {
+            Ok(()) | Err(Errno::EINVAL) => {}
+            Err(e) => log::warn!("cannot unmount {}: {}", self.mount_point.display(), e),
+        }
+        // A loop device still mounted somewhere is detached once it no
+        // longer is
+        if let Err(e) = run(Command::new("losetup").arg("--detach").arg(&self.device)) {
+            log::warn!("{:#}", e);
+        }
+        if let Err(e) = fs::remove_file(&self.image) {
+            log::warn!("cannot remove disk image {}: {}", self.image.display(), e);
+        }
+    }
+}
+
+/// The mounts a run leaves below `root` in our own mount namespace, as
+/// without clone_newns or with unshare_in_parent the child's mounts land
+/// there. Whatever is mounted below `root` that wasn't at `new` is detached,
+/// mounted last first, by `unmount` or at the latest on drop, panics and
+/// early returns included.
+#[derive(Debug)]
+pub struct MountGuard {
+    root: PathBuf,
+    /// Mount ids that were there at `new` and are left alone
+    before: BTreeSet<u64>,
+}
+
+impl MountGuard {
+    pub fn new(root: &Path) -> Self {
+        let root = fs::canonicalize(root).unwrap_or_else(|_| root.to_path_buf());
+        let before = mount_table().into_iter().map(|(id, _)| id).collect();
+        Self { root, before }
+    }
+
+    /// Detach the run's mounts and return how many there were. Can be
+    /// called again, when there are none left.
+    pub fn unmount(&mut self) -> usize {
+        let mounts: Vec<(u64, PathBuf)> = mount_table()
+            .into_iter()
+            .filter(|(id, point)| !self.before.contains(id) && point.starts_with(&self.root))
+            .collect();
+        for (id, point) in mounts.iter().rev() {
+            log::debug!("unmounting {}", point.display());
+            // EINVAL: already gone along with a mount above it
+            match umount2(point, MntFlags::MNT_DETACH) {
+                Ok(()) | Err(Errno::EINVAL) | Err(Errno::ENOENT) => {}
+                Err(e) => log::warn!("cannot unmount {}: {}", point.display(), e),
+            }
+            // Not retried, whatever the outcome
+            self.before.insert(*id);
+        }
+        mounts.len()
+    }
+}
+
+impl Drop for MountGuard {
+    fn drop(&mut self) {
+        self.unmount();
+    }
+}
+
+/// A net namespace bound to a file in NETNS_DIR, by which `ip netns` and
+/// friends know it. Unmounted and removed on drop.
+#[derive(Debug)]
+pub struct NetnsBinding {
+    path: PathBuf,
+}
+
+impl NetnsBinding {
+    /// Bind the net namespace of `pid` as `name`, which must not be taken.
+    pub fn create(pid: Pid, name: &str) -> Result<Self> {
+        fs::create_dir_all(NETNS_DIR).with_context(|| format!("cannot create {}", NETNS_DIR))?;
+        let path = Path::new(NETNS_DIR).join(name);
+        fs::File::create_new(&path).with_context(|| {
+            format!("cannot create {}, is netns {} taken?", path.display(), name)
+        })?;
+        // From here on dropping it removes the file again
+        let binding = Self { path };
+        let target = binding.path.display();
+        mount(
+            Some(Path::new(&format!("/proc/{}/ns/net", pid))),
+            &binding.path,
+            None::<&str>,
+            MsFlags::MS_BIND,
+            None::<&str>,
+        )
+        .with_context(|| format!("cannot bind the jail's net namespace to {}", target))?;
+        log::debug!("bound the net namespace of {} to {}", pid, target);
+        Ok(binding)
+    }
+}
+
+impl Drop for NetnsBinding {
+    fn drop(&mut self) {
+        // EINVAL: the mount failed, only the file is there
+        match umount2(&self.path, MntFlags::MNT_DETACH) {
+            Ok(()) | Err(Errno::EINVAL) => {}
+            Err(e) => log::warn!("cannot unmount {}: {}", self.path.display(), e),
+        }
+        if let Err(e) = fs::remove_file(&self.path) {
+            log::warn!("cannot remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// The id and mount point of every mount in the calling thread's mount
+/// namespace, in the order they were mounted.
+fn mount_table() -> Vec<(u64, PathBuf)> {
+    let Ok(mountinfo) = fs::read_to_string("/proc/thread-self/mountinfo") else {
+        return Vec::new();
+    };
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let id = fields.next()?.parse().ok()?;
+            let point = fields.nth(3)?;
+            Some((id, PathBuf::from(unescape_mountinfo(point))))
+        })
+        .collect()
+}
+
+/// Undo the octal escapes (\040 for a space) of a mountinfo path.
+fn unescape_mountinfo(path: &str) -> OsString {
+    let bytes = path.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = bytes.get(i + 1..i + 4);
+        if bytes[i] == b'\\'
+            && let Some(digits) = escape
+            && let Ok(byte) = u8::from_str_radix(&String::from_utf8_lossy(digits), 8)
+        {
+            unescaped.push(byte);
+            i += 4;
+        } else {
+            unescaped.push(bytes[i]);
+            i += 1;
+        }
+    }
+    OsString::from_vec(unescaped)
+}
+
+/// Run `command` and return its stdout, failing with its stderr.
+fn run(command: &mut Command) -> Result<String> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let output = match command.output() {
+        Ok(output) => output,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            bail!(
+                "disk_quota needs the {} command, which is not installed",
+                program
+            )
+        }
+        Err(e) => return Err(e).with_context(|| format!("cannot run {}", program)),
+    };
+    if !output.status.success() {
+        bail!(
+            "{} failed ({}): {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Like `fs::remove_dir_all`, but never descends into anything mounted on a
+/// different filesystem, so a leftover bind mount can't take host data with it.
+fn remove_dir_all_same_fs(path: &Path) -> std::io::Result<()> {
+    let root_dev = fs::symlink_metadata(path)?.dev();
+    remove_tree(path, root_dev)
+}
+
+fn remove_tree(path: &Path, root_dev: u64) -> std::io::Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            if metadata.dev() != root_dev {
+                return Err(std::io::Error::other(format!(
+                    "{} is still a mount point",
+                    entry.path().display()
+                )));
+            }
+            remove_tree(&entry.path(), root_dev)?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    fs::remove_dir(path)
+}
+
+/// Copy the tree at `src` into `dst`, keeping modes, owners and symlinks.
+/// Sockets, fifos and device nodes are left out.
+pub fn copy_tree(src: &Path, dst: &Path) -> std::io::Result<()> {
+    let metadata = fs::symlink_metadata(src)?;
+    fs::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let (from, to) = (entry.path(), dst.join(entry.file_name()));
+        // Doesn't follow symlinks
+        let metadata = entry.metadata()?;
+        let file_type = metadata.file_type();
+        if file_type.is_dir() {
+            copy_tree(&from, &to)?;
+            continue;
+        }
+        if file_type.is_symlink() {
+            symlink(fs::read_link(&from)?, &to)?;
+        } else if file_type.is_file() {
+            fs::copy(&from, &to)?;
+            fs::set_permissions(&to, metadata.permissions())?;
+        } else {
+            log::debug!("not copying special file {}", from.display());
+            continue;
+        }
+        lchown(&to, Some(metadata.uid()), Some(metadata.gid()))?;
+    }
+    // Last, in case the mode doesn't let us write into it
+    lchown(dst, Some(metadata.uid()), Some(metadata.gid()))?;
+    fs::set_permissions(dst, metadata.permissions())
+}
@@ -0,0 +1,433 @@
+use crate::config::{SeccompAction, SeccompPolicy};
+use anyhow::{Context, Result};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::thread::JoinHandle;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+/// x32 syscalls share x86_64's audit arch, with this bit set in the number
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// _IOWR('!', nr, T), as the kernel headers define the listener's ioctls
+const fn notif_ioctl<T>(nr: u64) -> u64 {
+    (3 << 30) | ((size_of::<T>() as u64) << 16) | ((b'!' as u64) << 8) | nr
+}
+const SECCOMP_IOCTL_NOTIF_RECV: u64 = notif_ioctl::<libc::seccomp_notif>(0);
+const SECCOMP_IOCTL_NOTIF_SEND: u64 = notif_ioctl::<libc::seccomp_notif_resp>(1);
+
+/// Offsets into struct seccomp_data
+const DATA_NR: u32 = 0;
+const DATA_ARCH: u32 = 4;
+
+/// The number of the syscall `name` on this architecture, among those
+/// syscall_limits can count and seccomp_policy filter.
+pub fn syscall_number(name: &str) -> Option<libc::c_long> {
+    Some(match name {
+        "clone" => libc::SYS_clone,
+        "clone3" => libc::SYS_clone3,
+        #[cfg(target_arch = "x86_64")]
+        "fork" => libc::SYS_fork,
+        #[cfg(target_arch = "x86_64")]
+        "vfork" => libc::SYS_vfork,
+        "execve" => libc::SYS_execve,
+        "execveat" => libc::SYS_execveat,
+        "kill" => libc::SYS_kill,
+        "tkill" => libc::SYS_tkill,
+        "tgkill" => libc::SYS_tgkill,
+        "ptrace" => libc::SYS_ptrace,
+        "socket" => libc::SYS_socket,
+        "connect" => libc::SYS_connect,
+        "bind" => libc::SYS_bind,
+        "accept" => libc::SYS_accept,
+        "accept4" => libc::SYS_accept4,
+        "sendto" => libc::SYS_sendto,
+        "sendmsg" => libc::SYS_sendmsg,
+        "openat" => libc::SYS_openat,
+        "unlinkat" => libc::SYS_unlinkat,
+        "mkdirat" => libc::SYS_mkdirat,
+        "mmap" => libc::SYS_mmap,
+        _ => return None,
+    })
+}
+
+fn statement(code: u32, k: u32) -> libc::sock_filter {
+    jump(code, k, 0, 0)
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+/// The start of each filter: load the syscall number, but kill the process
+/// for syscalls of another architecture, which would otherwise slip past
+/// the filter under other numbers.
+fn filter_prologue(arch: u32) -> Vec<libc::sock_filter> {
+    let kill = libc::SECCOMP_RET_KILL_PROCESS;
+    let mut filter = vec![
+        statement(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, DATA_ARCH),
+        jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, arch, 1, 0),
+        statement(libc::BPF_RET | libc::BPF_K, kill),
+        statement(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, DATA_NR),
+    ];
+    #[cfg(target_arch = "x86_64")]
+    filter.extend([
+        jump(
+            libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+            X32_SYSCALL_BIT,
+            0,
+            1,
+        ),
+        statement(libc::BPF_RET | libc::BPF_K, kill),
+    ]);
+    filter
+}
+
+/// A filter that passes each of `syscalls` to the listener and allows the
+/// rest.
+fn notify_filter(arch: u32, syscalls: &[libc::c_long]) -> Vec<libc::sock_filter> {
+    let mut filter = filter_prologue(arch);
+    for (i, &nr) in syscalls.iter().enumerate() {
+        // Past the remaining comparisons and the ALLOW to the USER_NOTIF
+        let to_notify = (syscalls.len() - i) as u8;
+        filter.push(jump(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            nr as u32,
+            to_notify,
+            0,
+        ));
+    }
+    filter.extend([
+        statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW),
+        statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_USER_NOTIF),
+    ]);
+    filter
+}
+
+/// The filter return value for `action`.
+fn action_value(action: SeccompAction) -> u32 {
+    match action {
+        SeccompAction::Allow => libc::SECCOMP_RET_ALLOW,
+        SeccompAction::Kill => libc::SECCOMP_RET_KILL_PROCESS,
+        SeccompAction::Errno => libc::SECCOMP_RET_ERRNO | libc::EPERM as u32,
+        SeccompAction::Trap => libc::SECCOMP_RET_TRAP,
+        SeccompAction::Log => libc::SECCOMP_RET_LOG,
+    }
+}
+
+/// The filter for `policy`: each of its syscalls gets its action, the rest
+/// the default one. Fails for names `syscall_number` doesn't know.
+pub fn policy_filter(policy: &SeccompPolicy) -> Result<Vec<libc::sock_filter>> {
+    let Some(arch) = AUDIT_ARCH else {
+        anyhow::bail!("seccomp filters are not supported on this architecture");
+    };
+    let mut filter = filter_prologue(arch);
+    for (name, &action) in &policy.syscalls {
+        let nr = syscall_number(name)
+            .with_context(|| format!("cannot filter syscall {:?} on this architecture", name))?;
+        // The return right after is this syscall's, the rest skip it
+        filter.extend([
+            jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, nr as u32, 0, 1),
+            statement(libc::BPF_RET | libc::BPF_K, action_value(action)),
+        ]);
+    }
+    filter.push(statement(
+        libc::BPF_RET | libc::BPF_K,
+        action_value(policy.default_action),
+    ));
+    Ok(filter)
+}
+
+/// Apply `policy` to the calling process and everything it starts from
+/// then on.
+pub fn install_policy(policy: &SeccompPolicy) -> Result<()> {
+    install(&policy_filter(policy)?, 0).context("cannot install the seccomp_policy filter")?;
+    Ok(())
+}
+
+/// Stop the calling process and everything it starts from then on at each
+/// of `syscalls` until the returned listener lets it go on.
+pub fn notify_listener(syscalls: &[libc::c_long]) -> Result<OwnedFd> {
+    let Some(arch) = AUDIT_ARCH else {
+        anyhow::bail!("seccomp filters are not supported on this architecture");
+    };
+    // Before Linux 5.0 there are no listeners
+    let fd = install(
+        &notify_filter(arch, syscalls),
+        libc::SECCOMP_FILTER_FLAG_NEW_LISTENER,
+    )
+    .context("cannot install the seccomp filter")?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+/// Install `filter` with `flags`, returning what seccomp(2) does. Sets
+/// no_new_privs first, like Landlock, as an unprivileged process must.
+fn install(filter: &[libc::sock_filter], flags: libc::c_ulong) -> io::Result<libc::c_long> {
+    let program = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_ptr() as *mut libc::sock_filter,
+    };
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            flags,
+            &program as *const libc::sock_fprog,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret)
+}
+
+/// A socket pair over which the child sends the parent its seccomp
+/// listener. The listener is close-on-exec, so it has to be passed before
+/// the exec; once sent it lives on in the parent of its own.
+#[derive(Debug)]
+pub struct ListenerSocket {
+    pub parent: OwnedFd,
+    pub child: OwnedFd,
+}
+
+impl ListenerSocket {
+    pub fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        let ret = unsafe {
+            libc::socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_STREAM | libc::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            parent: unsafe { OwnedFd::from_raw_fd(fds[0]) },
+            child: unsafe { OwnedFd::from_raw_fd(fds[1]) },
+        })
+    }
+}
+
+/// Room for one fd's SCM_RIGHTS message, aligned like a cmsghdr
+type ControlBuffer = [u64; 4];
+
+/// Send `listener` over the child's end of a `ListenerSocket`.
+pub fn send_listener(socket: RawFd, listener: &OwnedFd) -> io::Result<()> {
+    let mut byte = [0u8];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr().cast(),
+        iov_len: 1,
+    };
+    let mut control: ControlBuffer = [0; 4];
+    let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr().cast();
+    message.msg_controllen = unsafe { libc::CMSG_SPACE(size_of::<RawFd>() as u32) } as _;
+    unsafe {
+        let header = libc::CMSG_FIRSTHDR(&message);
+        (*header).cmsg_level = libc::SOL_SOCKET;
+        (*header).cmsg_type = libc::SCM_RIGHTS;
+        (*header).cmsg_len = libc::CMSG_LEN(size_of::<RawFd>() as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(header).cast(), listener.as_raw_fd());
+    }
+    if unsafe { libc::sendmsg(socket, &message, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Receive the listener sent with `send_listener`, or None once the child
+/// has closed its end without sending one.
+fn receive_listener(socket: &OwnedFd) -> io::Result<Option<OwnedFd>> {
+    let mut byte = [0u8];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr().cast(),
+        iov_len: 1,
+    };
+    let mut control: ControlBuffer = [0; 4];
+    let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr().cast();
+    message.msg_controllen = size_of::<ControlBuffer>() as _;
+    loop {
+        let received =
+            unsafe { libc::recvmsg(socket.as_raw_fd(), &mut message, libc::MSG_CMSG_CLOEXEC) };
+        if received == 0 {
+            return Ok(None);
+        }
+        if received > 0 {
+            break;
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+    unsafe {
+        let header = libc::CMSG_FIRSTHDR(&message);
+        if header.is_null() || (*header).cmsg_type != libc::SCM_RIGHTS {
+            return Ok(None);
+        }
+        let fd: RawFd = std::ptr::read_unaligned(libc::CMSG_DATA(header).cast());
+        Ok(Some(OwnedFd::from_raw_fd(fd)))
+    }
+}
+
+/// A thread in the parent that answers the child's seccomp notifications,
+/// counting each limited syscall across every process of the jail, and
+/// kills the child once one goes over its limit.
+#[derive(Debug)]
+pub struct SyscallGuard {
+    /// Closing ours stops the thread
+    stop: OwnedFd,
+    thread: JoinHandle<Option<String>>,
+}
+
+impl SyscallGuard {
+    /// Wait for the listener on `socket` in a new thread and enforce
+    /// `limits` from there on. Drops our copy of the child's end.
+    pub fn start(
+        socket: ListenerSocket,
+        child: Pid,
+        limits: &BTreeMap<String, u64>,
+    ) -> Result<Self> {
+        drop(socket.child);
+        let parent = socket.parent;
+        // Opened before anything can reap the child, like Launched's
+        let pidfd = crate::pidfd::open(child).ok();
+        let limits: HashMap<libc::c_long, (String, u64)> = limits
+            .iter()
+            .filter_map(|(name, &limit)| Some((syscall_number(name)?, (name.clone(), limit))))
+            .collect();
+        let (stopped, stop) = nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC)?;
+        let thread = std::thread::Builder::new()
+            .name("rsjail-seccomp".to_string())
+            .spawn(move || match receive_listener(&parent) {
+                Ok(Some(listener)) => {
+                    supervise(&listener, &stopped, (child, pidfd.as_ref()), &limits)
+                }
+                Ok(None) => None,
+                Err(e) => {
+                    log::warn!("cannot receive the seccomp listener: {}", e);
+                    None
+                }
+            })
+            .context("cannot start the syscall guard")?;
+        Ok(Self { stop, thread })
+    }
+
+    /// Stop answering and return the syscall the jail was killed for going
+    /// over, if any. Processes of the jail still running get ENOSYS from
+    /// the limited syscalls from then on.
+    pub fn finish(self) -> Option<String> {
+        drop(self.stop);
+        self.thread.join().ok().flatten()
+    }
+}
+
+fn supervise(
+    listener: &OwnedFd,
+    stopped: &OwnedFd,
+    child: (Pid, Option<&OwnedFd>),
+    limits: &HashMap<libc::c_long, (String, u64)>,
+) -> Option<String> {
+    let mut counts: HashMap<libc::c_long, u64> = HashMap::new();
+    let mut hit = None;
+    loop {
+        let mut fds = [
+            libc::pollfd {
+                fd: listener.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: stopped.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        if unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) } < 0 {
+            if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return hit;
+        }
+        // Stopped, or every process under the filter is gone
+        if fds[1].revents != 0 || fds[0].revents & libc::POLLIN == 0 {
+            return hit;
+        }
+
+        // The kernel insists on a zeroed buffer
+        let mut notification: libc::seccomp_notif = unsafe { std::mem::zeroed() };
+        if unsafe {
+            libc::ioctl(
+                listener.as_raw_fd(),
+                SECCOMP_IOCTL_NOTIF_RECV as _,
+                &mut notification,
+            )
+        } < 0
+        {
+            // ENOENT: the caller died while we were on our way
+            continue;
+        }
+        let nr = notification.data.nr as libc::c_long;
+        let mut response = libc::seccomp_notif_resp {
+            id: notification.id,
+            val: 0,
+            error: 0,
+            flags: libc::SECCOMP_USER_NOTIF_FLAG_CONTINUE as u32,
+        };
+        if let Some((name, limit)) = limits.get(&nr) {
+            let count = counts.entry(nr).or_default();
+            *count += 1;
+            if *count > *limit {
+                if hit.is_none() {
+                    log::warn!(
+                        "{} called more than {} times, killing the jail",
+                        name,
+                        limit
+                    );
+                    hit = Some(name.clone());
+                    let _ = match child {
+                        (_, Some(pidfd)) => crate::pidfd::send_signal(pidfd, Signal::SIGKILL),
+                        (pid, None) => kill(pid, Signal::SIGKILL).map_err(io::Error::from),
+                    };
+                }
+                // Without a PID namespace the caller may not go down with
+                // the child
+                let _ = kill(Pid::from_raw(notification.pid as i32), Signal::SIGKILL);
+                response.error = -libc::EPERM;
+                response.flags = 0;
+            }
+        }
+        unsafe {
+            libc::ioctl(
+                listener.as_raw_fd(),
+                SECCOMP_IOCTL_NOTIF_SEND as _,
+                &response,
+            )
+        };
+    }
+}
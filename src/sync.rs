@@ -0,0 +1,122 @@
+use nix::fcntl::OFlag;
+use nix::unistd::pipe2;
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::time::Duration;
+
+/// A pipe the parent and the child coordinate over. rsjail uses one to let
+/// the child notice the parent is gone, one that reaches EOF once the child
+/// has exec'd (setup_timeout) and carries a byte if its setup failed, with
+/// `notify` one the jailed program reports readiness on, and with
+/// `cgroup_delegate` one that holds the child back until the parent has
+/// moved it into its cgroup.
+///
+/// Both ends are close-on-exec, so none of them leak into the jailed program;
+/// an end it is meant to keep has to be handed over with `inherit_on_exec`.
+/// Handshakes rely on EOF, so right after the fork the child closes the
+/// parent's end with `close_parent_end`, and the parent drops the child's
+/// end once the child is running.
+#[derive(Debug)]
+pub struct Channel {
+    pub parent: OwnedFd,
+    pub child: OwnedFd,
+}
+
+impl Channel {
+    /// A channel the child writes and the parent reads.
+    pub fn to_parent() -> io::Result<Self> {
+        let (read, write) = pipe2(OFlag::O_CLOEXEC)?;
+        Ok(Self {
+            parent: read,
+            child: write,
+        })
+    }
+
+    /// A channel the parent writes and the child reads.
+    pub fn to_child() -> io::Result<Self> {
+        let (read, write) = pipe2(OFlag::O_CLOEXEC)?;
+        Ok(Self {
+            parent: write,
+            child: read,
+        })
+    }
+
+    /// Close the child's copy of the parent's end. Only for the child, right
+    /// after the fork.
+    pub fn close_parent_end(&self) {
+        unsafe { libc::close(self.parent.as_raw_fd()) };
+    }
+}
+
+/// Clear close-on-exec on `fd` so the program exec'd next inherits it.
+pub fn inherit_on_exec(fd: RawFd) -> io::Result<()> {
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Wait up to `timeout` (forever if None) for `fd` to become readable, which
+/// includes hitting EOF. Returns whether it did.
+pub fn wait_readable(fd: &OwnedFd, timeout: Option<Duration>) -> io::Result<bool> {
+    let timeout_ms = match timeout {
+        // Round up so a sub-millisecond remainder doesn't become a busy loop
+        Some(timeout) => timeout.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32,
+        None => -1,
+    };
+    let mut poll_fd = libc::pollfd {
+        fd: fd.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+
+    loop {
+        match unsafe { libc::poll(&mut poll_fd, 1, timeout_ms) } {
+            ready if ready >= 0 => return Ok(ready > 0),
+            _ => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+/// Let the process blocked in `wait_released` on the other end go on.
+pub fn release(fd: &OwnedFd) -> io::Result<()> {
+    let written = unsafe { libc::write(fd.as_raw_fd(), b"1".as_ptr().cast(), 1) };
+    if written < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Block until the other end calls `release` (true) or is closed without
+/// doing so (false).
+pub fn wait_released(fd: &OwnedFd) -> io::Result<bool> {
+    let mut byte = 0u8;
+    loop {
+        match unsafe { libc::read(fd.as_raw_fd(), (&mut byte as *mut u8).cast(), 1) } {
+            read if read >= 0 => return Ok(read > 0),
+            _ => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+/// Whether every write end of the pipe `fd` reads from is closed, checked
+/// without blocking.
+pub fn is_hung_up(fd: &OwnedFd) -> bool {
+    let mut poll_fd = libc::pollfd {
+        fd: fd.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let ready = unsafe { libc::poll(&mut poll_fd, 1, 0) };
+    ready > 0 && poll_fd.revents & libc::POLLHUP != 0
+}
@@ -0,0 +1,131 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::os::fd::{OwnedFd, RawFd};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Reported with the CLOCK_MONOTONIC time right before the exec, which the
+/// parent times from there up to the pipe's EOF
+const EXEC: &str = "exec";
+
+/// How long one phase of the jail's setup took.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SetupPhase {
+    pub name: String,
+    pub duration: Duration,
+}
+
+/// Run `f` as the setup phase `phase`, reporting its duration on `fd`, the
+/// write end of the timings pipe, if there is one. Without one this is
+/// only the call.
+pub fn time<T>(fd: Option<RawFd>, phase: &str, f: impl FnOnce() -> T) -> T {
+    let Some(fd) = fd else {
+        return f();
+    };
+    let started = Instant::now();
+    let result = f();
+    record(fd, phase, started.elapsed());
+    result
+}
+
+/// Report that the exec is next, for the parent to time.
+pub fn exec_next(fd: Option<RawFd>) {
+    if let Some(fd) = fd {
+        write_line(fd, &format!("{} {}\n", EXEC, monotonic_ns()));
+    }
+}
+
+/// Report that `phase` took `duration`.
+pub fn record(fd: RawFd, phase: &str, duration: Duration) {
+    write_line(fd, &format!("{} {}\n", phase, duration.as_nanos()));
+}
+
+/// In one write so phases reported from different processes don't
+/// interleave. Timing is best effort, so errors are ignored.
+fn write_line(fd: RawFd, line: &str) {
+    unsafe { libc::write(fd, line.as_ptr().cast(), line.len()) };
+}
+
+fn monotonic_ns() -> i128 {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    now.tv_sec as i128 * 1_000_000_000 + now.tv_nsec as i128
+}
+
+/// A thread in the parent that collects the phases from the timings pipe
+/// until every write end is gone, which for the child's is at its exec.
+/// Once rsjail has unshared a PID namespace it can't start threads, so
+/// the pipe is only read at the end, and the exec goes untimed.
+#[derive(Debug)]
+pub enum SetupTimer {
+    Thread(JoinHandle<Vec<SetupPhase>>),
+    Pipe(OwnedFd),
+}
+
+impl SetupTimer {
+    /// `clock_offset` is how many seconds the child's CLOCK_MONOTONIC is
+    /// ahead of ours, time_offset_monotonic in a time namespace.
+    pub fn start(pipe: OwnedFd, clock_offset: i64) -> Result<Self> {
+        let unread = pipe.try_clone().context("cannot start the setup timer")?;
+        match std::thread::Builder::new()
+            .name("rsjail-timings".to_string())
+            .spawn(move || read_phases(pipe, Some(clock_offset)))
+        {
+            Ok(thread) => Ok(Self::Thread(thread)),
+            Err(e) => {
+                log::debug!("cannot start the setup timer thread: {}", e);
+                Ok(Self::Pipe(unread))
+            }
+        }
+    }
+
+    /// The phases in the order they ended. Only complete once the child
+    /// has exec'd or exited.
+    pub fn finish(self) -> Vec<SetupPhase> {
+        match self {
+            Self::Thread(thread) => thread.join().unwrap_or_default(),
+            Self::Pipe(pipe) => read_phases(pipe, None),
+        }
+    }
+}
+
+/// Without a `clock_offset` the pipe's EOF is long past, so there is no
+/// exec phase.
+fn read_phases(pipe: OwnedFd, clock_offset: Option<i64>) -> Vec<SetupPhase> {
+    let mut pipe = BufReader::new(File::from(pipe));
+    let mut phases = Vec::new();
+    let mut exec_started = None;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match pipe.read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        }
+        let Some((name, value)) = line.trim_end().split_once(' ') else {
+            continue;
+        };
+        if name == EXEC {
+            exec_started = value.parse::<i128>().ok();
+        } else if let Ok(nanos) = value.parse() {
+            phases.push(SetupPhase {
+                name: name.to_string(),
+                duration: Duration::from_nanos(nanos),
+            });
+        }
+    }
+    if let (Some(started), Some(offset)) = (exec_started, clock_offset) {
+        let nanos = monotonic_ns() - (started - offset as i128 * 1_000_000_000);
+        phases.push(SetupPhase {
+            name: EXEC.to_string(),
+            duration: Duration::from_nanos(nanos.clamp(0, u64::MAX as i128) as u64),
+        });
+    }
+    phases
+}
@@ -1,6 +1,5 @@
 use anyhow::Result;
-use rsjail::{JailConfig, MountConfig};
-use serde_json;
+use rsjail::{JailConfig, MountConfig, NetworkMode, Rlimit, SetupStep};
 use tempfile::TempDir;
 
 #[test]
@@ -17,19 +16,24 @@ fn test_config_serialization() {
         clone_newuts: true,
         clone_newipc: true,
         clone_newuser: true,
-        rlimit_as: Some(1024 * 1024),
-        rlimit_cpu: Some(10),
-        rlimit_nofile: Some(64),
+        rlimit_as: Some(Rlimit::Both(1024 * 1024)),
+        rlimit_cpu: Some(Rlimit::Both(10)),
+        rlimit_nofile: Some(Rlimit::Split {
+            soft: 64,
+            hard: 128,
+        }),
         mounts: vec![MountConfig {
             src: "/bin".to_string(),
             dst: "/bin".to_string(),
             fstype: None,
             is_bind: true,
             rw: false,
+            ..Default::default()
         }],
         uid: Some(1000),
         gid: Some(1000),
         time_limit: Some(30),
+        ..Default::default()
     };
 
     // Test serialization
@@ -53,10 +57,11 @@ fn test_config_default() {
 
 #[test]
 fn test_config_validation() {
-    let mut config = JailConfig::default();
-    
     // Test empty exec file
-    config.exec_bin = "".to_string();
+    let mut config = JailConfig {
+        exec_bin: "".to_string(),
+        ..Default::default()
+    };
     assert!(validate_config(&config).is_err());
     
     // Test invalid chroot directory
@@ -65,8 +70,9 @@ fn test_config_validation() {
     assert!(validate_config(&config).is_err());
     
     // Test valid config
-    config.chroot_dir = Some("/tmp/test".to_string());
-    assert!(validate_config(&config).is_ok());cargo test --lib
+    let chroot = TempDir::new().unwrap();
+    config.chroot_dir = Some(chroot.path().to_string_lossy().into_owned());
+    assert!(validate_config(&config).is_ok());
 }
 
 fn validate_config(config: &JailConfig) -> Result<()> {
@@ -74,11 +80,2409 @@ fn validate_config(config: &JailConfig) -> Result<()> {
         return Err(anyhow::anyhow!("exec_bin cannot be empty"));
     }
     
-    if let Some(chroot_dir) = &config.chroot_dir {
-        if !std::path::Path::new(chroot_dir).exists() {
-            return Err(anyhow::anyhow!("chroot_dir does not exist"));
-        }
+    if let Some(chroot_dir) = &config.chroot_dir
+        && !std::path::Path::new(chroot_dir).exists()
+    {
+        return Err(anyhow::anyhow!("chroot_dir does not exist"));
     }
     
     Ok(())
 }
+
+#[test]
+fn test_kernel_version_parsing() {
+    assert_eq!(
+        rsjail::env::parse_kernel_version("6.8.0-45-generic"),
+        (6, 8)
+    );
+    assert_eq!(rsjail::env::parse_kernel_version("5.11"), (5, 11));
+    assert_eq!(rsjail::env::parse_kernel_version(""), (0, 0));
+}
+
+#[test]
+fn test_kernel_features_in_validation() {
+    let features = rsjail::env::detect_features();
+    let kernel_problems = |config: &JailConfig| {
+        config
+            .problems()
+            .iter()
+            .filter(|e| e.to_string().contains("this kernel") || e.to_string().contains("a kernel"))
+            .count()
+    };
+
+    // Whatever this kernel has, validation goes by the same detection
+    let mut config = JailConfig {
+        clone_newuser: true,
+        ..Default::default()
+    };
+    let expected = usize::from(!features.user_namespaces);
+    assert_eq!(kernel_problems(&config), expected);
+    config.ignore_unsupported_ns = true;
+    assert_eq!(kernel_problems(&config), 0);
+
+    config.seccomp_policy = Some(rsjail::SeccompPolicy::default());
+    assert_eq!(kernel_problems(&config), usize::from(!features.seccomp));
+}
+
+#[test]
+fn test_namespace_names() {
+    use nix::sched::CloneFlags;
+
+    let flags = CloneFlags::CLONE_NEWPID | CloneFlags::CLONE_NEWUSER;
+    assert_eq!(rsjail::jail::namespace_names(flags), vec!["user", "pid"]);
+    assert!(rsjail::jail::namespace_names(CloneFlags::empty()).is_empty());
+    assert_eq!(
+        rsjail::jail::namespace_names(CloneFlags::CLONE_NEWCGROUP),
+        vec!["cgroup"]
+    );
+}
+
+#[test]
+fn test_exec_args_file_expansion() {
+    let dir = TempDir::new().unwrap();
+    let args_file = dir.path().join("args.txt");
+    std::fs::write(&args_file, "one\ntwo words\nthree\n").unwrap();
+
+    let mut config = JailConfig {
+        exec_args: vec![
+            "/bin/echo".to_string(),
+            format!("@file:{}", args_file.display()),
+            "last".to_string(),
+        ],
+        ..Default::default()
+    };
+    config.expand_arg_files().unwrap();
+    assert_eq!(
+        config.exec_args,
+        vec!["/bin/echo", "one", "two words", "three", "last"]
+    );
+
+    config.exec_args = vec!["@file:/nonexistent/args.txt".to_string()];
+    let err = config.expand_arg_files().unwrap_err();
+    assert!(err.to_string().contains("/nonexistent/args.txt"));
+}
+
+#[test]
+fn test_environment_precedence() {
+    let mut config = JailConfig {
+        forward_term: Some(false),
+        ..Default::default()
+    };
+    config
+        .env
+        .insert("PATH".to_string(), "/usr/bin".to_string());
+    config.env.insert("LANG".to_string(), "C.UTF-8".to_string());
+
+    let env = rsjail::Jail::new(config).environment();
+    assert_eq!(env["PATH"], "/usr/bin");
+    assert_eq!(env["LANG"], "C.UTF-8");
+    assert_eq!(env["HOME"], "/");
+    assert!(!env.contains_key("TERM"));
+}
+
+#[test]
+fn test_timezone() {
+    let mut config = JailConfig {
+        forward_term: Some(false),
+        timezone: Some("Europe/Berlin".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(rsjail::Jail::new(config.clone()).environment()["TZ"], "Europe/Berlin");
+    assert!(config.validate().is_ok());
+
+    config.mount_localtime = true;
+    assert!(config.validate().is_err());
+
+    let temp_dir = TempDir::new().unwrap();
+    config.chroot_dir = Some(temp_dir.path().to_string_lossy().into_owned());
+    assert!(config.validate().is_ok());
+
+    config.timezone = Some("../../etc/shadow".to_string());
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_scratch_dir_lifecycle() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let base = TempDir::new().unwrap();
+    let scratch = rsjail::scratch::ScratchDir::create(base.path()).unwrap();
+    let path = scratch.path().to_path_buf();
+    assert_eq!(path.metadata().unwrap().permissions().mode() & 0o777, 0o700);
+
+    std::fs::create_dir(path.join("nested")).unwrap();
+    std::fs::write(path.join("nested/file"), b"data").unwrap();
+    drop(scratch);
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_root_chroot_rejected() {
+    let mut config = JailConfig {
+        chroot_dir: Some("/".to_string()),
+        ..Default::default()
+    };
+    assert!(config.validate().is_err());
+
+    config.chroot_dir = Some("/tmp/..".to_string());
+    assert!(config.validate().is_err());
+
+    config.allow_root_chroot = true;
+    assert!(config.validate().is_ok());
+}
+
+#[test]
+fn test_login_shell_argv() {
+    let config = JailConfig {
+        exec_bin: "/bin/bash".to_string(),
+        exec_args: vec!["/bin/bash".to_string(), "-i".to_string()],
+        login_shell: true,
+        uid: Some(0),
+        forward_term: Some(false),
+        ..Default::default()
+    };
+
+    let jail = rsjail::Jail::new(config);
+    assert_eq!(jail.exec_argv(), vec!["-bash", "-i"]);
+    assert!(jail.environment().contains_key("SHELL"));
+}
+
+#[test]
+fn test_elf_dependencies() {
+    let sh = std::path::Path::new("/bin/sh");
+    let info = rsjail::elf::parse(sh).unwrap();
+
+    // Assumes a dynamically linked /bin/sh, as on every mainstream distro
+    let interpreter = info.interpreter.expect("/bin/sh has a PT_INTERP");
+    let deps = rsjail::elf::resolve_dependencies(sh).unwrap();
+    assert!(deps.contains(&std::path::PathBuf::from(&interpreter)));
+    assert!(deps
+        .iter()
+        .any(|lib| lib.to_string_lossy().contains("libc.so")));
+
+    assert!(rsjail::elf::parse(std::path::Path::new("/etc/hostname")).is_err());
+}
+
+#[test]
+fn test_no_fork_conflicts() {
+    let mut config = JailConfig {
+        no_fork: true,
+        ..Default::default()
+    };
+    assert!(config.validate().is_err());
+
+    config.clone_newpid = false;
+    assert!(config.validate().is_ok());
+
+    config.time_limit = Some(10);
+    assert!(config.validate().is_err());
+
+    config.time_limit = None;
+    config.log_prefix = true;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_log_prefix_lines() {
+    let mut lines = Vec::new();
+    let output = "starting\n\nlistening on :80".as_bytes();
+    rsjail::relay::prefix_lines(output, "[web] ", |line| {
+        lines.push(String::from_utf8(line.to_vec()).unwrap());
+        Ok(())
+    })
+    .unwrap();
+    let expected = ["[web] starting\n", "[web] \n", "[web] listening on :80\n"];
+    assert_eq!(lines, expected);
+
+    let config: JailConfig = serde_json::from_value({
+        let mut json = serde_json::to_value(JailConfig::default()).unwrap();
+        json["log_prefix"] = true.into();
+        json
+    })
+    .unwrap();
+    assert!(config.log_prefix && config.validate().is_ok());
+}
+
+#[test]
+fn test_cleanup_timeout() {
+    let mut json = serde_json::to_value(JailConfig::default()).unwrap();
+    json["cleanup_timeout"] = "2m".into();
+    let mut config: JailConfig = serde_json::from_value(json).unwrap();
+    assert_eq!(config.cleanup_timeout, Some(120));
+    assert!(config.validate().is_ok());
+
+    config.no_fork = true;
+    config.clone_newpid = false;
+    assert!(config.validate().is_err());
+    config.cleanup_timeout = None;
+    assert!(config.validate().is_ok());
+}
+
+#[test]
+fn test_netns_name() {
+    let mut config = JailConfig {
+        netns_name: Some("web".to_string()),
+        clone_newnet: false,
+        ..Default::default()
+    };
+    assert!(config.validate().is_err());
+
+    config.clone_newnet = true;
+    assert!(config.validate().is_ok());
+    assert!(config.required_capabilities().contains(&"sys_admin".to_string()));
+
+    config.netns_name = Some("../web".to_string());
+    assert!(config.validate().is_err());
+    config.netns_name = Some("web".to_string());
+    config.unshare_in_parent = true;
+    config.clone_newns = true;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_env_var_expansion() {
+    use rsjail::config::expand_vars;
+
+    let lookup = |name: &str| match name {
+        "HOME" => Some("/home/alice".to_string()),
+        "EMPTY" => Some(String::new()),
+        _ => None,
+    };
+
+    assert_eq!(
+        expand_vars("${HOME}/jail", lookup).unwrap(),
+        "/home/alice/jail"
+    );
+    assert_eq!(
+        expand_vars("/srv/${NAME:-default}/root", lookup).unwrap(),
+        "/srv/default/root"
+    );
+    assert_eq!(expand_vars("a${EMPTY}b", lookup).unwrap(), "ab");
+    assert_eq!(expand_vars("/plain/$path", lookup).unwrap(), "/plain/$path");
+    assert!(expand_vars("${MISSING}/x", lookup).is_err());
+    assert!(expand_vars("${HOME", lookup).is_err());
+}
+
+#[test]
+fn test_landlock_rules_parse() {
+    use rsjail::{LandlockAccess, LandlockRule};
+
+    let rule: LandlockRule =
+        serde_json::from_str(r#"{"path": "/usr", "access": ["read", "execute"]}"#).unwrap();
+    assert_eq!(
+        rule.access,
+        vec![LandlockAccess::Read, LandlockAccess::Execute]
+    );
+
+    let bad = serde_json::from_str::<LandlockRule>(r#"{"path": "/usr", "access": ["delete"]}"#);
+    assert!(bad.is_err());
+}
+
+#[test]
+fn test_timeout_signal_parsing() {
+    use nix::sys::signal::Signal;
+    use rsjail::config::parse_signal;
+
+    assert_eq!(parse_signal("SIGTERM").unwrap(), Signal::SIGTERM);
+    assert_eq!(parse_signal("quit").unwrap(), Signal::SIGQUIT);
+    assert!(parse_signal("SIGNOPE").is_err());
+
+    let config = JailConfig {
+        timeout_signal: Some("SIGBOGUS".to_string()),
+        ..Default::default()
+    };
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_device_mount_validation() {
+    let mut config = JailConfig {
+        mounts: vec![MountConfig {
+            src: "/dev/null".to_string(),
+            dst: "/dev/null".to_string(),
+            is_bind: true,
+            device: true,
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    assert!(config.validate().is_ok());
+
+    config.mounts[0].src = "/etc/hostname".to_string();
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_pidfd_signal_and_reap() {
+    use nix::sys::signal::Signal;
+    use nix::sys::wait::WaitStatus;
+    use nix::unistd::Pid;
+    use rsjail::pidfd;
+
+    if !pidfd::available() {
+        return;
+    }
+
+    // Reaped through the pidfd below rather than Child::wait
+    let child = std::process::Command::new("sleep").arg("10").spawn();
+    let pid = Pid::from_raw(child.unwrap().id() as i32);
+    let fd = pidfd::open(pid).unwrap();
+
+    assert!(!pidfd::wait_exit(&fd, Some(std::time::Duration::ZERO)).unwrap());
+    pidfd::send_signal(&fd, Signal::SIGKILL).unwrap();
+    assert!(pidfd::wait_exit(&fd, None).unwrap());
+
+    let (status, _) = pidfd::reap(&fd).unwrap();
+    assert_eq!(status, WaitStatus::Signaled(pid, Signal::SIGKILL, false));
+}
+
+#[test]
+fn test_clone3_pidfd() {
+    use nix::sched::CloneFlags;
+    use nix::sys::wait::WaitStatus;
+    use rsjail::pidfd::{self, Cloned};
+
+    if !pidfd::available() {
+        return;
+    }
+    match unsafe { pidfd::clone3(CloneFlags::empty()) } {
+        Ok(Cloned::Child) => unsafe { nix::libc::_exit(7) },
+        Ok(Cloned::Parent(pid, fd)) => {
+            let (status, _) = pidfd::reap(&fd).unwrap();
+            assert_eq!(status, WaitStatus::Exited(pid, 7));
+        }
+        // Blocked by the seccomp profile we run under
+        Err(e) => assert_eq!(e.raw_os_error(), Some(nix::libc::ENOSYS)),
+    }
+}
+
+#[test]
+fn test_mount_data_validation() {
+    let mut config = JailConfig {
+        mounts: vec![MountConfig {
+            src: "tmpfs".to_string(),
+            dst: "/tmp".to_string(),
+            fstype: Some("tmpfs".to_string()),
+            rw: true,
+            data: Some("size=16m,mode=1777".to_string()),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    assert!(config.validate().is_ok());
+
+    config.mounts[0].fstype = None;
+    assert!(config.validate().is_err());
+
+    config.mounts[0].fstype = Some("tmpfs".to_string());
+    config.mounts[0].is_bind = true;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_mount_create_target_default() {
+    let mount: MountConfig = serde_json::from_str(
+        r#"{"src": "/etc/hosts", "dst": "/etc/hosts", "fstype": null, "is_bind": true, "rw": false}"#,
+    )
+    .unwrap();
+    assert!(mount.create_target);
+    assert!(!mount.skip_if_nonempty);
+    assert!(MountConfig::default().create_target);
+
+    let mount: MountConfig = serde_json::from_str(
+        r#"{"src": "/etc/hosts", "dst": "/etc/hosts", "fstype": null, "is_bind": true, "rw": false,
+            "create_target": false}"#,
+    )
+    .unwrap();
+    assert!(!mount.create_target);
+}
+
+#[test]
+fn test_mount_skip_if_nonempty() {
+    let temp_dir = TempDir::new().unwrap();
+    let empty = temp_dir.path().join("empty");
+    let provisioned = temp_dir.path().join("provisioned");
+    std::fs::create_dir(&empty).unwrap();
+    std::fs::create_dir(&provisioned).unwrap();
+    std::fs::write(provisioned.join("lib.so"), "").unwrap();
+
+    let mut mount: MountConfig = serde_json::from_str(
+        r#"{"src": "/usr/lib", "dst": "/usr/lib", "fstype": null, "is_bind": true, "rw": false,
+            "skip_if_nonempty": true}"#,
+    )
+    .unwrap();
+    assert!(mount.skipped_at(&provisioned));
+    assert!(!mount.skipped_at(&empty));
+    assert!(!mount.skipped_at(&temp_dir.path().join("missing")));
+
+    mount.skip_if_nonempty = false;
+    assert!(!mount.skipped_at(&provisioned));
+}
+
+#[test]
+fn test_remount_read_only() {
+    use nix::mount::{mount, MsFlags};
+    use nix::sched::{unshare, CloneFlags};
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd::{fork, ForkResult};
+
+    let temp_dir = TempDir::new().unwrap();
+    let src = temp_dir.path().join("src");
+    let dst = temp_dir.path().join("dst");
+    std::fs::create_dir(&src).unwrap();
+    std::fs::create_dir(&dst).unwrap();
+
+    // Mounted in a mount namespace of its own, which only a fork can have
+    match unsafe { fork() }.unwrap() {
+        ForkResult::Child => {
+            let passed = unshare(CloneFlags::CLONE_NEWNS).is_ok()
+                && mount(
+                    None::<&str>,
+                    "/",
+                    None::<&str>,
+                    MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+                    None::<&str>,
+                )
+                .is_ok()
+                // A bind ignores MS_RDONLY, so this alone stays writable
+                && mount(
+                    Some(&src),
+                    &dst,
+                    None::<&str>,
+                    MsFlags::MS_BIND | MsFlags::MS_RDONLY,
+                    None::<&str>,
+                )
+                .is_ok()
+                && std::fs::write(dst.join("before"), "").is_ok()
+                && rsjail::jail::remount_read_only(dst.to_str().unwrap()).is_ok()
+                && std::fs::write(dst.join("after"), "").unwrap_err().raw_os_error()
+                    == Some(nix::libc::EROFS);
+            unsafe { nix::libc::_exit(i32::from(!passed)) };
+        }
+        ForkResult::Parent { child } => {
+            assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+        }
+    }
+}
+
+#[test]
+fn test_check_executable() {
+    use rsjail::jail::check_executable;
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().unwrap();
+    let script = temp_dir.path().join("script");
+    std::fs::write(&script, "#!/bin/sh\n").unwrap();
+
+    let missing = check_executable(&temp_dir.path().join("missing")).unwrap_err();
+    assert!(missing.to_string().contains("not found"));
+
+    let not_executable = check_executable(&script).unwrap_err();
+    assert!(not_executable.to_string().contains("not executable"));
+    assert!(check_executable(temp_dir.path()).is_err());
+
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+    assert!(check_executable(&script).is_ok());
+    assert!(check_executable(std::path::Path::new("/bin/sh")).is_ok());
+}
+
+#[test]
+fn test_timing_stats() {
+    use rsjail::bench::TimingStats;
+    use std::time::Duration;
+
+    assert_eq!(TimingStats::from_samples(&[]), None);
+
+    let samples = [2, 4, 4, 4, 5, 5, 7, 9].map(Duration::from_millis);
+    let stats = TimingStats::from_samples(&samples).unwrap();
+    assert_eq!(stats.min, Duration::from_millis(2));
+    assert_eq!(stats.max, Duration::from_millis(9));
+    assert_eq!(stats.mean.as_micros(), 5000);
+    assert_eq!(stats.stddev.as_micros(), 2000);
+}
+
+#[test]
+fn test_run_metrics() {
+    use rsjail::metrics::RunMetrics;
+
+    let mut metrics = RunMetrics::default();
+    metrics.record(&serde_json::json!({
+        "exit_code": 0, "wall_time_us": 20_000, "max_rss_kb": 2048, "timed_out": false,
+    }));
+    metrics.record(&serde_json::json!({
+        "exit_code": null, "wall_time_us": 2_000_000, "max_rss_kb": 2048, "timed_out": true,
+    }));
+
+    let text = metrics.render();
+    assert!(text.contains("rsjail_runs_total 2\n"));
+    assert!(text.contains("rsjail_run_failures_total 1\n"));
+    assert!(text.contains("rsjail_run_timeouts_total 1\n"));
+    assert!(text.contains("rsjail_run_wall_time_seconds_bucket{le=\"0.025\"} 1\n"));
+    assert!(text.contains("rsjail_run_wall_time_seconds_bucket{le=\"+Inf\"} 2\n"));
+    assert!(text.contains("rsjail_run_max_rss_bytes_bucket{le=\"4194304\"} 2\n"));
+}
+
+#[test]
+fn test_masked_paths() {
+    use rsjail::Jail;
+
+    let mut config = JailConfig {
+        chroot_dir: Some("/tmp/jail".to_string()),
+        mount_proc: true,
+        mask_paths: vec!["/etc/shadow".to_string()],
+        ..Default::default()
+    };
+    let masked = Jail::new(config.clone()).masked_paths();
+    assert!(masked.contains(&"/proc/kcore".to_string()));
+    assert!(!masked.contains(&"/sys/firmware".to_string()));
+    assert_eq!(masked.last().unwrap(), "/etc/shadow");
+
+    config.default_proc_mask = false;
+    assert_eq!(
+        Jail::new(config.clone()).masked_paths(),
+        vec!["/etc/shadow"]
+    );
+
+    config.mask_paths = vec!["etc/shadow".to_string()];
+    assert!(config.validate().is_err());
+
+    config.mask_paths.clear();
+    config.chroot_dir = None;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_rlimit_units() {
+    let config: JailConfig = serde_json::from_str(
+        r#"{"name": "t", "hostname": null, "chroot_dir": null, "exec_bin": "/bin/true",
+            "exec_args": [], "clone_newpid": false, "clone_newnet": false,
+            "clone_newns": false, "clone_newuts": false, "clone_newipc": false,
+            "clone_newuser": false, "rlimit_as": "1G", "rlimit_cpu": {"soft": "10s", "hard": "1m"},
+            "rlimit_nofile": null, "mounts": [], "uid": null, "gid": null, "time_limit": "5m",
+            "memory_high": "512M"}"#,
+    )
+    .unwrap();
+    assert_eq!(config.rlimit_as, Some(Rlimit::Both(1 << 30)));
+    assert_eq!(config.rlimit_cpu, Some(Rlimit::Split { soft: 10, hard: 60 }));
+    assert_eq!(config.time_limit, Some(300));
+    assert_eq!(config.memory_high, Some(512 << 20));
+
+    assert_eq!(rsjail::config::parse_bytes("64k").unwrap(), 65536);
+    assert_eq!(rsjail::config::parse_seconds("2h").unwrap(), 7200);
+    assert!(rsjail::config::parse_bytes("1X").is_err());
+    assert!(rsjail::config::parse_seconds("1G").is_err());
+    assert!(rsjail::config::parse_bytes("99999999999T").is_err());
+}
+
+#[test]
+fn test_rlimit_memlock_and_data() {
+    let config: JailConfig = serde_json::from_str(
+        r#"{"name": "t", "hostname": null, "chroot_dir": null, "exec_bin": "/bin/true",
+            "exec_args": [], "clone_newpid": false, "clone_newnet": false,
+            "clone_newns": false, "clone_newuts": false, "clone_newipc": false,
+            "clone_newuser": false, "rlimit_as": null, "rlimit_cpu": null,
+            "rlimit_nofile": null, "rlimit_memlock": "64M",
+            "rlimit_data": {"soft": "1G", "hard": "2G"}, "mounts": [], "uid": null,
+            "gid": null, "time_limit": null}"#,
+    )
+    .unwrap();
+    assert_eq!(config.rlimit_memlock, Some(Rlimit::Both(64 << 20)));
+    assert_eq!(
+        config.rlimit_data,
+        Some(Rlimit::Split {
+            soft: 1 << 30,
+            hard: 2 << 30
+        })
+    );
+    assert!(config.validate().is_ok());
+    assert_eq!(JailConfig::default().rlimit_memlock, None);
+    assert_eq!(JailConfig::default().rlimit_data, None);
+
+    for inverted in [
+        JailConfig {
+            rlimit_memlock: Some(Rlimit::Split { soft: 2, hard: 1 }),
+            ..config.clone()
+        },
+        JailConfig {
+            rlimit_data: Some(Rlimit::Split { soft: 2, hard: 1 }),
+            ..config.clone()
+        },
+    ] {
+        assert!(inverted.validate().is_err());
+    }
+}
+
+#[test]
+fn test_problems_reports_every_check() {
+    let config = JailConfig {
+        exec_bin: String::new(),
+        memory_high: Some(0),
+        private_tmp: true,
+        clone_newns: false,
+        ..Default::default()
+    };
+    let problems = config.problems();
+    assert_eq!(problems.len(), 3, "{:?}", problems);
+    assert_eq!(
+        problems[0].to_string(),
+        config.validate().unwrap_err().to_string()
+    );
+
+    let config = JailConfig {
+        exec_bin: "/bin/true".to_string(),
+        ..Default::default()
+    };
+    assert_eq!(config.problems().is_empty(), config.validate().is_ok());
+}
+
+#[test]
+fn test_rlimit_soft_and_hard() {
+    let config: JailConfig = serde_json::from_str(
+        r#"{"name": "t", "hostname": null, "chroot_dir": null, "exec_bin": "/bin/true",
+            "exec_args": [], "clone_newpid": false, "clone_newnet": false,
+            "clone_newns": false, "clone_newuts": false, "clone_newipc": false,
+            "clone_newuser": false, "rlimit_as": 4096, "rlimit_cpu": {"soft": 5, "hard": 10},
+            "rlimit_nofile": null, "mounts": [], "uid": null, "gid": null, "time_limit": null}"#,
+    )
+    .unwrap();
+    assert_eq!(config.rlimit_as, Some(Rlimit::Both(4096)));
+    assert_eq!(config.rlimit_cpu.unwrap().soft(), 5);
+    assert_eq!(config.rlimit_cpu.unwrap().hard(), 10);
+    assert!(config.validate().is_ok());
+
+    let inverted = JailConfig {
+        rlimit_nofile: Some(Rlimit::Split {
+            soft: 128,
+            hard: 64,
+        }),
+        ..Default::default()
+    };
+    assert!(inverted.validate().is_err());
+}
+
+#[test]
+fn test_cpu_limit() {
+    let mut config = JailConfig {
+        cpu_limit: Some(2),
+        ..Default::default()
+    };
+    assert!(config.validate().is_ok());
+    assert_eq!(config.effective_rlimit_cpu(), Some(Rlimit::Both(2)));
+    assert_eq!(config.effective_time_limit(), Some(3));
+
+    config.wall_margin = Some(5);
+    assert_eq!(config.effective_time_limit(), Some(7));
+    // Each half can still be set on its own
+    config.time_limit = Some(60);
+    config.rlimit_cpu = Some(Rlimit::Both(10));
+    assert_eq!(config.effective_time_limit(), Some(60));
+    assert_eq!(config.effective_rlimit_cpu(), Some(Rlimit::Both(10)));
+
+    config.cpu_limit = None;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_fs_isolation() {
+    use rsjail::FsIsolation;
+
+    let temp_dir = TempDir::new().unwrap();
+    let mut config = JailConfig::default();
+    assert_eq!(config.fs_isolation(), FsIsolation::None);
+
+    config.chroot_dir = Some(temp_dir.path().to_string_lossy().into_owned());
+    assert_eq!(config.fs_isolation(), FsIsolation::Chroot);
+
+    config.fs_isolation = Some(FsIsolation::PivotRoot);
+    assert!(config.validate().is_ok());
+    config.clone_newns = false;
+    assert!(config.validate().is_err());
+
+    config.fs_isolation = Some(FsIsolation::Chroot);
+    config.chroot_dir = None;
+    assert!(config.validate().is_err());
+
+    let parsed: FsIsolation = serde_json::from_str("\"pivot_root\"").unwrap();
+    assert_eq!(parsed, FsIsolation::PivotRoot);
+}
+
+#[test]
+fn test_chroot_policy() {
+    use rsjail::ChrootPolicy;
+
+    assert_eq!(JailConfig::default().chroot_policy, ChrootPolicy::Reuse);
+    let parsed: ChrootPolicy = serde_json::from_str("\"require_empty\"").unwrap();
+    assert_eq!(parsed, ChrootPolicy::RequireEmpty);
+}
+
+#[test]
+fn test_sysctl_validation() {
+    let mut config = JailConfig::default();
+    config.sysctls.insert(
+        "net.ipv4.ip_unprivileged_port_start".to_string(),
+        "0".to_string(),
+    );
+    config
+        .sysctls
+        .insert("kernel.shmmax".to_string(), "1048576".to_string());
+    assert!(config.validate().is_ok());
+
+    config.clone_newnet = false;
+    assert!(config.validate().is_err());
+
+    config.clone_newnet = true;
+    config
+        .sysctls
+        .insert("kernel.pid_max".to_string(), "4096".to_string());
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_id_mapping_validation() {
+    use rsjail::IdMap;
+
+    let mut config = JailConfig {
+        uid: Some(0),
+        gid: Some(0),
+        ..Default::default()
+    };
+    assert!(config.check_id_mapping().is_ok());
+    assert_eq!(
+        config.uid_maps(1000),
+        vec![IdMap {
+            inside: 0,
+            outside: 1000,
+            count: 1
+        }]
+    );
+
+    config.uid = Some(1000);
+    let err = config.check_id_mapping().unwrap_err();
+    assert!(err.to_string().contains("uid 1000 is not mapped"));
+
+    config.uid_map = vec![IdMap {
+        inside: 1000,
+        outside: 100000,
+        count: 65536,
+    }];
+    assert!(config.check_id_mapping().is_ok());
+    config.uid = Some(1000 + 65536);
+    assert!(config.check_id_mapping().is_err());
+
+    config.uid = None;
+    config.gid = Some(5);
+    assert!(config.check_id_mapping().is_err());
+}
+
+#[test]
+fn test_root_subpath_validation() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut config = JailConfig {
+        root_subpath: Some("rootfs".to_string()),
+        ..Default::default()
+    };
+    assert!(config.validate().is_err());
+
+    config.chroot_dir = Some(temp_dir.path().to_string_lossy().into_owned());
+    assert!(config.validate().is_ok());
+
+    config.root_subpath = Some("rootfs/../../etc".to_string());
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_config_parse_error_location() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("config.json");
+    std::fs::write(&path, "{\n  \"name\": \"test\",\n  \"clone_newpid\": yes\n}\n").unwrap();
+
+    let error = JailConfig::load(&path).unwrap_err().to_string();
+    assert!(error.starts_with(&format!("{}:3:", path.display())));
+    assert!(!error.contains(" at line "));
+}
+
+#[test]
+fn test_mount_src_root() {
+    let layer = TempDir::new().unwrap();
+    std::fs::create_dir(layer.path().join("usr")).unwrap();
+    let mount = |src: &str| MountConfig {
+        src: src.to_string(),
+        dst: "/usr".to_string(),
+        fstype: None,
+        is_bind: true,
+        rw: false,
+        ..Default::default()
+    };
+    let mut config = JailConfig {
+        mounts: vec![mount("./usr")],
+        ..Default::default()
+    };
+    assert!(config.resolve_mount_sources().is_err());
+
+    config.src_root = Some(layer.path().to_string_lossy().into_owned());
+    config.resolve_mount_sources().unwrap();
+    let expected = layer.path().canonicalize().unwrap().join("usr");
+    assert_eq!(config.mounts[0].src, expected.to_string_lossy());
+
+    config.mounts = vec![mount("./usr/../..")];
+    assert!(config.resolve_mount_sources().is_err());
+}
+
+#[test]
+fn test_strict_config() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("config.json");
+    let mut config = serde_json::to_value(JailConfig {
+        mounts: vec![MountConfig {
+            src: "/bin".to_string(),
+            dst: "/bin".to_string(),
+            fstype: None,
+            is_bind: true,
+            rw: false,
+            device: false,
+            data: None,
+            create_target: true,
+            optional: false,
+            skip_if_nonempty: false,
+        }],
+        ..Default::default()
+    })
+    .unwrap();
+    std::fs::write(&path, config.to_string()).unwrap();
+    assert!(JailConfig::load_strict(&path).is_ok());
+
+    config["mounts"][0]["is_bnd"] = true.into();
+    std::fs::write(&path, config.to_string()).unwrap();
+    let error = JailConfig::load_strict(&path).unwrap_err().to_string();
+    assert!(error.ends_with("unknown field mounts[0].is_bnd"));
+
+    config["clone_newpd"] = false.into();
+    std::fs::write(&path, config.to_string()).unwrap();
+    assert!(JailConfig::load(&path).is_ok());
+    let error = JailConfig::load_strict(&path).unwrap_err().to_string();
+    assert!(error.ends_with("unknown field clone_newpd"));
+}
+
+#[test]
+fn test_private_tmp_validation() {
+    let mut config = JailConfig {
+        private_tmp: true,
+        ..Default::default()
+    };
+    assert!(config.validate().is_ok());
+
+    config.clone_newns = false;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_max_mounts_validation() {
+    let mount = |dst: &str| MountConfig {
+        src: "/usr".to_string(),
+        dst: dst.to_string(),
+        fstype: None,
+        is_bind: true,
+        rw: false,
+        ..Default::default()
+    };
+    let mut config = JailConfig {
+        mounts: vec![mount("/usr"), mount("/opt")],
+        max_mounts: 2,
+        ..Default::default()
+    };
+    assert!(config.validate().is_ok());
+
+    config.mounts.push(mount("/srv"));
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_max_connections_validation() {
+    let mut config = JailConfig {
+        max_connections: Some(16),
+        ..Default::default()
+    };
+    assert!(config.validate().is_ok());
+
+    config.max_connections = Some(0);
+    assert!(config.validate().is_err());
+
+    config.max_connections = Some(16);
+    config.clone_newnet = false;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_extra_hosts_validation() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut config = JailConfig {
+        chroot_dir: Some(temp_dir.path().to_string_lossy().into_owned()),
+        extra_hosts: vec![
+            "10.0.0.5 myservice my.service".to_string(),
+            "::1 ip6-host".to_string(),
+        ],
+        ..Default::default()
+    };
+    assert!(config.validate().is_ok());
+
+    for entry in [
+        "10.0.0.5",
+        "myservice 10.0.0.5",
+        "10.0.0.5 bad_name",
+        "10.0.0.5 a\nb",
+    ] {
+        config.extra_hosts = vec![entry.to_string()];
+        assert!(config.validate().is_err(), "{:?} accepted", entry);
+    }
+}
+
+#[test]
+fn test_duplicate_mount_validation() {
+    let bind = |src: &str, dst: &str| MountConfig {
+        src: src.to_string(),
+        dst: dst.to_string(),
+        is_bind: true,
+        ..Default::default()
+    };
+    let mut config = JailConfig {
+        mounts: vec![bind("/usr/local", "/usr/local"), bind("/usr", "/usr")],
+        ..Default::default()
+    };
+    assert!(config.validate().is_ok());
+
+    config.mounts.push(bind("/opt", "/usr/"));
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_glob_mounts() {
+    use rsjail::glob;
+
+    assert!(glob::matches("libc*.so*", "libc.so.6"));
+    assert!(glob::matches("lib?.so", "libc.so"));
+    assert!(glob::matches("lib[a-c].so", "libc.so"));
+    assert!(!glob::matches("lib[!a-c].so", "libc.so"));
+    assert!(!glob::matches("*", ".hidden"));
+    assert!(glob::matches("[", "["));
+
+    let temp_dir = TempDir::new().unwrap();
+    for name in ["libfoo.so.1", "libfoo.so.2", "libbar.so"] {
+        std::fs::write(temp_dir.path().join(name), b"").unwrap();
+    }
+    let pattern = format!("{}/libfoo*", temp_dir.path().display());
+    let matches = glob::expand(&pattern, 8).unwrap();
+    assert_eq!(
+        matches,
+        vec![
+            temp_dir.path().join("libfoo.so.1"),
+            temp_dir.path().join("libfoo.so.2"),
+        ]
+    );
+    // The cap on matches
+    assert!(glob::expand(&pattern, 1).is_err());
+
+    let mut config = JailConfig {
+        chroot_dir: Some("/tmp/jail".to_string()),
+        mounts: vec![MountConfig {
+            src: pattern,
+            dst: "/lib".to_string(),
+            is_bind: true,
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    assert!(config.validate().is_ok());
+    config.mounts[0].is_bind = false;
+    config.mounts[0].fstype = Some("tmpfs".to_string());
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_volume_validation() {
+    use rsjail::{VolumeBacking, VolumeConfig};
+
+    let temp_dir = TempDir::new().unwrap();
+    let volume = |name: &str, backing| VolumeConfig {
+        name: name.to_string(),
+        mount_path: format!("/{}", name),
+        backing,
+        size: None,
+    };
+    let mut config = JailConfig {
+        volumes: vec![volume("cache", VolumeBacking::Tmpfs), volume("data", VolumeBacking::Disk)],
+        ..Default::default()
+    };
+    assert!(config.validate().is_err());
+
+    config.chroot_dir = Some(temp_dir.path().to_string_lossy().into_owned());
+    assert!(config.validate().is_ok());
+
+    config.volumes[0].size = Some("64m".to_string());
+    assert!(config.validate().is_ok());
+    config.volumes[1].size = Some("64m".to_string());
+    assert!(config.validate().is_err());
+    config.volumes[1].size = None;
+
+    config.volumes.push(volume("cache", VolumeBacking::Disk));
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_disk_quota_validation() {
+    use rsjail::{DiskQuota, VolumeBacking, VolumeConfig};
+
+    let quota: DiskQuota = serde_json::from_str(r#"{"size": "64M"}"#).unwrap();
+    assert_eq!(quota.size, 64 << 20);
+    assert_eq!(quota.fstype, "ext4");
+    let mut config = JailConfig {
+        chroot_dir: Some("/tmp/jail".to_string()),
+        volumes: vec![VolumeConfig {
+            name: "data".to_string(),
+            mount_path: "/data".to_string(),
+            backing: VolumeBacking::Tmpfs,
+            size: None,
+        }],
+        disk_quota: Some(quota),
+        ..Default::default()
+    };
+    // Nothing for it to bound
+    assert!(config.validate().is_err());
+
+    config.volumes[0].backing = VolumeBacking::Disk;
+    assert!(config.validate().is_ok());
+    config.disk_quota.as_mut().unwrap().fstype = "ext4 -E".to_string();
+    assert!(config.validate().is_err());
+    config.disk_quota = Some(DiskQuota {
+        size: 4096,
+        fstype: "xfs".to_string(),
+    });
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_fuse_mount_validation() {
+    use rsjail::FuseMount;
+
+    let fuse = FuseMount {
+        mount_path: "/store".to_string(),
+        helper: vec!["/usr/bin/squashfuse".to_string()],
+        options: vec!["allow_other".to_string(), "nonempty".to_string()],
+    };
+    assert_eq!(fuse.kernel_options(), vec!["allow_other"]);
+    let mut config = JailConfig {
+        chroot_dir: Some("/tmp/jail".to_string()),
+        fuse_mounts: vec![fuse],
+        ..Default::default()
+    };
+    assert!(config.validate().is_ok());
+
+    let options = &mut config.fuse_mounts[0].options;
+    options.push("max_read=131072".to_string());
+    assert!(config.validate().is_ok());
+    config.fuse_mounts[0].options.push("ro".to_string());
+    assert!(config.validate().is_err());
+    config.fuse_mounts[0].options.clear();
+    config.fuse_mounts[0].helper = vec!["squashfuse".to_string()];
+    assert!(config.validate().is_err());
+    config.fuse_mounts[0].helper = vec!["/usr/bin/squashfuse".to_string()];
+    config.chroot_dir = None;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_provision_validation() {
+    use rsjail::ProvisionConfig;
+
+    let temp_dir = TempDir::new().unwrap();
+    let mut config = JailConfig {
+        chroot_dir: Some(temp_dir.path().to_string_lossy().into_owned()),
+        provision: Some(ProvisionConfig {
+            command: vec!["debootstrap".to_string(), "stable".to_string(), ".".to_string()],
+            marker: ".provisioned".to_string(),
+        }),
+        ..Default::default()
+    };
+    assert!(config.validate().is_ok());
+
+    config.provision.as_mut().unwrap().marker = "../outside".to_string();
+    assert!(config.validate().is_err());
+
+    config.provision.as_mut().unwrap().marker = ".provisioned".to_string();
+    config.provision.as_mut().unwrap().command.clear();
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_notify_validation() {
+    let mut config = JailConfig {
+        notify_timeout: Some(5),
+        ..Default::default()
+    };
+    assert!(config.validate().is_err());
+
+    config.notify = true;
+    assert!(config.validate().is_ok());
+
+    config.no_fork = true;
+    config.clone_newpid = false;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_setup_timeout_validation() {
+    let mut config = JailConfig {
+        setup_timeout: Some(10),
+        ..Default::default()
+    };
+    assert!(config.validate().is_ok());
+
+    config.no_fork = true;
+    config.clone_newpid = false;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_cgroup_delegate_validation() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut config = JailConfig {
+        cgroup_delegate: true,
+        ..Default::default()
+    };
+    assert!(config.validate().is_err());
+
+    config.chroot_dir = Some(temp_dir.path().to_string_lossy().into_owned());
+    assert!(config.validate().is_ok());
+
+    config.clone_newns = false;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_memory_high() {
+    let mut config = JailConfig {
+        memory_high: Some(64 << 20),
+        ..Default::default()
+    };
+    assert!(config.validate().is_ok());
+
+    config.memory_high = Some(0);
+    assert!(config.validate().is_err());
+
+    let events = "low 0\nhigh 12\nmax 3\noom 1\noom_kill 1\noom_group_kill 0\n";
+    let events = rsjail::cgroup::MemoryEvents::parse(events);
+    assert_eq!((events.high, events.max), (12, 3));
+}
+
+#[test]
+fn test_pam_service_validation() {
+    let mut config = JailConfig {
+        pam_service: Some("rsjail".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(config.validate().is_ok(), cfg!(feature = "pam"));
+
+    config.pam_service = Some("../login".to_string());
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_ambient_caps_validation() {
+    assert_eq!(rsjail::caps::from_name("CAP_NET_BIND_SERVICE").unwrap(), 10);
+    assert_eq!(rsjail::caps::from_name("sys_admin").unwrap(), 21);
+    assert!(rsjail::caps::from_name("CAP_NET_BIND").is_err());
+
+    let mut config = JailConfig {
+        ambient_caps: vec!["cap_net_raw".to_string()],
+        ..Default::default()
+    };
+    assert!(config.validate().is_err());
+
+    config.uid = Some(1000);
+    assert!(config.validate().is_ok());
+
+    config.ambient_caps.push("CAP_FLY".to_string());
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_chown_jail_dirs_validation() {
+    let mut config = JailConfig {
+        chroot_dir: Some("/tmp/jail".to_string()),
+        chown_jail_dirs: true,
+        ..Default::default()
+    };
+    assert!(config.validate().is_ok());
+
+    config.clone_newuser = false;
+    assert!(config.validate().is_err());
+    config.clone_newuser = true;
+    config.chroot_dir = None;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_copy_tree() {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    let src = TempDir::new().unwrap();
+    fs::create_dir(src.path().join("sub")).unwrap();
+    fs::write(src.path().join("sub/file"), "data").unwrap();
+    fs::set_permissions(src.path().join("sub/file"), fs::Permissions::from_mode(0o640)).unwrap();
+    std::os::unix::fs::symlink("sub/file", src.path().join("link")).unwrap();
+
+    let dst = TempDir::new().unwrap();
+    let copy = dst.path().join("copy");
+    rsjail::scratch::copy_tree(src.path(), &copy).unwrap();
+
+    assert_eq!(fs::read_to_string(copy.join("sub/file")).unwrap(), "data");
+    let mode = fs::metadata(copy.join("sub/file")).unwrap().permissions();
+    assert_eq!(mode.mode() & 0o777, 0o640);
+    let link = fs::read_link(copy.join("link")).unwrap();
+    assert_eq!(link.to_str(), Some("sub/file"));
+}
+
+#[test]
+fn test_wrapper_and_preload() {
+    let mut config = JailConfig {
+        exec_bin: "/bin/ls".to_string(),
+        exec_args: vec!["ls".to_string(), "-l".to_string()],
+        wrapper: Some(vec!["/usr/bin/valgrind".to_string(), "-q".to_string()]),
+        preload: Some("/lib/shim.so".to_string()),
+        env: [("LD_PRELOAD".to_string(), "/lib/other.so".to_string())].into(),
+        ..Default::default()
+    };
+    assert!(config.validate().is_ok());
+
+    let jail = rsjail::Jail::new(config.clone());
+    assert_eq!(
+        jail.exec_argv(),
+        vec!["/usr/bin/valgrind", "-q", "/bin/ls", "-l"]
+    );
+    assert_eq!(
+        jail.environment()["LD_PRELOAD"],
+        "/lib/shim.so:/lib/other.so"
+    );
+
+    config.login_shell = true;
+    assert!(config.validate().is_err());
+    config.login_shell = false;
+
+    config.wrapper = Some(vec!["valgrind".to_string()]);
+    assert!(config.validate().is_err());
+    config.wrapper = Some(Vec::new());
+    assert!(config.validate().is_err());
+
+    config.wrapper = None;
+    config.preload = Some("/lib/a.so:/lib/b.so".to_string());
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_resolved_mounts() {
+    use nix::mount::MsFlags;
+    use rsjail::{VolumeBacking, VolumeConfig};
+
+    let temp_dir = TempDir::new().unwrap();
+    std::fs::create_dir(temp_dir.path().join("etc")).unwrap();
+    let bind = |src: &str, dst: &str| MountConfig {
+        src: src.to_string(),
+        dst: dst.to_string(),
+        is_bind: true,
+        ..Default::default()
+    };
+    let config = JailConfig {
+        chroot_dir: Some(temp_dir.path().to_string_lossy().into_owned()),
+        mounts: vec![bind("/usr/local", "/usr/local"), bind("/usr", "/usr")],
+        volumes: vec![VolumeConfig {
+            name: "cache".to_string(),
+            mount_path: "/cache".to_string(),
+            backing: VolumeBacking::Disk,
+            size: None,
+        }],
+        mount_proc: true,
+        private_tmp: true,
+        default_proc_mask: false,
+        mask_paths: vec!["/etc".to_string(), "/root".to_string()],
+        ..Default::default()
+    };
+    let mounts = rsjail::Jail::new(config).resolved_mounts().unwrap();
+
+    let order: Vec<(&str, &str)> = mounts
+        .iter()
+        .map(|mount| (mount.src.as_str(), mount.dst.as_str()))
+        .collect();
+    assert_eq!(
+        order,
+        vec![
+            ("/usr", "/usr"),
+            ("<scratch>/volumes/cache", "/cache"),
+            ("/usr/local", "/usr/local"),
+            ("proc", "/proc"),
+            ("tmpfs", "/tmp"),
+            ("tmpfs", "/var/tmp"),
+            ("tmpfs", "/etc"),
+        ]
+    );
+    assert_eq!(mounts[0].options(), "bind,ro");
+    assert_eq!(mounts[1].options(), "bind");
+    assert_eq!(mounts[3].options(), "nosuid,nodev,noexec");
+    assert_eq!(mounts[4].data.as_deref(), Some("mode=1777"));
+    assert_eq!(mounts[6].flags, MsFlags::MS_RDONLY);
+}
+
+#[test]
+fn test_loader_check() {
+    let temp_dir = TempDir::new().unwrap();
+    let bind = |path: &str| MountConfig {
+        src: path.to_string(),
+        dst: path.to_string(),
+        is_bind: true,
+        ..Default::default()
+    };
+    let mut config = JailConfig {
+        chroot_dir: Some(temp_dir.path().to_string_lossy().into_owned()),
+        mounts: vec![bind("/bin/sh")],
+        ..Default::default()
+    };
+    let loader = rsjail::elf::parse(std::path::Path::new("/bin/sh"))
+        .unwrap()
+        .interpreter
+        .unwrap();
+    let error = rsjail::Jail::new(config.clone())
+        .resolved_mounts()
+        .unwrap_err();
+    assert!(error.to_string().contains(&loader), "{}", error);
+
+    // A placeholder left by an earlier run doesn't count
+    let placeholder = temp_dir.path().join(loader.trim_start_matches('/'));
+    std::fs::create_dir_all(placeholder.parent().unwrap()).unwrap();
+    std::fs::write(&placeholder, "").unwrap();
+    assert!(rsjail::Jail::new(config.clone()).resolved_mounts().is_err());
+
+    config.auto_mount_libs = true;
+    assert!(rsjail::Jail::new(config.clone()).resolved_mounts().is_ok());
+    config.auto_mount_libs = false;
+    config.mounts.push(bind(&loader));
+    assert!(rsjail::Jail::new(config).resolved_mounts().is_ok());
+}
+
+#[test]
+fn test_bind_ca_certs() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = JailConfig {
+        chroot_dir: Some(temp_dir.path().to_string_lossy().into_owned()),
+        mounts: vec![MountConfig {
+            src: "/bin/sh".to_string(),
+            dst: "/bin/sh".to_string(),
+            is_bind: true,
+            ..Default::default()
+        }],
+        auto_mount_libs: true,
+        bind_ca_certs: true,
+        ..Default::default()
+    };
+    let mounts = rsjail::Jail::new(config.clone()).resolved_mounts();
+    if std::path::Path::new("/etc/ssl/certs").is_dir() {
+        let certs = mounts
+            .unwrap()
+            .into_iter()
+            .find(|mount| mount.dst == "/etc/ssl/certs")
+            .unwrap();
+        assert_eq!(certs.src, "/etc/ssl/certs");
+        assert_eq!(certs.options(), "bind,ro");
+    } else if !std::path::Path::new("/etc/pki/tls/certs").is_dir() {
+        assert!(mounts.is_err());
+        let optional = JailConfig {
+            ca_certs_optional: true,
+            ..config.clone()
+        };
+        assert!(rsjail::Jail::new(optional).resolved_mounts().is_ok());
+    }
+
+    let no_isolation = JailConfig {
+        chroot_dir: None,
+        mounts: Vec::new(),
+        ..config
+    };
+    let error = no_isolation.validate().unwrap_err();
+    assert!(error.to_string().contains("bind_ca_certs"), "{}", error);
+}
+
+#[test]
+fn test_proc_hidepid() {
+    let mut config = JailConfig {
+        chroot_dir: Some("/tmp/jail".to_string()),
+        proc_hidepid: Some(2),
+        proc_gid: Some(27),
+        ..Default::default()
+    };
+    // Only for the jail's own procfs
+    assert!(config.validate().is_err());
+
+    config.mount_proc = true;
+    assert!(config.validate().is_ok());
+    let mounts = rsjail::Jail::new(config.clone()).resolved_mounts().unwrap();
+    let proc = mounts.iter().find(|mount| mount.dst == "/proc").unwrap();
+    assert_eq!(proc.data.as_deref(), Some("hidepid=2,gid=27"));
+
+    config.proc_hidepid = Some(3);
+    assert!(config.validate().is_err());
+}
+
+/// A config running `exec_bin` with `exec_args` (argv[0] included) in none
+/// of the namespaces, for tests that run a jail for real
+fn host_config(exec_bin: &str, exec_args: &[&str]) -> JailConfig {
+    JailConfig {
+        exec_bin: exec_bin.to_string(),
+        exec_args: exec_args.iter().map(|arg| arg.to_string()).collect(),
+        clone_newpid: false,
+        clone_newnet: false,
+        clone_newns: false,
+        clone_newuts: false,
+        clone_newipc: false,
+        clone_newuser: false,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_leftover_processes_killed() {
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd::{fork, ForkResult};
+
+    let config = host_config("/bin/sh", &["sh", "-c", "sleep 30 & exit 0"]);
+
+    // Its own process, so no other test's children look left behind
+    match unsafe { fork() }.unwrap() {
+        ForkResult::Child => {
+            let code = match rsjail::Jail::new(config).run() {
+                Ok(result) if result.wall_time.as_secs() < 10 => result.leftover_processes,
+                _ => 100,
+            };
+            unsafe { nix::libc::_exit(code as i32) };
+        }
+        ForkResult::Parent { child } => {
+            assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 1));
+        }
+    }
+}
+
+#[test]
+fn test_exec_strings_errors() {
+    use rsjail::jail::exec_strings;
+    use std::collections::BTreeMap;
+
+    let argv = vec!["sh".to_string(), "-c".to_string()];
+    let (args, env) = exec_strings(argv.clone(), [("A".into(), "1".into())].into()).unwrap();
+    assert_eq!(args.len(), 2);
+    assert_eq!(env[0].to_str().unwrap(), "A=1");
+
+    let error = |argv, env| format!("{:#}", exec_strings(argv, env).unwrap_err());
+    let with_nul = vec!["sh".to_string(), "a\0b".to_string()];
+    let message = error(with_nul, BTreeMap::new());
+    assert!(message.contains("argument 1"), "{}", message);
+    assert!(message.contains("a NUL byte at offset 1"), "{}", message);
+
+    let message = error(argv.clone(), [("BAD".into(), "x\0".into())].into());
+    assert!(message.contains("environment variable BAD"), "{}", message);
+
+    let message = error(vec!["x".repeat(200_000)], BTreeMap::new());
+    assert!(message.contains("argument 0"), "{}", message);
+    assert!(message.contains("execve takes at most"), "{}", message);
+}
+
+#[test]
+fn test_exec_via_fd() {
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd::{fork, ForkResult};
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().unwrap();
+    let script = temp_dir.path().join("script");
+    std::fs::write(&script, "#!/bin/sh\nexit 0\n").unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let script = script.to_string_lossy().into_owned();
+
+    let config = JailConfig {
+        exec_via_fd: true,
+        ..host_config("/bin/true", &["true"])
+    };
+    let exit_code = |config: JailConfig| {
+        let result = rsjail::Jail::new(config).run().unwrap();
+        (result.exit_code, result.setup_failed)
+    };
+
+    // Its own process, like test_leftover_processes_killed
+    match unsafe { fork() }.unwrap() {
+        ForkResult::Child => {
+            let ran = exit_code(config.clone()) == (Some(0), false);
+            // The interpreter couldn't reopen the script through the fd
+            let refused = exit_code(JailConfig {
+                exec_bin: script.clone(),
+                exec_args: vec![script],
+                ..config
+            }) == (Some(1), true);
+            unsafe { nix::libc::_exit(i32::from(!(ran && refused))) };
+        }
+        ForkResult::Parent { child } => {
+            assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+        }
+    }
+}
+
+#[test]
+fn test_new_session() {
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd::{fork, ForkResult};
+
+    // The session id is the sixth field of /proc/<pid>/stat
+    let script = r#"read -r pid comm state ppid pgrp sid rest < /proc/$$/stat; [ "$sid" = "$$" ]"#;
+    let config = JailConfig {
+        new_session: true,
+        ..host_config("/bin/sh", &["sh", "-c", script])
+    };
+    let exit_code = |config: JailConfig| rsjail::Jail::new(config).run().unwrap().exit_code;
+
+    // Its own process, like test_leftover_processes_killed
+    match unsafe { fork() }.unwrap() {
+        ForkResult::Child => {
+            let leader = exit_code(config.clone()) == Some(0);
+            let joined = exit_code(JailConfig {
+                new_session: false,
+                ..config
+            }) == Some(1);
+            unsafe { nix::libc::_exit(i32::from(!(leader && joined))) };
+        }
+        ForkResult::Parent { child } => {
+            assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+        }
+    }
+}
+
+#[test]
+fn test_die_with_parent() {
+    use nix::sys::signal::{kill, Signal};
+    use nix::sys::wait::waitpid;
+    use nix::unistd::{fork, ForkResult, Pid};
+    use std::time::{Duration, Instant};
+
+    let mut value = serde_json::to_value(JailConfig::default()).unwrap();
+    value.as_object_mut().unwrap().remove("die_with_parent");
+    let config: JailConfig = serde_json::from_value(value.clone()).unwrap();
+    assert!(config.die_with_parent);
+    value["die_with_parent"] = serde_json::json!(false);
+    let config: JailConfig = serde_json::from_value(value).unwrap();
+    assert!(!config.die_with_parent);
+
+    let config = host_config("/bin/sleep", &["sleep", "30"]);
+    let rsjail = match unsafe { fork() }.unwrap() {
+        ForkResult::Child => {
+            let _ = rsjail::Jail::new(config).run();
+            unsafe { nix::libc::_exit(0) };
+        }
+        ForkResult::Parent { child } => child,
+    };
+
+    // The program, once rsjail has started it
+    let children = format!("/proc/{}/task/{}/children", rsjail, rsjail);
+    let started = Instant::now();
+    let program = loop {
+        let children = std::fs::read_to_string(&children).unwrap_or_default();
+        if let Some(pid) = children.split_whitespace().next() {
+            break Pid::from_raw(pid.parse().unwrap());
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    let comm = format!("/proc/{}/comm", program);
+    while std::fs::read_to_string(&comm).is_ok_and(|comm| comm != "sleep\n") {
+        assert!(started.elapsed() < Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    kill(rsjail, Signal::SIGKILL).unwrap();
+    waitpid(rsjail, None).unwrap();
+    let stat = format!("/proc/{}/stat", program);
+    let gone = || {
+        std::fs::read_to_string(&stat)
+            .map(|stat| stat.contains(") Z "))
+            .unwrap_or(true)
+    };
+    while !gone() {
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "the program outlived rsjail"
+        );
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn test_report_json() {
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd::{fork, ForkResult};
+
+    let config = host_config("/bin/sh", &["sh", "-c", "exit 3"]);
+
+    // Its own process, like test_leftover_processes_killed
+    match unsafe { fork() }.unwrap() {
+        ForkResult::Child => {
+            let report = rsjail::Jail::new(config).run().unwrap().to_json();
+            let ok = report["exit_code"] == 3
+                && report["signal"].is_null()
+                && report["wall_time_us"].as_u64().is_some_and(|us| us > 0)
+                && report["user_time_us"].is_u64()
+                && report["sys_time_us"].is_u64()
+                && report["max_rss_kb"].as_u64().is_some_and(|kb| kb > 0)
+                && report["setup_failed"] == false;
+            unsafe { nix::libc::_exit(i32::from(!ok)) };
+        }
+        ForkResult::Parent { child } => {
+            assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+        }
+    }
+}
+
+#[test]
+fn test_namespaces_stay_with_the_child() {
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd::{fork, ForkResult};
+
+    let config = JailConfig {
+        hostname: Some("rsjail-test".to_string()),
+        clone_newuts: true,
+        clone_newcgroup: true,
+        ..host_config("/bin/true", &["true"])
+    };
+    let link = |ns: &str| std::fs::read_link(format!("/proc/self/ns/{}", ns)).unwrap();
+    let namespaces = || (link("uts"), link("cgroup"));
+
+    // unshare_in_parent moves whoever runs the jail, thread_mode only the
+    // setup thread
+    match unsafe { fork() }.unwrap() {
+        ForkResult::Child => {
+            let host = namespaces();
+            let cloned = rsjail::Jail::new(config.clone()).run().is_ok() && namespaces() == host;
+            let threaded = rsjail::Jail::new(JailConfig {
+                thread_mode: true,
+                ..config.clone()
+            })
+            .run()
+            .is_ok()
+                && namespaces() == host;
+            let unshared = rsjail::Jail::new(JailConfig {
+                unshare_in_parent: true,
+                ..config
+            })
+            .run()
+            .is_ok()
+                && namespaces() != host;
+            let passed = i32::from(cloned) + 2 * i32::from(threaded) + 4 * i32::from(unshared);
+            unsafe { nix::libc::_exit(passed) };
+        }
+        ForkResult::Parent { child } => {
+            assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 7));
+        }
+    }
+}
+
+#[test]
+fn test_thread_mode_validation() {
+    let mut config = JailConfig {
+        thread_mode: true,
+        clone_newpid: false,
+        clone_newuser: false,
+        ..Default::default()
+    };
+    assert!(config.validate().is_ok());
+
+    config.clone_newpid = true;
+    assert!(config.validate().is_err());
+    config.clone_newpid = false;
+    config.clone_newuser = true;
+    assert!(config.validate().is_err());
+    config.clone_newuser = false;
+    config.unshare_in_parent = true;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_exit_code_map() {
+    use nix::sys::signal::Signal;
+    use rsjail::ExitCodeMap;
+
+    let map: ExitCodeMap = serde_json::from_str(r#"{"offset": 10}"#).unwrap();
+    assert_eq!(map.setup_failed, 125);
+    assert_eq!(map.timeout, 124);
+    assert_eq!(map.code(Some(3), None, false, false), 13);
+    assert_eq!(map.code(Some(250), None, false, false), 255);
+    assert_eq!(map.code(None, Some(Signal::SIGTERM), false, false), 143);
+    assert_eq!(map.code(None, Some(Signal::SIGKILL), true, false), 124);
+    assert_eq!(map.code(Some(1), None, false, true), 125);
+
+    let mut config = JailConfig {
+        exit_code_map: map,
+        ..Default::default()
+    };
+    assert!(config.validate().is_ok());
+    config.exit_code_map.timeout = 256;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_spawn_and_kill() {
+    use nix::sys::signal::Signal;
+
+    // A PID namespace keeps other tests' children from looking left behind
+    let jail = rsjail::Jail::new(JailConfig {
+        clone_newpid: true,
+        ..host_config("/bin/sleep", &["sleep", "30"])
+    });
+    let mut handle = jail.spawn().unwrap();
+    assert!(handle.pid().as_raw() > 0);
+    assert_eq!(handle.try_wait().unwrap(), None);
+
+    handle.kill(Signal::SIGKILL).unwrap();
+    let result = handle.wait().unwrap();
+    assert_eq!(result.signal, Some(Signal::SIGKILL));
+    assert!(result.wall_time.as_secs() < 10);
+}
+
+#[test]
+fn test_time_namespace_validation() {
+    let mut config = JailConfig {
+        time_offset_boottime: Some(86400),
+        ..Default::default()
+    };
+    assert!(config.validate().is_err());
+
+    config.clone_newtime = true;
+    assert!(config.validate().is_ok());
+
+    config.no_fork = true;
+    config.clone_newpid = false;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_sync_channel() {
+    use rsjail::sync::{wait_readable, Channel};
+    use std::os::fd::AsRawFd;
+    use std::time::Duration;
+
+    let channel = Channel::to_parent().unwrap();
+    for fd in [&channel.parent, &channel.child] {
+        let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFD) };
+        assert_eq!(flags & libc::FD_CLOEXEC, libc::FD_CLOEXEC);
+    }
+    assert!(!wait_readable(&channel.parent, Some(Duration::ZERO)).unwrap());
+
+    // Dropping the only write end is the handshake: the reader sees EOF
+    let Channel { parent, child } = channel;
+    drop(child);
+    assert!(wait_readable(&parent, Some(Duration::ZERO)).unwrap());
+}
+
+#[test]
+fn test_compose_start_order() {
+    use rsjail::compose::ComposeFile;
+
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("jails.json");
+    let write = |jails: &str| std::fs::write(&path, format!(r#"{{"jails": {}}}"#, jails)).unwrap();
+
+    write(
+        r#"{"web": {"config": "web.json", "depends_on": ["db", "cache"]},
+            "db": {"config": "db.json"},
+            "cache": {"config": "cache.json", "depends_on": ["db"]}}"#,
+    );
+    let compose = ComposeFile::load(&path).unwrap();
+    assert_eq!(compose.start_order().unwrap(), vec!["db", "cache", "web"]);
+    assert_eq!(compose.config_path("db"), temp_dir.path().join("db.json"));
+    assert_eq!(compose.running("db"), None);
+
+    write(
+        r#"{"a": {"config": "a.json", "depends_on": ["b"]},
+            "b": {"config": "b.json", "depends_on": ["a"]}}"#,
+    );
+    assert!(ComposeFile::load(&path).is_err());
+    write(r#"{"a": {"config": "a.json", "depends_on": ["c"]}}"#);
+    assert!(ComposeFile::load(&path).is_err());
+
+    // JSON only, whatever the name suggests
+    let yaml = temp_dir.path().join("jails.yaml");
+    std::fs::write(&yaml, r#"{"jails": {"a": {"config": "a.json"}}}"#).unwrap();
+    let error = ComposeFile::load(&yaml).unwrap_err().to_string();
+    assert!(error.contains("YAML is not supported"), "{}", error);
+}
+
+#[test]
+fn test_held_jail_state() {
+    use rsjail::held::HeldJail;
+
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path();
+    let path = HeldJail::state_file(dir, "web").unwrap();
+    assert!(HeldJail::state_file(dir, "a/b").is_err());
+    assert_eq!(HeldJail::load(dir, "web").unwrap(), None);
+
+    // This test process standing in for the holder under its parent
+    let mut held = HeldJail {
+        config: dir.join("web.json"),
+        jail: JailConfig {
+            uid: Some(1000),
+            ..Default::default()
+        },
+        supervisor: nix::unistd::getppid().as_raw(),
+        holder: std::process::id() as i32,
+        cgroup: None,
+    };
+    held.save(&path).unwrap();
+    assert_eq!(HeldJail::load(dir, "web").unwrap(), Some(held.clone()));
+    let listed = HeldJail::list(dir).unwrap();
+    assert_eq!(listed, vec![("web".to_string(), held.clone())]);
+    // Nothing to freeze without a cgroup of its own
+    assert_eq!(held.is_frozen(), None);
+    assert!(held.freeze(true).is_err());
+
+    // A stand-in for the cgroup, whose cgroup.events the kernel updates
+    let cgroup = dir.join("cgroup");
+    std::fs::create_dir(&cgroup).unwrap();
+    held.cgroup = Some(cgroup.clone());
+    assert!(held.freeze(true).is_err());
+    std::fs::write(cgroup.join("cgroup.freeze"), "0").unwrap();
+    std::fs::write(cgroup.join("cgroup.events"), "populated 1\nfrozen 0\n").unwrap();
+    assert_eq!(held.is_frozen(), Some(false));
+    std::fs::write(cgroup.join("cgroup.events"), "populated 1\nfrozen 1\n").unwrap();
+    held.freeze(true).unwrap();
+    let written = std::fs::read_to_string(cgroup.join("cgroup.freeze")).unwrap();
+    assert_eq!(written, "1");
+    assert_eq!(held.is_frozen(), Some(true));
+
+    held.supervisor = 1;
+    held.save(&path).unwrap();
+    assert_eq!(HeldJail::load(dir, "web").unwrap(), None);
+}
+
+#[test]
+fn test_memory_sampling() {
+    use rsjail::sampler::{MemoryPeaks, MemorySampler};
+    use std::time::Duration;
+
+    let mut sleep = std::process::Command::new("sleep")
+        .arg("5")
+        .spawn()
+        .unwrap();
+    let pid = nix::unistd::Pid::from_raw(sleep.id() as i32);
+    let mut peaks = MemoryPeaks::default();
+    peaks.sample(pid);
+    assert_eq!(peaks.samples, 1);
+    assert!(peaks.vm_hwm_kb > 0 && peaks.vm_peak_kb >= peaks.vm_hwm_kb);
+    assert!(peaks.total_rss_kb > 0);
+
+    let sampler = MemorySampler::start(pid, Duration::from_millis(10)).unwrap();
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(sampler.finish().samples > 1);
+    sleep.kill().unwrap();
+    sleep.wait().unwrap();
+
+    let config = JailConfig {
+        sample_interval_ms: Some(10),
+        ..Default::default()
+    };
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_required_capabilities() {
+    let config = JailConfig {
+        chroot_dir: Some("/tmp/jail".to_string()),
+        uid: Some(1000),
+        ambient_caps: vec!["net_bind_service".to_string()],
+        clone_newuser: false,
+        ..Default::default()
+    };
+    assert_eq!(
+        config.required_capabilities(),
+        [
+            "net_bind_service",
+            "setgid",
+            "setuid",
+            "sys_admin",
+            "sys_chroot"
+        ]
+    );
+
+    // The user namespace grants them to the child
+    let config = JailConfig {
+        clone_newuser: true,
+        ..config
+    };
+    assert!(config.required_capabilities().is_empty());
+
+    assert!(rsjail::caps::missing(&[]).unwrap().is_empty());
+    assert!(rsjail::caps::missing(&["no_such_cap".to_string()]).is_err());
+}
+
+#[test]
+fn test_userns_mount_validation() {
+    let mut config = JailConfig {
+        mounts: vec![MountConfig {
+            src: "/dev/sdb1".to_string(),
+            dst: "/data".to_string(),
+            fstype: Some("ext4".to_string()),
+            is_bind: false,
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let error = config.validate().unwrap_err().to_string();
+    assert!(error.contains("/data") && error.contains("user namespace"));
+
+    config.clone_newuser = false;
+    assert!(config.validate().is_ok());
+
+    config.clone_newuser = true;
+    config.mounts[0].fstype = Some("tmpfs".to_string());
+    assert!(config.validate().is_ok());
+}
+
+#[test]
+fn test_env_file() {
+    let vars = rsjail::config::parse_env_file(
+        "# shared settings\n\
+         LANG=C.UTF-8\n\
+         export APP_MODE=production # inline comment\n\
+         \n\
+         GREETING=\"hello \\\"jail\\\"\\nbye\"\n\
+         RAW='no $expansion \\n here'\n\
+         EMPTY=\n",
+    )
+    .unwrap();
+    assert_eq!(
+        vars,
+        [
+            ("LANG".to_string(), "C.UTF-8".to_string()),
+            ("APP_MODE".to_string(), "production".to_string()),
+            ("GREETING".to_string(), "hello \"jail\"\nbye".to_string()),
+            ("RAW".to_string(), "no $expansion \\n here".to_string()),
+            ("EMPTY".to_string(), String::new()),
+        ]
+    );
+    assert!(rsjail::config::parse_env_file("NOT A VAR").is_err());
+    assert!(rsjail::config::parse_env_file("1X=y").is_err());
+    assert!(rsjail::config::parse_env_file("X=\"open").is_err());
+
+    let path = std::env::temp_dir().join(format!("rsjail-env-{}", std::process::id()));
+    std::fs::write(&path, "LANG=C\nTZ=UTC\n").unwrap();
+    let mut config = JailConfig {
+        env_file: Some(path.to_string_lossy().into_owned()),
+        ..Default::default()
+    };
+    config.env.insert("LANG".to_string(), "C.UTF-8".to_string());
+    config.load_env_file().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(config.env["LANG"], "C.UTF-8");
+    assert_eq!(config.env["TZ"], "UTC");
+}
+
+#[test]
+fn test_resource_limits() {
+    let limits = rsjail::env::resource_limits();
+    assert_eq!(limits.len(), rsjail::env::RLIMITS.len());
+    for (resource, soft, hard) in limits {
+        assert!(
+            soft <= hard,
+            "{:?} soft {} above hard {}",
+            resource,
+            soft,
+            hard
+        );
+    }
+}
+
+#[test]
+fn test_interpreter() {
+    let mut config = JailConfig {
+        interpreter: Some("qemu-aarch64-static".to_string()),
+        ..Default::default()
+    };
+    assert!(config.validate().is_err());
+
+    config.interpreter = Some("/usr/bin/qemu-aarch64-static".to_string());
+    assert!(config.validate().is_ok());
+    let argv = rsjail::Jail::new(config.clone()).exec_argv();
+    assert_eq!(argv, ["/usr/bin/qemu-aarch64-static", "/bin/sh"]);
+
+    config.wrapper = Some(vec!["/usr/bin/strace".to_string()]);
+    assert!(config.validate().is_err());
+
+    // A native exec_bin needs no interpreter
+    let config = JailConfig {
+        interpreter: Some("auto".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(rsjail::Jail::new(config).exec_argv(), ["/bin/sh"]);
+
+    // Assumes an x86_64 or aarch64 host, which qemu names as Rust does
+    let sh = rsjail::elf::parse(std::path::Path::new("/bin/sh")).unwrap();
+    assert_eq!(rsjail::elf::qemu_arch(&sh), Some(std::env::consts::ARCH));
+}
+
+#[test]
+fn test_require_writable_chroot() {
+    let dir = TempDir::new().unwrap();
+    let config = JailConfig {
+        // Not created yet, so checked where it would be
+        chroot_dir: Some(dir.path().join("jail").to_string_lossy().into_owned()),
+        require_writable_chroot: true,
+        ..Default::default()
+    };
+    assert!(config.validate().is_ok());
+}
+
+#[test]
+fn test_init() {
+    let mut config = JailConfig {
+        init: true,
+        init_helper: Some("/sbin/tini".to_string()),
+        ..Default::default()
+    };
+    assert!(config.validate().is_ok());
+    assert_eq!(
+        rsjail::Jail::new(config.clone()).exec_argv(),
+        ["/sbin/tini", "--", "/bin/sh"]
+    );
+
+    config.init = false;
+    assert!(config.validate().is_err());
+
+    // rsjail's own init forks the program with its argv unchanged
+    config.init = true;
+    config.init_helper = None;
+    assert_eq!(rsjail::Jail::new(config.clone()).exec_argv(), ["/bin/sh"]);
+
+    config.clone_newpid = false;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_supplementary_groups() {
+    let mut config = JailConfig {
+        clone_newuser: false,
+        uid: Some(1000),
+        groups: vec![100],
+        ..Default::default()
+    };
+    assert!(config.drop_groups);
+    assert!(config.switches_groups());
+    assert!(config.validate().is_ok());
+
+    // setgroups is denied in a user namespace
+    config.clone_newuser = true;
+    assert!(!config.switches_groups());
+    assert!(config.validate().is_err());
+
+    config.clone_newuser = false;
+    config.drop_groups = false;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_syscall_limits() {
+    assert_eq!(
+        rsjail::seccomp::syscall_number("clone"),
+        Some(libc::SYS_clone)
+    );
+    assert_eq!(rsjail::seccomp::syscall_number("no_such_call"), None);
+
+    let mut config = JailConfig {
+        syscall_limits: [("clone".to_string(), 10), ("clone3".to_string(), 10)].into(),
+        ..Default::default()
+    };
+    assert!(config.validate().is_ok());
+
+    config.no_fork = true;
+    config.clone_newpid = false;
+    assert!(config.validate().is_err());
+
+    config.no_fork = false;
+    config.syscall_limits.insert("no_such_call".to_string(), 1);
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_seccomp_policy_filter() {
+    use rsjail::{SeccompAction, SeccompPolicy};
+
+    let mut json = serde_json::to_value(JailConfig::default()).unwrap();
+    json["seccomp_policy"] =
+        serde_json::json!({"default_action": "log", "syscalls": {"ptrace": "errno"}});
+    let mut config: JailConfig = serde_json::from_value(json).unwrap();
+    assert!(config.validate().is_ok());
+    let policy = config.seccomp_policy.clone().unwrap();
+    assert_eq!(policy.default_action, SeccompAction::Log);
+
+    // Observe-only: whatever isn't listed is logged and let through
+    let ret = (libc::BPF_RET | libc::BPF_K) as u16;
+    let filter = rsjail::seccomp::policy_filter(&policy).unwrap();
+    let last = filter.last().unwrap();
+    assert_eq!((last.code, last.k), (ret, libc::SECCOMP_RET_LOG));
+    let ptrace = filter
+        .iter()
+        .position(|s| s.k == libc::SYS_ptrace as u32)
+        .unwrap();
+    let denied = filter[ptrace + 1];
+    let errno = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+    assert_eq!((denied.code, denied.k), (ret, errno));
+
+    let policy = SeccompPolicy::default();
+    let filter = rsjail::seccomp::policy_filter(&policy).unwrap();
+    assert_eq!(filter.last().unwrap().k, libc::SECCOMP_RET_ALLOW);
+
+    config.seccomp_policy = Some(SeccompPolicy {
+        syscalls: [("no_such_call".to_string(), SeccompAction::Log)].into(),
+        ..policy
+    });
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_network_mode() {
+    let mut config = JailConfig {
+        clone_newnet: true,
+        ..Default::default()
+    };
+    assert_eq!(config.network_mode(), NetworkMode::None);
+    config.clone_newnet = false;
+    assert_eq!(config.network_mode(), NetworkMode::Host);
+
+    // Overrides clone_newnet either way
+    config.network_mode = Some(NetworkMode::Loopback);
+    assert!(config.net_namespace());
+    config.clone_newnet = true;
+    config.network_mode = Some(NetworkMode::Host);
+    assert!(!config.net_namespace());
+
+    config.max_connections = Some(10);
+    assert!(config.validate().is_err());
+    config.max_connections = None;
+    config.network_mode = Some(NetworkMode::Veth);
+    let error = config.validate().unwrap_err().to_string();
+    assert!(error.contains("veth is not supported"), "{}", error);
+}
+
+#[test]
+fn test_setup_steps() {
+    let mut config = JailConfig::default();
+    assert_eq!(config.setup_steps(), SetupStep::DEFAULT_ORDER);
+    let step_problems = |config: &JailConfig| {
+        config
+            .problems()
+            .iter()
+            .filter(|e| e.to_string().contains("setup_steps"))
+            .count()
+    };
+    assert_eq!(step_problems(&config), 0);
+
+    // Rlimits and seccomp may go anywhere, or be left out
+    config.setup_steps = Some(vec![
+        SetupStep::Seccomp,
+        SetupStep::Mounts,
+        SetupStep::Setuid,
+    ]);
+    assert_eq!(step_problems(&config), 0);
+
+    for steps in [
+        vec![SetupStep::Setuid, SetupStep::Mounts],
+        vec![SetupStep::Landlock, SetupStep::Mounts],
+        vec![SetupStep::Caps, SetupStep::Setuid],
+        vec![SetupStep::Seccomp, SetupStep::NoNewPrivs],
+        vec![SetupStep::Rlimits, SetupStep::Rlimits],
+    ] {
+        config.setup_steps = Some(steps);
+        assert_eq!(step_problems(&config), 1);
+    }
+
+    config.setup_steps = Some(vec![SetupStep::Rlimits, SetupStep::Mounts]);
+    assert_eq!(step_problems(&config), 0);
+    config.thread_mode = true;
+    assert_eq!(step_problems(&config), 1);
+
+    // A step can't be left out while its settings are set
+    let leave_out = |step: SetupStep, config: JailConfig| {
+        let steps = SetupStep::DEFAULT_ORDER.into_iter();
+        assert_eq!(step_problems(&config), 0);
+        step_problems(&JailConfig {
+            setup_steps: Some(steps.filter(|&s| s != step).collect()),
+            ..config
+        })
+    };
+    let config = JailConfig::default();
+    for step in SetupStep::DEFAULT_ORDER {
+        assert_eq!(leave_out(step, config.clone()), 0);
+    }
+    let cases = [
+        (
+            SetupStep::Mounts,
+            JailConfig {
+                chroot_dir: Some("/tmp/jail".to_string()),
+                ..config.clone()
+            },
+        ),
+        (
+            SetupStep::Rlimits,
+            JailConfig {
+                rlimit_nofile: Some(Rlimit::Both(64)),
+                ..config.clone()
+            },
+        ),
+        (
+            SetupStep::Setuid,
+            JailConfig {
+                uid: Some(1000),
+                ..config.clone()
+            },
+        ),
+        (
+            SetupStep::Caps,
+            JailConfig {
+                uid: Some(1000),
+                ambient_caps: vec!["CAP_NET_BIND_SERVICE".to_string()],
+                ..config.clone()
+            },
+        ),
+        (
+            SetupStep::NoNewPrivs,
+            JailConfig {
+                no_new_privs: true,
+                ..config.clone()
+            },
+        ),
+        (
+            SetupStep::Landlock,
+            JailConfig {
+                landlock: vec![rsjail::LandlockRule {
+                    path: "/usr".to_string(),
+                    access: vec![rsjail::LandlockAccess::Read],
+                }],
+                ..config.clone()
+            },
+        ),
+        (
+            SetupStep::Seccomp,
+            JailConfig {
+                seccomp_policy: Some(rsjail::SeccompPolicy::default()),
+                ..config.clone()
+            },
+        ),
+        (
+            SetupStep::Seccomp,
+            JailConfig {
+                syscall_limits: [("fork".to_string(), 10)].into(),
+                ..config.clone()
+            },
+        ),
+    ];
+    for (step, config) in cases {
+        assert_eq!(leave_out(step, config), 1, "{:?}", step);
+    }
+}
+
+#[test]
+fn test_setup_timings() {
+    use rsjail::sync::Channel;
+    use rsjail::timings::{self, SetupTimer};
+    use std::os::fd::AsRawFd;
+    use std::time::Duration;
+
+    let pipe = Channel::to_parent().unwrap();
+    let fd = Some(pipe.child.as_raw_fd());
+    let timer = SetupTimer::start(pipe.parent, 0).unwrap();
+    let slept = timings::time(fd, "sleep", || {
+        std::thread::sleep(Duration::from_millis(5));
+        "slept"
+    });
+    assert_eq!(slept, "slept");
+    timings::exec_next(fd);
+    drop(pipe.child);
+
+    let phases = timer.finish();
+    let names: Vec<_> = phases.iter().map(|phase| phase.name.as_str()).collect();
+    assert_eq!(names, ["sleep", "exec"]);
+    assert!(phases[0].duration >= Duration::from_millis(5));
+
+    // Without a pipe only the call is made
+    assert_eq!(timings::time(None, "none", || 1), 1);
+}
+
+#[test]
+fn test_mount_guard() {
+    use nix::mount::{mount, MsFlags};
+    use nix::sched::{unshare, CloneFlags};
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd::{fork, ForkResult};
+    use rsjail::scratch::MountGuard;
+
+    let temp_dir = TempDir::new().unwrap();
+    let root = temp_dir.path();
+    let tmpfs = |target: &std::path::Path| {
+        std::fs::create_dir_all(target).unwrap();
+        mount(
+            Some("tmpfs"),
+            target,
+            Some("tmpfs"),
+            MsFlags::empty(),
+            None::<&str>,
+        )
+        .is_ok()
+    };
+
+    // A mount namespace of its own, so nothing can leak onto the host
+    match unsafe { fork() }.unwrap() {
+        ForkResult::Child => {
+            let private = MsFlags::MS_REC | MsFlags::MS_PRIVATE;
+            let unshared = unshare(CloneFlags::CLONE_NEWNS).is_ok()
+                && mount(None::<&str>, "/", None::<&str>, private, None::<&str>).is_ok();
+            let mut guard = MountGuard::new(root);
+            // b only exists inside a's tmpfs
+            let mounted = unshared && tmpfs(&root.join("a")) && tmpfs(&root.join("a/b"));
+            let unmounted = guard.unmount() == 2 && guard.unmount() == 0;
+            let passed = mounted && unmounted && !root.join("a/b").exists();
+            unsafe { nix::libc::_exit(i32::from(!passed)) };
+        }
+        ForkResult::Parent { child } => {
+            assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+        }
+    }
+}
+
+#[test]
+fn test_exec_path() {
+    let temp_dir = TempDir::new().unwrap();
+    std::fs::create_dir_all(temp_dir.path().join("usr/bin")).unwrap();
+    let mut config = JailConfig {
+        chroot_dir: Some(temp_dir.path().to_string_lossy().into_owned()),
+        forward_term: Some(false),
+        mounts: vec![MountConfig {
+            src: "/bin/sh".to_string(),
+            dst: "/sbin/sh".to_string(),
+            is_bind: true,
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    // Only what the jail has, a mount below a directory counting too
+    let env = rsjail::Jail::new(config.clone()).environment();
+    assert_eq!(env["PATH"], "/usr/bin:/sbin");
+
+    config.exec_path = Some("/opt/bin".to_string());
+    assert_eq!(
+        rsjail::Jail::new(config.clone()).environment()["PATH"],
+        "/opt/bin"
+    );
+    assert!(config.validate().is_ok());
+    config.env.insert("PATH".to_string(), "/bin".to_string());
+    assert!(config.validate().is_err());
+}